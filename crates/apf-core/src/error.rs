@@ -8,12 +8,30 @@ pub enum ApfError {
     #[error("Permission denied")]
     PermissionDenied,
 
+    #[error("Not authorized: {0}")]
+    NotAuthorized(String),
+
+    #[error("Invalid arguments: {0}")]
+    InvalidArgs(String),
+
     #[error("Invalid application ID: {0}")]
     InvalidAppId(String),
 
+    #[error("Invalid permission: {0}")]
+    InvalidPermission(String),
+
     #[error("Policy not found for app: {0}")]
     PolicyNotFound(String),
 
+    #[error("Policy is locked by a system-wide rule: {0}")]
+    PolicyLocked(String),
+
+    #[error("Request expired: {0}")]
+    RequestExpired(String),
+
+    #[error("Storage failure: {0}")]
+    Storage(String),
+
     #[error("DBus error: {0}")]
     DBus(String),
 
@@ -28,3 +46,74 @@ pub enum ApfError {
 }
 
 pub type Result<T> = std::result::Result<T, ApfError>;
+
+/// Prefix of the D-Bus error names the daemon replies with.
+pub const DBUS_ERROR_PREFIX: &str = "org.apf.Error";
+
+impl ApfError {
+    /// The D-Bus error name for this error, e.g. `org.apf.Error.PolicyLocked`.
+    pub fn dbus_name(&self) -> &'static str {
+        match self {
+            ApfError::Io(_) => "org.apf.Error.Io",
+            ApfError::PermissionDenied => "org.apf.Error.PermissionDenied",
+            ApfError::NotAuthorized(_) => "org.apf.Error.NotAuthorized",
+            ApfError::InvalidArgs(_) => "org.apf.Error.InvalidArgs",
+            ApfError::InvalidAppId(_) => "org.apf.Error.InvalidAppId",
+            ApfError::InvalidPermission(_) => "org.apf.Error.InvalidPermission",
+            ApfError::PolicyNotFound(_) => "org.apf.Error.PolicyNotFound",
+            ApfError::PolicyLocked(_) => "org.apf.Error.PolicyLocked",
+            ApfError::RequestExpired(_) => "org.apf.Error.RequestExpired",
+            ApfError::Storage(_) => "org.apf.Error.Storage",
+            ApfError::DBus(_) => "org.apf.Error.DBus",
+            ApfError::EnforcementFailed(_) => "org.apf.Error.EnforcementFailed",
+            ApfError::InvalidConfig(_) => "org.apf.Error.InvalidConfig",
+            ApfError::Unknown(_) => "org.apf.Error.Unknown",
+        }
+    }
+
+    /// The text carried in a D-Bus error reply: the error's detail, without
+    /// the description its name already gives.
+    pub fn dbus_message(&self) -> String {
+        match self {
+            ApfError::Io(e) => e.to_string(),
+            ApfError::PermissionDenied => String::new(),
+            ApfError::NotAuthorized(detail)
+            | ApfError::InvalidArgs(detail)
+            | ApfError::InvalidAppId(detail)
+            | ApfError::InvalidPermission(detail)
+            | ApfError::PolicyNotFound(detail)
+            | ApfError::PolicyLocked(detail)
+            | ApfError::RequestExpired(detail)
+            | ApfError::Storage(detail)
+            | ApfError::DBus(detail)
+            | ApfError::EnforcementFailed(detail)
+            | ApfError::InvalidConfig(detail)
+            | ApfError::Unknown(detail) => detail.clone(),
+        }
+    }
+
+    /// Maps a D-Bus error reply back to the error it was created from.
+    /// Names outside `org.apf.Error` give `None`, so clients can fall back
+    /// to their handling of generic D-Bus errors.
+    pub fn from_dbus(name: &str, message: &str) -> Option<Self> {
+        let detail = message.to_string();
+        let error = match name.strip_prefix(DBUS_ERROR_PREFIX)?.strip_prefix('.')? {
+            "Io" => ApfError::Io(std::io::Error::other(detail)),
+            "PermissionDenied" => ApfError::PermissionDenied,
+            "NotAuthorized" => ApfError::NotAuthorized(detail),
+            "InvalidArgs" => ApfError::InvalidArgs(detail),
+            "InvalidAppId" => ApfError::InvalidAppId(detail),
+            "InvalidPermission" => ApfError::InvalidPermission(detail),
+            "PolicyNotFound" => ApfError::PolicyNotFound(detail),
+            "PolicyLocked" => ApfError::PolicyLocked(detail),
+            "RequestExpired" => ApfError::RequestExpired(detail),
+            "Storage" => ApfError::Storage(detail),
+            "DBus" => ApfError::DBus(detail),
+            "EnforcementFailed" => ApfError::EnforcementFailed(detail),
+            "InvalidConfig" => ApfError::InvalidConfig(detail),
+            "Unknown" => ApfError::Unknown(detail),
+            _ => return None,
+        };
+        Some(error)
+    }
+}
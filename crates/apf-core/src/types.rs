@@ -1,6 +1,17 @@
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
+/// A permission an app can request.
+///
+/// Besides its serde form, every permission has a stable textual form used
+/// for database keys and on the command line (see `Display` and `FromStr`):
+/// `net:none`, `net:lan`, `net:internet`, `net:host:<domain>[?port=..&proto=..]`,
+/// `net:cidr:<addr>/<prefix>[?port=..&proto=..]`, `fs:<path>?mode=ro|rw|deny`,
+/// `device:microphone|camera|screen|usb`, `clipboard`, `background` and
+/// `autostart`. In `fs:` paths, `%` and `?` and bytes that are not UTF-8
+/// are written as `%XX`; a path must be absolute or start with a `PathVar`
+/// placeholder, and may not contain `..`.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum PermissionType {
     Network(NetworkLevel),
@@ -16,14 +27,331 @@ pub enum NetworkLevel {
     None,
     Lan,
     Internet,
+    /// Connections to the destinations a rule describes, e.g.
+    /// `*.example-chat.com` on TCP port 443.
+    Rule(NetworkRule),
 }
 
+/// A set of network destinations. Unset ports and protocol match any.
+///
+/// A request for a single destination is itself a rule: an exact host name
+/// or a full-length CIDR, one port and one protocol.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct NetworkRule {
+    pub host: HostPattern,
+    #[serde(default)]
+    pub ports: Option<PortRange>,
+    #[serde(default)]
+    pub protocol: Option<Protocol>,
+}
+
+/// Where a rule reaches. Deserializing a `Domain` that isn't a host name
+/// or wildcard pattern fails; names are lowercased.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "UncheckedHostPattern")]
+pub enum HostPattern {
+    /// A host name, or `*.<domain>` for every name below `domain` (but not
+    /// `domain` itself).
+    Domain(String),
+    Cidr(Cidr),
+}
+
+#[derive(Deserialize)]
+enum UncheckedHostPattern {
+    Domain(String),
+    Cidr(Cidr),
+}
+
+impl TryFrom<UncheckedHostPattern> for HostPattern {
+    type Error = crate::error::ApfError;
+
+    fn try_from(unchecked: UncheckedHostPattern) -> Result<Self, Self::Error> {
+        match unchecked {
+            UncheckedHostPattern::Domain(name) if is_host_pattern(&name) => Ok(HostPattern::Domain(name.to_ascii_lowercase())),
+            UncheckedHostPattern::Domain(name) => Err(crate::error::ApfError::InvalidPermission(format!("net:host:{}", name))),
+            UncheckedHostPattern::Cidr(cidr) => Ok(HostPattern::Cidr(cidr)),
+        }
+    }
+}
+
+/// A host name of letters, digits and hyphens, optionally behind `*.`.
+fn is_host_pattern(name: &str) -> bool {
+    let labels = name.strip_prefix("*.").unwrap_or(name);
+    !labels.is_empty()
+        && labels.split('.').all(|label| {
+            !label.is_empty() && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// An address block; deserializing one with a prefix longer than its
+/// address fails.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "UncheckedCidr")]
+pub struct Cidr {
+    pub addr: std::net::IpAddr,
+    pub prefix: u8,
+}
+
+#[derive(Deserialize)]
+struct UncheckedCidr {
+    addr: std::net::IpAddr,
+    prefix: u8,
+}
+
+impl TryFrom<UncheckedCidr> for Cidr {
+    type Error = crate::error::ApfError;
+
+    fn try_from(unchecked: UncheckedCidr) -> Result<Self, Self::Error> {
+        let cidr = Cidr { addr: unchecked.addr, prefix: unchecked.prefix };
+        if !cidr.is_valid() {
+            return Err(crate::error::ApfError::InvalidPermission(format!("net:cidr:{}/{}", cidr.addr, cidr.prefix)));
+        }
+        Ok(cidr)
+    }
+}
+
+/// An inclusive port range; deserializing one that ends before it starts
+/// fails.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "UncheckedPortRange")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+#[derive(Deserialize)]
+struct UncheckedPortRange {
+    start: u16,
+    end: u16,
+}
+
+impl TryFrom<UncheckedPortRange> for PortRange {
+    type Error = crate::error::ApfError;
+
+    fn try_from(unchecked: UncheckedPortRange) -> Result<Self, Self::Error> {
+        if unchecked.start > unchecked.end {
+            return Err(crate::error::ApfError::InvalidPermission(format!("port={}-{}", unchecked.start, unchecked.end)));
+        }
+        Ok(PortRange { start: unchecked.start, end: unchecked.end })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl NetworkRule {
+    /// Whether every destination `other` describes is also described by
+    /// this rule. Host names and addresses never cover each other; no name
+    /// resolution takes place.
+    pub fn covers(&self, other: &NetworkRule) -> bool {
+        let host = match (&self.host, &other.host) {
+            (HostPattern::Domain(pattern), HostPattern::Domain(name)) => domain_covers(pattern, name),
+            (HostPattern::Cidr(outer), HostPattern::Cidr(inner)) => outer.covers(inner),
+            _ => false,
+        };
+        let ports = match (self.ports, other.ports) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(outer), Some(inner)) => outer.start <= inner.start && inner.end <= outer.end,
+        };
+        let protocol = self.protocol.is_none() || self.protocol == other.protocol;
+        host && ports && protocol
+    }
+
+    /// Orders rules covering the same destination: the greater value is the
+    /// more specific rule.
+    pub fn specificity(&self) -> (u32, u32, bool) {
+        let host = match &self.host {
+            HostPattern::Domain(name) => match name.strip_prefix("*.") {
+                Some(domain) => domain.split('.').count() as u32,
+                None => u32::MAX,
+            },
+            HostPattern::Cidr(cidr) => cidr.prefix as u32,
+        };
+        let ports = self.ports.map_or(0, |range| 65536 - range.end.saturating_sub(range.start) as u32);
+        (host, ports, self.protocol.is_some())
+    }
+
+    /// Whether every destination lies on the local network: private,
+    /// loopback and link-local addresses. Host names are never local.
+    pub fn is_lan(&self) -> bool {
+        match &self.host {
+            HostPattern::Domain(_) => false,
+            HostPattern::Cidr(cidr) => Cidr::LAN.iter().any(|lan| lan.covers(cidr)),
+        }
+    }
+}
+
+impl Cidr {
+    const LAN: [Cidr; 7] = [
+        Cidr::v4([10, 0, 0, 0], 8),
+        Cidr::v4([172, 16, 0, 0], 12),
+        Cidr::v4([192, 168, 0, 0], 16),
+        Cidr::v4([127, 0, 0, 0], 8),
+        Cidr::v4([169, 254, 0, 0], 16),
+        Cidr { addr: std::net::IpAddr::V6(std::net::Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0)), prefix: 7 },
+        Cidr { addr: std::net::IpAddr::V6(std::net::Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)), prefix: 10 },
+    ];
+
+    const fn v4(octets: [u8; 4], prefix: u8) -> Self {
+        Cidr { addr: std::net::IpAddr::V4(std::net::Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])), prefix }
+    }
+
+    /// A block holding just `addr`.
+    pub fn host(addr: std::net::IpAddr) -> Self {
+        let prefix = if addr.is_ipv4() { 32 } else { 128 };
+        Cidr { addr, prefix }
+    }
+
+    /// Whether the prefix fits the address: at most 32 bits for IPv4, 128
+    /// for IPv6.
+    pub fn is_valid(&self) -> bool {
+        self.prefix <= Cidr::host(self.addr).prefix
+    }
+
+    /// Whether every address in `other` is in this block. Invalid blocks
+    /// cover nothing and are covered by nothing.
+    pub fn covers(&self, other: &Cidr) -> bool {
+        use std::net::IpAddr;
+        if !self.is_valid() || !other.is_valid() || other.prefix < self.prefix {
+            return false;
+        }
+        match (self.addr, other.addr) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(a) & mask == u32::from(b) & mask
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(a) & mask == u128::from(b) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Host names compare case-insensitively and without a trailing dot.
+fn domain_covers(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    if pattern == name {
+        return true;
+    }
+    let Some(domain) = pattern.strip_prefix("*.") else {
+        return false;
+    };
+    let inner = name.strip_prefix("*.").unwrap_or(&name);
+    inner.strip_suffix(domain).is_some_and(|label| label.ends_with('.'))
+}
+
+/// Access to a file or directory tree.
+///
+/// `path` is either absolute or starts with a `PathVar` placeholder, such
+/// as `$XDG_DOCUMENTS_DIR/invoices`, which stands for a directory of the
+/// requesting user and is resolved when a request is evaluated or a
+/// sandbox is set up. Paths never contain `..`, so rules on a directory
+/// can compare by prefix; deserializing such a path fails.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "UncheckedFilesystemAccess")]
 pub struct FilesystemAccess {
     pub path: PathBuf,
     pub mode: AccessMode,
 }
 
+/// `FilesystemAccess` as it comes off the wire, before its path is checked.
+#[derive(Deserialize)]
+struct UncheckedFilesystemAccess {
+    path: PathBuf,
+    mode: AccessMode,
+}
+
+impl TryFrom<UncheckedFilesystemAccess> for FilesystemAccess {
+    type Error = crate::error::ApfError;
+
+    fn try_from(unchecked: UncheckedFilesystemAccess) -> Result<Self, Self::Error> {
+        let fs = FilesystemAccess { path: unchecked.path, mode: unchecked.mode };
+        if !fs.is_valid() {
+            return Err(crate::error::ApfError::InvalidPermission(PermissionType::Filesystem(fs).to_string()));
+        }
+        Ok(fs)
+    }
+}
+
+/// A placeholder for a per-user directory at the start of a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PathVar {
+    Home,
+    Desktop,
+    Documents,
+    Download,
+    Music,
+    Pictures,
+    Videos,
+    Runtime,
+    /// The app's own data directory.
+    AppData,
+}
+
+impl PathVar {
+    pub const ALL: [PathVar; 9] = [
+        PathVar::Home, PathVar::Desktop, PathVar::Documents, PathVar::Download, PathVar::Music,
+        PathVar::Pictures, PathVar::Videos, PathVar::Runtime, PathVar::AppData,
+    ];
+
+    /// The placeholder as written in paths, e.g. `$XDG_DOCUMENTS_DIR`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PathVar::Home => "$HOME",
+            PathVar::Desktop => "$XDG_DESKTOP_DIR",
+            PathVar::Documents => "$XDG_DOCUMENTS_DIR",
+            PathVar::Download => "$XDG_DOWNLOAD_DIR",
+            PathVar::Music => "$XDG_MUSIC_DIR",
+            PathVar::Pictures => "$XDG_PICTURES_DIR",
+            PathVar::Videos => "$XDG_VIDEOS_DIR",
+            PathVar::Runtime => "$XDG_RUNTIME_DIR",
+            PathVar::AppData => "$APP_DATA",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|var| var.name() == name)
+    }
+}
+
+impl FilesystemAccess {
+    /// Whether the path is absolute or starts with a placeholder, and has
+    /// no `..` in it.
+    fn is_valid(&self) -> bool {
+        (self.path.is_absolute() || self.path_var().is_some())
+            && !self.path.components().any(|component| component == std::path::Component::ParentDir)
+    }
+
+    /// The placeholder the path starts with, if any.
+    pub fn path_var(&self) -> Option<PathVar> {
+        match self.path.components().next()? {
+            std::path::Component::Normal(first) => PathVar::from_name(first.to_str()?),
+            _ => None,
+        }
+    }
+
+    /// This access with its placeholder replaced by the directory `lookup`
+    /// gives for it. Absolute paths are returned unchanged; `None` when the
+    /// path starts with an unknown placeholder or `lookup` has no directory.
+    pub fn resolve(&self, lookup: impl FnOnce(PathVar) -> Option<PathBuf>) -> Option<FilesystemAccess> {
+        if self.path.is_absolute() {
+            return Some(self.clone());
+        }
+        let var = self.path_var()?;
+        let rest = self.path.strip_prefix(var.name()).ok()?;
+        let dir = lookup(var)?;
+        let path = if rest.as_os_str().is_empty() { dir } else { dir.join(rest) };
+        Some(FilesystemAccess { path, mode: self.mode.clone() })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum AccessMode {
     ReadOnly,
@@ -53,6 +381,107 @@ pub enum PromptDecision {
     DenyOnce,
     DenyAlways,
     AllowDuration(std::time::Duration),
+    /// Allow while `Schedule` is active; no decision outside it.
+    AllowDuring(Schedule),
+    /// Deny while `Schedule` is active; no decision outside it.
+    DenyDuring(Schedule),
+    /// Prompt on every request, whatever the default for the permission.
+    Ask,
+    /// Allow until the requesting app instance exits.
+    AllowUntilAppExit,
+    /// Allow until the user's login session ends.
+    AllowForLoginSession,
+}
+
+impl PromptDecision {
+    pub fn is_allow(&self) -> bool {
+        matches!(
+            self,
+            PromptDecision::AllowOnce
+                | PromptDecision::AllowAlways
+                | PromptDecision::AllowDuration(_)
+                | PromptDecision::AllowDuring(_)
+                | PromptDecision::AllowUntilAppExit
+                | PromptDecision::AllowForLoginSession
+        )
+    }
+
+    /// Whether the decision lasts as long as a process or login session
+    /// rather than being stored as a policy.
+    pub fn is_lifetime_scoped(&self) -> bool {
+        matches!(self, PromptDecision::AllowUntilAppExit | PromptDecision::AllowForLoginSession)
+    }
+
+    /// Whether the decision leaves the request to the user.
+    pub fn is_ask(&self) -> bool {
+        *self == PromptDecision::Ask
+    }
+
+    pub fn schedule(&self) -> Option<&Schedule> {
+        match self {
+            PromptDecision::AllowDuring(schedule) | PromptDecision::DenyDuring(schedule) => Some(schedule),
+            _ => None,
+        }
+    }
+
+    /// Whether the decision applies at local time `at`. Unconditional
+    /// decisions always apply.
+    pub fn applies_at(&self, at: NaiveDateTime) -> bool {
+        self.schedule().is_none_or(|schedule| schedule.is_active(at))
+    }
+}
+
+/// A set of weekly time windows, evaluated against local time.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Schedule {
+    pub windows: Vec<TimeWindow>,
+}
+
+impl Schedule {
+    pub fn is_active(&self, at: NaiveDateTime) -> bool {
+        self.windows.iter().any(|window| window.contains(at))
+    }
+}
+
+/// A daily window from `start` to `end` on the given days (every day when
+/// `days` is empty).
+///
+/// A window whose `end` is not after `start` runs past midnight, so
+/// 22:00–00:00 covers the rest of the evening and 22:00–06:00 the night.
+/// Such a window belongs to the day it starts on.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TimeWindow {
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn new(days: Vec<Weekday>, start: NaiveTime, end: NaiveTime) -> Self {
+        Self { days, start, end }
+    }
+
+    pub fn weekdays(start: NaiveTime, end: NaiveTime) -> Self {
+        use Weekday::*;
+        Self::new(vec![Mon, Tue, Wed, Thu, Fri], start, end)
+    }
+
+    pub fn contains(&self, at: NaiveDateTime) -> bool {
+        let time = at.time();
+        let today = at.weekday();
+
+        if self.start < self.end {
+            self.on_day(today) && time >= self.start && time < self.end
+        } else {
+            (self.on_day(today) && time >= self.start)
+                || (self.on_day(today.pred()) && time < self.end)
+        }
+    }
+
+    fn on_day(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -63,3 +492,232 @@ pub struct ProcessInfo {
     pub executable: PathBuf,
     pub cmdline: Vec<String>,
 }
+
+/// Who a stored policy applies to.
+///
+/// System-scope rules are set by an administrator and take precedence over
+/// the per-user rules of every user on the machine.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PolicyScope {
+    System,
+    User(u32),
+}
+
+impl PolicyScope {
+    /// Scopes consulted for a request from `uid`, highest precedence first.
+    pub fn resolution_order(uid: u32) -> [PolicyScope; 2] {
+        [PolicyScope::System, PolicyScope::User(uid)]
+    }
+}
+
+impl std::fmt::Display for PolicyScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyScope::System => write!(f, "system"),
+            PolicyScope::User(uid) => write!(f, "user:{}", uid),
+        }
+    }
+}
+
+impl std::str::FromStr for PolicyScope {
+    type Err = crate::error::ApfError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "system" {
+            return Ok(PolicyScope::System);
+        }
+        s.strip_prefix("user:")
+            .and_then(|uid| uid.parse().ok())
+            .map(PolicyScope::User)
+            .ok_or_else(|| crate::error::ApfError::InvalidConfig(format!("Invalid policy scope: {}", s)))
+    }
+}
+
+impl std::fmt::Display for PermissionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PermissionType::Network(level) => match level {
+                NetworkLevel::None => write!(f, "net:none"),
+                NetworkLevel::Lan => write!(f, "net:lan"),
+                NetworkLevel::Internet => write!(f, "net:internet"),
+                NetworkLevel::Rule(rule) => write!(f, "net:{}", rule),
+            },
+            PermissionType::Filesystem(fs) => {
+                let mode = match fs.mode {
+                    AccessMode::ReadOnly => "ro",
+                    AccessMode::ReadWrite => "rw",
+                    AccessMode::Deny => "deny",
+                };
+                write!(f, "fs:{}?mode={}", encode_path(&fs.path), mode)
+            }
+            PermissionType::Device(device) => write!(f, "device:{}", match device {
+                DeviceType::Microphone => "microphone",
+                DeviceType::Camera => "camera",
+                DeviceType::Screen => "screen",
+                DeviceType::Usb => "usb",
+            }),
+            PermissionType::Clipboard => write!(f, "clipboard"),
+            PermissionType::BackgroundExecution => write!(f, "background"),
+            PermissionType::Autostart => write!(f, "autostart"),
+        }
+    }
+}
+
+impl std::str::FromStr for PermissionType {
+    type Err = crate::error::ApfError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || crate::error::ApfError::InvalidPermission(s.to_string());
+
+        let permission = match s {
+            "net:none" => PermissionType::Network(NetworkLevel::None),
+            "net:lan" => PermissionType::Network(NetworkLevel::Lan),
+            "net:internet" => PermissionType::Network(NetworkLevel::Internet),
+            "device:microphone" => PermissionType::Device(DeviceType::Microphone),
+            "device:camera" => PermissionType::Device(DeviceType::Camera),
+            "device:screen" => PermissionType::Device(DeviceType::Screen),
+            "device:usb" => PermissionType::Device(DeviceType::Usb),
+            "clipboard" => PermissionType::Clipboard,
+            "background" => PermissionType::BackgroundExecution,
+            "autostart" => PermissionType::Autostart,
+            _ if s.starts_with("net:host:") || s.starts_with("net:cidr:") => {
+                let rule = s["net:".len()..].parse().map_err(|_| invalid())?;
+                PermissionType::Network(NetworkLevel::Rule(rule))
+            }
+            _ => {
+                let rest = s.strip_prefix("fs:").ok_or_else(invalid)?;
+                let (path, mode) = rest.rsplit_once("?mode=").ok_or_else(invalid)?;
+                let mode = match mode {
+                    "ro" => AccessMode::ReadOnly,
+                    "rw" => AccessMode::ReadWrite,
+                    "deny" => AccessMode::Deny,
+                    _ => return Err(invalid()),
+                };
+                if path.is_empty() || path.contains('?') {
+                    return Err(invalid());
+                }
+                let fs = FilesystemAccess { path: decode_path(path).ok_or_else(invalid)?, mode };
+                if !fs.is_valid() {
+                    return Err(invalid());
+                }
+                PermissionType::Filesystem(fs)
+            }
+        };
+        Ok(permission)
+    }
+}
+
+/// `host:<domain>` or `cidr:<addr>/<prefix>`, followed by `?port=<n>[-<m>]`
+/// and `&proto=tcp|udp` when set.
+impl std::fmt::Display for NetworkRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.host {
+            HostPattern::Domain(name) => write!(f, "host:{}", name)?,
+            HostPattern::Cidr(cidr) => write!(f, "cidr:{}/{}", cidr.addr, cidr.prefix)?,
+        }
+        let mut separator = '?';
+        if let Some(ports) = self.ports {
+            if ports.start == ports.end {
+                write!(f, "?port={}", ports.start)?;
+            } else {
+                write!(f, "?port={}-{}", ports.start, ports.end)?;
+            }
+            separator = '&';
+        }
+        if let Some(protocol) = self.protocol {
+            write!(f, "{}proto={}", separator, match protocol {
+                Protocol::Tcp => "tcp",
+                Protocol::Udp => "udp",
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for NetworkRule {
+    type Err = crate::error::ApfError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || crate::error::ApfError::InvalidPermission(format!("net:{}", s));
+
+        let (target, query) = s.split_once('?').unwrap_or((s, ""));
+        let host = if let Some(name) = target.strip_prefix("host:") {
+            if !is_host_pattern(name) {
+                return Err(invalid());
+            }
+            HostPattern::Domain(name.to_ascii_lowercase())
+        } else if let Some(block) = target.strip_prefix("cidr:") {
+            let cidr = match block.split_once('/') {
+                Some((addr, prefix)) => Cidr {
+                    addr: addr.parse().map_err(|_| invalid())?,
+                    prefix: prefix.parse().map_err(|_| invalid())?,
+                },
+                None => Cidr::host(block.parse().map_err(|_| invalid())?),
+            };
+            if !cidr.is_valid() {
+                return Err(invalid());
+            }
+            HostPattern::Cidr(cidr)
+        } else {
+            return Err(invalid());
+        };
+
+        let mut rule = NetworkRule { host, ports: None, protocol: None };
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            match pair.split_once('=').ok_or_else(invalid)? {
+                ("port", ports) if rule.ports.is_none() => {
+                    let (start, end) = ports.split_once('-').unwrap_or((ports, ports));
+                    let range = PortRange {
+                        start: start.parse().map_err(|_| invalid())?,
+                        end: end.parse().map_err(|_| invalid())?,
+                    };
+                    if range.start > range.end {
+                        return Err(invalid());
+                    }
+                    rule.ports = Some(range);
+                }
+                ("proto", "tcp") if rule.protocol.is_none() => rule.protocol = Some(Protocol::Tcp),
+                ("proto", "udp") if rule.protocol.is_none() => rule.protocol = Some(Protocol::Udp),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(rule)
+    }
+}
+
+fn encode_path(path: &std::path::Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut encoded = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '%' | '?' => encoded.push_str(&format!("%{:02X}", c as u8)),
+                _ => encoded.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn decode_path(encoded: &str) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = encoded.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(PathBuf::from(std::ffi::OsString::from_vec(decoded)))
+}
@@ -0,0 +1,34 @@
+use apf_core::error::{ApfError, DBUS_ERROR_PREFIX};
+
+#[test]
+fn test_dbus_error_names_round_trip() {
+    let errors = [
+        ApfError::Io(std::io::Error::other("disk gone")),
+        ApfError::PermissionDenied,
+        ApfError::NotAuthorized("scope system".to_string()),
+        ApfError::InvalidArgs("bad decision".to_string()),
+        ApfError::InvalidAppId("x".to_string()),
+        ApfError::InvalidPermission("net:wan".to_string()),
+        ApfError::PolicyNotFound("x".to_string()),
+        ApfError::PolicyLocked("org.example.Chat clipboard".to_string()),
+        ApfError::RequestExpired("req-1".to_string()),
+        ApfError::Storage("database is locked".to_string()),
+        ApfError::DBus("x".to_string()),
+        ApfError::EnforcementFailed("x".to_string()),
+        ApfError::InvalidConfig("x".to_string()),
+        ApfError::Unknown("x".to_string()),
+    ];
+
+    for error in errors {
+        let name = error.dbus_name();
+        assert!(name.starts_with(DBUS_ERROR_PREFIX), "{}", name);
+        let mapped = ApfError::from_dbus(name, &error.dbus_message()).unwrap();
+        assert_eq!(mapped.dbus_name(), name);
+        assert_eq!(mapped.to_string(), error.to_string());
+    }
+
+    assert_eq!(ApfError::PolicyLocked("a".to_string()).dbus_name(), "org.apf.Error.PolicyLocked");
+    assert!(ApfError::from_dbus("org.freedesktop.DBus.Error.Failed", "x").is_none());
+    assert!(ApfError::from_dbus("org.apf.ErrorX.Storage", "x").is_none());
+    assert!(ApfError::from_dbus("org.apf.Error.Nope", "x").is_none());
+}
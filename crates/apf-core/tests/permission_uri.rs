@@ -0,0 +1,152 @@
+use apf_core::types::{AccessMode, DeviceType, FilesystemAccess, NetworkLevel, NetworkRule, PermissionType};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+fn net(uri: &str) -> NetworkRule {
+    match uri.parse::<PermissionType>().unwrap() {
+        PermissionType::Network(NetworkLevel::Rule(rule)) => rule,
+        other => panic!("{:?} is not a network rule", other),
+    }
+}
+
+fn fs(path: impl Into<PathBuf>, mode: AccessMode) -> PermissionType {
+    PermissionType::Filesystem(FilesystemAccess { path: path.into(), mode })
+}
+
+#[test]
+fn test_permission_uri_round_trip() {
+    let cases = [
+        (PermissionType::Network(NetworkLevel::Lan), "net:lan"),
+        (PermissionType::Device(DeviceType::Camera), "device:camera"),
+        (PermissionType::Clipboard, "clipboard"),
+        (PermissionType::BackgroundExecution, "background"),
+        (fs("/home/u/Documents", AccessMode::ReadOnly), "fs:/home/u/Documents?mode=ro"),
+        (fs("/srv/what?100%", AccessMode::ReadWrite), "fs:/srv/what%3F100%25?mode=rw"),
+        (fs("/home/u/Документы", AccessMode::Deny), "fs:/home/u/Документы?mode=deny"),
+        (fs(OsStr::from_bytes(b"/tmp/\xff"), AccessMode::ReadOnly), "fs:/tmp/%FF?mode=ro"),
+        (fs("$XDG_DOCUMENTS_DIR/invoices", AccessMode::ReadOnly), "fs:$XDG_DOCUMENTS_DIR/invoices?mode=ro"),
+    ];
+
+    for (permission, uri) in cases {
+        assert_eq!(permission.to_string(), uri);
+        assert_eq!(uri.parse::<PermissionType>().unwrap(), permission);
+    }
+
+    for uri in [
+        "net:host:*.example-chat.com?port=443&proto=tcp",
+        "net:host:example.org",
+        "net:cidr:10.0.0.0/8?port=5000-6000",
+        "net:cidr:fd00::/8?proto=udp",
+    ] {
+        assert_eq!(uri.parse::<PermissionType>().unwrap().to_string(), uri);
+    }
+    assert_eq!(net("net:cidr:192.168.1.5").to_string(), "cidr:192.168.1.5/32");
+    assert_eq!(net("net:host:Chat.Example.COM").to_string(), "host:chat.example.com");
+}
+
+#[test]
+fn test_network_rule_covers() {
+    let chat = net("net:host:*.example-chat.com?port=443&proto=tcp");
+    assert!(chat.covers(&net("net:host:eu.example-chat.com?port=443&proto=tcp")));
+    assert!(chat.covers(&net("net:host:a.b.example-chat.com?port=443&proto=tcp")));
+    assert!(!chat.covers(&net("net:host:example-chat.com?port=443&proto=tcp")));
+    assert!(!chat.covers(&net("net:host:evil-example-chat.com?port=443&proto=tcp")));
+    assert!(!chat.covers(&net("net:host:eu.example-chat.com?port=80&proto=tcp")));
+    assert!(!chat.covers(&net("net:host:eu.example-chat.com?port=443&proto=udp")));
+    assert!(!chat.covers(&net("net:host:eu.example-chat.com?proto=tcp")));
+
+    let lan = net("net:cidr:10.0.0.0/8?port=5000-6000");
+    assert!(lan.covers(&net("net:cidr:10.1.2.3?port=5353&proto=udp")));
+    assert!(lan.covers(&net("net:cidr:10.1.0.0/16?port=5000-5100")));
+    assert!(!lan.covers(&net("net:cidr:11.0.0.1?port=5353")));
+    assert!(!lan.covers(&net("net:cidr:::ffff:10.0.0.1?port=5353")));
+    assert!(!lan.covers(&net("net:host:printer.local?port=5353")));
+
+    assert!(net("net:cidr:10.1.2.3").is_lan());
+    assert!(net("net:cidr:fe80::1").is_lan());
+    assert!(!net("net:cidr:0.0.0.0/0").is_lan());
+    assert!(!net("net:host:printer.local").is_lan());
+    assert!(net("net:host:a.example.com").specificity() > net("net:host:*.a.example.com").specificity());
+    assert!(net("net:host:*.a.example.com").specificity() > net("net:host:*.example.com?port=443").specificity());
+}
+
+#[test]
+fn test_invalid_permission_uris() {
+    for uri in ["", "net", "net:wan", "Clipboard", "fs:/tmp", "fs:?mode=ro", "fs:/tmp?mode=rx", "fs:/a?b?mode=ro", "fs:/%zz?mode=ro",
+        "fs:tmp?mode=ro", "fs:$TMPDIR/x?mode=ro", "fs:$HOMEDIR?mode=ro", "fs:/usr/../etc/shadow?mode=ro", "fs:$HOME/..?mode=rw",
+        "net:host:", "net:host:a..b", "net:host:a.*.b", "net:host:a?port=0-", "net:host:a?port=9-1",
+        "net:host:a?proto=sctp", "net:host:a?port=1&port=2", "net:cidr:10.0.0.0/33", "net:cidr:nope"] {
+        assert!(uri.parse::<PermissionType>().is_err(), "{:?} should not parse", uri);
+    }
+}
+
+#[test]
+fn test_path_placeholders_resolve() {
+    use apf_core::types::PathVar;
+
+    let lookup = |var| match var {
+        PathVar::Home => Some(PathBuf::from("/home/u")),
+        _ => None,
+    };
+    let resolve = |path: &str| FilesystemAccess { path: path.into(), mode: AccessMode::ReadOnly }.resolve(lookup).map(|fs| fs.path);
+
+    assert_eq!(resolve("$HOME"), Some(PathBuf::from("/home/u")));
+    assert_eq!(resolve("$HOME/.config/app"), Some(PathBuf::from("/home/u/.config/app")));
+    assert_eq!(resolve("/etc/$HOME"), Some(PathBuf::from("/etc/$HOME")));
+    assert_eq!(resolve("$XDG_RUNTIME_DIR/bus"), None);
+    assert_eq!(resolve("$NOPE/x"), None);
+    assert_eq!(PathVar::from_name("$APP_DATA"), Some(PathVar::AppData));
+}
+
+#[test]
+fn test_parent_dirs_are_rejected() {
+    use apf_core::types::{FilesystemAccess as Fs, PromptDecision};
+
+    // `..` would let a path slip past rules on the directory it starts in.
+    let escaping = serde_json::json!({"Filesystem": {"path": "/usr/../etc/shadow", "mode": "ReadOnly"}});
+    assert!(serde_json::from_value::<PermissionType>(escaping).is_err());
+    assert!(serde_json::from_str::<Fs>(r#"{"path": "$HOME/../bob", "mode": "ReadWrite"}"#).is_err());
+    assert!(serde_json::from_str::<Fs>(r#"{"path": "relative", "mode": "ReadWrite"}"#).is_err());
+    let policy = r#"[[{"Filesystem": {"path": "/srv/../etc", "mode": "ReadWrite"}}, "AllowAlways"]]"#;
+    assert!(serde_json::from_str::<Vec<(PermissionType, PromptDecision)>>(policy).is_err());
+
+    let plain = fs("/usr/share/fonts", AccessMode::ReadOnly);
+    let json = serde_json::to_string(&plain).unwrap();
+    assert_eq!(serde_json::from_str::<PermissionType>(&json).unwrap(), plain);
+}
+
+#[test]
+fn test_invalid_network_rules_are_rejected() {
+    use apf_core::types::{Cidr, HostPattern};
+
+    // A prefix longer than the address would cover everything once masked.
+    let wide = r#"{"Network": {"Rule": {"host": {"Cidr": {"addr": "10.0.0.1", "prefix": 200}}}}}"#;
+    assert!(serde_json::from_str::<PermissionType>(wide).is_err());
+    let v6 = r#"{"Network": {"Rule": {"host": {"Cidr": {"addr": "fd00::", "prefix": 129}}}}}"#;
+    assert!(serde_json::from_str::<PermissionType>(v6).is_err());
+    let inverted = r#"{"Network": {"Rule": {"host": {"Domain": "example.org"}, "ports": {"start": 443, "end": 80}}}}"#;
+    assert!(serde_json::from_str::<PermissionType>(inverted).is_err());
+    // Names that would write a different or ambiguous permission key.
+    for name in ["example.org?port=1", "a&b.example", "evil.example/x", "two words", "", "*.", "a..b", "*.*.example"] {
+        let json = serde_json::json!({"Network": {"Rule": {"host": {"Domain": name}}}});
+        assert!(serde_json::from_value::<PermissionType>(json).is_err(), "{:?}", name);
+    }
+    let mixed = r#"{"host": {"Domain": "*.Example-Chat.COM"}}"#;
+    assert_eq!(serde_json::from_str::<NetworkRule>(mixed).unwrap(), net("net:host:*.example-chat.com"));
+
+    let valid = net("net:cidr:10.0.0.0/8?port=5000-6000");
+    let json = serde_json::to_string(&valid).unwrap();
+    assert_eq!(serde_json::from_str::<NetworkRule>(&json).unwrap(), valid);
+
+    // Built by hand, invalid values still don't panic or match anything.
+    let broken = Cidr { addr: "10.0.0.1".parse().unwrap(), prefix: 200 };
+    assert!(!broken.covers(&Cidr::host("192.0.2.1".parse().unwrap())));
+    let rule = NetworkRule {
+        host: HostPattern::Cidr(broken),
+        ports: Some(apf_core::types::PortRange { start: 443, end: 80 }),
+        protocol: None,
+    };
+    assert!(!rule.covers(&net("net:cidr:192.0.2.1?port=443")));
+    let _ = rule.specificity();
+}
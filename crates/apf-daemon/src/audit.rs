@@ -5,8 +5,10 @@ use std::sync::Arc;
 use tokio::sync::Mutex;
 use tracing::{info, warn};
 
-use apf_core::{app_id::AppId, types::PermissionType};
-use crate::database::Database;
+use apf_core::{app_id::AppId, types::{HostPattern, NetworkLevel, NetworkRule, PermissionType}};
+use crate::database::{Database, DNS_BLOCKED_REASON, DNS_QUERY_REASON, EXPIRED_REASON, LEARNING_REASON};
+use crate::dbus_service::PermissionRequest;
+use crate::grants::Grant;
 
 pub struct AuditLogger {
     db: Arc<Mutex<Database>>,
@@ -22,13 +24,12 @@ impl AuditLogger {
     pub async fn log_permission_check(
         &mut self,
         app_id: &AppId,
+        pid: u32,
+        uid: u32,
         permission: &PermissionType,
         granted: bool,
         was_prompted: bool,
     ) -> Result<()> {
-        let pid = std::process::id();
-        let uid = nix::unistd::getuid().as_raw();
-
         let mut db = self.db.lock().await;
         db.log_audit(
             app_id,
@@ -38,11 +39,13 @@ impl AuditLogger {
             None,
             granted,
             was_prompted,
+            None,
         )?;
 
         if granted {
             info!(
                 app_id = %app_id.primary,
+                uid,
                 permission = ?permission,
                 prompted = was_prompted,
                 "Permission granted"
@@ -50,6 +53,7 @@ impl AuditLogger {
         } else {
             warn!(
                 app_id = %app_id.primary,
+                uid,
                 permission = ?permission,
                 prompted = was_prompted,
                 "Permission denied"
@@ -59,10 +63,148 @@ impl AuditLogger {
         Ok(())
     }
 
+    /// Records a request that got no prompt of its own for `reason`, such
+    /// as a throttled or automatically denied prompt, or one sharing a
+    /// prompt already pending.
+    pub async fn log_suppressed(
+        &mut self,
+        app_id: &AppId,
+        pid: u32,
+        uid: u32,
+        permission: &PermissionType,
+        reason: &str,
+    ) -> Result<()> {
+        let mut db = self.db.lock().await;
+        db.log_audit(app_id, pid, uid, permission, None, false, false, Some(reason))?;
+
+        warn!(
+            app_id = %app_id.primary,
+            uid,
+            permission = ?permission,
+            reason,
+            "Prompt suppressed"
+        );
+
+        Ok(())
+    }
+
+    /// Records a prompted request denied because the user didn't answer
+    /// before its deadline.
+    pub async fn log_expired(&mut self, request: &PermissionRequest) -> Result<()> {
+        let mut db = self.db.lock().await;
+        db.log_audit(&request.app_id, request.pid, request.uid, &request.permission, None, false, true, Some(EXPIRED_REASON))?;
+
+        warn!(
+            app_id = %request.app_id.primary,
+            uid = request.uid,
+            permission = ?request.permission,
+            "Prompt expired unanswered"
+        );
+
+        Ok(())
+    }
+
+    /// Records a request granted without a prompt because the app is in
+    /// learning mode. These entries are what the draft policy is built from.
+    pub async fn log_learned(
+        &mut self,
+        app_id: &AppId,
+        pid: u32,
+        uid: u32,
+        permission: &PermissionType,
+    ) -> Result<()> {
+        let mut db = self.db.lock().await;
+        db.log_audit(app_id, pid, uid, permission, None, true, false, Some(LEARNING_REASON))?;
+
+        info!(
+            app_id = %app_id.primary,
+            uid,
+            permission = ?permission,
+            "Permission granted in learning mode"
+        );
+
+        Ok(())
+    }
+
+    /// Records a name a sandboxed app looked up, as the host it names, and
+    /// whether the lookup was refused because the name is blocklisted.
+    pub async fn log_dns_query(
+        &mut self,
+        app_id: &AppId,
+        pid: u32,
+        uid: u32,
+        name: &str,
+        blocked: bool,
+    ) -> Result<()> {
+        let permission = PermissionType::Network(NetworkLevel::Rule(NetworkRule {
+            host: HostPattern::Domain(name.to_string()),
+            ports: None,
+            protocol: None,
+        }));
+        let reason = if blocked { DNS_BLOCKED_REASON } else { DNS_QUERY_REASON };
+        let mut db = self.db.lock().await;
+        db.log_audit(app_id, pid, uid, &permission, None, !blocked, false, Some(reason))?;
+
+        info!(
+            app_id = %app_id.primary,
+            uid,
+            name,
+            blocked,
+            "DNS query"
+        );
+
+        Ok(())
+    }
+
+    /// Records a request granted by a lifetime grant: the answer that made
+    /// the grant, or a later request it covered. `reason` names the kind
+    /// of grant.
+    pub async fn log_lifetime_grant(
+        &mut self,
+        app_id: &AppId,
+        pid: u32,
+        uid: u32,
+        permission: &PermissionType,
+        was_prompted: bool,
+        reason: &str,
+    ) -> Result<()> {
+        let mut db = self.db.lock().await;
+        db.log_audit(app_id, pid, uid, permission, None, true, was_prompted, Some(reason))?;
+
+        info!(
+            app_id = %app_id.primary,
+            uid,
+            permission = ?permission,
+            prompted = was_prompted,
+            reason,
+            "Permission granted"
+        );
+
+        Ok(())
+    }
+
+    /// Records the end of a grant that lasted until an app exited or a
+    /// login session ended.
+    pub async fn log_revoked(&mut self, grant: &Grant) -> Result<()> {
+        let reason = grant.lifetime.revoke_reason();
+        let mut db = self.db.lock().await;
+        db.log_audit(&grant.app_id, grant.pid, grant.uid, &grant.permission, Some(&grant.decision), false, false, Some(reason))?;
+
+        info!(
+            app_id = %grant.app_id.primary,
+            uid = grant.uid,
+            permission = ?grant.permission,
+            reason,
+            "Grant revoked"
+        );
+
+        Ok(())
+    }
 
-    pub async fn get_recent_entries(&self, limit: usize) -> Result<Vec<AuditEntryView>> {
+    /// Returns recent entries, restricted to `uid` when given.
+    pub async fn get_recent_entries(&self, limit: usize, uid: Option<u32>) -> Result<Vec<AuditEntryView>> {
         let db = self.db.lock().await;
-        let entries = db.get_audit_entries(limit)?;
+        let entries = db.get_audit_entries(limit, uid)?;
 
         let mut views = Vec::new();
         for entry in entries {
@@ -71,10 +213,11 @@ impl AuditLogger {
                 app_id: entry.app_id,
                 pid: entry.pid,
                 uid: entry.uid,
-                permission: entry.permission_json,
+                permission: entry.permission,
                 decision: entry.decision_json,
                 granted: entry.granted,
                 was_prompted: entry.was_prompted,
+                reason: entry.reason,
             });
         }
 
@@ -93,5 +236,6 @@ pub struct AuditEntryView {
     pub decision: String,
     pub granted: bool,
     pub was_prompted: bool,
+    pub reason: Option<String>,
 }
 
@@ -0,0 +1,178 @@
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use apf_core::{app_id::AppId, types::{NetworkLevel, PermissionType, PolicyScope, PromptDecision}};
+use apf_policy::is_templated;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub app: String,
+    pub uid: u32,
+    pub permission: PermissionType,
+}
+
+impl CacheKey {
+    pub fn new(app_id: &AppId, uid: u32, permission: &PermissionType) -> Self {
+        Self {
+            app: app_id.primary.clone(),
+            uid,
+            permission: permission.clone(),
+        }
+    }
+}
+
+/// A stored decision as loaded from the database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedDecision {
+    pub decision: PromptDecision,
+    /// Unix time after which an `AllowDuration` grant no longer applies.
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub invalidations: u64,
+    pub entries: usize,
+}
+
+/// Stored decisions per (app, uid, permission), in resolution order. Empty
+/// entries are cached too, so requests nothing is stored for do not hit the
+/// database either.
+#[derive(Debug, Default)]
+pub struct DecisionCache {
+    entries: HashMap<CacheKey, Vec<CachedDecision>>,
+    stats: CacheStats,
+}
+
+impl DecisionCache {
+    /// Looks up `key`. An entry holding a grant that has expired by `now`
+    /// is dropped and counts as a miss.
+    pub fn get(&mut self, key: &CacheKey, now: i64) -> Option<Vec<CachedDecision>> {
+        let expired = match self.entries.get(key) {
+            Some(decisions) => decisions.iter().any(|d| d.expires_at.is_some_and(|expiry| now > expiry)),
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+        if expired {
+            self.entries.remove(key);
+            self.stats.invalidations += 1;
+            self.stats.misses += 1;
+            return None;
+        }
+
+        self.stats.hits += 1;
+        self.entries.get(key).cloned()
+    }
+
+    pub fn insert(&mut self, key: CacheKey, decisions: Vec<CachedDecision>) {
+        self.entries.insert(key, decisions);
+    }
+
+    /// Drops what a change to `permission` in `scope` could affect: one
+    /// user's entry, or every user's for a system-scope change. Any network
+    /// change affects every cached network destination, and a change to a
+    /// path written with a placeholder every cached filesystem request.
+    pub fn invalidate_permission(&mut self, app_id: &AppId, scope: PolicyScope, permission: &PermissionType) {
+        self.retain(|key| {
+            let affected = key.permission == *permission
+                || matches!((&key.permission, permission),
+                    (PermissionType::Network(NetworkLevel::Rule(_)), PermissionType::Network(_)))
+                || (matches!(key.permission, PermissionType::Filesystem(_)) && is_templated(permission));
+            !(key.app == app_id.primary && affected && affects(scope, key.uid))
+        });
+    }
+
+    /// Drops every entry for `app_id` that a change in `scope` could affect.
+    pub fn invalidate_app(&mut self, app_id: &AppId, scope: PolicyScope) {
+        self.retain(|key| !(key.app == app_id.primary && affects(scope, key.uid)));
+    }
+
+    pub fn clear(&mut self) {
+        self.stats.invalidations += self.entries.len() as u64;
+        self.entries.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats { entries: self.entries.len(), ..self.stats }
+    }
+
+    fn retain(&mut self, keep: impl Fn(&CacheKey) -> bool) {
+        let before = self.entries.len();
+        self.entries.retain(|key, _| keep(key));
+        self.stats.invalidations += (before - self.entries.len()) as u64;
+    }
+}
+
+fn affects(scope: PolicyScope, uid: u32) -> bool {
+    match scope {
+        PolicyScope::System => true,
+        PolicyScope::User(owner) => owner == uid,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apf_core::types::DeviceType;
+
+    fn entry(decision: PromptDecision, expires_at: Option<i64>) -> Vec<CachedDecision> {
+        vec![CachedDecision { decision, expires_at }]
+    }
+
+    #[test]
+    fn test_expired_grant_is_a_miss() {
+        let app = AppId::from_desktop("org.example.Chat", false);
+        let key = CacheKey::new(&app, 1000, &PermissionType::Clipboard);
+        let mut cache = DecisionCache::default();
+
+        assert_eq!(cache.get(&key, 100), None);
+        cache.insert(key.clone(), entry(PromptDecision::AllowAlways, Some(200)));
+        assert!(cache.get(&key, 200).is_some());
+        assert_eq!(cache.get(&key, 201), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.invalidations, stats.entries), (1, 2, 1, 0));
+    }
+
+    #[test]
+    fn test_invalidation_follows_scope() {
+        let app = AppId::from_desktop("org.example.Chat", false);
+        let camera = PermissionType::Device(DeviceType::Camera);
+        let mut cache = DecisionCache::default();
+        for uid in [1000, 1001] {
+            for permission in [&camera, &PermissionType::Clipboard] {
+                cache.insert(CacheKey::new(&app, uid, permission), entry(PromptDecision::DenyAlways, None));
+            }
+        }
+
+        cache.invalidate_permission(&app, PolicyScope::User(1000), &camera);
+        assert_eq!(cache.stats().entries, 3);
+        assert!(cache.get(&CacheKey::new(&app, 1001, &camera), 0).is_some());
+
+        cache.invalidate_permission(&app, PolicyScope::System, &camera);
+        assert_eq!(cache.stats().entries, 2);
+
+        cache.invalidate_app(&app, PolicyScope::User(1001));
+        assert_eq!(cache.stats().entries, 1);
+        assert!(cache.get(&CacheKey::new(&app, 1000, &PermissionType::Clipboard), 0).is_some());
+    }
+
+    #[test]
+    fn test_placeholder_change_drops_filesystem_entries() {
+        let app = AppId::from_desktop("org.example.Notes", false);
+        let notes: PermissionType = "fs:/home/u/Documents/notes?mode=rw".parse().unwrap();
+        let template: PermissionType = "fs:$XDG_DOCUMENTS_DIR/notes?mode=rw".parse().unwrap();
+        let mut cache = DecisionCache::default();
+        cache.insert(CacheKey::new(&app, 1000, &notes), Vec::new());
+        cache.insert(CacheKey::new(&app, 1000, &PermissionType::Clipboard), Vec::new());
+
+        cache.invalidate_permission(&app, PolicyScope::User(1000), &template);
+        assert_eq!(cache.get(&CacheKey::new(&app, 1000, &notes), 0), None);
+        assert_eq!(cache.stats().entries, 1);
+    }
+}
@@ -1,10 +1,31 @@
 
 use anyhow::{Context, Result};
-use rusqlite::{Connection, params};
+use rusqlite::{Connection, OpenFlags, params};
+use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use tracing::{debug, info, warn};
 
-use apf_core::{app_id::AppId, types::{PermissionType, PromptDecision}};
+use apf_core::{app_id::AppId, types::{PermissionType, PolicyScope, PromptDecision}};
+
+/// Schema version `run_migrations` brings a database to.
+const SCHEMA_VERSION: i64 = 6;
+
+/// `policies.uid` value for system-scope rules.
+const SYSTEM_SCOPE_UID: i64 = -1;
+
+/// `audit_log.reason` of requests granted because the app was learning.
+pub const LEARNING_REASON: &str = "learning";
+
+/// `audit_log.reason` of names a sandbox looked up through its DNS stub,
+/// and of those refused because they are blocklisted.
+pub const DNS_QUERY_REASON: &str = "dns:query";
+pub const DNS_BLOCKED_REASON: &str = "dns:blocked";
+
+/// `audit_log.reason` of prompts denied because nobody answered them in time.
+pub const EXPIRED_REASON: &str = "expired";
+
+/// `policy_history.source` of decisions stored by answering a prompt.
+pub const PROMPT_SOURCE: &str = "prompt";
 
 pub struct Database {
     conn: Connection,
@@ -43,6 +64,23 @@ impl Database {
         Ok(db)
     }
 
+    /// Opens an existing database for reading only, e.g. the daemon's live
+    /// database from a command-line tool. Unlike `new` it creates nothing
+    /// and doesn't migrate, so the schema has to be current already.
+    pub fn open_read_only(db_path: impl AsRef<Path>) -> Result<Self> {
+        let path = db_path.as_ref().to_path_buf();
+        let conn = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+            .with_context(|| format!("Failed to open database at {}", path.display()))?;
+        let schema_version: Option<i64> = conn.query_row("SELECT MAX(version) FROM migrations", [], |row| row.get(0))
+            .with_context(|| format!("{} is not an AppFence database", path.display()))?;
+        let schema_version = schema_version.unwrap_or(0);
+        if schema_version != SCHEMA_VERSION {
+            anyhow::bail!("Database at {} has schema version {}, expected {}; start the daemon once to migrate it",
+                path.display(), schema_version, SCHEMA_VERSION);
+        }
+        Ok(Self { conn, path, schema_version })
+    }
+
     fn run_migrations(&mut self) -> Result<()> {
         // Create migrations table if not exists
         self.conn.execute(
@@ -117,6 +155,127 @@ impl Database {
             self.schema_version = 1;
             info!("Migration v1 applied");
         }
+        if self.schema_version < 2 {
+            // Policies gain a scope. Existing rows applied to every user, so
+            // they become system-scope rules. The audit log can't tell whose
+            // answer they were: v1 recorded the daemon's own uid there.
+            let tx = self.conn.transaction()?;
+            tx.execute_batch(
+                "CREATE TABLE policies_v2 (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    app_id TEXT NOT NULL,
+                    uid INTEGER NOT NULL,
+                    permission_type TEXT NOT NULL,
+                    decision TEXT NOT NULL,
+                    expires_at INTEGER,
+                    created_at INTEGER NOT NULL,
+                    FOREIGN KEY (app_id) REFERENCES applications(app_id) ON DELETE CASCADE,
+                    UNIQUE(app_id, uid, permission_type)
+                );
+                INSERT INTO policies_v2 (id, app_id, uid, permission_type, decision, expires_at, created_at)
+                    SELECT id, app_id, -1, permission_type, decision, expires_at, created_at FROM policies;
+                DROP TABLE policies;
+                ALTER TABLE policies_v2 RENAME TO policies;
+                CREATE INDEX IF NOT EXISTS idx_policies_app_id ON policies(app_id);
+                CREATE INDEX IF NOT EXISTS idx_audit_uid ON audit_log(uid);",
+            ).context("Failed to add scope to policies table")?;
+            tx.execute(
+                "INSERT INTO migrations (version, applied_at) VALUES (?1, ?2)",
+                rusqlite::params![2, current_timestamp()],
+            )?;
+            tx.commit()?;
+            self.schema_version = 2;
+            info!("Migration v2 applied");
+        }
+        if self.schema_version < 3 {
+            let tx = self.conn.transaction()?;
+            tx.execute("ALTER TABLE audit_log ADD COLUMN reason TEXT", [])
+                .context("Failed to add reason to audit_log table")?;
+            tx.execute(
+                "INSERT INTO migrations (version, applied_at) VALUES (?1, ?2)",
+                rusqlite::params![3, current_timestamp()],
+            )?;
+            tx.commit()?;
+            self.schema_version = 3;
+            info!("Migration v3 applied");
+        }
+        if self.schema_version < 4 {
+            let tx = self.conn.transaction()?;
+            tx.execute(
+                "CREATE TABLE IF NOT EXISTS learning_sessions (
+                    app_id TEXT NOT NULL,
+                    uid INTEGER NOT NULL,
+                    started_at INTEGER NOT NULL,
+                    FOREIGN KEY (app_id) REFERENCES applications(app_id) ON DELETE CASCADE,
+                    PRIMARY KEY (app_id, uid)
+                )",
+                [],
+            ).context("Failed to create learning_sessions table")?;
+            tx.execute(
+                "INSERT INTO migrations (version, applied_at) VALUES (?1, ?2)",
+                rusqlite::params![4, current_timestamp()],
+            )?;
+            tx.commit()?;
+            self.schema_version = 4;
+            info!("Migration v4 applied");
+        }
+        if self.schema_version < 5 {
+            let tx = self.conn.transaction()?;
+            tx.execute_batch(
+                "CREATE TABLE IF NOT EXISTS policy_history (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    app_id TEXT NOT NULL,
+                    uid INTEGER NOT NULL,
+                    permission_type TEXT NOT NULL,
+                    old_decision TEXT,
+                    old_expires_at INTEGER,
+                    new_decision TEXT,
+                    changed_by INTEGER NOT NULL,
+                    source TEXT NOT NULL,
+                    changed_at INTEGER NOT NULL,
+                    FOREIGN KEY (app_id) REFERENCES applications(app_id) ON DELETE CASCADE
+                );
+                CREATE INDEX IF NOT EXISTS idx_policy_history_app ON policy_history(app_id, uid);",
+            ).context("Failed to create policy_history table")?;
+            tx.execute(
+                "INSERT INTO migrations (version, applied_at) VALUES (?1, ?2)",
+                rusqlite::params![5, current_timestamp()],
+            )?;
+            tx.commit()?;
+            self.schema_version = 5;
+            info!("Migration v5 applied");
+        }
+        if self.schema_version < 6 {
+            // Permissions were keyed by their serde_json form; switch to the
+            // stable textual form so renaming an enum variant cannot orphan
+            // stored rows.
+            let tx = self.conn.transaction()?;
+            for table in ["policies", "policy_history", "audit_log"] {
+                let keys: Vec<String> = {
+                    let mut stmt = tx.prepare(&format!("SELECT DISTINCT permission_type FROM {}", table))?;
+                    let rows = stmt.query_map([], |row| row.get(0))?;
+                    rows.collect::<rusqlite::Result<_>>()?
+                };
+                for json in keys {
+                    match serde_json::from_str::<PermissionType>(&json) {
+                        Ok(permission) => {
+                            tx.execute(
+                                &format!("UPDATE {} SET permission_type = ?1 WHERE permission_type = ?2", table),
+                                params![permission.to_string(), json],
+                            )?;
+                        }
+                        Err(e) => warn!("Leaving unreadable permission {} in {}: {}", json, table, e),
+                    }
+                }
+            }
+            tx.execute(
+                "INSERT INTO migrations (version, applied_at) VALUES (?1, ?2)",
+                rusqlite::params![6, current_timestamp()],
+            )?;
+            tx.commit()?;
+            self.schema_version = 6;
+            info!("Migration v6 applied");
+        }
         // Add further migrations here
         Ok(())
     }
@@ -196,10 +355,10 @@ impl Database {
         Ok(())
     }
 
-    pub fn store_policy(&mut self, app_id: &AppId, permission: &PermissionType, decision: &PromptDecision) -> Result<()> {
+    pub fn store_policy(&mut self, app_id: &AppId, scope: PolicyScope, permission: &PermissionType, decision: &PromptDecision, origin: ChangeOrigin) -> Result<()> {
         self.register_application(app_id)?;
 
-        let permission_json = serde_json::to_string(permission)?;
+        let permission_key = permission.to_string();
         let decision_json = serde_json::to_string(decision)?;
         let now = current_timestamp();
         
@@ -210,37 +369,139 @@ impl Database {
             _ => None,
         };
 
-        self.conn.execute(
-            "INSERT INTO policies (app_id, permission_type, decision, expires_at, created_at)
-             VALUES (?1, ?2, ?3, ?4, ?5)
-             ON CONFLICT(app_id, permission_type) DO UPDATE SET
-                decision = ?3,
-                expires_at = ?4,
-                created_at = ?5",
-            params![
-                &app_id.primary,
-                permission_json,
-                decision_json,
-                expires_at,
-                now,
-            ],
-        ).context("Failed to store policy")?;
+        let tx = self.conn.transaction()?;
+        write_policy(&tx, &app_id.primary, scope_to_uid(scope), &permission_key, Some((&decision_json, expires_at)), origin)
+            .context("Failed to store policy")?;
+        tx.commit()?;
 
-        debug!("Stored policy for {} - {:?}", app_id.primary, permission);
+        debug!("Stored {} policy for {} - {:?}", scope, app_id.primary, permission);
         Ok(())
     }
 
-    pub fn get_policy(&self, app_id: &AppId, permission: &PermissionType) -> Result<Option<PromptDecision>> {
-        let permission_json = serde_json::to_string(permission)?;
+    /// Removes the rule for `permission`, so requests fall back to the
+    /// default again. Returns false if there was no rule.
+    pub fn delete_policy(&mut self, app_id: &AppId, scope: PolicyScope, permission: &PermissionType, origin: ChangeOrigin) -> Result<bool> {
+        let permission_key = permission.to_string();
+
+        let tx = self.conn.transaction()?;
+        let deleted = write_policy(&tx, &app_id.primary, scope_to_uid(scope), &permission_key, None, origin)
+            .context("Failed to delete policy")?;
+        tx.commit()?;
+
+        debug!("Deleted {} policy for {} - {:?}", scope, app_id.primary, permission);
+        Ok(deleted)
+    }
+
+    /// Changes to the rules for `app_id` in `scope`, newest first.
+    pub fn get_policy_history(&self, app_id: &AppId, scope: PolicyScope) -> Result<Vec<PolicyChange>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, permission_type, old_decision, new_decision, changed_by, source, changed_at
+             FROM policy_history
+             WHERE app_id = ?1 AND uid = ?2
+             ORDER BY id DESC"
+        )?;
+
+        let rows = stmt.query_map(params![&app_id.primary, scope_to_uid(scope)], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, u32>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, i64>(6)?,
+            ))
+        })?;
+
+        let mut changes = Vec::new();
+        for row in rows {
+            let (version, perm_key, old_json, new_json, changed_by, source, changed_at) = row?;
+            let Some(permission) = parse_stored_permission(&perm_key) else {
+                continue;
+            };
+            changes.push(PolicyChange {
+                version,
+                app_id: app_id.primary.clone(),
+                scope,
+                permission,
+                old_decision: old_json.map(|json| serde_json::from_str(&json)).transpose()?,
+                new_decision: new_json.map(|json| serde_json::from_str(&json)).transpose()?,
+                changed_by,
+                source,
+                changed_at,
+            });
+        }
+        Ok(changes)
+    }
+
+    /// Restores the rules for `app_id` in `scope` to how they were right
+    /// after change `version`, or before any change for version 0. Expiry
+    /// times are restored as they were. The rollback is itself recorded in
+    /// the history, so it can be rolled back too. Returns the number of
+    /// rules changed.
+    pub fn rollback_policy(&mut self, app_id: &AppId, scope: PolicyScope, version: i64, origin: ChangeOrigin) -> Result<usize> {
+        let uid = scope_to_uid(scope);
+        let tx = self.conn.transaction()?;
+
+        if version != 0 {
+            let known: bool = tx.query_row(
+                "SELECT EXISTS(SELECT 1 FROM policy_history WHERE id = ?1 AND app_id = ?2 AND uid = ?3)",
+                params![version, &app_id.primary, uid],
+                |row| row.get(0),
+            )?;
+            if !known {
+                anyhow::bail!("Unknown policy version {} for {} ({})", version, app_id.primary, scope);
+            }
+        }
+
+        let targets = rollback_targets(&tx, &app_id.primary, uid, version)?;
+
+        let mut changed = 0;
+        for (permission_key, decision_json, expires_at) in &targets {
+            let target = decision_json.as_deref().map(|json| (json, *expires_at));
+            if write_policy(&tx, &app_id.primary, uid, permission_key, target, origin)? {
+                changed += 1;
+            }
+        }
+        tx.commit()?;
+
+        info!("Rolled back {} ({}) to version {}: {} rule(s) changed", app_id.primary, scope, version, changed);
+        Ok(changed)
+    }
+
+    /// The rules `rollback_policy` would restore, each with the decision it
+    /// would go back to, or `None` where the rule would be removed.
+    pub fn rollback_preview(&self, app_id: &AppId, scope: PolicyScope, version: i64) -> Result<Vec<(PermissionType, Option<PromptDecision>)>> {
+        let targets = rollback_targets(&self.conn, &app_id.primary, scope_to_uid(scope), version)?;
+        let mut preview = Vec::new();
+        for (permission_key, decision_json, _) in targets {
+            let Some(permission) = parse_stored_permission(&permission_key) else {
+                continue;
+            };
+            preview.push((
+                permission,
+                decision_json.map(|json| serde_json::from_str(&json)).transpose()?,
+            ));
+        }
+        Ok(preview)
+    }
+
+    pub fn get_policy(&self, app_id: &AppId, scope: PolicyScope, permission: &PermissionType) -> Result<Option<PromptDecision>> {
+        Ok(self.get_policy_entry(app_id, scope, permission)?.map(|(decision, _)| decision))
+    }
+
+    /// Like `get_policy`, also returning when the decision expires.
+    pub fn get_policy_entry(&self, app_id: &AppId, scope: PolicyScope, permission: &PermissionType) -> Result<Option<(PromptDecision, Option<i64>)>> {
+        let permission_key = permission.to_string();
         let now = current_timestamp();
 
         let mut stmt = self.conn.prepare(
             "SELECT decision, expires_at FROM policies
-             WHERE app_id = ?1 AND permission_type = ?2"
+             WHERE app_id = ?1 AND uid = ?2 AND permission_type = ?3"
         )?;
 
         let result = stmt.query_row(
-            params![&app_id.primary, permission_json],
+            params![&app_id.primary, scope_to_uid(scope), permission_key],
             |row| {
                 let decision_json: String = row.get(0)?;
                 let expires_at: Option<i64> = row.get(1)?;
@@ -259,30 +520,30 @@ impl Database {
 
                 let decision: PromptDecision = serde_json::from_str(&decision_json)?;
                 debug!("Found cached policy for {}", app_id.primary);
-                Ok(Some(decision))
+                Ok(Some((decision, expires_at)))
             }
             Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
             Err(e) => Err(e.into()),
         }
     }
 
-    pub fn get_app_policies(&self, app_id: &AppId) -> Result<Vec<(PermissionType, PromptDecision)>> {
+    pub fn get_app_policies(&self, app_id: &AppId, scope: PolicyScope) -> Result<Vec<(PermissionType, PromptDecision)>> {
         let mut stmt = self.conn.prepare(
             "SELECT permission_type, decision, expires_at FROM policies
-             WHERE app_id = ?1"
+             WHERE app_id = ?1 AND uid = ?2"
         )?;
 
         let now = current_timestamp();
-        let rows = stmt.query_map(params![&app_id.primary], |row| {
-            let permission_json: String = row.get(0)?;
+        let rows = stmt.query_map(params![&app_id.primary, scope_to_uid(scope)], |row| {
+            let permission_key: String = row.get(0)?;
             let decision_json: String = row.get(1)?;
             let expires_at: Option<i64> = row.get(2)?;
-            Ok((permission_json, decision_json, expires_at))
+            Ok((permission_key, decision_json, expires_at))
         })?;
 
         let mut policies = Vec::new();
         for row in rows {
-            let (perm_json, dec_json, expires_at) = row?;
+            let (perm_key, dec_json, expires_at) = row?;
             
             if let Some(expiry) = expires_at {
                 if now > expiry {
@@ -290,7 +551,9 @@ impl Database {
                 }
             }
 
-            let permission: PermissionType = serde_json::from_str(&perm_json)?;
+            let Some(permission) = parse_stored_permission(&perm_key) else {
+                continue;
+            };
             let decision: PromptDecision = serde_json::from_str(&dec_json)?;
             policies.push((permission, decision));
         }
@@ -298,6 +561,41 @@ impl Database {
         Ok(policies)
     }
 
+    pub fn get_all_policies(&self) -> Result<Vec<(String, PolicyScope, PermissionType, PromptDecision)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT app_id, uid, permission_type, decision, expires_at FROM policies"
+        )?;
+
+        let now = current_timestamp();
+        let rows = stmt.query_map([], |row| {
+            let app_id: String = row.get(0)?;
+            let uid: i64 = row.get(1)?;
+            let permission_key: String = row.get(2)?;
+            let decision_json: String = row.get(3)?;
+            let expires_at: Option<i64> = row.get(4)?;
+            Ok((app_id, uid, permission_key, decision_json, expires_at))
+        })?;
+
+        let mut policies = Vec::new();
+        for row in rows {
+            let (app_id, uid, perm_key, dec_json, expires_at) = row?;
+
+            if let Some(expiry) = expires_at {
+                if now > expiry {
+                    continue;
+                }
+            }
+
+            let Some(permission) = parse_stored_permission(&perm_key) else {
+                continue;
+            };
+            let decision: PromptDecision = serde_json::from_str(&dec_json)?;
+            policies.push((app_id, scope_from_uid(uid), permission, decision));
+        }
+
+        Ok(policies)
+    }
+
 
     pub fn log_audit(&mut self, 
         app_id: &AppId, 
@@ -306,28 +604,30 @@ impl Database {
         permission: &PermissionType, 
         decision: Option<&PromptDecision>,
         granted: bool,
-        was_prompted: bool
+        was_prompted: bool,
+        reason: Option<&str>,
     ) -> Result<()> {
         self.register_application(app_id)?;
 
-        let permission_json = serde_json::to_string(permission)?;
+        let permission_key = permission.to_string();
         let decision_json = decision.map(|d| serde_json::to_string(d).ok()).flatten()
             .unwrap_or_else(|| "null".to_string());
         let now = current_timestamp();
 
         self.conn.execute(
             "INSERT INTO audit_log 
-             (timestamp, app_id, pid, uid, permission_type, decision, granted, was_prompted)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+             (timestamp, app_id, pid, uid, permission_type, decision, granted, was_prompted, reason)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
             params![
                 now,
                 &app_id.primary,
                 pid,
                 uid,
-                permission_json,
+                permission_key,
                 decision_json,
                 granted as i32,
                 was_prompted as i32,
+                reason,
             ],
         )?;
 
@@ -335,26 +635,17 @@ impl Database {
         Ok(())
     }
 
-    pub fn get_audit_entries(&self, limit: usize) -> Result<Vec<AuditEntry>> {
+    /// Returns the most recent audit entries, optionally only those for `uid`.
+    pub fn get_audit_entries(&self, limit: usize, uid: Option<u32>) -> Result<Vec<AuditEntry>> {
         let mut stmt = self.conn.prepare(
-            "SELECT timestamp, app_id, pid, uid, permission_type, decision, granted, was_prompted
+            "SELECT timestamp, app_id, pid, uid, permission_type, decision, granted, was_prompted, reason
              FROM audit_log
+             WHERE ?2 IS NULL OR uid = ?2
              ORDER BY timestamp DESC
              LIMIT ?1"
         )?;
 
-        let rows = stmt.query_map(params![limit], |row| {
-            Ok(AuditEntry {
-                timestamp: row.get(0)?,
-                app_id: row.get(1)?,
-                pid: row.get(2)?,
-                uid: row.get(3)?,
-                permission_json: row.get(4)?,
-                decision_json: row.get(5)?,
-                granted: row.get::<_, i32>(6)? != 0,
-                was_prompted: row.get::<_, i32>(7)? != 0,
-            })
-        })?;
+        let rows = stmt.query_map(params![limit, uid], audit_entry_from_row)?;
 
         let mut entries = Vec::new();
         for row in rows {
@@ -364,6 +655,83 @@ impl Database {
         Ok(entries)
     }
 
+    /// Returns the `limit` most recent entries that are policy outcomes, see
+    /// `AuditEntry::is_policy_outcome`, however many other entries lie
+    /// between them.
+    pub fn get_policy_outcomes(&self, limit: usize) -> Result<Vec<AuditEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT timestamp, app_id, pid, uid, permission_type, decision, granted, was_prompted, reason
+             FROM audit_log
+             ORDER BY timestamp DESC, id DESC"
+        )?;
+
+        let mut entries = Vec::new();
+        for row in stmt.query_map([], audit_entry_from_row)? {
+            if entries.len() == limit {
+                break;
+            }
+            let entry = row?;
+            if entry.is_policy_outcome() {
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Puts `app_id` into learning mode for `uid`. Restarting a session that
+    /// is already running keeps its original start time.
+    pub fn start_learning(&mut self, app_id: &AppId, uid: u32) -> Result<()> {
+        self.register_application(app_id)?;
+        self.conn.execute(
+            "INSERT OR IGNORE INTO learning_sessions (app_id, uid, started_at) VALUES (?1, ?2, ?3)",
+            params![&app_id.primary, uid, current_timestamp()],
+        ).context("Failed to start learning session")?;
+        Ok(())
+    }
+
+    /// Start time of the learning session for `app_id` and `uid`, if any.
+    pub fn learning_started_at(&self, app_id: &AppId, uid: u32) -> Result<Option<i64>> {
+        let result = self.conn.query_row(
+            "SELECT started_at FROM learning_sessions WHERE app_id = ?1 AND uid = ?2",
+            params![&app_id.primary, uid],
+            |row| row.get(0),
+        );
+        match result {
+            Ok(started_at) => Ok(Some(started_at)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Ends the learning session, returning when it started.
+    pub fn stop_learning(&mut self, app_id: &AppId, uid: u32) -> Result<Option<i64>> {
+        let started_at = self.learning_started_at(app_id, uid)?;
+        self.conn.execute(
+            "DELETE FROM learning_sessions WHERE app_id = ?1 AND uid = ?2",
+            params![&app_id.primary, uid],
+        )?;
+        Ok(started_at)
+    }
+
+    /// Permissions granted to `app_id` for `uid` in learning mode since
+    /// `since`, oldest first.
+    pub fn get_learned_permissions(&self, app_id: &AppId, uid: u32, since: i64) -> Result<Vec<PermissionType>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT permission_type FROM audit_log
+             WHERE app_id = ?1 AND uid = ?2 AND reason = ?3 AND timestamp >= ?4
+             ORDER BY id"
+        )?;
+
+        let rows = stmt.query_map(params![&app_id.primary, uid, LEARNING_REASON, since], |row| row.get::<_, String>(0))?;
+
+        let mut permissions = Vec::new();
+        for row in rows {
+            permissions.extend(parse_stored_permission(&row?));
+        }
+        Ok(permissions)
+    }
+
     pub fn cleanup_expired_policies(&mut self) -> Result<usize> {
         let now = current_timestamp();
         let count = self.conn.execute(
@@ -386,10 +754,188 @@ pub struct AuditEntry {
     pub app_id: String,
     pub pid: u32,
     pub uid: u32,
-    pub permission_json: String,
+    /// Permission in its textual form, e.g. `net:internet`.
+    pub permission: String,
     pub decision_json: String,
     pub granted: bool,
     pub was_prompted: bool,
+    /// Why the entry was recorded without a normal decision, e.g. a
+    /// throttled prompt.
+    pub reason: Option<String>,
+}
+
+impl AuditEntry {
+    /// Whether the entry records a permission request rather than, say, a
+    /// lifetime grant being revoked or a sandbox's DNS lookup.
+    pub fn is_request(&self) -> bool {
+        !self.reason.as_deref().is_some_and(|reason| reason.starts_with("revoked:") || reason.starts_with("dns:"))
+    }
+
+    /// Whether the entry records a request that policy decided, so that a
+    /// change of policy could decide it differently. Requests granted
+    /// because the app was learning or by an earlier lifetime grant,
+    /// throttled prompts and the marker of an automatic DenyAlways are not.
+    pub fn is_policy_outcome(&self) -> bool {
+        self.is_request() && !self.reason.as_deref().is_some_and(|reason| {
+            reason == LEARNING_REASON
+                || reason.starts_with("throttled:")
+                || reason == "auto-deny-always"
+                || (reason.starts_with("granted:") && !self.was_prompted)
+        })
+    }
+}
+
+/// Who changed a policy, and through which D-Bus method or prompt.
+#[derive(Debug, Clone, Copy)]
+pub struct ChangeOrigin<'a> {
+    pub uid: u32,
+    pub source: &'a str,
+}
+
+/// One entry of the policy history. `None` decisions mean no rule existed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyChange {
+    pub version: i64,
+    pub app_id: String,
+    pub scope: PolicyScope,
+    pub permission: PermissionType,
+    pub old_decision: Option<PromptDecision>,
+    pub new_decision: Option<PromptDecision>,
+    pub changed_by: u32,
+    pub source: String,
+    pub changed_at: i64,
+}
+
+/// Sets (or with `decision` of `None`, removes) one rule and records the
+/// change in `policy_history`. Returns false if the rule already matched.
+fn write_policy(
+    tx: &rusqlite::Transaction,
+    app_id: &str,
+    uid: i64,
+    permission_key: &str,
+    decision: Option<(&str, Option<i64>)>,
+    origin: ChangeOrigin,
+) -> Result<bool> {
+    let old: Option<(String, Option<i64>)> = match tx.query_row(
+        "SELECT decision, expires_at FROM policies WHERE app_id = ?1 AND uid = ?2 AND permission_type = ?3",
+        params![app_id, uid, permission_key],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ) {
+        Ok(old) => Some(old),
+        Err(rusqlite::Error::QueryReturnedNoRows) => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let unchanged = match (&old, decision) {
+        (Some((old_json, old_expiry)), Some((json, expiry))) => old_json == json && *old_expiry == expiry,
+        (None, None) => true,
+        _ => false,
+    };
+    if unchanged {
+        return Ok(false);
+    }
+
+    let now = current_timestamp();
+    match decision {
+        Some((decision_json, expires_at)) => {
+            tx.execute(
+                "INSERT INTO policies (app_id, uid, permission_type, decision, expires_at, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT(app_id, uid, permission_type) DO UPDATE SET
+                    decision = ?4,
+                    expires_at = ?5,
+                    created_at = ?6",
+                params![app_id, uid, permission_key, decision_json, expires_at, now],
+            )?;
+        }
+        None => {
+            tx.execute(
+                "DELETE FROM policies WHERE app_id = ?1 AND uid = ?2 AND permission_type = ?3",
+                params![app_id, uid, permission_key],
+            )?;
+        }
+    }
+
+    let (old_decision, old_expires_at) = old.map_or((None, None), |(json, expiry)| (Some(json), expiry));
+    tx.execute(
+        "INSERT INTO policy_history
+         (app_id, uid, permission_type, old_decision, old_expires_at, new_decision, changed_by, source, changed_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            app_id,
+            uid,
+            permission_key,
+            old_decision,
+            old_expires_at,
+            decision.map(|(json, _)| json),
+            origin.uid,
+            origin.source,
+            now,
+        ],
+    )?;
+    Ok(true)
+}
+
+/// A rule's permission, decision and expiry as stored, before a change.
+type StoredRule = (String, Option<String>, Option<i64>);
+
+/// The state to go back to for each rule changed after `version`, held by
+/// the oldest change after it.
+fn rollback_targets(conn: &Connection, app_id: &str, uid: i64, version: i64) -> Result<Vec<StoredRule>> {
+    let mut stmt = conn.prepare(
+        "SELECT permission_type, old_decision, old_expires_at FROM policy_history
+         WHERE id IN (
+             SELECT MIN(id) FROM policy_history
+             WHERE app_id = ?1 AND uid = ?2 AND id > ?3
+             GROUP BY permission_type
+         )
+         ORDER BY id"
+    )?;
+    let rows = stmt.query_map(params![app_id, uid, version], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+fn audit_entry_from_row(row: &rusqlite::Row) -> rusqlite::Result<AuditEntry> {
+    Ok(AuditEntry {
+        timestamp: row.get(0)?,
+        app_id: row.get(1)?,
+        pid: row.get(2)?,
+        uid: row.get(3)?,
+        permission: row.get(4)?,
+        decision_json: row.get(5)?,
+        granted: row.get::<_, i32>(6)? != 0,
+        was_prompted: row.get::<_, i32>(7)? != 0,
+        reason: row.get(8)?,
+    })
+}
+
+/// A stored permission key, or `None` with a warning for one no longer
+/// valid, so that one bad row doesn't hide the rest.
+fn parse_stored_permission(key: &str) -> Option<PermissionType> {
+    match key.parse() {
+        Ok(permission) => Some(permission),
+        Err(e) => {
+            warn!("Skipping stored permission {:?}: {}", key, e);
+            None
+        }
+    }
+}
+
+fn scope_to_uid(scope: PolicyScope) -> i64 {
+    match scope {
+        PolicyScope::System => SYSTEM_SCOPE_UID,
+        PolicyScope::User(uid) => uid as i64,
+    }
+}
+
+fn scope_from_uid(uid: i64) -> PolicyScope {
+    if uid == SYSTEM_SCOPE_UID {
+        PolicyScope::System
+    } else {
+        PolicyScope::User(uid as u32)
+    }
 }
 
 fn current_timestamp() -> i64 {
@@ -399,3 +945,190 @@ fn current_timestamp() -> i64 {
         .as_secs() as i64
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apf_core::types::DeviceType;
+
+    const TEST: ChangeOrigin = ChangeOrigin { uid: 0, source: "test" };
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("apf-db-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_policies_are_scoped_by_uid() {
+        let mut db = Database::new(temp_path("scoped")).unwrap();
+        let app = AppId::from_desktop("org.example.Chat", false);
+        let camera = PermissionType::Device(DeviceType::Camera);
+
+        db.store_policy(&app, PolicyScope::User(1000), &camera, &PromptDecision::AllowAlways, TEST).unwrap();
+        db.store_policy(&app, PolicyScope::User(1001), &camera, &PromptDecision::DenyAlways, TEST).unwrap();
+
+        assert_eq!(db.get_policy(&app, PolicyScope::User(1000), &camera).unwrap(), Some(PromptDecision::AllowAlways));
+        assert_eq!(db.get_policy(&app, PolicyScope::User(1001), &camera).unwrap(), Some(PromptDecision::DenyAlways));
+        assert_eq!(db.get_policy(&app, PolicyScope::System, &camera).unwrap(), None);
+    }
+
+    #[test]
+    fn test_rollback_restores_earlier_version() {
+        let mut db = Database::new(temp_path("rollback")).unwrap();
+        let app = AppId::from_desktop("org.example.Chat", false);
+        let scope = PolicyScope::User(1000);
+        let camera = PermissionType::Device(DeviceType::Camera);
+        let user = ChangeOrigin { uid: 1000, source: PROMPT_SOURCE };
+
+        db.store_policy(&app, scope, &camera, &PromptDecision::DenyAlways, user).unwrap();
+        db.store_policy(&app, scope, &camera, &PromptDecision::AllowAlways, user).unwrap();
+        db.store_policy(&app, scope, &PermissionType::Clipboard, &PromptDecision::AllowAlways, user).unwrap();
+        // Storing the same decision again is not a change.
+        db.store_policy(&app, scope, &PermissionType::Clipboard, &PromptDecision::AllowAlways, user).unwrap();
+
+        let history = db.get_policy_history(&app, scope).unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[1].old_decision, Some(PromptDecision::DenyAlways));
+        assert_eq!(history[1].new_decision, Some(PromptDecision::AllowAlways));
+        assert_eq!(history[1].source, PROMPT_SOURCE);
+
+        let first = history[2].version;
+        assert_eq!(db.rollback_policy(&app, scope, first, TEST).unwrap(), 2);
+        assert_eq!(db.get_policy(&app, scope, &camera).unwrap(), Some(PromptDecision::DenyAlways));
+        assert_eq!(db.get_policy(&app, scope, &PermissionType::Clipboard).unwrap(), None);
+
+        // The rollback is recorded and can itself be undone.
+        let latest = db.get_policy_history(&app, scope).unwrap();
+        assert_eq!(latest.len(), 5);
+        assert_eq!(db.rollback_policy(&app, scope, history[0].version, TEST).unwrap(), 2);
+        assert_eq!(db.get_policy(&app, scope, &camera).unwrap(), Some(PromptDecision::AllowAlways));
+
+        assert!(db.rollback_policy(&app, PolicyScope::User(1001), first, TEST).is_err());
+    }
+
+    #[test]
+    fn test_learned_permissions_come_from_current_session() {
+        let mut db = Database::new(temp_path("learning")).unwrap();
+        let app = AppId::from_desktop("org.example.Legacy", false);
+        let camera = PermissionType::Device(DeviceType::Camera);
+
+        db.log_audit(&app, 1, 1000, &PermissionType::Clipboard, None, true, false, Some(LEARNING_REASON)).unwrap();
+        db.start_learning(&app, 1000).unwrap();
+        let started_at = db.learning_started_at(&app, 1000).unwrap().unwrap();
+        db.conn.execute("UPDATE audit_log SET timestamp = ?1", params![started_at - 1]).unwrap();
+
+        db.log_audit(&app, 1, 1000, &camera, None, true, false, Some(LEARNING_REASON)).unwrap();
+        db.log_audit(&app, 1, 1000, &PermissionType::Autostart, None, false, false, None).unwrap();
+        db.log_audit(&app, 1, 1001, &PermissionType::Autostart, None, true, false, Some(LEARNING_REASON)).unwrap();
+
+        assert_eq!(db.get_learned_permissions(&app, 1000, started_at).unwrap(), vec![camera]);
+        assert_eq!(db.stop_learning(&app, 1000).unwrap(), Some(started_at));
+        assert_eq!(db.learning_started_at(&app, 1000).unwrap(), None);
+    }
+
+    #[test]
+    fn test_dns_queries_are_not_requests() {
+        let mut db = Database::new(temp_path("dns")).unwrap();
+        let app = AppId::from_desktop("org.example.Chat", false);
+        let name: PermissionType = "net:host:telemetry.example.com".parse().unwrap();
+
+        db.start_learning(&app, 1000).unwrap();
+        let started_at = db.learning_started_at(&app, 1000).unwrap().unwrap();
+        db.log_audit(&app, 1, 1000, &name, None, true, false, Some(DNS_QUERY_REASON)).unwrap();
+        db.log_audit(&app, 1, 1000, &name, None, false, false, Some(DNS_BLOCKED_REASON)).unwrap();
+        db.log_audit(&app, 1, 1000, &PermissionType::Clipboard, None, true, false, None).unwrap();
+
+        let entries = db.get_audit_entries(10, Some(1000)).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries.iter().filter(|entry| entry.is_request()).count(), 1);
+        assert!(db.get_learned_permissions(&app, 1000, started_at).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_listings_skip_unparseable_permissions() {
+        let mut db = Database::new(temp_path("bad-key")).unwrap();
+        let app = AppId::from_desktop("org.example.Chat", false);
+        db.store_policy(&app, PolicyScope::User(1000), &PermissionType::Clipboard, &PromptDecision::AllowAlways, TEST).unwrap();
+        let decision_json = serde_json::to_string(&PromptDecision::DenyAlways).unwrap();
+        db.conn.execute(
+            "INSERT INTO policies (app_id, uid, permission_type, decision, created_at) VALUES (?1, 1000, 'net:host:a b?port=1', ?2, 0)",
+            params![&app.primary, decision_json],
+        ).unwrap();
+
+        assert_eq!(db.get_app_policies(&app, PolicyScope::User(1000)).unwrap(), vec![(PermissionType::Clipboard, PromptDecision::AllowAlways)]);
+        assert_eq!(db.get_all_policies().unwrap().len(), 1);
+
+        db.conn.execute(
+            "INSERT INTO policy_history (app_id, uid, permission_type, new_decision, changed_by, source, changed_at)
+             VALUES (?1, 1000, 'net:host:a b?port=1', ?2, 1000, 'test', 0)",
+            params![&app.primary, decision_json],
+        ).unwrap();
+        assert_eq!(db.rollback_preview(&app, PolicyScope::User(1000), 0).unwrap(), vec![(PermissionType::Clipboard, None)]);
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let path = temp_path("read-only");
+        assert!(Database::open_read_only(&path).is_err());
+        assert!(!path.exists());
+
+        let app = AppId::from_desktop("org.example.Chat", false);
+        Database::new(&path).unwrap()
+            .store_policy(&app, PolicyScope::User(1000), &PermissionType::Clipboard, &PromptDecision::AllowAlways, TEST).unwrap();
+
+        let mut db = Database::open_read_only(&path).unwrap();
+        assert_eq!(db.get_all_policies().unwrap().len(), 1);
+        assert!(db.log_audit(&app, 1, 1000, &PermissionType::Clipboard, None, true, false, None).is_err());
+
+        Connection::open(&path).unwrap().execute("DELETE FROM migrations WHERE version = 6", []).unwrap();
+        assert!(Database::open_read_only(&path).is_err());
+    }
+
+    #[test]
+    fn test_v1_policies_migrate_to_system_scope() {
+        let path = temp_path("migrate");
+        let camera_json = serde_json::to_string(&PermissionType::Device(DeviceType::Camera)).unwrap();
+        let clipboard_json = serde_json::to_string(&PermissionType::Clipboard).unwrap();
+        let decision_json = serde_json::to_string(&PromptDecision::AllowAlways).unwrap();
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE migrations (version INTEGER PRIMARY KEY, applied_at INTEGER NOT NULL);
+                 INSERT INTO migrations VALUES (1, 0);
+                 CREATE TABLE applications (app_id TEXT PRIMARY KEY NOT NULL, binary_hash TEXT,
+                     first_seen INTEGER NOT NULL, last_seen INTEGER NOT NULL);
+                 CREATE TABLE policies (id INTEGER PRIMARY KEY AUTOINCREMENT, app_id TEXT NOT NULL,
+                     permission_type TEXT NOT NULL, decision TEXT NOT NULL, expires_at INTEGER,
+                     created_at INTEGER NOT NULL, UNIQUE(app_id, permission_type));
+                 CREATE TABLE audit_log (id INTEGER PRIMARY KEY AUTOINCREMENT, timestamp INTEGER NOT NULL,
+                     app_id TEXT NOT NULL, pid INTEGER NOT NULL, uid INTEGER NOT NULL,
+                     permission_type TEXT NOT NULL, decision TEXT NOT NULL, granted INTEGER NOT NULL,
+                     was_prompted INTEGER NOT NULL);
+                 INSERT INTO applications VALUES ('org.example.Chat', NULL, 0, 0);",
+            ).unwrap();
+            for permission_json in [&camera_json, &clipboard_json] {
+                conn.execute(
+                    "INSERT INTO policies (app_id, permission_type, decision, created_at) VALUES ('org.example.Chat', ?1, ?2, 0)",
+                    params![permission_json, decision_json],
+                ).unwrap();
+            }
+            // The camera rule was answered at a prompt. v1 logged the
+            // daemon's uid, not the user's.
+            for (timestamp, prompted) in [(1, 1), (2, 0)] {
+                conn.execute(
+                    "INSERT INTO audit_log (timestamp, app_id, pid, uid, permission_type, decision, granted, was_prompted)
+                     VALUES (?1, 'org.example.Chat', 1, 0, ?2, ?3, 1, ?4)",
+                    params![timestamp, camera_json, decision_json, prompted],
+                ).unwrap();
+            }
+        }
+
+        let db = Database::new(&path).unwrap();
+        let app = AppId::from_desktop("org.example.Chat", false);
+        let camera = PermissionType::Device(DeviceType::Camera);
+        assert_eq!(db.get_all_policies().unwrap().len(), 2);
+        assert_eq!(db.get_policy(&app, PolicyScope::System, &camera).unwrap(), Some(PromptDecision::AllowAlways));
+        assert_eq!(db.get_policy(&app, PolicyScope::User(0), &camera).unwrap(), None);
+        assert_eq!(db.get_policy(&app, PolicyScope::System, &PermissionType::Clipboard).unwrap(), Some(PromptDecision::AllowAlways));
+    }
+}
@@ -0,0 +1,97 @@
+
+use apf_core::error::ApfError;
+use zbus::message::{Builder, Header, Message};
+use zbus::names::ErrorName;
+
+/// An error reply of the daemon's interface, named after the `ApfError` it
+/// carries (`org.apf.Error.PolicyLocked`, ...) so clients can tell failures
+/// apart and map them back with `ApfError::from_dbus`.
+#[derive(Debug)]
+pub struct ServiceError {
+    error: ApfError,
+    message: String,
+}
+
+impl ServiceError {
+    /// For engine and database calls: an `ApfError` they return keeps its
+    /// name, anything else is a storage failure described by `context`.
+    pub fn storage(context: &str) -> impl FnOnce(anyhow::Error) -> Self + '_ {
+        move |e| match e.downcast::<ApfError>() {
+            Ok(error) => error.into(),
+            Err(e) => ApfError::Storage(format!("{}: {:#}", context, e)).into(),
+        }
+    }
+
+    pub fn serialization(e: serde_json::Error) -> Self {
+        ApfError::Unknown(format!("Serialization failed: {}", e)).into()
+    }
+}
+
+impl From<ApfError> for ServiceError {
+    fn from(error: ApfError) -> Self {
+        let message = error.dbus_message();
+        Self { error, message }
+    }
+}
+
+impl From<zbus::Error> for ServiceError {
+    fn from(e: zbus::Error) -> Self {
+        ApfError::DBus(e.to_string()).into()
+    }
+}
+
+impl From<zbus::fdo::Error> for ServiceError {
+    fn from(e: zbus::fdo::Error) -> Self {
+        ApfError::DBus(e.to_string()).into()
+    }
+}
+
+impl zbus::DBusError for ServiceError {
+    fn create_reply(&self, call: &Header<'_>) -> zbus::Result<Message> {
+        // The trait only hands us the call's header, which the replacement
+        // `Message::method_error` does not accept.
+        #[allow(deprecated)]
+        Builder::error(call, self.name())?.build(&(self.message.as_str(),))
+    }
+
+    fn name(&self) -> ErrorName<'_> {
+        ErrorName::from_static_str_unchecked(self.error.dbus_name())
+    }
+
+    fn description(&self) -> Option<&str> {
+        Some(&self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zbus::DBusError;
+
+    #[test]
+    fn test_reply_maps_back_to_apf_error() {
+        let call = Message::method("/org/apf/Daemon", "SubmitDecision").unwrap()
+            .sender(":1.42").unwrap()
+            .build(&()).unwrap();
+        let error = ServiceError::from(ApfError::RequestExpired("req-1".to_string()));
+        let reply = error.create_reply(&call.header()).unwrap();
+
+        let zbus::Error::MethodError(name, message, _) = zbus::Error::from(reply) else {
+            panic!("not a method error");
+        };
+        assert_eq!(name.as_str(), "org.apf.Error.RequestExpired");
+        let mapped = ApfError::from_dbus(name.as_str(), message.as_deref().unwrap_or_default());
+        assert!(matches!(mapped, Some(ApfError::RequestExpired(id)) if id == "req-1"));
+    }
+
+    #[test]
+    fn test_engine_errors_keep_their_name() {
+        let locked = anyhow::Error::from(ApfError::PolicyLocked("org.example.Chat clipboard".to_string()));
+        assert_eq!(ServiceError::storage("Failed to update policy")(locked).name().as_str(), "org.apf.Error.PolicyLocked");
+
+        let failure = anyhow::anyhow!("database is locked");
+        let error = ServiceError::storage("Failed to update policy")(failure);
+        assert_eq!(error.name().as_str(), "org.apf.Error.Storage");
+        assert_eq!(error.description(), Some("Failed to update policy: database is locked"));
+    }
+}
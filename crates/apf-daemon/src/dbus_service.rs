@@ -1,14 +1,24 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 use tracing::{debug, info, warn};
 use zbus::{Connection, ConnectionBuilder, interface};
 use zbus::fdo;
+use zbus::message::Header;
+use zbus::SignalContext;
 
-use apf_core::{app_id::AppId, types::{PermissionType, PromptDecision}};
+use apf_core::{app_id::AppId, error::ApfError, types::{HostPattern, NetworkRule, PermissionType, PolicyScope, PromptDecision}};
+use apf_policy::{DefaultAction, PolicySet, ScopedRule, SensitivityClassifier};
 use crate::policy_engine::PolicyEngine;
 use crate::audit::AuditLogger;
+use crate::dbus_error::ServiceError;
+use crate::database::{ChangeOrigin, PROMPT_SOURCE};
+use crate::grants::{Grant, GrantLifetime, LifetimeGrants};
+use crate::polkit::{PolkitAction, PolkitAuthority};
+use crate::throttle::{PromptKey, PromptThrottle, StreakAction, ThrottleConfig, COALESCED_REASON};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct RequestId(pub String);
@@ -32,41 +42,278 @@ pub struct PermissionRequest {
     pub uid: u32,
     pub permission: PermissionType,
     pub timestamp: u64,
+    /// Answer the prompt should offer first, e.g. DenyAlways after the user
+    /// has repeatedly denied the same permission.
+    #[serde(default)]
+    pub suggested_decision: Option<PromptDecision>,
+}
+
+impl PermissionRequest {
+    fn prompt_key(&self) -> PromptKey {
+        PromptKey {
+            uid: self.uid,
+            app: self.app_id.primary.clone(),
+            permission: self.permission.clone(),
+        }
+    }
+}
+
+/// How long a prompt waits for the user before the request is denied. The
+/// launcher gives up on an answer after as long.
+const PROMPT_DEADLINE: Duration = Duration::from_secs(120);
+
+/// A request whose prompt is waiting for the user's answer.
+struct PendingRequest {
+    request: PermissionRequest,
+    deadline: Instant,
+}
+
+type PendingRequests = HashMap<RequestId, PendingRequest>;
+
+/// Removes the requests nobody answered before their deadline.
+fn take_expired(pending: &mut PendingRequests, now: Instant) -> Vec<PermissionRequest> {
+    let mut expired = Vec::new();
+    pending.retain(|_, pending| {
+        if pending.deadline > now {
+            return true;
+        }
+        expired.push(pending.request.clone());
+        false
+    });
+    expired
+}
+
+/// Credentials of the D-Bus peer that sent a method call, as reported by the bus.
+#[derive(Debug, Clone, Copy)]
+struct Caller {
+    pid: u32,
+    uid: u32,
+}
+
+impl Caller {
+    async fn from_message(connection: &Connection, hdr: &Header<'_>) -> Result<Self, ServiceError> {
+        let sender = hdr.sender()
+            .ok_or_else(|| ApfError::NotAuthorized("Message has no sender".to_string()))?;
+        let credentials = fdo::DBusProxy::new(connection).await?
+            .get_connection_credentials(sender.clone().into())
+            .await?;
+
+        let uid = credentials.unix_user_id()
+            .ok_or_else(|| ApfError::NotAuthorized("Caller UID unavailable".to_string()))?;
+        let pid = credentials.process_id()
+            .ok_or_else(|| ApfError::NotAuthorized("Caller PID unavailable".to_string()))?;
+
+        Ok(Self { pid, uid })
+    }
+
+    fn is_root(&self) -> bool {
+        self.uid == 0
+    }
 }
 
 pub struct DaemonService {
     policy_engine: Arc<Mutex<PolicyEngine>>,
     audit_logger: Arc<Mutex<AuditLogger>>,
-    pending_requests: Arc<Mutex<std::collections::HashMap<RequestId, PermissionRequest>>>,
+    polkit: PolkitAuthority,
+    pending_requests: Arc<Mutex<PendingRequests>>,
+    throttle: Arc<Mutex<PromptThrottle>>,
+    grants: Arc<Mutex<LifetimeGrants>>,
 }
 
 impl DaemonService {
-    pub fn new(policy_engine: PolicyEngine, audit_logger: AuditLogger) -> Self {
+    pub fn new(policy_engine: PolicyEngine, audit_logger: AuditLogger, throttle_config: ThrottleConfig) -> Self {
         Self {
             policy_engine: Arc::new(Mutex::new(policy_engine)),
             audit_logger: Arc::new(Mutex::new(audit_logger)),
-            pending_requests: Arc::new(Mutex::new(std::collections::HashMap::new())),
+            polkit: PolkitAuthority::new(),
+            pending_requests: Arc::new(Mutex::new(HashMap::new())),
+            throttle: Arc::new(Mutex::new(PromptThrottle::new(throttle_config))),
+            grants: Arc::new(Mutex::new(LifetimeGrants::default())),
         }
     }
 
-    async fn verify_caller(&self, pid: u32, uid: u32) -> Result<()> {
-        debug!("Verifying caller credentials: pid={}, uid={}", pid, uid);
+    /// Periodically drops grants whose app or login session has ended and
+    /// records their revocation. Lookups already ignore ended grants; this
+    /// keeps the audit log complete.
+    fn spawn_revocation_task(&self) {
+        let grants = Arc::clone(&self.grants);
+        let audit_logger = Arc::clone(&self.audit_logger);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                let ended = grants.lock().await.take_ended();
+                if ended.is_empty() {
+                    continue;
+                }
+                let mut logger = audit_logger.lock().await;
+                for grant in &ended {
+                    let _ = logger.log_revoked(grant).await;
+                }
+            }
+        });
+    }
+
+    /// Periodically denies prompts that went unanswered past their deadline,
+    /// announcing them as decided so requesters stop waiting and a new
+    /// request gets a prompt of its own. Throttle state that no longer holds
+    /// anything back is dropped along the way.
+    fn spawn_expiry_task(&self, connection: Connection) {
+        let pending_requests = Arc::clone(&self.pending_requests);
+        let throttle = Arc::clone(&self.throttle);
+        let audit_logger = Arc::clone(&self.audit_logger);
+        tokio::spawn(async move {
+            let ctxt = SignalContext::new(&connection, "/org/apf/Daemon").expect("valid object path");
+            let mut interval = tokio::time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                let now = Instant::now();
+                let expired = take_expired(&mut *pending_requests.lock().await, now);
+                throttle.lock().await.prune(now);
+                for request in &expired {
+                    info!("No answer to request {} in time, denying", request.request_id.0);
+                    let _ = audit_logger.lock().await.log_expired(request).await;
+                    if let Err(e) = Self::request_decided(&ctxt, &request.request_id.0, false).await {
+                        warn!("Failed to announce decision for {}: {}", request.request_id.0, e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Non-root callers may only make requests for their own processes.
+    async fn verify_caller(&self, caller: &Caller, pid: u32, uid: u32) -> Result<()> {
+        debug!("Verifying caller credentials: pid={}, uid={}, caller_uid={}", pid, uid, caller.uid);
+
+        if caller.is_root() {
+            return Ok(());
+        }
+        if caller.uid != uid {
+            anyhow::bail!("caller uid {} cannot act for uid {}", caller.uid, uid);
+        }
+
+        use std::os::unix::fs::MetadataExt;
+        let owner = std::fs::metadata(format!("/proc/{}", pid))?.uid();
+        if owner != uid {
+            anyhow::bail!("process {} is not owned by uid {}", pid, uid);
+        }
+        Ok(())
+    }
+
+    /// Parses a scope argument; an empty string means the caller's own scope.
+    fn parse_scope(scope: &str, caller: &Caller) -> Result<PolicyScope, ServiceError> {
+        if scope.is_empty() {
+            return Ok(PolicyScope::User(caller.uid));
+        }
+        scope.parse()
+            .map_err(|e: ApfError| ApfError::InvalidArgs(e.dbus_message()).into())
+    }
+
+    /// Callers manage their own scope freely; anything else needs Polkit.
+    async fn authorize_scope(&self, caller: &Caller, scope: PolicyScope) -> Result<(), ServiceError> {
+        if scope == PolicyScope::User(caller.uid) {
+            return Ok(());
+        }
+        self.authorize_policy_update(caller, &format!("Not authorized for scope {}", scope)).await
+    }
+
+    /// Asks Polkit for the policy update action, even for the caller's own
+    /// scope. Root needs no authorization.
+    async fn authorize_policy_update(&self, caller: &Caller, denied: &str) -> Result<(), ServiceError> {
+        if caller.is_root() {
+            return Ok(());
+        }
+
+        let authorized = self.polkit.check_authorization(PolkitAction::UpdatePolicy, caller.pid).await
+            .map_err(|e| ApfError::DBus(format!("Authorization check failed: {}", e)))?;
+        if !authorized {
+            return Err(ApfError::NotAuthorized(denied.to_string()).into());
+        }
         Ok(())
     }
 
-    async fn should_prompt(&self, app_id: &AppId, permission: &PermissionType) -> Result<bool> {
+    async fn learned_draft(&self, hdr: Header<'_>, connection: &Connection, app_id_json: String, stop: bool) -> Result<String, ServiceError> {
+        let app_id: AppId = serde_json::from_str(&app_id_json)
+            .map_err(|e| ApfError::InvalidAppId(e.to_string()))?;
+        let caller = Caller::from_message(connection, &hdr).await?;
+
+        let mut engine = self.policy_engine.lock().await;
+        let draft = engine.draft_learned_policy(&app_id, caller.uid, stop).await
+            .map_err(ServiceError::storage("Failed to draft policy"))?;
+
+        serde_json::to_string(&PolicySet { apps: vec![draft] })
+            .map_err(ServiceError::serialization)
+    }
+
+    /// Puts a request that needs the user's answer up for a prompt, sharing
+    /// one already pending for the same thing unless throttling suppresses
+    /// it. Returns what `request_permission` replies.
+    async fn queue_prompt(&self, app_id: AppId, pid: u32, uid: u32, permission: PermissionType) -> (bool, String, bool) {
+        let mut pending = self.pending_requests.lock().await;
+
+        // A prompt for the same thing is already on screen; share it.
+        // One past its deadline is about to be denied and is not shared.
+        let now = Instant::now();
+        let duplicate = pending.values().find(|pending| {
+            let request = &pending.request;
+            pending.deadline > now
+                && request.uid == uid && request.app_id.primary == app_id.primary && request.permission == permission
+        });
+        if let Some(PendingRequest { request, .. }) = duplicate {
+            let request_id = request.request_id.0.clone();
+            drop(pending);
+            debug!("Coalesced with pending request: {}", request_id);
+            let mut logger = self.audit_logger.lock().await;
+            let _ = logger.log_suppressed(&app_id, pid, uid, &permission, COALESCED_REASON).await;
+            return (true, request_id, false);
+        }
+
+        let key = PromptKey { uid, app: app_id.primary.clone(), permission: permission.clone() };
+        let mut throttle = self.throttle.lock().await;
+        let verdict = throttle.check(&key, now);
+        if let Some(reason) = verdict.audit_reason() {
+            drop(throttle);
+            drop(pending);
+            let mut logger = self.audit_logger.lock().await;
+            let _ = logger.log_suppressed(&app_id, pid, uid, &permission, reason).await;
+            return (false, String::new(), false);
+        }
+        let suggested_decision = throttle.suggests_deny_always(&key).then_some(PromptDecision::DenyAlways);
+        drop(throttle);
+
+        let request_id = RequestId::new();
+        let request = PermissionRequest {
+            request_id: request_id.clone(),
+            app_id,
+            pid,
+            uid,
+            permission,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            suggested_decision,
+        };
+
+        pending.insert(request_id.clone(), PendingRequest { request, deadline: now + PROMPT_DEADLINE });
+
+        info!("Prompt required, request_id: {}", request_id.0);
+        (true, request_id.0, false)
+    }
+
+    async fn default_action(&self, uid: u32, permission: &PermissionType) -> DefaultAction {
         let engine = self.policy_engine.lock().await;
-        engine.should_prompt(app_id, permission).await
+        engine.default_action(uid, permission)
     }
 
-    async fn get_cached_decision(&self, app_id: &AppId, permission: &PermissionType) -> Result<Option<PromptDecision>> {
+    async fn get_cached_decision(&self, app_id: &AppId, uid: u32, permission: &PermissionType) -> Result<Option<PromptDecision>> {
         let engine = self.policy_engine.lock().await;
-        engine.get_cached_decision(app_id, permission).await
+        engine.get_cached_decision(app_id, uid, permission).await
     }
 
-    async fn store_decision(&self, app_id: &AppId, permission: &PermissionType, decision: PromptDecision) -> Result<()> {
+    async fn store_decision(&self, app_id: &AppId, scope: PolicyScope, permission: &PermissionType, decision: PromptDecision, origin: ChangeOrigin<'_>) -> Result<()> {
         let mut engine = self.policy_engine.lock().await;
-        engine.store_decision(app_id, permission, decision).await
+        engine.store_decision(app_id, scope, permission, decision, origin).await
     }
 }
 
@@ -74,161 +321,756 @@ impl DaemonService {
 impl DaemonService {
     async fn request_permission(
         &mut self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
         app_id_json: String,
         pid: u32,
         uid: u32,
         permission_json: String,
-    ) -> fdo::Result<(bool, String, bool)> {
+    ) -> Result<(bool, String, bool), ServiceError> {
         info!("Permission request: pid={}, uid={}", pid, uid);
 
         let app_id: AppId = serde_json::from_str(&app_id_json)
-            .map_err(|e| fdo::Error::InvalidArgs(format!("Invalid app_id: {}", e)))?;
+            .map_err(|e| ApfError::InvalidAppId(e.to_string()))?;
         let permission: PermissionType = serde_json::from_str(&permission_json)
-            .map_err(|e| fdo::Error::InvalidArgs(format!("Invalid permission: {}", e)))?;
+            .map_err(|e| ApfError::InvalidPermission(e.to_string()))?;
 
-        self.verify_caller(pid, uid).await
-            .map_err(|e| fdo::Error::AccessDenied(format!("Credential verification failed: {}", e)))?;
+        let caller = Caller::from_message(connection, &hdr).await?;
+        self.verify_caller(&caller, pid, uid).await
+            .map_err(|e| ApfError::NotAuthorized(format!("Credential verification failed: {}", e)))?;
 
-        if let Ok(Some(decision)) = self.get_cached_decision(&app_id, &permission).await {
+        let stored = self.get_cached_decision(&app_id, uid, &permission).await.unwrap_or(None);
+        if let Some(decision) = stored.as_ref().filter(|decision| !decision.is_ask()) {
             info!("Using cached decision for {:?}: {:?}", app_id.primary, decision);
             
-            let granted = matches!(decision, PromptDecision::AllowAlways | PromptDecision::AllowOnce | PromptDecision::AllowDuration(_));
+            let granted = decision.is_allow();
             
             let mut logger = self.audit_logger.lock().await;
-            let _ = logger.log_permission_check(&app_id, &permission, granted, false).await;
+            let _ = logger.log_permission_check(&app_id, pid, uid, &permission, granted, false).await;
 
             return Ok((false, String::new(), granted));
         }
+        // A permission pinned to Ask is prompted even in learning mode and
+        // whatever its default.
+        let pinned_ask = stored.is_some();
 
-        let needs_prompt = self.should_prompt(&app_id, &permission).await
-            .map_err(|e| fdo::Error::Failed(format!("Policy check failed: {}", e)))?;
+        let lifetime_grant = if pinned_ask {
+            None
+        } else {
+            self.grants.lock().await.grant_for(&app_id, uid, pid, &permission).map(|grant| grant.lifetime.grant_reason())
+        };
+        if let Some(reason) = lifetime_grant {
+            debug!("Using lifetime grant for {:?}", app_id.primary);
 
-        if needs_prompt {
-            let request_id = RequestId::new();
-            let request = PermissionRequest {
-                request_id: request_id.clone(),
-                app_id,
-                pid,
-                uid,
-                permission,
-                timestamp: std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            };
+            let mut logger = self.audit_logger.lock().await;
+            let _ = logger.log_lifetime_grant(&app_id, pid, uid, &permission, false, reason).await;
 
-            let mut pending = self.pending_requests.lock().await;
-            pending.insert(request_id.clone(), request);
+            return Ok((false, String::new(), true));
+        }
 
-            info!("Prompt required, request_id: {}", request_id.0);
-            Ok((true, request_id.0, false))
+        let action = if pinned_ask {
+            DefaultAction::Prompt
+        } else {
+            self.default_action(uid, &permission).await
+        };
+
+        // Learning mode grants what would be prompted or allowed; what the
+        // classifier denies stays denied.
+        if !pinned_ask && action != DefaultAction::Deny {
+            let learning = self.policy_engine.lock().await.is_learning(&app_id, uid).await
+                .map_err(ServiceError::storage("Policy check failed"))?;
+            if learning {
+                let mut logger = self.audit_logger.lock().await;
+                let _ = logger.log_learned(&app_id, pid, uid, &permission).await;
+                return Ok((false, String::new(), true));
+            }
+        }
+
+        if action == DefaultAction::Allow {
+            debug!("Permission allowed by default: {:?}", app_id.primary);
+
+            let mut logger = self.audit_logger.lock().await;
+            let _ = logger.log_permission_check(&app_id, pid, uid, &permission, true, false).await;
+
+            Ok((false, String::new(), true))
+        } else if action == DefaultAction::Prompt {
+            Ok(self.queue_prompt(app_id, pid, uid, permission).await)
         } else {
             warn!("Permission denied by default: {:?}", app_id.primary);
             
             let mut logger = self.audit_logger.lock().await;
-            let _ = logger.log_permission_check(&app_id, &permission, false, false).await;
+            let _ = logger.log_permission_check(&app_id, pid, uid, &permission, false, false).await;
 
             Ok((false, String::new(), false))
         }
     }
 
+    /// Records a name a sandboxed app looked up through its DNS stub, for
+    /// the audit log; `blocked` when the stub refused it.
+    #[allow(clippy::too_many_arguments)]
+    async fn record_dns_query(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
+        app_id_json: String,
+        pid: u32,
+        uid: u32,
+        name: String,
+        blocked: bool,
+    ) -> Result<(), ServiceError> {
+        let app_id: AppId = serde_json::from_str(&app_id_json)
+            .map_err(|e| ApfError::InvalidAppId(e.to_string()))?;
+        // Only plain host names; a wildcard would read as a pattern.
+        let rule: NetworkRule = format!("host:{}", name).parse()?;
+        let HostPattern::Domain(name) = rule.host else {
+            return Err(ApfError::InvalidPermission(format!("net:host:{}", name)).into());
+        };
+        if name.starts_with("*.") {
+            return Err(ApfError::InvalidPermission(format!("net:host:{}", name)).into());
+        }
+
+        let caller = Caller::from_message(connection, &hdr).await?;
+        self.verify_caller(&caller, pid, uid).await
+            .map_err(|e| ApfError::NotAuthorized(format!("Credential verification failed: {}", e)))?;
+
+        let mut logger = self.audit_logger.lock().await;
+        logger.log_dns_query(&app_id, pid, uid, &name, blocked).await
+            .map_err(ServiceError::storage("Failed to record DNS query"))
+    }
+
     async fn submit_decision(
         &mut self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
+        #[zbus(signal_context)]
+        ctxt: SignalContext<'_>,
         request_id_str: String,
         decision_json: String,
-    ) -> fdo::Result<bool> {
+    ) -> Result<bool, ServiceError> {
         let request_id = RequestId(request_id_str);
         
         debug!("Received decision for request: {}", request_id.0);
 
         let decision: PromptDecision = serde_json::from_str(&decision_json)
-            .map_err(|e| fdo::Error::InvalidArgs(format!("Invalid decision: {}", e)))?;
+            .map_err(|e| ApfError::InvalidArgs(format!("Invalid decision: {}", e)))?;
+        if decision.is_ask() {
+            return Err(ApfError::InvalidArgs("Ask is not an answer to a prompt".to_string()).into());
+        }
+
+        let caller = Caller::from_message(connection, &hdr).await?;
 
         let mut pending = self.pending_requests.lock().await;
-        let request = pending.remove(&request_id)
-            .ok_or_else(|| fdo::Error::Failed("Request ID not found or expired".to_string()))?;
+        match pending.get(&request_id) {
+            Some(pending) if pending.request.uid != caller.uid && !caller.is_root() => {
+                return Err(ApfError::NotAuthorized("Request belongs to another user".to_string()).into());
+            }
+            // Left for the expiry task, which denies it.
+            Some(pending) if pending.deadline <= Instant::now() => {
+                return Err(ApfError::RequestExpired(request_id.0).into());
+            }
+            Some(_) => {}
+            None => return Err(ApfError::RequestExpired(request_id.0).into()),
+        }
+        let request = pending.remove(&request_id).expect("request checked above").request;
 
-        let should_store = matches!(decision, PromptDecision::AllowAlways | PromptDecision::DenyAlways | PromptDecision::AllowDuration(_));
+        let should_store = matches!(
+            decision,
+            PromptDecision::AllowAlways
+                | PromptDecision::DenyAlways
+                | PromptDecision::AllowDuration(_)
+                | PromptDecision::AllowDuring(_)
+                | PromptDecision::DenyDuring(_)
+        );
         if should_store {
-            self.store_decision(&request.app_id, &request.permission, decision.clone()).await
-                .map_err(|e| fdo::Error::Failed(format!("Failed to store decision: {}", e)))?;
+            let origin = ChangeOrigin { uid: caller.uid, source: PROMPT_SOURCE };
+            if let Err(e) = self.store_decision(&request.app_id, PolicyScope::User(request.uid), &request.permission, decision.clone(), origin).await {
+                // The request has already left `pending`; deny it rather than
+                // leave the requester waiting for an answer that never comes.
+                if let Err(e) = Self::request_decided(&ctxt, &request_id.0, false).await {
+                    warn!("Failed to announce decision for {}: {}", request_id.0, e);
+                }
+                return Err(ServiceError::storage("Failed to store decision")(e));
+            }
+        }
+
+        // If the app or session cannot be tracked the grant still covers
+        // this request, like AllowOnce.
+        let lifetime = GrantLifetime::for_decision(&decision, request.pid).unwrap_or_else(|e| {
+            warn!("Granting once only: {:#}", e);
+            None
+        });
+        let grant_reason = lifetime.as_ref().map(GrantLifetime::grant_reason);
+        if let Some(lifetime) = lifetime {
+            self.grants.lock().await.insert(Grant {
+                app_id: request.app_id.clone(),
+                pid: request.pid,
+                uid: request.uid,
+                permission: request.permission.clone(),
+                decision: decision.clone(),
+                lifetime,
+            });
         }
 
-        let granted = matches!(decision, PromptDecision::AllowAlways | PromptDecision::AllowOnce | PromptDecision::AllowDuration(_));
+        let granted = decision.is_allow();
 
         let mut logger = self.audit_logger.lock().await;
-        let _ = logger.log_permission_check(&request.app_id, &request.permission, granted, true).await;
+        let _ = match grant_reason {
+            Some(reason) => logger.log_lifetime_grant(&request.app_id, request.pid, request.uid, &request.permission, true, reason).await,
+            None => logger.log_permission_check(&request.app_id, request.pid, request.uid, &request.permission, granted, true).await,
+        };
+
+        if let Err(e) = Self::request_decided(&ctxt, &request_id.0, granted).await {
+            warn!("Failed to announce decision for {}: {}", request_id.0, e);
+        }
+
+        let action = self.throttle.lock().await
+            .record_answer(&request.prompt_key(), &decision, Instant::now());
+        if action == StreakAction::ApplyDenyAlways {
+            info!("Repeated denials, applying DenyAlways for {:?}", request.app_id.primary);
+            let origin = ChangeOrigin { uid: caller.uid, source: "prompt:auto-deny-always" };
+            // The user's answer has already taken effect; failing to add
+            // the standing denial on top of it is not the caller's error.
+            match self.store_decision(&request.app_id, PolicyScope::User(request.uid), &request.permission, PromptDecision::DenyAlways, origin).await {
+                Ok(()) => {
+                    let _ = logger.log_suppressed(&request.app_id, request.pid, request.uid, &request.permission, "auto-deny-always").await;
+                }
+                Err(e) => warn!("Failed to store automatic DenyAlways for {:?}: {:#}", request.app_id.primary, e),
+            }
+        }
 
         info!("Decision processed: request={}, granted={}", request_id.0, granted);
         Ok(granted)
     }
 
-    async fn get_app_policy(&self, app_id_json: String) -> fdo::Result<String> {
+    /// Announces the answer to a prompt, for the requester waiting on it.
+    #[zbus(signal)]
+    async fn request_decided(ctxt: &SignalContext<'_>, request_id: &str, granted: bool) -> zbus::Result<()>;
+
+    /// Returns the pending request as JSON so the agent can render its prompt.
+    async fn get_pending_request(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
+        request_id_str: String,
+    ) -> Result<String, ServiceError> {
+        let caller = Caller::from_message(connection, &hdr).await?;
+
+        let pending = self.pending_requests.lock().await;
+        let request_id = RequestId(request_id_str);
+        let request = pending.get(&request_id)
+            .map(|pending| &pending.request)
+            .ok_or_else(|| ApfError::RequestExpired(request_id.0.clone()))?;
+        if request.uid != caller.uid && !caller.is_root() {
+            return Err(ApfError::NotAuthorized("Request belongs to another user".to_string()).into());
+        }
+
+        serde_json::to_string(request)
+            .map_err(ServiceError::serialization)
+    }
+
+    async fn get_app_policy(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
+        app_id_json: String,
+        scope: String,
+    ) -> Result<String, ServiceError> {
         let app_id: AppId = serde_json::from_str(&app_id_json)
-            .map_err(|e| fdo::Error::InvalidArgs(format!("Invalid app_id: {}", e)))?;
+            .map_err(|e| ApfError::InvalidAppId(e.to_string()))?;
+
+        let caller = Caller::from_message(connection, &hdr).await?;
+        let scope = Self::parse_scope(&scope, &caller)?;
+        // System-wide rules apply to everyone, so everyone may read them.
+        if scope != PolicyScope::System {
+            self.authorize_scope(&caller, scope).await?;
+        }
 
         let engine = self.policy_engine.lock().await;
-        let policy = engine.get_app_policy(&app_id).await
-            .map_err(|e| fdo::Error::Failed(format!("Failed to get policy: {}", e)))?;
+        let policy = engine.get_app_policy(&app_id, scope).await
+            .map_err(ServiceError::storage("Failed to get policy"))?;
 
         serde_json::to_string(&policy)
-            .map_err(|e| fdo::Error::Failed(format!("Serialization failed: {}", e)))
+            .map_err(ServiceError::serialization)
     }
 
     async fn update_app_policy(
         &mut self,
         #[zbus(header)]
-        _hdr: zbus::message::Header<'_>,
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
         app_id_json: String,
+        scope: String,
         policy_json: String,
-    ) -> fdo::Result<()> {
+    ) -> Result<(), ServiceError> {
         info!("Policy update requested");
 
         let app_id: AppId = serde_json::from_str(&app_id_json)
-            .map_err(|e| fdo::Error::InvalidArgs(format!("Invalid app_id: {}", e)))?;
+            .map_err(|e| ApfError::InvalidAppId(e.to_string()))?;
         
         let policy: Vec<(PermissionType, PromptDecision)> = serde_json::from_str(&policy_json)
-            .map_err(|e| fdo::Error::InvalidArgs(format!("Invalid policy: {}", e)))?;
+            .map_err(|e| ApfError::InvalidArgs(format!("Invalid policy: {}", e)))?;
+
+        let caller = Caller::from_message(connection, &hdr).await?;
+        let scope = Self::parse_scope(&scope, &caller)?;
+        self.authorize_scope(&caller, scope).await?;
 
         let mut engine = self.policy_engine.lock().await;
-        engine.update_app_policy(&app_id, policy).await
-            .map_err(|e| fdo::Error::Failed(format!("Failed to update policy: {}", e)))?;
+        let origin = ChangeOrigin { uid: caller.uid, source: "UpdateAppPolicy" };
+        engine.update_app_policy(&app_id, scope, policy, origin).await
+            .map_err(ServiceError::storage("Failed to update policy"))?;
 
-        info!("Policy updated for: {:?}", app_id.primary);
+        info!("Policy updated for: {:?} ({})", app_id.primary, scope);
         Ok(())
     }
 
+    /// Returns the changes made to `app_id`'s rules in `scope` as JSON,
+    /// newest first. Each change's `version` can be passed to RollbackPolicy.
+    async fn get_policy_history(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
+        app_id_json: String,
+        scope: String,
+    ) -> Result<String, ServiceError> {
+        let app_id: AppId = serde_json::from_str(&app_id_json)
+            .map_err(|e| ApfError::InvalidAppId(e.to_string()))?;
+
+        let caller = Caller::from_message(connection, &hdr).await?;
+        let scope = Self::parse_scope(&scope, &caller)?;
+        if scope != PolicyScope::System {
+            self.authorize_scope(&caller, scope).await?;
+        }
+
+        let engine = self.policy_engine.lock().await;
+        let history = engine.get_policy_history(&app_id, scope).await
+            .map_err(ServiceError::storage("Failed to get policy history"))?;
+
+        serde_json::to_string(&history)
+            .map_err(ServiceError::serialization)
+    }
+
+    /// Restores `app_id`'s rules in `scope` to how they were right after
+    /// change `version` (0 for before the first change). Returns the number
+    /// of rules changed.
+    async fn rollback_policy(
+        &mut self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
+        app_id_json: String,
+        scope: String,
+        version: i64,
+    ) -> Result<u32, ServiceError> {
+        let app_id: AppId = serde_json::from_str(&app_id_json)
+            .map_err(|e| ApfError::InvalidAppId(e.to_string()))?;
+
+        let caller = Caller::from_message(connection, &hdr).await?;
+        let scope = Self::parse_scope(&scope, &caller)?;
+        self.authorize_scope(&caller, scope).await?;
+
+        let mut engine = self.policy_engine.lock().await;
+        let origin = ChangeOrigin { uid: caller.uid, source: "RollbackPolicy" };
+        let changed = engine.rollback_policy(&app_id, scope, version, origin).await
+            .map_err(ServiceError::storage("Failed to roll back policy"))?;
+
+        info!("Policy for {:?} ({}) rolled back to version {}", app_id.primary, scope, version);
+        Ok(changed as u32)
+    }
+
+    /// Lints the rules that apply in `scope` for `app_id` and returns the
+    /// findings as JSON. A user scope is linted together with the system
+    /// rules that override it.
+    async fn lint_app_policy(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
+        app_id_json: String,
+        scope: String,
+    ) -> Result<String, ServiceError> {
+        let app_id: AppId = serde_json::from_str(&app_id_json)
+            .map_err(|e| ApfError::InvalidAppId(e.to_string()))?;
+
+        let caller = Caller::from_message(connection, &hdr).await?;
+        let scope = Self::parse_scope(&scope, &caller)?;
+        if scope != PolicyScope::System {
+            self.authorize_scope(&caller, scope).await?;
+        }
+
+        let engine = self.policy_engine.lock().await;
+        let rules = match scope {
+            PolicyScope::User(uid) => engine.get_effective_rules(&app_id, uid).await,
+            PolicyScope::System => engine.get_app_policy(&app_id, scope).await.map(|policy| {
+                policy.into_iter()
+                    .map(|(permission, decision)| ScopedRule { scope, permission, decision })
+                    .collect()
+            }),
+        }.map_err(ServiceError::storage("Failed to get policy"))?;
+
+        let findings = apf_policy::lint_app(&app_id.primary, Some(&app_id.origin), &rules);
+        serde_json::to_string(&findings)
+            .map_err(ServiceError::serialization)
+    }
+
+    /// Puts `app_id` into learning mode for the caller: requests without a
+    /// stored decision are granted and recorded instead of prompted, unless
+    /// the classifier denies them. Since that grants whatever the app asks
+    /// for, it needs the same authorization as a policy update, so an app
+    /// running as the user cannot switch it on for itself.
+    async fn start_learning(
+        &mut self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
+        app_id_json: String,
+    ) -> Result<(), ServiceError> {
+        let app_id: AppId = serde_json::from_str(&app_id_json)
+            .map_err(|e| ApfError::InvalidAppId(e.to_string()))?;
+        let caller = Caller::from_message(connection, &hdr).await?;
+        self.authorize_policy_update(&caller, "Not authorized to start learning mode").await?;
+
+        let mut engine = self.policy_engine.lock().await;
+        engine.start_learning(&app_id, caller.uid).await
+            .map_err(ServiceError::storage("Failed to start learning"))?;
+
+        info!("Learning mode started for {:?} (uid {})", app_id.primary, caller.uid);
+        Ok(())
+    }
+
+    /// Ends learning mode and returns the drafted policy as a JSON policy
+    /// set. Nothing is stored until the draft is passed to ApplyPolicyDraft.
+    async fn stop_learning(
+        &mut self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
+        app_id_json: String,
+    ) -> Result<String, ServiceError> {
+        self.learned_draft(hdr, connection, app_id_json, true).await
+    }
+
+    /// Returns the policy drafted so far without ending learning mode.
+    async fn get_learning_draft(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
+        app_id_json: String,
+    ) -> Result<String, ServiceError> {
+        self.learned_draft(hdr, connection, app_id_json, false).await
+    }
+
+    /// Stores every rule of a reviewed policy set, such as a learning draft.
+    async fn apply_policy_draft(
+        &mut self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
+        draft_json: String,
+    ) -> Result<(), ServiceError> {
+        let draft = PolicySet::from_json(&draft_json)
+            .map_err(|e| ApfError::InvalidArgs(format!("Invalid policy draft: {}", e)))?;
+
+        let caller = Caller::from_message(connection, &hdr).await?;
+        for app in &draft.apps {
+            self.authorize_scope(&caller, app.scope()).await?;
+        }
+
+        let mut engine = self.policy_engine.lock().await;
+        for app in draft.apps {
+            let scope = app.scope();
+            let app_id = AppId::from_desktop(app.id, false);
+            let policy = app.rules.into_iter().map(|rule| (rule.permission, rule.decision)).collect();
+            let origin = ChangeOrigin { uid: caller.uid, source: "ApplyPolicyDraft" };
+            engine.update_app_policy(&app_id, scope, policy, origin).await
+                .map_err(ServiceError::storage("Failed to update policy"))?;
+            info!("Policy draft applied for: {:?} ({})", app_id.primary, scope);
+        }
+        Ok(())
+    }
+
+    /// Removes a stored rule so the permission falls back to its default.
+    /// Returns false if no rule was stored.
+    async fn delete_policy(
+        &mut self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
+        app_id_json: String,
+        scope: String,
+        permission_json: String,
+    ) -> Result<bool, ServiceError> {
+        let app_id: AppId = serde_json::from_str(&app_id_json)
+            .map_err(|e| ApfError::InvalidAppId(e.to_string()))?;
+        let permission: PermissionType = serde_json::from_str(&permission_json)
+            .map_err(|e| ApfError::InvalidPermission(e.to_string()))?;
+
+        let caller = Caller::from_message(connection, &hdr).await?;
+        let scope = Self::parse_scope(&scope, &caller)?;
+        self.authorize_scope(&caller, scope).await?;
+
+        let mut engine = self.policy_engine.lock().await;
+        let origin = ChangeOrigin { uid: caller.uid, source: "DeletePolicy" };
+        let deleted = engine.delete_policy(&app_id, scope, &permission, origin).await
+            .map_err(ServiceError::storage("Failed to delete policy"))?;
+
+        info!("Policy deleted for: {:?} ({}) {:?}", app_id.primary, scope, permission);
+        Ok(deleted)
+    }
+
+    /// Hit and miss counters of the decision cache, as JSON.
+    async fn get_cache_stats(&self) -> Result<String, ServiceError> {
+        let engine = self.policy_engine.lock().await;
+        serde_json::to_string(&engine.cache_stats())
+            .map_err(ServiceError::serialization)
+    }
+
     async fn get_audit_log(
         &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &Connection,
         limit: u32,
-    ) -> fdo::Result<String> {
+    ) -> Result<String, ServiceError> {
+        let caller = Caller::from_message(connection, &hdr).await?;
+        let view_all = caller.is_root()
+            || self.polkit.check_authorization_silent(PolkitAction::ViewAuditLog, caller.pid).await
+                .unwrap_or(false);
+        let uid = if view_all { None } else { Some(caller.uid) };
+
         let logger = self.audit_logger.lock().await;
-        let entries = logger.get_recent_entries(limit as usize).await
-            .map_err(|e| fdo::Error::Failed(format!("Failed to get audit log: {}", e)))?;
+        let entries = logger.get_recent_entries(limit as usize, uid).await
+            .map_err(ServiceError::storage("Failed to get audit log"))?;
 
         serde_json::to_string(&entries)
-            .map_err(|e| fdo::Error::Failed(format!("Serialization failed: {}", e)))
+            .map_err(ServiceError::serialization)
     }
 
-    async fn ping(&self) -> fdo::Result<String> {
+    async fn ping(&self) -> Result<String, ServiceError> {
         Ok("pong".to_string())
     }
 }
 
+/// Applies reloaded configuration to the running service. Cached decisions
+/// are dropped as well, since the policies may have been edited meanwhile.
+pub async fn reload(
+    connection: &Connection,
+    classifier: SensitivityClassifier,
+    throttle_config: ThrottleConfig,
+) -> Result<()> {
+    let service = connection.object_server()
+        .interface::<_, DaemonService>("/org/apf/Daemon")
+        .await?;
+    let service = service.get().await;
+
+    let mut engine = service.policy_engine.lock().await;
+    engine.set_classifier(classifier);
+    engine.clear_cache();
+    service.throttle.lock().await.set_config(throttle_config);
+    info!("Configuration reloaded");
+    Ok(())
+}
+
 pub async fn start_dbus_service(
     policy_engine: PolicyEngine,
     audit_logger: AuditLogger,
+    throttle_config: ThrottleConfig,
 ) -> Result<Connection> {
     info!("Starting DBus service: org.apf.Daemon");
 
-    let service = DaemonService::new(policy_engine, audit_logger);
+    let service = DaemonService::new(policy_engine, audit_logger, throttle_config);
+    service.spawn_revocation_task();
 
     let connection = ConnectionBuilder::system()?
         .name("org.apf.Daemon")?
         .serve_at("/org/apf/Daemon", service)?
         .build()
         .await?;
+    connection.object_server()
+        .interface::<_, DaemonService>("/org/apf/Daemon")
+        .await?
+        .get()
+        .await
+        .spawn_expiry_task(connection.clone());
 
     info!("DBus service started successfully");
     Ok(connection)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::CommandExt;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+    use crate::database::Database;
+
+    fn temp_db(name: &str) -> Database {
+        let path = std::env::temp_dir().join(format!("apf-dbus-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Database::new(path).unwrap()
+    }
+
+    /// The methods the daemon exports, as introspected.
+    fn exported_methods(service: &DaemonService) -> Vec<String> {
+        let mut xml = String::new();
+        zbus::object_server::Interface::introspect_to_writer(service, &mut xml, 0);
+        xml.split("<method name=\"").skip(1)
+            .map(|rest| rest.split('"').next().unwrap().to_string())
+            .collect()
+    }
+
+    /// A bus with the system bus's deny-by-default rules and the shipped
+    /// policy; the test's own user may own the daemon's name.
+    fn bus_config(dir: &Path) -> String {
+        let owner = nix::unistd::User::from_uid(nix::unistd::getuid()).unwrap().unwrap().name;
+        let policy = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../dbus/org.apf.Daemon.conf");
+        format!(r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-BUS Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <listen>unix:path={bus}</listen>
+  <auth>EXTERNAL</auth>
+  <policy context="default">
+    <allow user="*"/>
+    <deny own="*"/>
+    <deny send_type="method_call"/>
+    <allow send_type="signal"/>
+    <allow send_requested_reply="true" send_type="method_return"/>
+    <allow send_requested_reply="true" send_type="error"/>
+    <allow receive_type="method_call"/>
+    <allow receive_type="method_return"/>
+    <allow receive_type="error"/>
+    <allow receive_type="signal"/>
+    <allow send_destination="org.freedesktop.DBus" send_interface="org.freedesktop.DBus"/>
+  </policy>
+  <policy user="{owner}">
+    <allow own="org.apf.Daemon"/>
+  </policy>
+  <include>{policy}</include>
+</busconfig>
+"#, bus = dir.join("bus").display(), owner = owner, policy = policy.display())
+    }
+
+    /// Every method the daemon exports can be called by an unprivileged
+    /// user through the shipped bus policy; the daemon checks callers
+    /// itself. Skips without dbus-daemon and dbus-send.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bus_policy_admits_every_method() {
+        let available = |tool: &str| Command::new(tool).arg("--help").stdout(Stdio::null()).stderr(Stdio::null()).status().is_ok();
+        if !available("dbus-daemon") || !available("dbus-send") {
+            eprintln!("dbus-daemon or dbus-send unavailable, skipping");
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("apf-bus-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let config = dir.join("bus.conf");
+        std::fs::write(&config, bus_config(&dir)).unwrap();
+        let mut bus = Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", config.display()))
+            .arg("--nofork")
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let socket = dir.join("bus");
+        for _ in 0..100 {
+            if socket.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        let address = format!("unix:path={}", socket.display());
+
+        let service = DaemonService::new(PolicyEngine::new(temp_db("policy")), AuditLogger::new(temp_db("audit")), ThrottleConfig::default());
+        let methods = exported_methods(&service);
+        assert!(methods.contains(&"RecordDnsQuery".to_string()), "{:?}", methods);
+        let _connection = ConnectionBuilder::address(address.as_str()).unwrap()
+            .name("org.apf.Daemon").unwrap()
+            .serve_at("/org/apf/Daemon", service).unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let mut denied = Vec::new();
+        for method in &methods {
+            // Called without arguments: a method the bus lets through is
+            // answered by the daemon, with an error about them.
+            let mut call = Command::new("dbus-send");
+            call.arg(format!("--bus={}", address))
+                .args(["--print-reply", "--reply-timeout=5000", "--dest=org.apf.Daemon", "/org/apf/Daemon"])
+                .arg(format!("org.apf.Daemon.{}", method));
+            if nix::unistd::getuid().is_root() {
+                call.uid(65534).gid(65534);
+            }
+            let output = tokio::task::spawn_blocking(move || call.output()).await.unwrap().unwrap();
+            if String::from_utf8_lossy(&output.stderr).contains("AccessDenied") {
+                denied.push(method.clone());
+            }
+        }
+        let _ = bus.kill();
+        let _ = bus.wait();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(denied.is_empty(), "denied by the bus policy: {:?}", denied);
+    }
+
+    #[test]
+    fn test_unanswered_requests_expire() {
+        let now = Instant::now();
+        let mut pending = PendingRequests::new();
+        for (name, deadline) in [("stale", now), ("fresh", now + PROMPT_DEADLINE)] {
+            let request = PermissionRequest {
+                request_id: RequestId(name.to_string()),
+                app_id: AppId::from_flatpak("org.example.Chat"),
+                pid: 1,
+                uid: 1000,
+                permission: PermissionType::Clipboard,
+                timestamp: 0,
+                suggested_decision: None,
+            };
+            pending.insert(request.request_id.clone(), PendingRequest { request, deadline });
+        }
+
+        let expired = take_expired(&mut pending, now);
+        assert_eq!(expired.iter().map(|request| request.request_id.0.as_str()).collect::<Vec<_>>(), vec!["stale"]);
+        assert!(pending.contains_key(&RequestId("fresh".to_string())));
+        assert!(take_expired(&mut pending, now).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_requests_are_audited() {
+        let service = DaemonService::new(PolicyEngine::new(temp_db("coalesce-policy")), AuditLogger::new(temp_db("coalesce-audit")), ThrottleConfig::default());
+        let app_id = AppId::from_flatpak("org.example.Chat");
+
+        let (pending, first, _) = service.queue_prompt(app_id.clone(), 1, 1000, PermissionType::Clipboard).await;
+        assert!(pending);
+        let (pending, second, granted) = service.queue_prompt(app_id, 2, 1000, PermissionType::Clipboard).await;
+        assert!(pending && !granted);
+        assert_eq!(second, first);
+
+        let entries = service.audit_logger.lock().await.get_recent_entries(10, Some(1000)).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].pid, 2);
+        assert_eq!(entries[0].reason.as_deref(), Some(COALESCED_REASON));
+    }
+}
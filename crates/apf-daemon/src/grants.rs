@@ -0,0 +1,292 @@
+
+use anyhow::{Context, Result};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+
+use apf_core::{app_id::AppId, types::{PermissionType, PromptDecision}};
+use nix::libc;
+
+/// Most parents walked looking for the process a grant was made to.
+const ANCESTORS_MAX: usize = 64;
+
+/// What a lifetime grant is tied to.
+#[derive(Debug)]
+pub enum GrantLifetime {
+    /// The requesting process, tracked through a pidfd so a recycled PID
+    /// cannot inherit the grant. `cgroup` is the app instance's own cgroup,
+    /// when it has one, which its other processes share.
+    Process { pidfd: OwnedFd, cgroup: Option<String> },
+    /// The logind session the requesting process belongs to.
+    Session { id: String },
+}
+
+impl GrantLifetime {
+    /// The lifetime `decision` is bound to for a request from `pid`, or
+    /// `None` for decisions that are not lifetime-scoped.
+    pub fn for_decision(decision: &PromptDecision, pid: u32) -> Result<Option<Self>> {
+        match decision {
+            PromptDecision::AllowUntilAppExit => {
+                let pidfd = pidfd_open(pid)?;
+                let cgroup = read_cgroup(pid).ok()
+                    .and_then(|cgroup| unified_cgroup(&cgroup))
+                    .filter(|path| is_instance_cgroup(path));
+                Ok(Some(Self::Process { pidfd, cgroup }))
+            }
+            PromptDecision::AllowForLoginSession => {
+                let id = session_from_cgroup(&read_cgroup(pid)?)
+                    .with_context(|| format!("Process {} is not in a login session", pid))?;
+                Ok(Some(Self::Session { id }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn is_alive(&self) -> bool {
+        match self {
+            Self::Process { pidfd, .. } => !has_exited(pidfd),
+            Self::Session { id } => Path::new("/run/systemd/sessions").join(id).exists(),
+        }
+    }
+
+    /// Audit reason recorded when the grant is made and each time it
+    /// covers a request.
+    pub fn grant_reason(&self) -> &'static str {
+        match self {
+            Self::Process { .. } => "granted:until-app-exit",
+            Self::Session { .. } => "granted:login-session",
+        }
+    }
+
+    /// Audit reason recorded when the grant ends.
+    pub fn revoke_reason(&self) -> &'static str {
+        match self {
+            Self::Process { .. } => "revoked:app-exit",
+            Self::Session { .. } => "revoked:session-end",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Grant {
+    pub app_id: AppId,
+    pub pid: u32,
+    pub uid: u32,
+    pub permission: PermissionType,
+    pub decision: PromptDecision,
+    pub lifetime: GrantLifetime,
+}
+
+impl Grant {
+    /// Whether a request from `pid` comes from what the grant was made to:
+    /// the same app instance (the process itself, a descendant of it, or
+    /// another process in its instance cgroup), or the same login session.
+    pub fn covers_process(&self, pid: u32) -> bool {
+        match &self.lifetime {
+            GrantLifetime::Process { cgroup, .. } => {
+                is_descendant(pid, self.pid)
+                    || cgroup.as_ref().is_some_and(|cgroup| {
+                        read_cgroup(pid).ok().and_then(|own| unified_cgroup(&own)).as_ref() == Some(cgroup)
+                    })
+            }
+            GrantLifetime::Session { id } => {
+                read_cgroup(pid).ok().and_then(|cgroup| session_from_cgroup(&cgroup)).as_ref() == Some(id)
+            }
+        }
+    }
+}
+
+/// Grants that last until an app exits or a login session ends. They live
+/// only in memory; a daemon restart ends them all.
+#[derive(Debug, Default)]
+pub struct LifetimeGrants {
+    grants: Vec<Grant>,
+}
+
+impl LifetimeGrants {
+    pub fn insert(&mut self, grant: Grant) {
+        self.grants.push(grant);
+    }
+
+    /// The grant whose lifetime has not ended that covers the request
+    /// from `pid`.
+    pub fn grant_for(&self, app_id: &AppId, uid: u32, pid: u32, permission: &PermissionType) -> Option<&Grant> {
+        self.grants.iter().find(|grant| {
+            grant.uid == uid
+                && grant.app_id.primary == app_id.primary
+                && grant.permission == *permission
+                && grant.lifetime.is_alive()
+                && grant.covers_process(pid)
+        })
+    }
+
+    /// Removes and returns the grants whose lifetime has ended.
+    pub fn take_ended(&mut self) -> Vec<Grant> {
+        let (ended, alive) = std::mem::take(&mut self.grants)
+            .into_iter()
+            .partition(|grant| !grant.lifetime.is_alive());
+        self.grants = alive;
+        ended
+    }
+}
+
+fn pidfd_open(pid: u32) -> Result<OwnedFd> {
+    // SAFETY: pidfd_open takes no pointers; a non-negative result is a new
+    // file descriptor we own.
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to open pidfd for process {}", pid));
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// A pidfd becomes readable once its process has exited.
+fn has_exited(pidfd: &OwnedFd) -> bool {
+    let mut poll_fd = libc::pollfd { fd: pidfd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    // SAFETY: `poll_fd` is a single valid pollfd and the timeout is zero.
+    let ready = unsafe { libc::poll(&mut poll_fd, 1, 0) };
+    ready > 0 && poll_fd.revents & libc::POLLIN != 0
+}
+
+fn read_cgroup(pid: u32) -> Result<String> {
+    std::fs::read_to_string(format!("/proc/{}/cgroup", pid))
+        .with_context(|| format!("Failed to read cgroup of process {}", pid))
+}
+
+/// The cgroup v2 path of a `/proc/<pid>/cgroup` listing.
+fn unified_cgroup(cgroup: &str) -> Option<String> {
+    cgroup.lines().find_map(|line| line.strip_prefix("0::")).map(str::to_string)
+}
+
+/// Whether the cgroup at `path` holds one app instance, such as an
+/// `app-*.scope` or a sandbox's cgroup, rather than a whole login session,
+/// the user's service manager or the root.
+fn is_instance_cgroup(path: &str) -> bool {
+    let Some(leaf) = path.rsplit('/').find(|segment| !segment.is_empty()) else {
+        return false;
+    };
+    !(leaf.ends_with(".slice")
+        || (leaf.starts_with("session-") && leaf.ends_with(".scope"))
+        || (leaf.starts_with("user@") && leaf.ends_with(".service"))
+        || leaf == "init.scope")
+}
+
+/// Whether `pid` is `ancestor` or one of its descendants.
+fn is_descendant(pid: u32, ancestor: u32) -> bool {
+    let mut current = pid;
+    for _ in 0..ANCESTORS_MAX {
+        if current == ancestor {
+            return true;
+        }
+        match std::fs::read_to_string(format!("/proc/{}/stat", current)).ok().and_then(|stat| parse_parent(&stat)) {
+            Some(parent) if parent > 1 => current = parent,
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// Field 4 of `/proc/<pid>/stat`, after the parenthesized command name.
+fn parse_parent(stat: &str) -> Option<u32> {
+    let (_, fields) = stat.rsplit_once(") ")?;
+    fields.split(' ').nth(1)?.parse().ok()
+}
+
+/// Extracts the logind session id from a `/proc/<pid>/cgroup` listing,
+/// e.g. `3` from `0::/user.slice/user-1000.slice/session-3.scope`.
+fn session_from_cgroup(cgroup: &str) -> Option<String> {
+    cgroup.lines()
+        .filter_map(|line| line.rsplit(':').next())
+        .flat_map(|path| path.split('/'))
+        .find_map(|segment| segment.strip_prefix("session-")?.strip_suffix(".scope"))
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_from_cgroup() {
+        assert_eq!(
+            session_from_cgroup("0::/user.slice/user-1000.slice/session-3.scope\n").as_deref(),
+            Some("3"),
+        );
+        assert_eq!(
+            session_from_cgroup("0::/user.slice/user-1000.slice/user@1000.service/app.slice/app-foo.scope\n"),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_instance_cgroups() {
+        assert!(is_instance_cgroup("/user.slice/user-1000.slice/user@1000.service/app.slice/app-org.example.Chat-1234.scope"));
+        assert!(is_instance_cgroup("/user.slice/apf-4321-0"));
+        assert!(!is_instance_cgroup("/user.slice/user-1000.slice/session-3.scope"));
+        assert!(!is_instance_cgroup("/user.slice/user-1000.slice/user@1000.service"));
+        assert!(!is_instance_cgroup("/user.slice/user-1000.slice"));
+        assert!(!is_instance_cgroup("/"));
+        assert_eq!(unified_cgroup("1:name=systemd:/x\n0::/user.slice/a.scope\n").as_deref(), Some("/user.slice/a.scope"));
+        assert_eq!(parse_parent("42 (a) b) S 7 42 42 0"), Some(7));
+    }
+
+    #[test]
+    fn test_grants_cover_only_their_instance_and_session() {
+        let app = AppId::from_desktop("org.example.Chat", false);
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+
+        // Made to this process: its children are the same instance.
+        let mut grants = LifetimeGrants::default();
+        grants.insert(Grant {
+            app_id: app.clone(),
+            pid: std::process::id(),
+            uid: 1000,
+            permission: PermissionType::Clipboard,
+            decision: PromptDecision::AllowUntilAppExit,
+            lifetime: GrantLifetime::Process { pidfd: pidfd_open(std::process::id()).unwrap(), cgroup: None },
+        });
+        grants.insert(Grant {
+            app_id: app.clone(),
+            pid: std::process::id(),
+            uid: 1000,
+            permission: PermissionType::BackgroundExecution,
+            decision: PromptDecision::AllowForLoginSession,
+            lifetime: GrantLifetime::Session { id: "apf-test-no-such-session".into() },
+        });
+        assert!(grants.grant_for(&app, 1000, child.id(), &PermissionType::Clipboard).is_some());
+        // Neither the process's parent nor another session's.
+        assert!(grants.grant_for(&app, 1000, std::os::unix::process::parent_id(), &PermissionType::Clipboard).is_none());
+        assert!(grants.grant_for(&app, 1000, child.id(), &PermissionType::BackgroundExecution).is_none());
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_process_grant_ends_with_process() {
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        let app = AppId::from_desktop("org.example.Chat", false);
+        let decision = PromptDecision::AllowUntilAppExit;
+
+        let mut grants = LifetimeGrants::default();
+        grants.insert(Grant {
+            app_id: app.clone(),
+            pid: child.id(),
+            uid: 1000,
+            permission: PermissionType::Clipboard,
+            lifetime: GrantLifetime::for_decision(&decision, child.id()).unwrap().unwrap(),
+            decision,
+        });
+        let grant = grants.grant_for(&app, 1000, child.id(), &PermissionType::Clipboard).unwrap();
+        assert_eq!(grant.lifetime.grant_reason(), "granted:until-app-exit");
+        assert!(grants.grant_for(&app, 1001, child.id(), &PermissionType::Clipboard).is_none());
+        assert!(grants.take_ended().is_empty());
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(grants.grant_for(&app, 1000, child.id(), &PermissionType::Clipboard).is_none());
+        let ended = grants.take_ended();
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].lifetime.revoke_reason(), "revoked:app-exit");
+    }
+}
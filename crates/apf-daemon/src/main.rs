@@ -1,17 +1,26 @@
 mod audit;
+mod cache;
 mod database;
+mod dbus_error;
 mod dbus_service;
+mod grants;
 mod permissions;
 mod polkit;
 mod policy_engine;
+mod simulate;
+mod throttle;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
 use tracing::{error, info};
 
 use crate::audit::AuditLogger;
 use crate::database::Database;
 use crate::permissions::ApfPaths;
 use crate::policy_engine::PolicyEngine;
+use crate::throttle::ThrottleConfig;
+use apf_core::types::PolicyScope;
+use apf_policy::SensitivityClassifier;
 
 #[derive(Parser)]
 #[command(name = "apfd")]
@@ -25,6 +34,52 @@ struct Args {
 
     #[arg(long)]
     no_dbus: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Replay the audit log against a candidate policy set and report changed outcomes
+    Simulate {
+        /// Candidate policy file (TOML or JSON)
+        policy: PathBuf,
+
+        /// Database to read policies and audit history from
+        #[arg(long)]
+        database: Option<PathBuf>,
+
+        /// Number of most recent audit entries to replay
+        #[arg(long, default_value_t = 10000)]
+        limit: usize,
+
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check a policy file, or an app's stored rules, for conflicts and dangerous grants
+    Lint {
+        /// Policy file (TOML or JSON) to check
+        #[arg(required_unless_present = "app")]
+        policy: Option<PathBuf>,
+
+        /// Check the stored rules for this app id instead of a file
+        #[arg(long, conflicts_with = "policy")]
+        app: Option<String>,
+
+        /// User whose rules are checked together with the system rules
+        #[arg(long, requires = "app")]
+        uid: Option<u32>,
+
+        /// Database to read stored rules from
+        #[arg(long)]
+        database: Option<PathBuf>,
+
+        /// Print the findings as JSON
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 #[tokio::main]
@@ -42,6 +97,10 @@ async fn main() -> anyhow::Result<()> {
         .finish();
     tracing::subscriber::set_global_default(subscriber)?;
 
+    if let Some(command) = args.command {
+        return run_command(command, &args.config_dir).await;
+    }
+
     info!("Starting AppFence System Daemon v{}", env!("CARGO_PKG_VERSION"));
     info!("Config directory: {}", args.config_dir);
 
@@ -65,7 +124,8 @@ async fn main() -> anyhow::Result<()> {
         info!("Cleaned up {} expired policies", expired_count);
     }
 
-    let policy_engine = PolicyEngine::new(db);
+    let mut policy_engine = PolicyEngine::new(db);
+    policy_engine.set_classifier(load_classifier(&args.config_dir)?);
     info!("Policy engine initialized");
 
     let audit_db = Database::new(&paths.db_path)?;
@@ -73,12 +133,33 @@ async fn main() -> anyhow::Result<()> {
     info!("Audit logger initialized");
 
     if !args.no_dbus {
-        let _connection = dbus_service::start_dbus_service(policy_engine, audit_logger).await?;
+        let throttle_config = load_throttle_config(&args.config_dir)?;
+        let connection = dbus_service::start_dbus_service(policy_engine, audit_logger, throttle_config).await?;
         info!("DBus service started: org.apf.Daemon");
 
         info!("Daemon running, waiting for signals...");
-        tokio::signal::ctrl_c().await?;
-        
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        loop {
+            tokio::select! {
+                result = tokio::signal::ctrl_c() => {
+                    result?;
+                    break;
+                }
+                _ = hangup.recv() => {
+                    info!("Received SIGHUP, reloading configuration");
+                    let reloaded = match (load_classifier(&args.config_dir), load_throttle_config(&args.config_dir)) {
+                        (Ok(classifier), Ok(throttle_config)) => {
+                            dbus_service::reload(&connection, classifier, throttle_config).await
+                        }
+                        (Err(e), _) | (_, Err(e)) => Err(e),
+                    };
+                    if let Err(e) = reloaded {
+                        error!("Reload failed, keeping current configuration: {:#}", e);
+                    }
+                }
+            }
+        }
+
         info!("Received shutdown signal");
     } else {
         info!("Running in no-DBus mode");
@@ -88,3 +169,86 @@ async fn main() -> anyhow::Result<()> {
     info!("Shutting down gracefully");
     Ok(())
 }
+
+async fn run_command(command: Command, config_dir: &str) -> anyhow::Result<()> {
+    match command {
+        Command::Simulate { policy, database, limit, json } => {
+            let candidate = apf_policy::PolicySet::load(&policy)?;
+            let db_path = database.unwrap_or_else(|| ApfPaths::default().db_path);
+            let db = Database::open_read_only(&db_path)?;
+
+            let classifier = load_classifier(config_dir)?;
+            let report = simulate::run_simulation(&db, &candidate, classifier, limit).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                simulate::print_report(&report);
+            }
+        }
+        Command::Lint { policy, app, uid, database, json } => {
+            let findings = match (policy, app) {
+                (Some(policy), _) => apf_policy::lint_policy_set(&apf_policy::PolicySet::load(&policy)?),
+                (None, Some(app)) => {
+                    let db_path = database.unwrap_or_else(|| ApfPaths::default().db_path);
+                    let engine = PolicyEngine::new(Database::open_read_only(&db_path)?);
+                    let app_id = apf_core::AppId::from_desktop(app, false);
+                    let rules = match uid {
+                        Some(uid) => engine.get_effective_rules(&app_id, uid).await?,
+                        None => engine.get_app_policy(&app_id, PolicyScope::System).await?
+                            .into_iter()
+                            .map(|(permission, decision)| apf_policy::ScopedRule {
+                                scope: PolicyScope::System,
+                                permission,
+                                decision,
+                            })
+                            .collect(),
+                    };
+                    apf_policy::lint_app(&app_id.primary, None, &rules)
+                }
+                (None, None) => unreachable!("clap requires a policy file or --app"),
+            };
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&findings)?);
+            } else {
+                for finding in &findings {
+                    println!(
+                        "{:?} [{}] {}: {}",
+                        finding.severity, finding.code.as_str(), finding.app_id, finding.message
+                    );
+                }
+                println!("{} finding(s)", findings.len());
+            }
+
+            let errors = findings.iter().filter(|f| f.severity == apf_policy::Severity::Error).count();
+            if errors > 0 {
+                anyhow::bail!("{} lint error(s)", errors);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Loads `sensitivity.toml` from the config directory, falling back to the
+/// built-in classification when it is not installed.
+fn load_classifier(config_dir: &str) -> anyhow::Result<SensitivityClassifier> {
+    let path = Path::new(config_dir).join("sensitivity.toml");
+    if path.exists() {
+        info!("Loading sensitivity rules from {}", path.display());
+        SensitivityClassifier::load(&path)
+    } else {
+        Ok(SensitivityClassifier::default())
+    }
+}
+
+/// Loads `throttle.toml` from the config directory, falling back to the
+/// default prompt throttling when it is not installed.
+fn load_throttle_config(config_dir: &str) -> anyhow::Result<ThrottleConfig> {
+    let path = Path::new(config_dir).join("throttle.toml");
+    if path.exists() {
+        info!("Loading prompt throttling from {}", path.display());
+        ThrottleConfig::load(&path)
+    } else {
+        Ok(ThrottleConfig::default())
+    }
+}
@@ -2,65 +2,315 @@
 use anyhow::Result;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use apf_core::{app_id::AppId, types::{PermissionType, PromptDecision}};
-use crate::database::Database;
+use apf_core::{app_id::AppId, error::ApfError, types::{PermissionType, PolicyScope, PromptDecision}};
+use apf_policy::{deciding_rules, AppPolicy, Clock, DefaultAction, ScopedRule, SensitivityClassifier, SystemClock, UserDirsCache};
+use crate::cache::{CacheKey, CacheStats, CachedDecision, DecisionCache};
+use crate::database::{ChangeOrigin, Database, PolicyChange};
 
 pub struct PolicyEngine {
     db: Arc<Mutex<Database>>,
+    clock: Arc<dyn Clock>,
+    classifier: SensitivityClassifier,
+    cache: std::sync::Mutex<DecisionCache>,
+    user_dirs: UserDirsCache,
 }
 
 impl PolicyEngine {
     pub fn new(db: Database) -> Self {
-        Self { 
-            db: Arc::new(Mutex::new(db))
+        Self::with_clock(db, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(db: Database, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            db: Arc::new(Mutex::new(db)),
+            clock,
+            classifier: SensitivityClassifier::default(),
+            cache: std::sync::Mutex::new(DecisionCache::default()),
+            user_dirs: UserDirsCache::default(),
         }
     }
 
-    pub async fn should_prompt(&self, app_id: &AppId, permission: &PermissionType) -> Result<bool> {
+    /// Replaces the classifier that decides requests without a stored decision.
+    pub fn set_classifier(&mut self, classifier: SensitivityClassifier) {
+        self.classifier = classifier;
+    }
+
+    /// What happens to a request from `uid` that no stored decision covers.
+    pub fn default_action(&self, uid: u32, permission: &PermissionType) -> DefaultAction {
+        self.classifier.classify(uid, permission, &self.user_dirs)
+    }
+
+    /// Resolves the decision for a request from `uid`: system-scope rules
+    /// first, then the user's own rules, each scope's as
+    /// `apf_policy::deciding_rules` orders them. Scheduled decisions only
+    /// count while their schedule is active on the local clock.
+    pub async fn get_cached_decision(&self, app_id: &AppId, uid: u32, permission: &PermissionType) -> Result<Option<PromptDecision>> {
+        let now = self.clock.now();
+        let key = CacheKey::new(app_id, uid, permission);
+
+        let cached = self.cache.lock().expect("decision cache poisoned").get(&key, unix_time());
+        let decisions = match cached {
+            Some(decisions) => decisions,
+            None => {
+                // The user's directories are read before taking the database.
+                let dirs = match permission {
+                    PermissionType::Filesystem(_) => self.user_dirs.get(uid),
+                    _ => None,
+                };
+                let db = self.db.lock().await;
+                let mut decisions = Vec::new();
+                for scope in PolicyScope::resolution_order(uid) {
+                    let stored = db.get_app_policies(app_id, scope)?;
+                    // Entries are looked up again for their expiry, which
+                    // the cache honours.
+                    for (candidate, _) in deciding_rules(app_id, permission, &stored, dirs.as_deref()) {
+                        if let Some((decision, expires_at)) = db.get_policy_entry(app_id, scope, candidate)? {
+                            decisions.push(CachedDecision { decision, expires_at });
+                        }
+                    }
+                }
+                self.cache.lock().expect("decision cache poisoned").insert(key, decisions.clone());
+                decisions
+            }
+        };
+
+        Ok(decisions.into_iter()
+            .map(|cached| cached.decision)
+            .find(|decision| decision.applies_at(now)))
+    }
+
+    pub async fn store_decision(&mut self, app_id: &AppId, scope: PolicyScope, permission: &PermissionType, decision: PromptDecision, origin: ChangeOrigin<'_>) -> Result<()> {
+        Self::check_storable(&decision)?;
+        let mut db = self.db.lock().await;
+        Self::check_not_locked(&db, app_id, scope, permission)?;
+        self.cache.get_mut().expect("decision cache poisoned").invalidate_permission(app_id, scope, permission);
+        db.store_policy(app_id, scope, permission, &decision, origin)
+    }
+
+    pub async fn delete_policy(&mut self, app_id: &AppId, scope: PolicyScope, permission: &PermissionType, origin: ChangeOrigin<'_>) -> Result<bool> {
+        let mut db = self.db.lock().await;
+        self.cache.get_mut().expect("decision cache poisoned").invalidate_permission(app_id, scope, permission);
+        db.delete_policy(app_id, scope, permission, origin)
+    }
+
+    /// Forgets every cached decision, e.g. after policies were changed
+    /// outside the daemon.
+    pub fn clear_cache(&mut self) {
+        self.cache.get_mut().expect("decision cache poisoned").clear();
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().expect("decision cache poisoned").stats()
+    }
+
+    pub async fn get_app_policy(&self, app_id: &AppId, scope: PolicyScope) -> Result<Vec<(PermissionType, PromptDecision)>> {
         let db = self.db.lock().await;
-        let decision = db.get_policy(app_id, permission)?;
-        
-        if decision.is_some() {
-            return Ok(false); // No prompt needed
-        }
+        db.get_app_policies(app_id, scope)
+    }
 
-        Ok(Self::is_sensitive(permission))
+    /// Every stored rule that can apply to `uid` for this app, system rules first.
+    pub async fn get_effective_rules(&self, app_id: &AppId, uid: u32) -> Result<Vec<ScopedRule>> {
+        let db = self.db.lock().await;
+        let mut rules = Vec::new();
+        for scope in PolicyScope::resolution_order(uid) {
+            for (permission, decision) in db.get_app_policies(app_id, scope)? {
+                rules.push(ScopedRule { scope, permission, decision });
+            }
+        }
+        Ok(rules)
     }
 
-    fn is_sensitive(permission: &PermissionType) -> bool {
-        use apf_core::types::{NetworkLevel, DeviceType};
-        
-        matches!(permission,
-            PermissionType::Network(NetworkLevel::Internet) |
-            PermissionType::Network(NetworkLevel::Lan) |
-            PermissionType::Device(DeviceType::Camera) |
-            PermissionType::Device(DeviceType::Microphone) |
-            PermissionType::Device(DeviceType::Screen) |
-            PermissionType::Clipboard |
-            PermissionType::Autostart
-        )
+    pub async fn update_app_policy(&mut self, app_id: &AppId, scope: PolicyScope, policies: Vec<(PermissionType, PromptDecision)>, origin: ChangeOrigin<'_>) -> Result<()> {
+        let mut db = self.db.lock().await;
+        for (permission, decision) in &policies {
+            Self::check_storable(decision)?;
+            Self::check_not_locked(&db, app_id, scope, permission)?;
+        }
+        self.cache.get_mut().expect("decision cache poisoned").invalidate_app(app_id, scope);
+        for (permission, decision) in policies {
+            db.store_policy(app_id, scope, &permission, &decision, origin)?;
+        }
+        Ok(())
     }
 
-    pub async fn get_cached_decision(&self, app_id: &AppId, permission: &PermissionType) -> Result<Option<PromptDecision>> {
+    pub async fn get_policy_history(&self, app_id: &AppId, scope: PolicyScope) -> Result<Vec<PolicyChange>> {
         let db = self.db.lock().await;
-        db.get_policy(app_id, permission)
+        db.get_policy_history(app_id, scope)
     }
 
-    pub async fn store_decision(&mut self, app_id: &AppId, permission: &PermissionType, decision: PromptDecision) -> Result<()> {
+    pub async fn rollback_policy(&mut self, app_id: &AppId, scope: PolicyScope, version: i64, origin: ChangeOrigin<'_>) -> Result<usize> {
         let mut db = self.db.lock().await;
-        db.store_policy(app_id, permission, &decision)
+        // Removing a rule is allowed under a lock, as with DeletePolicy.
+        for (permission, decision) in db.rollback_preview(app_id, scope, version)? {
+            if decision.is_some() {
+                Self::check_not_locked(&db, app_id, scope, &permission)?;
+            }
+        }
+        self.cache.get_mut().expect("decision cache poisoned").invalidate_app(app_id, scope);
+        db.rollback_policy(app_id, scope, version, origin)
     }
 
-    pub async fn get_app_policy(&self, app_id: &AppId) -> Result<Vec<(PermissionType, PromptDecision)>> {
+    pub async fn start_learning(&mut self, app_id: &AppId, uid: u32) -> Result<()> {
+        let mut db = self.db.lock().await;
+        db.start_learning(app_id, uid)
+    }
+
+    pub async fn is_learning(&self, app_id: &AppId, uid: u32) -> Result<bool> {
         let db = self.db.lock().await;
-        db.get_app_policies(app_id)
+        Ok(db.learning_started_at(app_id, uid)?.is_some())
     }
 
-    pub async fn update_app_policy(&mut self, app_id: &AppId, policies: Vec<(PermissionType, PromptDecision)>) -> Result<()> {
+    /// Drafts a user-scope policy from what `app_id` requested during its
+    /// learning session. With `stop` set the session ends as well.
+    pub async fn draft_learned_policy(&mut self, app_id: &AppId, uid: u32, stop: bool) -> Result<AppPolicy> {
         let mut db = self.db.lock().await;
-        for (permission, decision) in policies {
-            db.store_policy(app_id, &permission, &decision)?;
+        let started_at = if stop {
+            db.stop_learning(app_id, uid)?
+        } else {
+            db.learning_started_at(app_id, uid)?
+        };
+        let started_at = started_at
+            .ok_or_else(|| anyhow::anyhow!("{} is not in learning mode", app_id.primary))?;
+
+        let observed = db.get_learned_permissions(app_id, uid, started_at)?;
+        Ok(apf_policy::draft_policy(&app_id.primary, Some(uid), &observed))
+    }
+
+    /// Lifetime-scoped grants belong to a running process or session and
+    /// have no meaning as a stored policy.
+    fn check_storable(decision: &PromptDecision) -> Result<()> {
+        if decision.is_lifetime_scoped() {
+            return Err(ApfError::InvalidConfig(format!("{:?} cannot be stored as a policy", decision)).into());
+        }
+        Ok(())
+    }
+
+    /// A user-scope rule would never be consulted behind an unconditional
+    /// system-scope rule for the same permission, so refuse to store one.
+    /// Scheduled system rules leave the user in charge outside their schedule.
+    fn check_not_locked(db: &Database, app_id: &AppId, scope: PolicyScope, permission: &PermissionType) -> Result<()> {
+        if let PolicyScope::User(_) = scope {
+            let system = db.get_policy(app_id, PolicyScope::System, permission)?;
+            if system.is_some_and(|decision| decision.schedule().is_none()) {
+                return Err(ApfError::PolicyLocked(format!("{} {:?}", app_id.primary, permission)).into());
+            }
         }
         Ok(())
     }
 }
+
+fn unix_time() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apf_core::types::{DeviceType, NetworkLevel, Schedule, TimeWindow};
+    use apf_policy::FixedClock;
+    use chrono::{NaiveDate, NaiveTime};
+
+    const TEST: ChangeOrigin = ChangeOrigin { uid: 0, source: "test" };
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("apf-engine-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> Arc<FixedClock> {
+        // 2024-01-01 was a Monday.
+        let date = NaiveDate::from_ymd_opt(2024, 1, day).unwrap();
+        Arc::new(FixedClock(date.and_hms_opt(hour, minute, 0).unwrap()))
+    }
+
+    fn hm(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_weekday_office_hours_grant() {
+        let app = AppId::from_desktop("org.example.Meet", false);
+        let microphone = PermissionType::Device(DeviceType::Microphone);
+        let decision = PromptDecision::AllowDuring(Schedule {
+            windows: vec![TimeWindow::weekdays(hm(9, 0), hm(18, 0))],
+        });
+
+        let path = temp_path("office");
+        let mut db = Database::new(&path).unwrap();
+        db.store_policy(&app, PolicyScope::User(1000), &microphone, &decision, TEST).unwrap();
+
+        let cases = [(3, 10, 30, true), (3, 18, 0, false), (6, 10, 30, false)];
+        for (day, hour, minute, granted) in cases {
+            let engine = PolicyEngine::with_clock(Database::new(&path).unwrap(), at(day, hour, minute));
+            let found = engine.get_cached_decision(&app, 1000, &microphone).await.unwrap();
+            assert_eq!(found.is_some_and(|d| d.is_allow()), granted, "day {} {}:{}", day, hour, minute);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_night_deny_falls_back_to_user_rule() {
+        let app = AppId::from_desktop("org.example.Chat", false);
+        let network = PermissionType::Network(NetworkLevel::Internet);
+        let night = PromptDecision::DenyDuring(Schedule {
+            windows: vec![TimeWindow::new(vec![], hm(22, 0), hm(0, 0))],
+        });
+
+        let path = temp_path("night");
+        let mut db = Database::new(&path).unwrap();
+        db.store_policy(&app, PolicyScope::System, &network, &night, TEST).unwrap();
+        db.store_policy(&app, PolicyScope::User(1000), &network, &PromptDecision::AllowAlways, TEST).unwrap();
+
+        let engine = PolicyEngine::with_clock(Database::new(&path).unwrap(), at(2, 23, 15));
+        let found = engine.get_cached_decision(&app, 1000, &network).await.unwrap();
+        assert_eq!(found, Some(night));
+
+        let engine = PolicyEngine::with_clock(Database::new(&path).unwrap(), at(2, 21, 59));
+        let found = engine.get_cached_decision(&app, 1000, &network).await.unwrap();
+        assert_eq!(found, Some(PromptDecision::AllowAlways));
+    }
+
+    #[tokio::test]
+    async fn test_rollback_respects_system_lock() {
+        let app = AppId::from_desktop("org.example.Chat", false);
+        let camera = PermissionType::Device(DeviceType::Camera);
+
+        let mut engine = PolicyEngine::new(Database::new(temp_path("rollback-lock")).unwrap());
+        engine.store_decision(&app, PolicyScope::User(1000), &camera, PromptDecision::AllowAlways, TEST).await.unwrap();
+        let version = engine.get_policy_history(&app, PolicyScope::User(1000)).await.unwrap()[0].version;
+        engine.delete_policy(&app, PolicyScope::User(1000), &camera, TEST).await.unwrap();
+        engine.store_decision(&app, PolicyScope::System, &camera, PromptDecision::DenyAlways, TEST).await.unwrap();
+
+        let err = engine.rollback_policy(&app, PolicyScope::User(1000), version, TEST).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<ApfError>(), Some(ApfError::PolicyLocked(_))));
+        assert_eq!(engine.get_app_policy(&app, PolicyScope::User(1000)).await.unwrap(), vec![]);
+
+        // Going back to before the rule existed only removes rules.
+        engine.delete_policy(&app, PolicyScope::System, &camera, TEST).await.unwrap();
+        engine.store_decision(&app, PolicyScope::User(1000), &camera, PromptDecision::AllowAlways, TEST).await.unwrap();
+        engine.store_decision(&app, PolicyScope::System, &camera, PromptDecision::DenyAlways, TEST).await.unwrap();
+        assert_eq!(engine.rollback_policy(&app, PolicyScope::User(1000), 0, TEST).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_new_network_rule_invalidates_cached_destinations() {
+        let app = AppId::from_desktop("org.example.Chat", false);
+        let destination: PermissionType = "net:host:eu.example-chat.com?port=443&proto=tcp".parse().unwrap();
+        let rule: PermissionType = "net:host:*.example-chat.com?port=443".parse().unwrap();
+
+        let path = temp_path("network");
+        let mut engine = PolicyEngine::new(Database::new(&path).unwrap());
+        engine.store_decision(&app, PolicyScope::User(1000), &PermissionType::Network(NetworkLevel::Internet),
+            PromptDecision::DenyAlways, TEST).await.unwrap();
+        let found = engine.get_cached_decision(&app, 1000, &destination).await.unwrap();
+        assert_eq!(found, Some(PromptDecision::DenyAlways));
+
+        engine.store_decision(&app, PolicyScope::User(1000), &rule, PromptDecision::AllowAlways, TEST).await.unwrap();
+        let found = engine.get_cached_decision(&app, 1000, &destination).await.unwrap();
+        assert_eq!(found, Some(PromptDecision::AllowAlways));
+        assert_eq!(engine.get_cached_decision(&app, 1001, &destination).await.unwrap(), None);
+    }
+}
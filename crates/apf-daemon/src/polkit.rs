@@ -1 +1,108 @@
+use anyhow::{Context, Result};
+use std::os::unix::fs::MetadataExt;
+use tokio::process::Command;
+use tracing::{debug, warn};
 
+#[derive(Debug, Clone, Copy)]
+pub enum PolkitAction {
+    UpdatePolicy,
+    ViewAuditLog,
+}
+
+impl PolkitAction {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::UpdatePolicy => "org.apf.policy.update",
+            Self::ViewAuditLog => "org.apf.audit.view",
+        }
+    }
+}
+
+pub struct PolkitAuthority;
+
+impl PolkitAuthority {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Checks `action` for the process `pid`, letting Polkit ask the user to
+    /// authenticate if the action requires it.
+    pub async fn check_authorization(&self, action: PolkitAction, pid: u32) -> Result<bool> {
+        self.pkcheck(action, pid, true).await
+    }
+
+    /// Checks `action` for the process `pid` without any authentication dialog.
+    pub async fn check_authorization_silent(&self, action: PolkitAction, pid: u32) -> Result<bool> {
+        self.pkcheck(action, pid, false).await
+    }
+
+    async fn pkcheck(&self, action: PolkitAction, pid: u32, interactive: bool) -> Result<bool> {
+        debug!("Checking authorization: action={}, pid={}", action.as_str(), pid);
+
+        // The start time and uid pin the check to this process, so a pid
+        // reused after the caller exits can't pass for it.
+        let subject = process_subject(pid).await
+            .with_context(|| format!("Failed to identify process {}", pid))?;
+        let mut cmd = Command::new("pkcheck");
+        cmd.arg("--action-id")
+            .arg(action.as_str())
+            .arg("--process")
+            .arg(subject);
+        if interactive {
+            cmd.arg("--allow-user-interaction");
+        }
+
+        let output = cmd.output().await.context("Failed to execute pkcheck")?;
+        let authorized = output.status.success();
+
+        if authorized {
+            debug!("Authorization granted for {}", action.as_str());
+        } else {
+            warn!("Authorization denied for {}", action.as_str());
+        }
+
+        Ok(authorized)
+    }
+}
+
+/// The `pid,start-time,uid` form of a process for `pkcheck --process`.
+async fn process_subject(pid: u32) -> Result<String> {
+    let stat = tokio::fs::read_to_string(format!("/proc/{}/stat", pid)).await?;
+    let uid = tokio::fs::metadata(format!("/proc/{}", pid)).await?.uid();
+    let start_time = parse_start_time(&stat).context("Malformed stat")?;
+    Ok(format!("{},{},{}", pid, start_time, uid))
+}
+
+/// Field 22 of `/proc/<pid>/stat`. The command name before it is in
+/// parentheses and may itself contain spaces and parentheses.
+fn parse_start_time(stat: &str) -> Option<u64> {
+    let (_, fields) = stat.rsplit_once(") ")?;
+    fields.split(' ').nth(19)?.parse().ok()
+}
+
+impl Default for PolkitAuthority {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_subject() {
+        let stat = "4242 (a (weird) name) S 1 4242 4242 0 -1 4194560 120 0 0 0 1 0 0 0 20 0 1 0 987654 1024 100";
+        assert_eq!(parse_start_time(stat), Some(987654));
+        assert_eq!(parse_start_time("4242 (truncated"), None);
+    }
+
+    #[tokio::test]
+    async fn test_own_process_subject() {
+        let subject = process_subject(std::process::id()).await.unwrap();
+        let fields: Vec<&str> = subject.split(',').collect();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0], std::process::id().to_string());
+        assert_eq!(fields[2], nix::unistd::getuid().to_string());
+    }
+}
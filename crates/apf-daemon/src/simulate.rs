@@ -0,0 +1,156 @@
+
+use anyhow::Result;
+use apf_core::{app_id::AppId, types::PermissionType};
+use apf_policy::{PolicyEngine, PolicySet, PolicyStorage, RecordedRequest, SensitivityClassifier, SimulationReport};
+use tracing::{info, warn};
+
+use crate::database::Database;
+
+/// Replays the stored audit log against the current policies with `candidate`
+/// applied on top. Each app and scope named in `candidate` has its stored
+/// rules replaced.
+pub async fn run_simulation(db: &Database, candidate: &PolicySet, classifier: SensitivityClassifier, limit: usize) -> Result<SimulationReport> {
+    let mut storage = PolicyStorage::in_memory();
+
+    for (app, scope, permission, decision) in db.get_all_policies()? {
+        if candidate.app(&app, scope).is_none() {
+            storage.store_decision(&policy_app_id(app), scope, &permission, decision).await?;
+        }
+    }
+    for app in &candidate.apps {
+        let app_id = policy_app_id(app.id.clone());
+        for rule in &app.rules {
+            storage.store_decision(&app_id, app.scope(), &rule.permission, rule.decision.clone()).await?;
+        }
+    }
+
+    let mut history = Vec::new();
+    // Entries come back newest first; replay them in the order they happened.
+    for entry in db.get_policy_outcomes(limit)?.into_iter().rev() {
+        let permission: PermissionType = match entry.permission.parse() {
+            Ok(permission) => permission,
+            Err(e) => {
+                warn!("Skipping audit entry with unreadable permission: {}", e);
+                continue;
+            }
+        };
+        history.push(RecordedRequest::from_audit(
+            entry.timestamp,
+            policy_app_id(entry.app_id),
+            entry.uid,
+            permission,
+            entry.granted,
+            entry.was_prompted,
+        ));
+    }
+
+    let mut engine = PolicyEngine::new(storage);
+    engine.set_classifier(classifier);
+    let report = engine.simulate(&history).await?;
+    info!(
+        "Simulation replayed {} requests, {} outcomes changed",
+        report.replayed,
+        report.changed_count()
+    );
+    Ok(report)
+}
+
+pub fn print_report(report: &SimulationReport) {
+    println!("Replayed {} requests, {} would change", report.replayed, report.changed_count());
+    for (app, changes) in &report.changes {
+        println!();
+        println!("{}:", app);
+        for change in changes {
+            println!(
+                "  [{}] uid={} {}: {} -> {}",
+                change.timestamp, change.uid, change.permission, change.before.as_str(), change.after.as_str()
+            );
+        }
+    }
+}
+
+// Policies and audit entries are keyed by the primary id only.
+fn policy_app_id(primary: String) -> AppId {
+    AppId::from_desktop(primary, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apf_core::types::{DeviceType, NetworkLevel, PolicyScope, PromptDecision};
+    use apf_policy::{AppPolicy, Outcome, PolicyRule};
+    use crate::database::{ChangeOrigin, LEARNING_REASON};
+
+    const TEST: ChangeOrigin = ChangeOrigin { uid: 0, source: "test" };
+
+    fn temp_db(name: &str) -> Database {
+        let path = std::env::temp_dir().join(format!("apf-sim-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Database::new(&path).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tightened_policy_reports_changes() {
+        let mut db = temp_db("tighten");
+        let app = AppId::from_desktop("org.example.Chat", false);
+        let camera = PermissionType::Device(DeviceType::Camera);
+        let network = PermissionType::Network(NetworkLevel::Internet);
+
+        db.store_policy(&app, PolicyScope::System, &network, &PromptDecision::AllowAlways, TEST).unwrap();
+        db.log_audit(&app, 1, 1000, &network, None, true, false, None).unwrap();
+        db.log_audit(&app, 1, 1000, &camera, None, true, true, None).unwrap();
+        // None of these was decided by policy.
+        db.log_audit(&app, 1, 1000, &camera, None, true, false, Some(LEARNING_REASON)).unwrap();
+        db.log_audit(&app, 1, 1000, &camera, None, false, false, Some("throttled:rate-limit")).unwrap();
+        db.log_audit(&app, 1, 1000, &camera, None, true, false, Some("granted:until-app-exit")).unwrap();
+
+        let candidate = PolicySet {
+            apps: vec![AppPolicy {
+                id: app.primary.clone(),
+                uid: None,
+                rules: vec![PolicyRule { permission: camera.clone(), decision: PromptDecision::AllowAlways }],
+            }],
+        };
+
+        let report = run_simulation(&db, &candidate, SensitivityClassifier::default(), 100).await.unwrap();
+        assert_eq!(report.replayed, 2);
+
+        let changes = &report.changes[&app.primary];
+        assert_eq!(changes.len(), 2);
+        assert!(changes.iter().any(|c| c.permission == network
+            && c.before == Outcome::Granted
+            && c.after == Outcome::Prompted));
+        assert!(changes.iter().any(|c| c.permission == camera
+            && c.before == Outcome::Prompted
+            && c.after == Outcome::Granted));
+    }
+
+    #[tokio::test]
+    async fn test_limit_counts_only_policy_outcomes() {
+        let mut db = temp_db("limit");
+        let app = AppId::from_desktop("org.example.Chat", false);
+        let camera = PermissionType::Device(DeviceType::Camera);
+
+        db.log_audit(&app, 1, 1000, &camera, None, true, true, None).unwrap();
+        db.log_audit(&app, 1, 1000, &PermissionType::Clipboard, None, false, true, None).unwrap();
+        db.log_audit(&app, 1, 1000, &camera, None, true, false, Some(LEARNING_REASON)).unwrap();
+        db.log_audit(&app, 1, 1000, &camera, None, false, false, Some("throttled:rate-limit")).unwrap();
+
+        let report = run_simulation(&db, &PolicySet::default(), SensitivityClassifier::default(), 2).await.unwrap();
+        assert_eq!(report.replayed, 2);
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_policy_reports_nothing() {
+        let mut db = temp_db("unchanged");
+        let app = AppId::from_desktop("org.example.Editor", false);
+        let clipboard = PermissionType::Clipboard;
+
+        db.store_policy(&app, PolicyScope::User(1000), &clipboard, &PromptDecision::DenyAlways, TEST).unwrap();
+        db.log_audit(&app, 1, 1000, &clipboard, None, false, false, None).unwrap();
+
+        let report = run_simulation(&db, &PolicySet::default(), SensitivityClassifier::default(), 100).await.unwrap();
+        assert_eq!(report.replayed, 1);
+        assert!(report.changes.is_empty());
+    }
+}
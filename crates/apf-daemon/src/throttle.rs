@@ -0,0 +1,288 @@
+
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use apf_core::types::{PermissionType, PromptDecision};
+use serde::{Deserialize, Deserializer};
+
+/// Identifies a stream of prompts: one user, one app, one permission.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PromptKey {
+    pub uid: u32,
+    pub app: String,
+    pub permission: PermissionType,
+}
+
+/// Prompt throttling settings, read from `throttle.toml`. Durations are
+/// given in seconds; unset fields keep their defaults.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleConfig {
+    /// Prompts allowed per app and user within `rate_window`.
+    pub max_prompts: usize,
+    #[serde(deserialize_with = "seconds")]
+    pub rate_window: Duration,
+    /// Quiet period after the first "Deny once"; doubles with each further one.
+    #[serde(deserialize_with = "seconds")]
+    pub base_backoff: Duration,
+    #[serde(deserialize_with = "seconds")]
+    pub max_backoff: Duration,
+    /// Consecutive "Deny once" answers after which DenyAlways is suggested.
+    pub deny_streak: u32,
+    /// Store DenyAlways instead of only suggesting it.
+    pub auto_deny_always: bool,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            max_prompts: 5,
+            rate_window: Duration::from_secs(60),
+            base_backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(3600),
+            deny_streak: 3,
+            auto_deny_always: false,
+        }
+    }
+}
+
+impl ThrottleConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read throttle config {}", path.display()))?;
+        Self::from_toml(&content)
+            .with_context(|| format!("Invalid throttle config {}", path.display()))
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self> {
+        let config: Self = toml::from_str(content)?;
+        if config.max_prompts == 0 {
+            anyhow::bail!("max_prompts must be at least 1");
+        }
+        if config.deny_streak == 0 {
+            anyhow::bail!("deny_streak must be at least 1");
+        }
+        Ok(config)
+    }
+}
+
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+/// `audit_log.reason` of requests that share a prompt already pending for
+/// the same app, user and permission.
+pub const COALESCED_REASON: &str = "throttled:coalesced";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Prompt,
+    /// The app has used up its prompts for the current window.
+    RateLimited,
+    /// The user recently denied this permission; stay quiet until the
+    /// back-off runs out.
+    BackingOff,
+}
+
+impl Verdict {
+    /// Reason recorded in the audit log for a throttled request.
+    pub fn audit_reason(&self) -> Option<&'static str> {
+        match self {
+            Verdict::Prompt => None,
+            Verdict::RateLimited => Some("throttled:rate-limit"),
+            Verdict::BackingOff => Some("throttled:backoff"),
+        }
+    }
+}
+
+/// What the caller should do after an answer has been recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreakAction {
+    None,
+    SuggestDenyAlways,
+    ApplyDenyAlways,
+}
+
+#[derive(Debug, Default)]
+struct DenialState {
+    streak: u32,
+    quiet_until: Option<Instant>,
+}
+
+/// Tracks prompt volume and recent denials so a misbehaving app cannot flood
+/// the user with prompts.
+pub struct PromptThrottle {
+    config: ThrottleConfig,
+    recent_prompts: HashMap<(u32, String), VecDeque<Instant>>,
+    denials: HashMap<PromptKey, DenialState>,
+}
+
+impl PromptThrottle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self {
+            config,
+            recent_prompts: HashMap::new(),
+            denials: HashMap::new(),
+        }
+    }
+
+    /// Applies reloaded settings; prompts and denials already counted stay.
+    pub fn set_config(&mut self, config: ThrottleConfig) {
+        self.config = config;
+    }
+
+    /// Decides whether a new prompt may be shown for `key`, and counts it
+    /// against the app's rate limit if so.
+    pub fn check(&mut self, key: &PromptKey, now: Instant) -> Verdict {
+        if let Some(state) = self.denials.get(key) {
+            if state.quiet_until.is_some_and(|until| now < until) {
+                return Verdict::BackingOff;
+            }
+        }
+
+        let window = self.config.rate_window;
+        let prompts = self.recent_prompts.entry((key.uid, key.app.clone())).or_default();
+        while prompts.front().is_some_and(|at| now.duration_since(*at) >= window) {
+            prompts.pop_front();
+        }
+        if prompts.len() >= self.config.max_prompts {
+            return Verdict::RateLimited;
+        }
+
+        prompts.push_back(now);
+        Verdict::Prompt
+    }
+
+    /// Forgets apps with no prompts left in the rate window, and denial
+    /// streaks whose back-off ended longer ago than the longest back-off, so
+    /// the state doesn't grow with every app and permission ever prompted.
+    pub fn prune(&mut self, now: Instant) {
+        let window = self.config.rate_window;
+        self.recent_prompts.retain(|_, prompts| prompts.back().is_some_and(|at| now.duration_since(*at) < window));
+        let forget_after = self.config.max_backoff;
+        self.denials.retain(|_, state| state.quiet_until.is_some_and(|until| now < until + forget_after));
+    }
+
+    /// Whether the next prompt for `key` should offer DenyAlways up front.
+    pub fn suggests_deny_always(&self, key: &PromptKey) -> bool {
+        self.denials.get(key).is_some_and(|state| state.streak >= self.config.deny_streak)
+    }
+
+    /// Records the user's answer to a prompt for `key`.
+    pub fn record_answer(&mut self, key: &PromptKey, decision: &PromptDecision, now: Instant) -> StreakAction {
+        if *decision != PromptDecision::DenyOnce {
+            self.denials.remove(key);
+            return StreakAction::None;
+        }
+
+        let state = self.denials.entry(key.clone()).or_default();
+        state.streak += 1;
+
+        let backoff = self.config.base_backoff
+            .saturating_mul(1u32.checked_shl(state.streak - 1).unwrap_or(u32::MAX))
+            .min(self.config.max_backoff);
+        state.quiet_until = Some(now + backoff);
+
+        if state.streak < self.config.deny_streak {
+            StreakAction::None
+        } else if self.config.auto_deny_always {
+            self.denials.remove(key);
+            StreakAction::ApplyDenyAlways
+        } else {
+            StreakAction::SuggestDenyAlways
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(app: &str) -> PromptKey {
+        PromptKey { uid: 1000, app: app.to_string(), permission: PermissionType::Clipboard }
+    }
+
+    #[test]
+    fn test_rate_limit_per_app() {
+        let mut throttle = PromptThrottle::new(ThrottleConfig { max_prompts: 2, ..Default::default() });
+        let now = Instant::now();
+
+        assert_eq!(throttle.check(&key("a"), now), Verdict::Prompt);
+        assert_eq!(throttle.check(&key("a"), now), Verdict::Prompt);
+        assert_eq!(throttle.check(&key("a"), now), Verdict::RateLimited);
+        assert_eq!(throttle.check(&key("b"), now), Verdict::Prompt);
+        assert_eq!(throttle.check(&key("a"), now + Duration::from_secs(60)), Verdict::Prompt);
+    }
+
+    #[test]
+    fn test_backoff_doubles_after_each_denial() {
+        let mut throttle = PromptThrottle::new(ThrottleConfig { deny_streak: 10, ..Default::default() });
+        let key = key("a");
+        let now = Instant::now();
+
+        throttle.record_answer(&key, &PromptDecision::DenyOnce, now);
+        assert_eq!(throttle.check(&key, now + Duration::from_secs(9)), Verdict::BackingOff);
+        assert_eq!(throttle.check(&key, now + Duration::from_secs(10)), Verdict::Prompt);
+
+        throttle.record_answer(&key, &PromptDecision::DenyOnce, now);
+        assert_eq!(throttle.check(&key, now + Duration::from_secs(19)), Verdict::BackingOff);
+        assert_eq!(throttle.check(&key, now + Duration::from_secs(20)), Verdict::Prompt);
+
+        throttle.record_answer(&key, &PromptDecision::AllowOnce, now);
+        assert_eq!(throttle.check(&key, now), Verdict::Prompt);
+    }
+
+    #[test]
+    fn test_deny_streak_suggests_or_applies_deny_always() {
+        let now = Instant::now();
+        let key = key("a");
+
+        let mut throttle = PromptThrottle::new(ThrottleConfig::default());
+        assert_eq!(throttle.record_answer(&key, &PromptDecision::DenyOnce, now), StreakAction::None);
+        assert_eq!(throttle.record_answer(&key, &PromptDecision::DenyOnce, now), StreakAction::None);
+        assert!(!throttle.suggests_deny_always(&key));
+        assert_eq!(throttle.record_answer(&key, &PromptDecision::DenyOnce, now), StreakAction::SuggestDenyAlways);
+        assert!(throttle.suggests_deny_always(&key));
+
+        let mut throttle = PromptThrottle::new(ThrottleConfig { auto_deny_always: true, ..Default::default() });
+        for _ in 0..2 {
+            throttle.record_answer(&key, &PromptDecision::DenyOnce, now);
+        }
+        assert_eq!(throttle.record_answer(&key, &PromptDecision::DenyOnce, now), StreakAction::ApplyDenyAlways);
+    }
+
+    #[test]
+    fn test_config_from_toml() {
+        let config = ThrottleConfig::from_toml("auto_deny_always = true\nrate_window = 120\n").unwrap();
+        assert!(config.auto_deny_always);
+        assert_eq!(config.rate_window, Duration::from_secs(120));
+        assert_eq!(config.max_prompts, ThrottleConfig::default().max_prompts);
+
+        assert!(ThrottleConfig::from_toml("auto_deny_alwyas = true").is_err());
+        assert!(ThrottleConfig::from_toml("max_prompts = 0").is_err());
+    }
+
+    #[test]
+    fn test_prune_forgets_stale_state() {
+        let mut throttle = PromptThrottle::new(ThrottleConfig { max_prompts: 1, ..Default::default() });
+        let now = Instant::now();
+        assert_eq!(throttle.check(&key("a"), now), Verdict::Prompt);
+        throttle.record_answer(&key("b"), &PromptDecision::DenyOnce, now);
+
+        // Still rate limited and backing off.
+        throttle.prune(now + Duration::from_secs(5));
+        assert_eq!(throttle.recent_prompts.len(), 1);
+        assert_eq!(throttle.check(&key("b"), now + Duration::from_secs(5)), Verdict::BackingOff);
+
+        // The streak outlives its back-off, so a further denial builds on it.
+        throttle.prune(now + Duration::from_secs(60));
+        assert!(throttle.recent_prompts.is_empty());
+        assert_eq!(throttle.denials[&key("b")].streak, 1);
+
+        throttle.prune(now + Duration::from_secs(10) + Duration::from_secs(3600));
+        assert!(throttle.denials.is_empty());
+    }
+}
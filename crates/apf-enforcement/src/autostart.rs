@@ -1,5 +1,6 @@
 use anyhow::Result;
 use tracing::{info, warn};
+use crate::plan::{PlanContributor, SandboxPlan};
 
 pub struct AutostartBackend {
     pub allowed: bool,
@@ -17,3 +18,12 @@ impl AutostartBackend {
         Ok(())
     }
 }
+
+/// Nothing to contribute yet: autostart is not restricted inside the sandbox.
+impl PlanContributor for AutostartBackend {
+    fn contribute(&self, _plan: &mut SandboxPlan) {
+        if !self.allowed {
+            warn!("Autostart is not restricted inside the sandbox yet");
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::Result;
 use tracing::{info, warn};
+use crate::plan::{PlanContributor, SandboxPlan};
 
 pub struct BackgroundBackend {
     pub allowed: bool,
@@ -17,3 +18,12 @@ impl BackgroundBackend {
         Ok(())
     }
 }
+
+/// Nothing to contribute yet: background execution is not restricted inside the sandbox.
+impl PlanContributor for BackgroundBackend {
+    fn contribute(&self, _plan: &mut SandboxPlan) {
+        if !self.allowed {
+            warn!("Background execution is not restricted inside the sandbox yet");
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::File;
+use std::io::{Seek, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+use anyhow::{Context, Result};
+use nix::libc;
+use crate::plan::{runtime_mounts, Namespace};
+use crate::seccomp::SeccompFilter;
+
+/// The bubblewrap executable.
+pub const BWRAP: &str = "bwrap";
+
+/// Above this many option arguments, `command` hands them to bwrap through
+/// `--args` instead of the command line.
+pub const INLINE_ARGS_MAX: usize = 512;
+
+/// Whether bwrap is on `PATH`.
+pub fn is_available() -> bool {
+    find_program(BWRAP).is_some()
+}
+
+/// The executable `name` on `PATH`.
+pub(crate) fn find_program(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| {
+            std::fs::metadata(candidate)
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+}
+
+/// One bwrap option. Mount options apply in order, so a `Tmpfs` over a
+/// directory hides what was bound there before it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BwrapOption {
+    UnshareAll,
+    /// Keeps the network namespace after `UnshareAll`.
+    ShareNet,
+    /// One namespace, for when some others stay shared.
+    Unshare(Namespace),
+    DieWithParent,
+    NewSession,
+    ClearEnv,
+    /// `--bind`, or `--bind-try` when `optional` and `src` may not exist.
+    Bind { src: PathBuf, dest: PathBuf, optional: bool },
+    RoBind { src: PathBuf, dest: PathBuf, optional: bool },
+    DevBind { src: PathBuf, dest: PathBuf, optional: bool },
+    Tmpfs(PathBuf),
+    Proc(PathBuf),
+    Dev(PathBuf),
+    Dir(PathBuf),
+    Symlink { target: PathBuf, link: PathBuf },
+    RemountRo(PathBuf),
+    SetEnv(OsString, OsString),
+    UnsetEnv(OsString),
+    Chdir(PathBuf),
+}
+
+impl BwrapOption {
+    fn push_args(&self, argv: &mut Vec<OsString>) {
+        let mut push = |args: &[&OsStr]| argv.extend(args.iter().map(|arg| arg.to_os_string()));
+        let flag = |name: &'static str| OsStr::new(name);
+        let bind = |name: &'static str, optional: bool| {
+            if optional { OsString::from(format!("{}-try", name)) } else { OsString::from(name) }
+        };
+        match self {
+            BwrapOption::UnshareAll => push(&[flag("--unshare-all")]),
+            BwrapOption::ShareNet => push(&[flag("--share-net")]),
+            BwrapOption::Unshare(namespace) => push(&[flag(unshare_flag(*namespace))]),
+            BwrapOption::DieWithParent => push(&[flag("--die-with-parent")]),
+            BwrapOption::NewSession => push(&[flag("--new-session")]),
+            BwrapOption::ClearEnv => push(&[flag("--clearenv")]),
+            BwrapOption::Bind { src, dest, optional } => {
+                push(&[&bind("--bind", *optional), src.as_os_str(), dest.as_os_str()])
+            }
+            BwrapOption::RoBind { src, dest, optional } => {
+                push(&[&bind("--ro-bind", *optional), src.as_os_str(), dest.as_os_str()])
+            }
+            BwrapOption::DevBind { src, dest, optional } => {
+                push(&[&bind("--dev-bind", *optional), src.as_os_str(), dest.as_os_str()])
+            }
+            BwrapOption::Tmpfs(path) => push(&[flag("--tmpfs"), path.as_os_str()]),
+            BwrapOption::Proc(path) => push(&[flag("--proc"), path.as_os_str()]),
+            BwrapOption::Dev(path) => push(&[flag("--dev"), path.as_os_str()]),
+            BwrapOption::Dir(path) => push(&[flag("--dir"), path.as_os_str()]),
+            BwrapOption::Symlink { target, link } => push(&[flag("--symlink"), target.as_os_str(), link.as_os_str()]),
+            BwrapOption::RemountRo(path) => push(&[flag("--remount-ro"), path.as_os_str()]),
+            BwrapOption::SetEnv(name, value) => push(&[flag("--setenv"), name, value]),
+            BwrapOption::UnsetEnv(name) => push(&[flag("--unsetenv"), name]),
+            BwrapOption::Chdir(path) => push(&[flag("--chdir"), path.as_os_str()]),
+        }
+    }
+}
+
+/// A bwrap invocation built from typed options.
+///
+/// ```
+/// use apf_enforcement::bwrap::Bwrap;
+///
+/// let mut bwrap = Bwrap::new();
+/// bwrap.unshare_all().die_with_parent().ro_bind("/usr", "/usr");
+/// assert_eq!(bwrap.argv(&["true"]), ["bwrap", "--unshare-all", "--die-with-parent",
+///     "--ro-bind", "/usr", "/usr", "--", "true"]);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bwrap {
+    options: Vec<BwrapOption>,
+    /// The raw BPF program for `--seccomp`.
+    seccomp: Option<Vec<u8>>,
+}
+
+impl Bwrap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn option(&mut self, option: BwrapOption) -> &mut Self {
+        self.options.push(option);
+        self
+    }
+
+    pub fn options(&self) -> &[BwrapOption] {
+        &self.options
+    }
+
+    pub fn unshare_all(&mut self) -> &mut Self {
+        self.option(BwrapOption::UnshareAll)
+    }
+
+    pub fn share_net(&mut self) -> &mut Self {
+        self.option(BwrapOption::ShareNet)
+    }
+
+    pub fn die_with_parent(&mut self) -> &mut Self {
+        self.option(BwrapOption::DieWithParent)
+    }
+
+    pub fn new_session(&mut self) -> &mut Self {
+        self.option(BwrapOption::NewSession)
+    }
+
+    pub fn bind(&mut self, src: impl Into<PathBuf>, dest: impl Into<PathBuf>) -> &mut Self {
+        self.option(BwrapOption::Bind { src: src.into(), dest: dest.into(), optional: false })
+    }
+
+    pub fn ro_bind(&mut self, src: impl Into<PathBuf>, dest: impl Into<PathBuf>) -> &mut Self {
+        self.option(BwrapOption::RoBind { src: src.into(), dest: dest.into(), optional: false })
+    }
+
+    pub fn dev_bind(&mut self, src: impl Into<PathBuf>, dest: impl Into<PathBuf>) -> &mut Self {
+        self.option(BwrapOption::DevBind { src: src.into(), dest: dest.into(), optional: false })
+    }
+
+    pub fn tmpfs(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.option(BwrapOption::Tmpfs(path.into()))
+    }
+
+    pub fn proc(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.option(BwrapOption::Proc(path.into()))
+    }
+
+    pub fn dev(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.option(BwrapOption::Dev(path.into()))
+    }
+
+    pub fn symlink(&mut self, target: impl Into<PathBuf>, link: impl Into<PathBuf>) -> &mut Self {
+        self.option(BwrapOption::Symlink { target: target.into(), link: link.into() })
+    }
+
+    pub fn setenv(&mut self, name: impl Into<OsString>, value: impl Into<OsString>) -> &mut Self {
+        self.option(BwrapOption::SetEnv(name.into(), value.into()))
+    }
+
+    pub fn unsetenv(&mut self, name: impl Into<OsString>) -> &mut Self {
+        self.option(BwrapOption::UnsetEnv(name.into()))
+    }
+
+    /// The mounts of `plan::runtime_mounts`, enough to start most programs
+    /// on top of an empty root.
+    pub fn system_runtime(&mut self) -> &mut Self {
+        for mount in runtime_mounts() {
+            self.option((&mount).into());
+        }
+        self
+    }
+
+    /// The option arguments, without the executable and the command.
+    pub fn option_args(&self) -> Vec<OsString> {
+        let mut argv = Vec::new();
+        for option in &self.options {
+            option.push_args(&mut argv);
+        }
+        argv
+    }
+
+    /// The full command line running `command` in the sandbox.
+    pub fn argv<S: AsRef<OsStr>>(&self, command: &[S]) -> Vec<OsString> {
+        let mut argv = vec![OsString::from(BWRAP)];
+        argv.extend(self.option_args());
+        push_command(&mut argv, command);
+        argv
+    }
+
+    /// The command line when the options are read from `fd`, which must
+    /// hold `args_data`.
+    pub fn argv_with_args_fd<S: AsRef<OsStr>>(&self, fd: RawFd, command: &[S]) -> Vec<OsString> {
+        let mut argv = vec![OsString::from(BWRAP), OsString::from("--args"), OsString::from(fd.to_string())];
+        push_command(&mut argv, command);
+        argv
+    }
+
+    /// The options in the NUL-terminated form bwrap reads from `--args`.
+    pub fn args_data(&self) -> Vec<u8> {
+        nul_terminated(&self.option_args())
+    }
+
+    /// Has bwrap install `filter` on the sandboxed command.
+    pub fn seccomp(&mut self, filter: &SeccompFilter) -> &mut Self {
+        self.seccomp = Some(filter.to_bytes());
+        self
+    }
+
+    /// A `Command` running `command` in the sandbox. The seccomp program,
+    /// and long option lists through `--args`, go in anonymous files only
+    /// the spawned bwrap inherits.
+    pub fn command<S: AsRef<OsStr>>(&self, command: &[S]) -> Result<Command> {
+        let mut inherited = Vec::new();
+        let mut options = self.option_args();
+        if let Some(program) = &self.seccomp {
+            let file = memfd("apf-seccomp", program)?;
+            options.push(OsString::from("--seccomp"));
+            options.push(OsString::from(file.as_raw_fd().to_string()));
+            inherited.push(file);
+        }
+
+        let mut cmd = Command::new(BWRAP);
+        if options.len() <= INLINE_ARGS_MAX {
+            cmd.args(&options);
+        } else {
+            let file = memfd("apf-bwrap-args", &nul_terminated(&options))?;
+            cmd.arg("--args").arg(file.as_raw_fd().to_string());
+            inherited.push(file);
+        }
+        cmd.arg("--").args(command);
+
+        if !inherited.is_empty() {
+            // SAFETY: fcntl is async-signal-safe, and the files are moved
+            // into the closure so their descriptors stay open until the spawn.
+            unsafe {
+                cmd.pre_exec(move || {
+                    for file in &inherited {
+                        if libc::fcntl(file.as_raw_fd(), libc::F_SETFD, 0) < 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
+            }
+        }
+        Ok(cmd)
+    }
+
+    /// Runs `command` in the sandbox and waits for it.
+    pub fn status<S: AsRef<OsStr>>(&self, command: &[S]) -> Result<std::process::ExitStatus> {
+        self.command(command)?
+            .status()
+            .with_context(|| format!("Failed to launch {}", BWRAP))
+    }
+}
+
+fn unshare_flag(namespace: Namespace) -> &'static str {
+    // The user and cgroup namespaces aren't available everywhere; bwrap
+    // only tries them, as `--unshare-all` does.
+    match namespace {
+        Namespace::User => "--unshare-user-try",
+        Namespace::Ipc => "--unshare-ipc",
+        Namespace::Pid => "--unshare-pid",
+        Namespace::Net => "--unshare-net",
+        Namespace::Uts => "--unshare-uts",
+        Namespace::Cgroup => "--unshare-cgroup-try",
+    }
+}
+
+fn push_command<S: AsRef<OsStr>>(argv: &mut Vec<OsString>, command: &[S]) {
+    argv.push(OsString::from("--"));
+    argv.extend(command.iter().map(|arg| arg.as_ref().to_os_string()));
+}
+
+fn nul_terminated(args: &[OsString]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for arg in args {
+        data.extend_from_slice(arg.as_bytes());
+        data.push(0);
+    }
+    data
+}
+
+/// An unlinked in-memory file holding `data`, positioned at its start.
+pub(crate) fn memfd(name: &str, data: &[u8]) -> Result<File> {
+    let name = CString::new(name)?;
+    // SAFETY: the name is a valid NUL-terminated string; a non-negative
+    // result is a new descriptor we own.
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to create an in-memory file");
+    }
+    let mut file = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    file.write_all(data).context("Failed to write an in-memory file")?;
+    file.rewind()?;
+    Ok(file)
+}
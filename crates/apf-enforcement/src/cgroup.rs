@@ -0,0 +1,422 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use anyhow::{bail, Context, Result};
+use apf_core::types::{HostPattern, NetworkLevel, NetworkRule, Protocol};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use tracing::{info, warn};
+use crate::bwrap::find_program;
+use crate::dns;
+use crate::lan::{LAN_IPV4, LAN_IPV6};
+use crate::plan::{Namespace, PlanContributor, SandboxPlan};
+
+/// How long to wait between checks of `cgroup.events` when the kernel
+/// doesn't wake us up.
+const EVENTS_POLL_MS: libc::c_int = 1000;
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Prefix of the nftables tables confining cgroups.
+const TABLE_PREFIX: &str = "apf_cg_";
+
+/// Where the cgroup v2 hierarchy is mounted.
+pub fn cgroup2_mount() -> Option<PathBuf> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo.lines().find_map(|line| {
+        let (fields, fstype) = line.split_once(" - ")?;
+        if !fstype.starts_with("cgroup2 ") {
+            return None;
+        }
+        fields.split(' ').nth(4).map(PathBuf::from)
+    })
+}
+
+/// The calling process's cgroup, relative to the cgroup v2 root.
+pub fn current_cgroup() -> Option<PathBuf> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    cgroups.lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| PathBuf::from(path.trim_start_matches('/')))
+}
+
+/// nftables matches that accept the destinations `rule` describes. Host
+/// names are resolved now, so later changes to their addresses are
+/// missed; wildcard names can't be resolved and match nothing.
+pub fn destination_matches(rule: &NetworkRule) -> Vec<String> {
+    let addrs: Vec<String> = match &rule.host {
+        HostPattern::Cidr(cidr) => vec![format!("{} daddr {}/{}", family(cidr.addr), cidr.addr, cidr.prefix)],
+        HostPattern::Domain(name) if name.starts_with("*.") => {
+            warn!("Cannot match {} by address; no connections to it are allowed", name);
+            Vec::new()
+        }
+        HostPattern::Domain(name) => match (name.as_str(), 0).to_socket_addrs() {
+            Ok(resolved) => {
+                let mut addrs: Vec<IpAddr> = resolved.map(|addr| addr.ip()).collect();
+                addrs.sort();
+                addrs.dedup();
+                addrs.into_iter().map(|addr| format!("{} daddr {}", family(addr), addr)).collect()
+            }
+            Err(err) => {
+                warn!("Cannot resolve {}: {}; no connections to it are allowed", name, err);
+                Vec::new()
+            }
+        },
+    };
+    let ports = match (rule.protocol, rule.ports) {
+        (protocol, Some(ports)) => {
+            let range = if ports.start == ports.end { ports.start.to_string() } else { format!("{}-{}", ports.start, ports.end) };
+            match protocol {
+                Some(protocol) => format!(" {} dport {}", protocol_name(protocol), range),
+                None => format!(" meta l4proto {{ tcp, udp }} th dport {}", range),
+            }
+        }
+        (Some(protocol), None) => format!(" meta l4proto {}", protocol_name(protocol)),
+        (None, None) => String::new(),
+    };
+    addrs.into_iter().map(|addr| format!("{}{}", addr, ports)).collect()
+}
+
+fn family(addr: IpAddr) -> &'static str {
+    if addr.is_ipv4() { "ip" } else { "ip6" }
+}
+
+fn protocol_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Tcp => "tcp",
+        Protocol::Udp => "udp",
+    }
+}
+
+/// The nftables table confining the sockets of `cgroup` (relative to the
+/// cgroup v2 root) to `level` plus each of `rules`, or `None` when nothing
+/// needs confining. Rules naming a host may also reach `resolvers` to look
+/// it up.
+/// The app shares the host's loopback, so that is no exception: local
+/// services are reached only where the level or a rule allows them.
+/// Everything else on the host passes untouched.
+pub fn cgroup_ruleset(
+    table: &str,
+    cgroup: &Path,
+    level: &NetworkLevel,
+    rules: &[NetworkRule],
+    resolvers: &[SocketAddr],
+) -> Option<String> {
+    let mut accepts = Vec::new();
+    let mut allowed: Vec<&NetworkRule> = Vec::new();
+    match level {
+        NetworkLevel::Internet => return None,
+        NetworkLevel::None => {}
+        NetworkLevel::Lan => {
+            accepts.push(format!("ip daddr {{ {} }}", LAN_IPV4.join(", ")));
+            accepts.push(format!("ip6 daddr {{ {} }}", LAN_IPV6.join(", ")));
+        }
+        NetworkLevel::Rule(rule) => allowed.push(rule),
+    }
+    allowed.extend(rules);
+    if allowed.iter().any(|rule| matches!(rule.host, HostPattern::Domain(_))) {
+        // The app has to look the name up itself first, from the
+        // resolvers the host uses and no one else.
+        accepts.extend(resolvers.iter().map(|resolver| {
+            format!("{} daddr {} meta l4proto {{ tcp, udp }} th dport {}", family(resolver.ip()), resolver.ip(), resolver.port())
+        }));
+    }
+    for rule in allowed {
+        accepts.extend(destination_matches(rule));
+    }
+    let depth = cgroup.components().count();
+    let mut ruleset = format!(
+        "table inet {} {{\n\
+         \tchain output {{\n\
+         \t\ttype filter hook output priority 0; policy accept;\n\
+         \t\tsocket cgroupv2 level {} \"{}\" goto app\n\
+         \t}}\n\
+         \tchain app {{\n",
+        table,
+        depth,
+        cgroup.display(),
+    );
+    for accept in accepts {
+        ruleset.push_str(&format!("\t\t{} accept\n", accept));
+    }
+    ruleset.push_str("\t\tdrop\n\t}\n}\n");
+    Some(ruleset)
+}
+
+/// The `apf_cg_*` tables of `ruleset`, as `nft list ruleset inet` prints
+/// it, whose cgroup no longer exists below `mount`: left behind by a
+/// launcher killed before it could clean up. nft prints a cgroup it can't
+/// find anymore by its number rather than its path.
+pub fn stale_tables(ruleset: &str, mount: &Path) -> Vec<String> {
+    let mut stale = Vec::new();
+    let mut table = None;
+    for line in ruleset.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix("table inet ").and_then(|rest| rest.strip_suffix(" {")) {
+            table = name.starts_with(TABLE_PREFIX).then(|| name.to_string());
+        } else if let Some((_, matched)) = line.split_once("socket cgroupv2 level ") {
+            let Some(name) = table.take() else {
+                continue;
+            };
+            if !matched.split('"').nth(1).is_some_and(|path| mount.join(path).is_dir()) {
+                stale.push(name);
+            }
+        }
+    }
+    stale
+}
+
+/// Deletes the tables `stale_tables` finds in the loaded ruleset.
+fn sweep_stale_tables(mount: &Path) {
+    let listed = match Command::new("nft").args(["list", "ruleset", "inet"]).stderr(Stdio::null()).output() {
+        Ok(output) if output.status.success() => output.stdout,
+        Ok(_) | Err(_) => return,
+    };
+    for table in stale_tables(&String::from_utf8_lossy(&listed), mount) {
+        match nft(&["delete", "table", "inet", &table], None) {
+            Ok(()) => info!("Removed nftables table {} of a cgroup that is gone", table),
+            Err(err) => warn!("Failed to remove stale nftables table {}: {}", table, err),
+        }
+    }
+}
+
+/// Moves the calling process into the cgroup whose `cgroup.procs` is open
+/// as `procs`. Safe to call between `fork` and `exec`.
+pub(crate) fn enter(procs: RawFd) -> nix::Result<()> {
+    // SAFETY: writes from a static buffer to a descriptor the caller owns.
+    Errno::result(unsafe { libc::write(procs, b"0".as_ptr().cast(), 1) }).map(drop)
+}
+
+/// A cgroup of its own for a sandboxed app in the host's network
+/// namespace, with an nftables table matching its sockets by cgroup. The
+/// table and cgroup are removed once the last process in the cgroup is
+/// gone: right away on drop when it already is, otherwise by a thread
+/// waiting for it.
+#[derive(Debug)]
+pub struct CgroupNetwork {
+    /// The cgroup's directory.
+    dir: PathBuf,
+    /// The cgroup relative to the cgroup v2 root.
+    cgroup: PathBuf,
+    table: Option<String>,
+    procs: Option<File>,
+}
+
+impl CgroupNetwork {
+    /// Creates the cgroup below the caller's own and loads the rules for
+    /// `level` and `rules`. Loading rules needs `CAP_NET_ADMIN` in the host's network
+    /// namespace, and creating the cgroup a delegated or writable
+    /// hierarchy.
+    pub fn create(level: &NetworkLevel, rules: &[NetworkRule]) -> Result<Self> {
+        let Some(mount) = cgroup2_mount() else {
+            bail!("Cgroup network filtering needs the cgroup v2 hierarchy mounted");
+        };
+        let Some(parent) = current_cgroup() else {
+            bail!("Cannot find the cgroup v2 group of this process");
+        };
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let name = format!("apf-{}-{}", std::process::id(), id);
+        let cgroup = parent.join(&name);
+        let table = format!("{}{}_{}", TABLE_PREFIX, std::process::id(), id);
+        let ruleset = cgroup_ruleset(&table, &cgroup, level, rules, &dns::host_resolvers());
+        if ruleset.is_some() {
+            if find_program("nft").is_none() {
+                bail!("Cgroup network filtering needs nft");
+            }
+            sweep_stale_tables(&mount);
+        }
+
+        let dir = mount.join(&cgroup);
+        std::fs::create_dir(&dir).with_context(|| format!("Failed to create cgroup {}", dir.display()))?;
+        let mut network = Self { dir, cgroup, table: None, procs: None };
+        network.procs = Some(
+            OpenOptions::new()
+                .write(true)
+                .open(network.dir.join("cgroup.procs"))
+                .context("Failed to open cgroup.procs")?,
+        );
+        // The cgroup has to exist before nft can resolve its path.
+        if let Some(ruleset) = ruleset {
+            nft(&["-f", "-"], Some(&ruleset))?;
+            network.table = Some(table);
+        }
+        info!("Sandbox network confined to {:?} and {} rules through cgroup {}", level, rules.len(), network.cgroup.display());
+        Ok(network)
+    }
+
+    /// The cgroup relative to the cgroup v2 root.
+    pub fn cgroup(&self) -> &Path {
+        &self.cgroup
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn table(&self) -> Option<&str> {
+        self.table.as_deref()
+    }
+
+    pub(crate) fn procs_fd(&self) -> RawFd {
+        self.procs.as_ref().expect("cgroup.procs is open until drop").as_raw_fd()
+    }
+
+    /// A `Command` whose process starts inside the cgroup.
+    pub fn command(&self, program: impl AsRef<std::ffi::OsStr>) -> Command {
+        let mut cmd = Command::new(program);
+        self.apply(&mut cmd);
+        cmd
+    }
+
+    /// Moves `cmd`'s process into the cgroup just before it execs. `self`
+    /// has to outlive spawning it.
+    pub fn apply(&self, cmd: &mut Command) {
+        use std::os::unix::process::CommandExt;
+        let procs = self.procs_fd();
+        // SAFETY: `enter` only makes a syscall.
+        unsafe {
+            cmd.pre_exec(move || enter(procs).map_err(std::io::Error::from));
+        }
+    }
+
+    pub fn is_populated(&self) -> Result<bool> {
+        is_populated(&self.dir)
+    }
+
+    /// Sends `signal` to every process in the cgroup.
+    pub fn signal(&self, signal: Signal) -> Result<()> {
+        let procs = std::fs::read_to_string(self.dir.join("cgroup.procs"))
+            .with_context(|| format!("Failed to read processes of cgroup {}", self.dir.display()))?;
+        for pid in procs.lines().filter_map(|line| line.parse().ok()) {
+            let _ = kill(Pid::from_raw(pid), signal);
+        }
+        Ok(())
+    }
+
+    /// Blocks until no process is left in the cgroup.
+    pub fn wait_empty(&self) -> Result<()> {
+        wait_empty(&self.dir)
+    }
+}
+
+impl Drop for CgroupNetwork {
+    fn drop(&mut self) {
+        let cleanup = Cleanup { dir: std::mem::take(&mut self.dir), table: self.table.take() };
+        self.procs = None;
+        if cleanup.populated() {
+            let spawned = std::thread::Builder::new()
+                .name("apf-cgroup-cleanup".into())
+                .spawn(move || {
+                    if let Err(err) = wait_empty(&cleanup.dir) {
+                        warn!("Failed to watch cgroup {}: {}", cleanup.dir.display(), err);
+                    }
+                });
+            if let Err(err) = spawned {
+                warn!("Cannot watch cgroup for cleanup: {}", err);
+            }
+        }
+    }
+}
+
+/// Removes the table and the cgroup when dropped.
+struct Cleanup {
+    dir: PathBuf,
+    table: Option<String>,
+}
+
+impl Cleanup {
+    fn populated(&self) -> bool {
+        is_populated(&self.dir).unwrap_or(false)
+    }
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        if let Some(table) = &self.table {
+            if let Err(err) = nft(&["delete", "table", "inet", table], None) {
+                warn!("Failed to remove nftables table {}: {}", table, err);
+            }
+        }
+        if let Err(err) = std::fs::remove_dir(&self.dir) {
+            warn!("Failed to remove cgroup {}: {}", self.dir.display(), err);
+        }
+    }
+}
+
+fn is_populated(dir: &Path) -> Result<bool> {
+    let events = std::fs::read_to_string(dir.join("cgroup.events"))
+        .with_context(|| format!("Failed to read events of cgroup {}", dir.display()))?;
+    Ok(events.lines().any(|line| line == "populated 1"))
+}
+
+/// Waits on `cgroup.events`, which the kernel flags as changed whenever
+/// the cgroup empties or fills.
+fn wait_empty(dir: &Path) -> Result<()> {
+    let events = File::open(dir.join("cgroup.events"))
+        .with_context(|| format!("Failed to open events of cgroup {}", dir.display()))?;
+    let mut buf = [0u8; 256];
+    loop {
+        let len = events.read_at(&mut buf, 0)?;
+        let populated = String::from_utf8_lossy(&buf[..len]).lines().any(|line| line == "populated 1");
+        if !populated {
+            return Ok(());
+        }
+        let mut fd = libc::pollfd { fd: events.as_raw_fd(), events: libc::POLLPRI, revents: 0 };
+        // SAFETY: `fd` is one valid pollfd.
+        if let Err(err) = Errno::result(unsafe { libc::poll(&mut fd, 1, EVENTS_POLL_MS) }) {
+            if err != Errno::EINTR {
+                return Err(err.into());
+            }
+        }
+    }
+}
+
+fn nft(args: &[&str], stdin: Option<&str>) -> Result<()> {
+    let mut child = Command::new("nft")
+        .args(args)
+        .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+        .spawn()
+        .context("Failed to run nft")?;
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        pipe.write_all(input.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("nft {:?} failed: {}", args, status);
+    }
+    Ok(())
+}
+
+/// An alternative to `NetworkBackend` for apps that break in a network
+/// namespace of their own: the app keeps the host's network, and a
+/// cgroup's nftables rules decide where its sockets may send.
+pub struct CgroupNetworkBackend {
+    pub allowed_level: NetworkLevel,
+    /// Destinations reachable on top of `allowed_level`.
+    pub allowed_rules: Vec<NetworkRule>,
+}
+
+impl CgroupNetworkBackend {
+    pub fn new(level: NetworkLevel) -> Self {
+        Self { allowed_level: level, allowed_rules: Vec::new() }
+    }
+
+    /// Also lets the app reach the destinations `rules` describe.
+    pub fn allow(mut self, rules: impl IntoIterator<Item = NetworkRule>) -> Self {
+        self.allowed_rules.extend(rules);
+        self
+    }
+}
+
+impl PlanContributor for CgroupNetworkBackend {
+    fn contribute(&self, plan: &mut SandboxPlan) {
+        plan.share(Namespace::Net);
+        plan.cgroup_network(self.allowed_level.clone(), self.allowed_rules.clone());
+    }
+}
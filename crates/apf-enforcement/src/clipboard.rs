@@ -1,5 +1,6 @@
 use anyhow::Result;
 use tracing::{info, warn};
+use crate::plan::{PlanContributor, SandboxPlan};
 
 pub struct ClipboardBackend {
     pub allowed: bool,
@@ -17,3 +18,12 @@ impl ClipboardBackend {
         Ok(())
     }
 }
+
+/// Nothing to contribute yet: clipboard is not restricted inside the sandbox.
+impl PlanContributor for ClipboardBackend {
+    fn contribute(&self, _plan: &mut SandboxPlan) {
+        if !self.allowed {
+            warn!("Clipboard is not restricted inside the sandbox yet");
+        }
+    }
+}
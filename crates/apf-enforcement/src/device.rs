@@ -1,6 +1,8 @@
+use std::path::PathBuf;
 use anyhow::Result;
 use tracing::{info, warn, error};
 use apf_core::types::DeviceType;
+use crate::plan::{Mount, PlanContributor, SandboxPlan};
 
 pub struct DeviceBackend {
     pub allowed_devices: Vec<DeviceType>,
@@ -20,3 +22,42 @@ impl DeviceBackend {
         Ok(())
     }
 }
+
+/// Passes the nodes of each allowed device class through to the sandbox's
+/// otherwise minimal `/dev`.
+impl PlanContributor for DeviceBackend {
+    fn contribute(&self, plan: &mut SandboxPlan) {
+        for device in &self.allowed_devices {
+            match device {
+                DeviceType::Camera => {
+                    for node in device_nodes("video") {
+                        plan.mount(Mount::DevBind { src: node.clone(), dest: node, optional: true });
+                    }
+                }
+                DeviceType::Microphone => {
+                    plan.mount(Mount::DevBind { src: "/dev/snd".into(), dest: "/dev/snd".into(), optional: true });
+                }
+                DeviceType::Usb => {
+                    plan.mount(Mount::DevBind { src: "/dev/bus/usb".into(), dest: "/dev/bus/usb".into(), optional: true });
+                }
+                DeviceType::Screen => {
+                    warn!("Screen capture goes through the portal and needs no device nodes");
+                }
+            }
+        }
+    }
+}
+
+/// The host's `/dev` entries whose name starts with `prefix`.
+fn device_nodes(prefix: &str) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir("/dev") else {
+        return Vec::new();
+    };
+    let mut nodes: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(prefix))
+        .map(|entry| entry.path())
+        .collect();
+    nodes.sort();
+    nodes
+}
@@ -0,0 +1,424 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use apf_core::app_id::AppId;
+use tracing::{debug, warn};
+
+/// Largest message relayed; room for any EDNS payload size.
+const MESSAGE_MAX: usize = 65535;
+
+/// How long a query waits for the upstream resolver.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most compression pointers followed in one name, against loops.
+const POINTERS_MAX: usize = 16;
+
+/// Most queries a stub answers at once; more are dropped, as a busy
+/// resolver would, rather than taking a thread each.
+pub const QUERIES_MAX: usize = 32;
+
+/// Most queries waiting to be reported to the observer. Queries past that
+/// are answered but not reported.
+const REPORTS_MAX: usize = 256;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+
+/// The `NXDOMAIN` response code.
+pub const RCODE_NAME_ERROR: u8 = 3;
+
+/// The blocklist applied to every sandbox, in hosts-file format.
+pub const GLOBAL_BLOCKLIST: &str = "/etc/appfence/dns-blocklist";
+
+/// Where each app's own blocklist is, named by its app id.
+pub const APP_BLOCKLIST_DIR: &str = "/etc/appfence/dns-blocklist.d";
+
+/// Names hosts files map for the machine itself rather than to block.
+const HOSTS_OWN_NAMES: [&str; 8] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "0.0.0.0",
+];
+
+/// The `nameserver` addresses of a resolv.conf, or the loopback address
+/// the resolver falls back to when there are none.
+pub fn parse_resolv_conf(contents: &str) -> Vec<IpAddr> {
+    let servers: Vec<IpAddr> = contents.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => words.next()?.split('%').next()?.parse().ok(),
+                _ => None,
+            }
+        })
+        .collect();
+    if servers.is_empty() {
+        vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]
+    } else {
+        servers
+    }
+}
+
+/// The host's resolvers, from `/etc/resolv.conf`.
+pub fn host_resolvers() -> Vec<SocketAddr> {
+    let contents = std::fs::read_to_string(Path::new("/etc/resolv.conf")).unwrap_or_default();
+    parse_resolv_conf(&contents).into_iter().map(|ip| SocketAddr::new(ip, 53)).collect()
+}
+
+/// Host names the sandbox may not look up or connect to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Blocklist {
+    names: BTreeSet<String>,
+}
+
+impl Blocklist {
+    /// The names of a hosts file, whatever address they are mapped to:
+    /// `0.0.0.0 ads.example.com tracker.example.net`. Lines without an
+    /// address first are skipped, as are the machine's own names.
+    pub fn parse_hosts(contents: &str) -> Self {
+        let mut names = BTreeSet::new();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            if words.next().and_then(|address| address.parse::<IpAddr>().ok()).is_none() {
+                continue;
+            }
+            names.extend(words.map(normalize).filter(|name| !HOSTS_OWN_NAMES.contains(&name.as_str())));
+        }
+        Self { names }
+    }
+
+    /// The blocklist in the hosts file at `path`, empty if there is none.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        match std::fs::read_to_string(path) {
+            Ok(contents) => Ok(Self::parse_hosts(&contents)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_context(|| format!("Failed to read blocklist {}", path.display())),
+        }
+    }
+
+    pub fn merge(&mut self, other: &Blocklist) -> &mut Self {
+        self.names.extend(other.names.iter().cloned());
+        self
+    }
+
+    /// Whether `name` is listed. Names compare case-insensitively and
+    /// without a trailing dot; names below a listed one are not covered.
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains(&normalize(name))
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The blocklist of the app `app_id` names, applied on top of the global
+/// one. Apps known by their executable's path have none, as their ids
+/// can't name a file there.
+pub fn app_blocklist(app_id: &AppId) -> Option<PathBuf> {
+    let id = app_id.primary.as_str();
+    if id.is_empty() || id.contains('/') || id.starts_with('.') {
+        return None;
+    }
+    Some(Path::new(APP_BLOCKLIST_DIR).join(id))
+}
+
+/// A query the DNS stub answered, or refused because the name is blocked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsQuery {
+    pub name: String,
+    pub qtype: u16,
+    pub blocked: bool,
+}
+
+/// Told of every query the DNS stub takes.
+pub type DnsObserver = Arc<dyn Fn(&DnsQuery) + Send + Sync>;
+
+/// The name the first question of a DNS message asks about, and its type.
+pub fn question(message: &[u8]) -> Option<(String, u16)> {
+    if question_count(message)? == 0 {
+        return None;
+    }
+    let (name, next) = read_name(message, 12)?;
+    let qtype = u16::from_be_bytes(message.get(next..next + 2)?.try_into().ok()?);
+    Some((name, qtype))
+}
+
+/// The addresses of a response's A and AAAA answers.
+pub fn answer_addresses(message: &[u8]) -> Vec<IpAddr> {
+    let mut addresses = Vec::new();
+    let Some(answers) = message.get(6..8).map(|count| u16::from_be_bytes([count[0], count[1]])) else {
+        return addresses;
+    };
+    let Some(mut offset) = skip_questions(message) else {
+        return addresses;
+    };
+    for _ in 0..answers {
+        let Some((_, next)) = read_name(message, offset) else {
+            break;
+        };
+        let Some(header) = message.get(next..next + 10) else {
+            break;
+        };
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let Some(data) = message.get(next + 10..next + 10 + len) else {
+            break;
+        };
+        match (rtype, len) {
+            (TYPE_A, 4) => addresses.push(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = data.try_into().expect("16 bytes");
+                addresses.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => {}
+        }
+        offset = next + 10 + len;
+    }
+    addresses
+}
+
+/// A response to `query` carrying only its question and `NXDOMAIN`.
+pub fn name_error(query: &[u8]) -> Option<Vec<u8>> {
+    let end = skip_questions(query)?;
+    let mut response = query[..end].to_vec();
+    // A response, with the opcode and recursion desired kept and recursion
+    // available.
+    response[2] = 0x80 | (query[2] & 0x79);
+    response[3] = 0x80 | RCODE_NAME_ERROR;
+    response[6..12].fill(0);
+    Some(response)
+}
+
+fn question_count(message: &[u8]) -> Option<u16> {
+    message.get(4..6).map(|count| u16::from_be_bytes([count[0], count[1]]))
+}
+
+/// Where the answer section starts.
+fn skip_questions(message: &[u8]) -> Option<usize> {
+    let mut offset = 12;
+    for _ in 0..question_count(message)? {
+        offset = read_name(message, offset)?.1 + 4;
+    }
+    (offset <= message.len()).then_some(offset)
+}
+
+/// The name at `offset`, lowercased and without the root's dot, and the
+/// offset just past it where it is stored.
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *message.get(offset)? as usize;
+        match len & 0xc0 {
+            0x00 if len == 0 => {
+                let next = end.unwrap_or(offset + 1);
+                return Some((labels.join("."), next));
+            }
+            0x00 => {
+                let label = message.get(offset + 1..offset + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                offset += 1 + len;
+            }
+            0xc0 => {
+                pointers += 1;
+                if pointers > POINTERS_MAX {
+                    return None;
+                }
+                let low = *message.get(offset + 1)? as usize;
+                end.get_or_insert(offset + 2);
+                offset = (len & 0x3f) << 8 | low;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// What the sandbox's DNS lookups resolved to, so a connection made to an
+/// address can be put down to the name it was looked up by.
+#[derive(Debug, Default)]
+pub struct ResolvedNames {
+    names: Mutex<HashMap<IpAddr, String>>,
+}
+
+impl ResolvedNames {
+    pub fn record(&self, name: &str, addresses: &[IpAddr]) {
+        let mut names = self.names.lock().expect("resolved names poisoned");
+        for address in addresses {
+            names.insert(*address, name.to_string());
+        }
+    }
+
+    /// The name `address` was last looked up by.
+    pub fn name_of(&self, address: &IpAddr) -> Option<String> {
+        self.names.lock().expect("resolved names poisoned").get(address).cloned()
+    }
+}
+
+/// Answers the sandbox's DNS queries on a socket at a resolver's address
+/// by relaying them to that resolver, noting the names they resolve.
+/// Blocked names get `NXDOMAIN` without the resolver hearing of them.
+#[derive(Clone)]
+pub struct DnsStub {
+    socket: Arc<UdpSocket>,
+    upstream: SocketAddr,
+    names: Arc<ResolvedNames>,
+    blocklist: Arc<Blocklist>,
+    reports: Option<SyncSender<DnsQuery>>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// One query being answered; counted in `DnsStub::in_flight` until dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl DnsStub {
+    pub fn new(socket: UdpSocket, upstream: SocketAddr, names: Arc<ResolvedNames>) -> Self {
+        Self {
+            socket: Arc::new(socket),
+            upstream,
+            names,
+            blocklist: Arc::default(),
+            reports: None,
+            in_flight: Arc::default(),
+        }
+    }
+
+    pub fn blocking(mut self, blocklist: Arc<Blocklist>) -> Self {
+        self.blocklist = blocklist;
+        self
+    }
+
+    /// Reports every query to `observer` on a thread of its own, after it
+    /// is answered, so a slow observer holds up no lookup.
+    pub fn observed_by(mut self, observer: DnsObserver) -> Self {
+        let (reports, queue) = mpsc::sync_channel::<DnsQuery>(REPORTS_MAX);
+        let reporter = std::thread::Builder::new()
+            .name("apf-dns-report".into())
+            .spawn(move || queue.iter().for_each(|query| observer(&query)));
+        match reporter {
+            Ok(_) => self.reports = Some(reports),
+            Err(err) => warn!("DNS queries from the sandbox won't be reported: {}", err),
+        }
+        self
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Takes one waiting query and answers it on a thread of its own, so a
+    /// slow upstream holds up nothing else. With `QUERIES_MAX` queries
+    /// already being answered the query is dropped.
+    pub fn serve_one(&self) -> Result<()> {
+        let mut query = vec![0u8; MESSAGE_MAX];
+        let (len, client) = self.socket.recv_from(&mut query)?;
+        query.truncate(len);
+        if self.in_flight.fetch_add(1, Ordering::SeqCst) >= QUERIES_MAX {
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            debug!("Too many DNS queries from the sandbox at once; one dropped");
+            return Ok(());
+        }
+        let in_flight = InFlight(self.in_flight.clone());
+        let stub = self.clone();
+        std::thread::Builder::new()
+            .name("apf-dns-query".into())
+            .spawn(move || {
+                let _in_flight = in_flight;
+                if let Err(err) = stub.answer(&query, client) {
+                    warn!("DNS query from the sandbox went unanswered: {:#}", err);
+                }
+            })?;
+        Ok(())
+    }
+
+    fn answer(&self, query: &[u8], client: SocketAddr) -> Result<()> {
+        // Only the first question would be checked against the blocklist,
+        // while the resolver would answer them all.
+        if question_count(query) != Some(1) {
+            bail!("DNS query without exactly one question");
+        }
+        let Some((name, qtype)) = question(query) else {
+            bail!("Malformed DNS query");
+        };
+        let blocked = self.blocklist.contains(&name);
+        debug!("DNS query for {} (type {}){}", name, qtype, if blocked { ", blocked" } else { "" });
+        let answered = self.respond(query, &name, blocked, client);
+        self.report(DnsQuery { name, qtype, blocked });
+        answered
+    }
+
+    fn respond(&self, query: &[u8], name: &str, blocked: bool, client: SocketAddr) -> Result<()> {
+        let response = if blocked {
+            name_error(query).context("Malformed DNS query")?
+        } else {
+            let response = self.forward(query)
+                .with_context(|| format!("No answer from {} for {}", self.upstream, name))?;
+            self.names.record(name, &answer_addresses(&response));
+            response
+        };
+        self.socket.send_to(&response, client)?;
+        Ok(())
+    }
+
+    /// Queues `query` for the observer, leaving it out when the queue is
+    /// full.
+    fn report(&self, query: DnsQuery) {
+        let Some(reports) = &self.reports else {
+            return;
+        };
+        match reports.try_send(query) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+            Err(TrySendError::Full(query)) => debug!("DNS reports backed up; {} not reported", query.name),
+        }
+    }
+
+    /// The upstream resolver's response to `query`.
+    fn forward(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let local: SocketAddr = if self.upstream.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+        socket.connect(self.upstream)?;
+        socket.send(query)?;
+        let mut response = vec![0u8; MESSAGE_MAX];
+        loop {
+            let len = socket.recv(&mut response)?;
+            // Anything not answering this query's id is stale.
+            if len >= 12 && response[..2] == query[..2] {
+                response.truncate(len);
+                return Ok(response);
+            }
+        }
+    }
+}
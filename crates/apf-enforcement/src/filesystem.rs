@@ -1,8 +1,11 @@
 
-use std::process::Command;
 use std::path::PathBuf;
 use anyhow::{Result, Context};
+use crate::plan::{Mount, PlanContributor, SandboxPlan};
+use crate::sandbox::SandboxBackend;
 use tracing::{info, warn, error};
+use apf_core::{app_id::AppId, types::{self, FilesystemAccess}};
+use apf_policy::UserDirs;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AccessMode {
@@ -25,23 +28,39 @@ impl FilesystemBackend {
         self.allowed_paths.push((path, mode));
     }
 
+    /// Allows a granted filesystem permission, resolving a path placeholder
+    /// against the directories of the user the app runs as. Denied access
+    /// needs no bind mount and is skipped.
+    pub fn add_access(&mut self, access: &FilesystemAccess, dirs: &UserDirs, app_id: &AppId) -> Result<()> {
+        let resolved = dirs.resolve(access, app_id)
+            .with_context(|| format!("Cannot resolve {} for {}", access.path.display(), app_id.primary))?;
+        match resolved.mode {
+            types::AccessMode::ReadOnly => self.add_allowed_path(resolved.path, AccessMode::ReadOnly),
+            types::AccessMode::ReadWrite => self.add_allowed_path(resolved.path, AccessMode::ReadWrite),
+            types::AccessMode::Deny => {}
+        }
+        Ok(())
+    }
+
     pub fn launch_with_bubblewrap(&self, command: &[String]) -> Result<()> {
-        let mut args = vec!["--unshare-all".to_string(), "--die-with-parent".to_string()];
-        for (path, mode) in &self.allowed_paths {
-            match mode {
-                AccessMode::ReadOnly => {
-                    args.push(format!("--ro-bind {} {}", path.display(), path.display()));
-                }
-                AccessMode::ReadWrite => {
-                    args.push(format!("--bind {} {}", path.display(), path.display()));
-                }
-            }
+        let plan = SandboxPlan::from_contributors(&[&SandboxBackend::new(), self]);
+        let status = plan.launch(command)?;
+        if !status.success() {
+            error!("Bubblewrap exited with failure: {:?}", status);
+            error!("Filesystem violation: denied access attempt for command: {:?}", command);
+            // TODO: Integrate audit logging here
+            return Err(anyhow::anyhow!("Bubblewrap failed"));
         }
-        // Default deny outside allowed paths
-        args.push("--tmpfs /".to_string());
+        info!("Bubblewrap sandbox exited successfully");
+        Ok(())
+    }
+}
 
-        // Symlink escape prevention and violation logging
-        for (path, _) in &self.allowed_paths {
+/// Binds each allowed path into the sandbox at the same location.
+impl PlanContributor for FilesystemBackend {
+    fn contribute(&self, plan: &mut SandboxPlan) {
+        for (path, mode) in &self.allowed_paths {
+            // Symlink escape prevention and violation logging
             if let Ok(meta) = std::fs::symlink_metadata(path) {
                 if meta.file_type().is_symlink() {
                     warn!("Symlink detected in allowed path: {}", path.display());
@@ -49,25 +68,7 @@ impl FilesystemBackend {
                     // TODO: Integrate audit logging here
                 }
             }
+            plan.mount(Mount::bind(path, mode.clone()));
         }
-
-        // Logging
-        info!("Launching bubblewrap sandbox: {:?}", args);
-        let mut bw_cmd = Command::new("bubblewrap");
-        for arg in &args {
-            bw_cmd.arg(arg);
-        }
-        for c in command {
-            bw_cmd.arg(c);
-        }
-        let status = bw_cmd.status().context("Failed to launch bubblewrap")?;
-        if !status.success() {
-            error!("Bubblewrap exited with failure: {:?}", status);
-            error!("Filesystem violation: denied access attempt for command: {:?}", command);
-            // TODO: Integrate audit logging here
-            return Err(anyhow::anyhow!("Bubblewrap failed"));
-        }
-        info!("Bubblewrap sandbox exited successfully");
-        Ok(())
     }
 }
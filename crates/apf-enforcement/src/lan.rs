@@ -0,0 +1,368 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::mem::size_of;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sched::{setns, unshare, CloneFlags};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{self, ForkResult, Gid, Pid, Uid};
+use tracing::{info, warn};
+use crate::bwrap::find_program;
+use crate::native::write_file;
+
+/// Private, link-local and multicast IPv4 destinations, mDNS and
+/// broadcast included.
+pub const LAN_IPV4: [&str; 6] = [
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "169.254.0.0/16",
+    "224.0.0.0/4",
+    "255.255.255.255",
+];
+
+/// Unique local, link-local and multicast IPv6 destinations.
+pub const LAN_IPV6: [&str; 3] = ["fc00::/7", "fe80::/10", "ff00::/8"];
+
+/// The nftables ruleset loaded into a LAN-only namespace: loopback and LAN
+/// destinations pass, anything else is dropped on the way out.
+pub fn lan_ruleset() -> String {
+    format!(
+        "table inet apf_lan {{\n\
+         \tset lan4 {{\n\t\ttype ipv4_addr\n\t\tflags interval\n\t\telements = {{ {} }}\n\t}}\n\
+         \tset lan6 {{\n\t\ttype ipv6_addr\n\t\tflags interval\n\t\telements = {{ {} }}\n\t}}\n\
+         \tchain output {{\n\
+         \t\ttype filter hook output priority 0; policy drop;\n\
+         \t\toifname \"lo\" accept\n\
+         \t\tip daddr @lan4 accept\n\
+         \t\tip6 daddr @lan6 accept\n\
+         \t}}\n\
+         }}\n",
+        LAN_IPV4.join(", "),
+        LAN_IPV6.join(", "),
+    )
+}
+
+/// Enters the user and network namespaces behind `userns` and `netns`.
+/// Safe to call between `fork` and `exec`.
+pub(crate) fn join(userns: RawFd, netns: RawFd) -> nix::Result<()> {
+    // SAFETY: the descriptors stay open as long as the `LanNetwork` they
+    // belong to, which outlives every command joining it.
+    unsafe {
+        setns(BorrowedFd::borrow_raw(userns), CloneFlags::CLONE_NEWUSER)?;
+        setns(BorrowedFd::borrow_raw(netns), CloneFlags::CLONE_NEWNET)
+    }
+}
+
+/// A network namespace whose only way out is to the LAN. A holder process
+/// keeps it, and the user namespace owning it, alive; the sandbox joins
+/// both before setting itself up, so the app never owns the namespace and
+/// can't change its rules.
+#[derive(Debug)]
+pub struct LanNetwork {
+    holder: Pid,
+    userns: File,
+    netns: File,
+}
+
+impl LanNetwork {
+    /// A LAN-only namespace linked to the host's network through pasta's
+    /// user-mode networking.
+    pub fn create() -> Result<Self> {
+        let network = Self::unlinked()?;
+        network.connect_pasta()?;
+        Ok(network)
+    }
+
+    /// A LAN-only namespace with nothing but loopback, for the caller to
+    /// link up.
+    pub fn unlinked() -> Result<Self> {
+        if find_program("nft").is_none() {
+            bail!("LAN-only networking needs nft");
+        }
+        let network = Self::loopback_only()?;
+        network.run("nft", &["-f", "-"], Some(&lan_ruleset()))?;
+        Ok(network)
+    }
+
+    /// A namespace with loopback up and no rules loaded yet.
+    pub fn loopback_only() -> Result<Self> {
+        let network = Self::spawn_holder()?;
+        network.run("ip", &["link", "set", "lo", "up"], None)?;
+        Ok(network)
+    }
+
+    pub fn holder_pid(&self) -> u32 {
+        self.holder.as_raw() as u32
+    }
+
+    pub(crate) fn namespace_fds(&self) -> (RawFd, RawFd) {
+        (self.userns.as_raw_fd(), self.netns.as_raw_fd())
+    }
+
+    /// A `Command` running as root of the namespace, for configuring it.
+    pub fn command(&self, program: impl AsRef<OsStr>) -> Command {
+        let (userns, netns) = self.namespace_fds();
+        let mut cmd = Command::new(program);
+        // SAFETY: `join` only makes syscalls.
+        unsafe {
+            cmd.pre_exec(move || join(userns, netns).map_err(std::io::Error::from));
+        }
+        cmd
+    }
+
+    /// Links the namespace to the host's network with pasta, without
+    /// forwarding any ports into it.
+    pub fn connect_pasta(&self) -> Result<()> {
+        let Some(pasta) = find_program("pasta") else {
+            bail!("LAN-only networking needs pasta (from passt) to reach the network");
+        };
+        let pid = self.holder.as_raw();
+        // pasta maps the gateway's address, private like the LAN, to the
+        // host's loopback unless told not to; the rules would let the
+        // sandbox reach every service listening there.
+        let status = Command::new(pasta)
+            .args(["--config-net", "--quiet", "--no-map-gw", "-t", "none", "-u", "none", "-T", "none", "-U", "none"])
+            .arg("--userns").arg(format!("/proc/{}/ns/user", pid))
+            .arg("--netns").arg(format!("/proc/{}/ns/net", pid))
+            .status()
+            .context("Failed to start pasta")?;
+        if !status.success() {
+            bail!("pasta failed to link the LAN-only namespace: {}", status);
+        }
+        info!("LAN-only namespace of holder {} linked through pasta", pid);
+        Ok(())
+    }
+
+    /// Gives the namespace's loopback `ip` as well, so a socket bound to it
+    /// inside the namespace answers the sandbox at a host's address.
+    pub fn add_local_address(&self, ip: IpAddr) -> Result<()> {
+        let prefix = if ip.is_ipv4() { 32 } else { 128 };
+        self.run("ip", &["addr", "add", &format!("{}/{}", ip, prefix), "dev", "lo"], None)
+    }
+
+    /// Sockets bound to `addrs` inside the namespace, the stream ones
+    /// listening, for something outside it to answer the sandbox through.
+    /// A short-lived child joins the namespace to create them and passes
+    /// them back; this process can't enter it while it has other threads.
+    pub fn bind_inside(&self, addrs: &[(SocketAddr, libc::c_int)]) -> Result<Vec<OwnedFd>> {
+        let addrs: Vec<_> = addrs.iter().map(|(addr, kind)| (*kind, sockaddr(addr))).collect();
+        let (userns, netns) = self.namespace_fds();
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for both ends.
+        Errno::result(unsafe {
+            libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0, fds.as_mut_ptr())
+        })?;
+        // SAFETY: both ends are new descriptors we own.
+        let (parent, child) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        // SAFETY: the child only makes syscalls on memory prepared above.
+        match unsafe { unistd::fork() }? {
+            ForkResult::Child => {
+                let bound = join(userns, netns).is_ok()
+                    && addrs.iter().all(|(kind, (addr, len))| bind_and_send(child.as_raw_fd(), *kind, addr, *len).is_ok());
+                unsafe { libc::_exit(if bound { 0 } else { 1 }) }
+            }
+            ForkResult::Parent { child: pid } => {
+                drop(child);
+                let mut sockets = Vec::new();
+                while sockets.len() < addrs.len() {
+                    match receive_fd(&parent)? {
+                        Some(fd) => sockets.push(fd),
+                        None => break,
+                    }
+                }
+                let status = waitpid(pid, None)?;
+                if sockets.len() != addrs.len() || status != WaitStatus::Exited(pid, 0) {
+                    bail!("Cannot bind sockets inside the namespace of holder {}", self.holder);
+                }
+                Ok(sockets)
+            }
+        }
+    }
+
+    fn run(&self, program: &str, args: &[&str], stdin: Option<&str>) -> Result<()> {
+        let mut child = self.command(program)
+            .args(args)
+            .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+            .spawn()
+            .with_context(|| format!("Failed to run {} in the LAN-only namespace", program))?;
+        if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            pipe.write_all(input.as_bytes())?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("{} {:?} failed in the LAN-only namespace: {}", program, args, status);
+        }
+        Ok(())
+    }
+
+    fn spawn_holder() -> Result<Self> {
+        let uid_map = format!("0 {} 1", Uid::current()).into_bytes();
+        let gid_map = format!("0 {} 1", Gid::current()).into_bytes();
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for both ends.
+        Errno::result(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+        // SAFETY: both ends are new descriptors we own.
+        let (mut ready, notify) = unsafe { (File::from(OwnedFd::from_raw_fd(fds[0])), OwnedFd::from_raw_fd(fds[1])) };
+
+        // SAFETY: the child only makes syscalls on memory prepared above.
+        match unsafe { unistd::fork() }? {
+            ForkResult::Child => {
+                let setup = || -> nix::Result<()> {
+                    Errno::result(unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) })?;
+                    unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNET)?;
+                    write_file(c"/proc/self/setgroups", b"deny")?;
+                    write_file(c"/proc/self/uid_map", &uid_map)?;
+                    write_file(c"/proc/self/gid_map", &gid_map)
+                };
+                let status: &[u8] = if setup().is_ok() { b"k" } else { b"e" };
+                let _ = unistd::write(notify.as_raw_fd(), status);
+                drop(notify);
+                loop {
+                    unistd::pause();
+                }
+            }
+            ForkResult::Parent { child } => {
+                drop(notify);
+                let mut status = [0u8];
+                let ready = ready.read_exact(&mut status).is_ok() && status == *b"k";
+                // From here on, failing kills the holder.
+                let holder = HolderGuard(Some(child));
+                if !ready {
+                    bail!("Cannot create a user and network namespace for LAN-only networking");
+                }
+                let userns = File::open(format!("/proc/{}/ns/user", child))?;
+                let netns = File::open(format!("/proc/{}/ns/net", child))?;
+                Ok(Self { holder: holder.release(), userns, netns })
+            }
+        }
+    }
+}
+
+fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: an all-zero sockaddr_storage is valid.
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr { s_addr: u32::from(*addr.ip()).to_be() },
+                sin_zero: [0; 8],
+            };
+            // SAFETY: sockaddr_storage is larger than any sockaddr.
+            unsafe { std::ptr::write((&mut storage as *mut libc::sockaddr_storage).cast(), sin) };
+            size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo().to_be(),
+                sin6_addr: libc::in6_addr { s6_addr: addr.ip().octets() },
+                sin6_scope_id: addr.scope_id(),
+            };
+            // SAFETY: as above.
+            unsafe { std::ptr::write((&mut storage as *mut libc::sockaddr_storage).cast(), sin6) };
+            size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// Creates a socket of `kind` bound to `addr` and sends it over `sock`.
+/// Safe to call between `fork` and `exec`.
+fn bind_and_send(sock: RawFd, kind: libc::c_int, addr: &libc::sockaddr_storage, len: libc::socklen_t) -> nix::Result<()> {
+    let on: libc::c_int = 1;
+    let mut byte = [0u8];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr().cast(), iov_len: 1 };
+    let mut control = [0u64; 4];
+    // SAFETY: plain syscalls on buffers on this stack frame; the header
+    // has room for one descriptor.
+    unsafe {
+        let fd = Errno::result(libc::socket(addr.ss_family as libc::c_int, kind | libc::SOCK_CLOEXEC, 0))?;
+        let sent = (|| {
+            Errno::result(libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_REUSEADDR,
+                (&on as *const libc::c_int).cast(),
+                size_of::<libc::c_int>() as libc::socklen_t,
+            ))?;
+            Errno::result(libc::bind(fd, (addr as *const libc::sockaddr_storage).cast(), len))?;
+            if kind == libc::SOCK_STREAM {
+                Errno::result(libc::listen(fd, libc::SOMAXCONN))?;
+            }
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = libc::CMSG_SPACE(size_of::<libc::c_int>() as u32) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<libc::c_int>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, fd);
+            Errno::result(libc::sendmsg(sock, &msg, 0)).map(drop)
+        })();
+        libc::close(fd);
+        sent
+    }
+}
+
+/// The other side of `bind_and_send`; `None` once the child is done.
+fn receive_fd(sock: &OwnedFd) -> Result<Option<OwnedFd>> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr().cast(), iov_len: 1 };
+    let mut control = [0u64; 4];
+    // SAFETY: the header points at buffers on this stack frame; the
+    // descriptor is only read when the kernel attached one.
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = size_of::<[u64; 4]>() as _;
+        let len = Errno::result(libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC))?;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if len == 0 || cmsg.is_null() || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            return Ok(None);
+        }
+        let fd = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+        Ok(Some(OwnedFd::from_raw_fd(fd)))
+    }
+}
+
+impl Drop for LanNetwork {
+    fn drop(&mut self) {
+        drop(HolderGuard(Some(self.holder)));
+    }
+}
+
+/// Kills and reaps the holder when dropped, unless released.
+struct HolderGuard(Option<Pid>);
+
+impl HolderGuard {
+    fn release(mut self) -> Pid {
+        self.0.take().expect("holder already released")
+    }
+}
+
+impl Drop for HolderGuard {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0 {
+            if let Err(err) = kill(pid, Signal::SIGKILL) {
+                warn!("Failed to stop LAN namespace holder {}: {}", pid, err);
+            }
+            let _ = waitpid(pid, None);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::ffi::{CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::libc;
+use tracing::warn;
+use apf_core::{app_id::AppId, types::{self, EnforcementStrength, FilesystemAccess}};
+use apf_policy::UserDirs;
+use crate::filesystem::AccessMode;
+use crate::plan::{PlanContributor, SandboxPlan};
+
+const CREATE_RULESET_VERSION: u32 = 1;
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_EXECUTE: u64 = 1 << 0;
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+/// ABI 2: linking or renaming into another directory.
+const ACCESS_REFER: u64 = 1 << 13;
+/// ABI 3.
+const ACCESS_TRUNCATE: u64 = 1 << 14;
+/// ABI 5.
+const ACCESS_IOCTL_DEV: u64 = 1 << 15;
+
+/// Everything ABI 1 handles: the four above and creating or removing
+/// each kind of file.
+const ACCESS_ABI_1: u64 = (1 << 13) - 1;
+const ACCESS_READ: u64 = ACCESS_EXECUTE | ACCESS_READ_FILE | ACCESS_READ_DIR;
+/// The rights that apply to a file rather than a directory's entries.
+const ACCESS_FILE: u64 = ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE | ACCESS_TRUNCATE | ACCESS_IOCTL_DEV;
+
+/// What every app needs to start: the system runtime read-only, and the
+/// standard devices and `/tmp` writable. Inside a namespace sandbox these
+/// are the sandbox's own.
+const SYSTEM_RULES: [(&str, AccessMode); 10] = [
+    ("/usr", AccessMode::ReadOnly),
+    ("/etc", AccessMode::ReadOnly),
+    ("/bin", AccessMode::ReadOnly),
+    ("/sbin", AccessMode::ReadOnly),
+    ("/lib", AccessMode::ReadOnly),
+    ("/lib32", AccessMode::ReadOnly),
+    ("/lib64", AccessMode::ReadOnly),
+    ("/proc", AccessMode::ReadOnly),
+    ("/dev", AccessMode::ReadWrite),
+    ("/tmp", AccessMode::ReadWrite),
+];
+
+/// The Landlock ABI version the kernel offers, or `None` when it lacks
+/// Landlock or has it disabled.
+pub fn abi_version() -> Option<u32> {
+    // SAFETY: a null attribute with the version flag only queries the ABI.
+    let abi = unsafe {
+        libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<u8>(), 0usize, CREATE_RULESET_VERSION)
+    };
+    (abi > 0).then_some(abi as u32)
+}
+
+/// The filesystem rights a kernel with this ABI can restrict.
+fn handled_access(abi: u32) -> u64 {
+    let mut access = ACCESS_ABI_1;
+    if abi >= 2 {
+        access |= ACCESS_REFER;
+    }
+    if abi >= 3 {
+        access |= ACCESS_TRUNCATE;
+    }
+    if abi >= 5 {
+        access |= ACCESS_IOCTL_DEV;
+    }
+    access
+}
+
+/// Paths the sandboxed process keeps access to; everything else is
+/// denied. Landlock can only add rights below a path, so a rule narrower
+/// than one for a parent directory has no effect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LandlockRuleset {
+    abi: u32,
+    rules: Vec<(PathBuf, AccessMode)>,
+}
+
+impl LandlockRuleset {
+    pub fn new(abi: u32) -> Self {
+        Self { abi, rules: Vec::new() }
+    }
+
+    pub fn abi(&self) -> u32 {
+        self.abi
+    }
+
+    pub fn allow(&mut self, path: impl Into<PathBuf>, mode: AccessMode) -> &mut Self {
+        self.rules.push((path.into(), mode));
+        self
+    }
+
+    pub fn rules(&self) -> &[(PathBuf, AccessMode)] {
+        &self.rules
+    }
+
+    /// Adds the rules of `other`, handling what both ABIs handle.
+    pub fn merge(&mut self, other: &LandlockRuleset) {
+        self.abi = self.abi.min(other.abi);
+        self.rules.extend(other.rules.iter().cloned());
+    }
+
+    pub(crate) fn compile(&self) -> Result<CompiledRuleset> {
+        let handled = handled_access(self.abi);
+        let rules = self.rules.iter()
+            .map(|(path, mode)| {
+                let access = match mode {
+                    AccessMode::ReadOnly => ACCESS_READ,
+                    AccessMode::ReadWrite => handled,
+                };
+                let path = CString::new(path.as_os_str().as_bytes())
+                    .with_context(|| format!("Landlock path {} contains a NUL byte", path.display()))?;
+                Ok((path, access))
+            })
+            .collect::<Result<_>>()?;
+        Ok(CompiledRuleset { handled, rules })
+    }
+}
+
+/// A ruleset ready to apply in a forked child, where nothing may allocate.
+#[derive(Debug)]
+pub(crate) struct CompiledRuleset {
+    handled: u64,
+    rules: Vec<(CString, u64)>,
+}
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+impl CompiledRuleset {
+    /// Restricts the calling process and everything it execs. Paths that
+    /// don't exist are left out, which denies them like any other.
+    pub(crate) fn restrict_self(&self) -> nix::Result<()> {
+        let attr = RulesetAttr { handled_access_fs: self.handled };
+        // SAFETY: `attr` is a valid ruleset attribute of the size passed.
+        let ruleset = Errno::result(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0u32,
+            )
+        })? as libc::c_int;
+
+        let result = self.add_rules(ruleset).and_then(|()| {
+            // SAFETY: prctl and landlock_restrict_self take integers.
+            Errno::result(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+            Errno::result(unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0u32) }).map(drop)
+        });
+        // SAFETY: `ruleset` is the descriptor created above.
+        unsafe { libc::close(ruleset) };
+        result
+    }
+
+    fn add_rules(&self, ruleset: libc::c_int) -> nix::Result<()> {
+        for (path, access) in &self.rules {
+            // SAFETY: `path` is NUL-terminated; the descriptor is closed below.
+            let fd = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+            if fd < 0 {
+                continue;
+            }
+            let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+            // SAFETY: `stat` is written by a successful fstat.
+            let is_dir = unsafe {
+                libc::fstat(fd, stat.as_mut_ptr()) == 0 && stat.assume_init().st_mode & libc::S_IFMT == libc::S_IFDIR
+            };
+            let access = if is_dir { access & self.handled } else { access & self.handled & ACCESS_FILE };
+            let attr = PathBeneathAttr { allowed_access: access, parent_fd: fd };
+            // SAFETY: `attr` is a valid path-beneath attribute.
+            let result = Errno::result(unsafe {
+                libc::syscall(libc::SYS_landlock_add_rule, ruleset, RULE_PATH_BENEATH, &attr as *const PathBeneathAttr, 0u32)
+            });
+            unsafe { libc::close(fd) };
+            result?;
+        }
+        Ok(())
+    }
+}
+
+/// Filesystem permissions enforced with Landlock. Unprivileged and
+/// independent of namespaces, so it still confines apps where user
+/// namespaces are disabled.
+pub struct LandlockBackend {
+    pub rules: Vec<FilesystemAccess>,
+    abi: Option<u32>,
+}
+
+impl LandlockBackend {
+    pub fn new() -> Self {
+        Self::with_abi(abi_version())
+    }
+
+    /// A backend for a kernel with the given Landlock ABI.
+    pub fn with_abi(abi: Option<u32>) -> Self {
+        Self { rules: Vec::new(), abi }
+    }
+
+    pub fn abi(&self) -> Option<u32> {
+        self.abi
+    }
+
+    pub fn add_rule(&mut self, access: FilesystemAccess) {
+        self.rules.push(access);
+    }
+
+    /// Adds a filesystem permission, resolving a path placeholder against
+    /// the directories of the user the app runs as.
+    pub fn add_access(&mut self, access: &FilesystemAccess, dirs: &UserDirs, app_id: &AppId) -> Result<()> {
+        let resolved = dirs.resolve(access, app_id)
+            .with_context(|| format!("Cannot resolve {} for {}", access.path.display(), app_id.primary))?;
+        self.add_rule(resolved);
+        Ok(())
+    }
+
+    /// Rules Landlock can't enforce: those granting less than a rule or
+    /// system path for a directory above them, such as a `Deny` inside a
+    /// read-write folder.
+    pub fn unenforceable(&self) -> Vec<&FilesystemAccess> {
+        let rank = |mode: &types::AccessMode| match mode {
+            types::AccessMode::Deny => 0,
+            types::AccessMode::ReadOnly => 1,
+            types::AccessMode::ReadWrite => 2,
+        };
+        let system = SYSTEM_RULES.iter().map(|(path, mode)| {
+            let mode = match mode {
+                AccessMode::ReadOnly => types::AccessMode::ReadOnly,
+                AccessMode::ReadWrite => types::AccessMode::ReadWrite,
+            };
+            (Path::new(*path), mode)
+        });
+        let granted: Vec<(&Path, types::AccessMode)> = system
+            .chain(self.rules.iter().map(|rule| (rule.path.as_path(), rule.mode.clone())))
+            .collect();
+        self.rules.iter()
+            .filter(|rule| {
+                granted.iter().any(|(path, mode)| {
+                    rule.path != *path && rule.path.starts_with(path) && rank(&rule.mode) < rank(mode)
+                })
+            })
+            .collect()
+    }
+
+    /// `Medium` when Landlock enforces every rule, `Weak` when the kernel
+    /// lacks it or some rule can't be expressed.
+    pub fn strength(&self) -> EnforcementStrength {
+        if self.abi.is_none() || !self.unenforceable().is_empty() {
+            EnforcementStrength::Weak
+        } else {
+            EnforcementStrength::Medium
+        }
+    }
+
+    /// The system paths plus every allowed rule; `Deny` needs no rule.
+    pub fn ruleset(&self) -> Option<LandlockRuleset> {
+        let mut ruleset = LandlockRuleset::new(self.abi?);
+        for (path, mode) in SYSTEM_RULES {
+            ruleset.allow(path, mode);
+        }
+        for rule in &self.rules {
+            match rule.mode {
+                types::AccessMode::ReadOnly => ruleset.allow(&rule.path, AccessMode::ReadOnly),
+                types::AccessMode::ReadWrite => ruleset.allow(&rule.path, AccessMode::ReadWrite),
+                types::AccessMode::Deny => &mut ruleset,
+            };
+        }
+        Some(ruleset)
+    }
+}
+
+impl Default for LandlockBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlanContributor for LandlockBackend {
+    fn contribute(&self, plan: &mut SandboxPlan) {
+        for rule in self.unenforceable() {
+            warn!("Landlock cannot enforce {:?} on {}", rule.mode, rule.path.display());
+        }
+        match self.ruleset() {
+            Some(ruleset) => {
+                plan.landlock(ruleset);
+            }
+            None => warn!("Landlock is not available, filesystem rules rely on the sandbox alone"),
+        }
+    }
+}
+
+/// A `Command` confined by the plan's Landlock ruleset alone, on the
+/// host's own namespaces and filesystem.
+pub fn command<S: AsRef<OsStr>>(plan: &SandboxPlan, command: &[S]) -> Result<Command> {
+    let Some(ruleset) = plan.landlock_ruleset() else {
+        bail!("No sandbox is available: no bwrap, no user namespaces and no Landlock rules");
+    };
+    let Some((program, args)) = command.split_first() else {
+        bail!("No command to run in the sandbox");
+    };
+    warn!("Running with Landlock only; mounts and namespaces of the sandbox plan are not applied");
+    let ruleset = ruleset.compile()?;
+    let seccomp = plan.seccomp_filter()?;
+    let new_session = plan.starts_new_session();
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    plan.apply_env(&mut cmd);
+    // SAFETY: only syscalls on memory prepared before the fork.
+    unsafe {
+        cmd.pre_exec(move || {
+            if new_session {
+                nix::unistd::setsid()?;
+            }
+            ruleset.restrict_self()?;
+            if let Some(seccomp) = &seccomp {
+                seccomp.install()?;
+            }
+            Ok(())
+        });
+    }
+    Ok(cmd)
+}
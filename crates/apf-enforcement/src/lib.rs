@@ -1,8 +1,28 @@
 
+pub mod bwrap;
+pub mod cgroup;
+pub mod dns;
+pub mod lan;
+pub mod landlock;
+pub mod native;
+pub mod notify;
+pub mod plan;
+pub mod proxy;
+pub mod seccomp;
 pub mod sandbox;
 pub mod network;
 pub mod filesystem;
 
+pub use bwrap::{Bwrap, BwrapOption};
+pub use cgroup::{CgroupNetwork, CgroupNetworkBackend};
+pub use lan::LanNetwork;
+pub use landlock::{LandlockBackend, LandlockRuleset};
+pub use native::NativeSandbox;
+pub use notify::{ConnectRequest, ConnectSupervisor, NotifyChannel};
+pub use plan::{Mount, Namespace, PlanContributor, SandboxPlan, SandboxRuntime};
+pub use dns::{Blocklist, DnsQuery};
+pub use proxy::{EgressPolicy, EgressProxy, EgressProxyBackend, EgressRequest};
+pub use seccomp::{SeccompAction, SeccompFilter, SeccompRule};
 pub use sandbox::SandboxBackend;
 pub use filesystem::{FilesystemBackend, AccessMode};
 pub mod device;
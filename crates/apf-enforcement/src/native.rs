@@ -0,0 +1,523 @@
+use std::ffi::{CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::libc;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::stat::Mode;
+use nix::sys::statvfs::{statvfs, FsFlags};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{self, ForkResult, Gid, Uid};
+use crate::filesystem::AccessMode;
+use crate::cgroup::{self, CgroupNetwork};
+use crate::lan::{self, LanNetwork};
+use crate::landlock::CompiledRuleset;
+use crate::seccomp::SeccompFilter;
+use crate::plan::{Mount, Namespace, SandboxPlan};
+
+/// Where the new root and the host's root are found between the two
+/// `pivot_root` calls.
+const NEW_ROOT: &str = "/newroot";
+const OLD_ROOT: &str = "/oldroot";
+
+/// Device nodes the minimal `/dev` gets from the host.
+const DEV_NODES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
+
+/// The host's mount table, as seen between the two `pivot_root` calls.
+const OLD_MOUNTINFO: &CStr = c"/oldroot/proc/self/mountinfo";
+
+/// `MOUNT_ATTR_RDONLY` for `mount_setattr`.
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+
+/// `struct mount_attr` of `mount_setattr`.
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+/// Whether this process can run the native sandbox: as root, or through
+/// a user namespace.
+pub fn available() -> bool {
+    Uid::effective().is_root() || user_namespaces_available()
+}
+
+/// Whether this process may create a user namespace, and so run the
+/// native sandbox without privileges.
+pub fn user_namespaces_available() -> bool {
+    // SAFETY: the child only makes raw syscalls before exiting.
+    match unsafe { unistd::fork() } {
+        Ok(ForkResult::Child) => {
+            let code = if unshare(CloneFlags::CLONE_NEWUSER).is_ok() { 0 } else { 1 };
+            unsafe { libc::_exit(code) }
+        }
+        Ok(ForkResult::Parent { child }) => {
+            matches!(waitpid(child, None), Ok(WaitStatus::Exited(_, 0)))
+        }
+        Err(_) => false,
+    }
+}
+
+/// One step of assembling the root, with every path already rooted under
+/// `NEW_ROOT` or `OLD_ROOT`.
+#[derive(Debug)]
+enum Step {
+    Dir(CString),
+    /// An empty file to bind a non-directory over.
+    File(CString),
+    Bind { src: CString, dest: CString, read_only: bool },
+    Tmpfs(CString),
+    Proc(CString),
+    Symlink { target: CString, link: CString },
+}
+
+/// A `SandboxPlan` compiled for the built-in launcher: namespaces from
+/// `unshare`, the root assembled on a tmpfs and entered with `pivot_root`.
+/// Everything the child needs is prepared up front, since it must not
+/// allocate between `fork` and `exec`.
+#[derive(Debug)]
+pub struct NativeSandbox {
+    namespaces: CloneFlags,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    steps: Vec<Step>,
+    new_session: bool,
+    cwd: Option<CString>,
+    landlock: Option<CompiledRuleset>,
+    seccomp: Option<SeccompFilter>,
+    /// Network namespace to start from, held open while the sandbox is.
+    network: Option<LanNetwork>,
+    /// Cgroup to move into first, held open while the sandbox is.
+    cgroup: Option<Arc<CgroupNetwork>>,
+}
+
+impl NativeSandbox {
+    pub fn new(plan: &SandboxPlan) -> Result<Self> {
+        let mut namespaces = CloneFlags::CLONE_NEWNS;
+        for (namespace, flag) in [
+            (Namespace::User, CloneFlags::CLONE_NEWUSER),
+            (Namespace::Ipc, CloneFlags::CLONE_NEWIPC),
+            (Namespace::Pid, CloneFlags::CLONE_NEWPID),
+            (Namespace::Net, CloneFlags::CLONE_NEWNET),
+            (Namespace::Uts, CloneFlags::CLONE_NEWUTS),
+            (Namespace::Cgroup, CloneFlags::CLONE_NEWCGROUP),
+        ] {
+            if plan.is_unshared(namespace) {
+                namespaces |= flag;
+            }
+        }
+
+        let mut steps = Vec::new();
+        for mount in plan.mounts() {
+            add_steps(&mut steps, mount)?;
+        }
+
+        // Keep the working directory when the sandbox has it, as bwrap does.
+        let cwd = std::env::current_dir().ok()
+            .map(|cwd| cstring(cwd.as_os_str()))
+            .transpose()?;
+
+        Ok(Self {
+            namespaces,
+            uid_map: format!("{0} {0} 1", Uid::current()).into_bytes(),
+            gid_map: format!("{0} {0} 1", Gid::current()).into_bytes(),
+            steps,
+            new_session: plan.starts_new_session(),
+            cwd,
+            landlock: plan.landlock_ruleset().map(|ruleset| ruleset.compile()).transpose()?,
+            seccomp: plan.seccomp_filter()?,
+            network: None,
+            cgroup: None,
+        })
+    }
+
+    /// Starts from the namespaces of `network` rather than the host's.
+    /// Inside its user namespace we are root, so the app's own user
+    /// namespace maps our uid onto that root.
+    pub fn joining(mut self, network: LanNetwork) -> Self {
+        self.network = Some(network);
+        self.namespaces.remove(CloneFlags::CLONE_NEWNET);
+        self.uid_map = format!("{} 0 1", Uid::current()).into_bytes();
+        self.gid_map = format!("{} 0 1", Gid::current()).into_bytes();
+        self
+    }
+
+    /// Starts the sandbox inside `cgroup`.
+    pub fn in_cgroup(mut self, cgroup: Arc<CgroupNetwork>) -> Self {
+        self.cgroup = Some(cgroup);
+        self
+    }
+
+    /// A `Command` running `command` in the sandbox.
+    pub fn command<S: AsRef<OsStr>>(self, plan: &SandboxPlan, command: &[S]) -> Result<Command> {
+        let Some((program, args)) = command.split_first() else {
+            bail!("No command to run in the sandbox");
+        };
+        let mut cmd = Command::new(program);
+        cmd.args(args);
+        plan.apply_env(&mut cmd);
+        // SAFETY: `enter` only makes syscalls on memory prepared before the
+        // fork.
+        unsafe {
+            cmd.pre_exec(move || self.enter().map_err(std::io::Error::from));
+        }
+        Ok(cmd)
+    }
+
+    /// Runs in the forked child: moves it into the sandbox just before exec.
+    fn enter(&self) -> nix::Result<()> {
+        die_with_parent()?;
+        if let Some(cgroup) = &self.cgroup {
+            cgroup::enter(cgroup.procs_fd())?;
+        }
+        if let Some(network) = &self.network {
+            let (userns, netns) = network.namespace_fds();
+            lan::join(userns, netns)?;
+        }
+
+        let mut namespaces = self.namespaces;
+        if let Err(err) = unshare(namespaces) {
+            // Like bwrap's --unshare-*-try: root needs no user namespace,
+            // and old kernels have no cgroup namespace. Having joined a
+            // network we are root only in the user namespace that owns it;
+            // without one of its own the app could rewrite its rules.
+            if err == Errno::EINVAL {
+                namespaces.remove(CloneFlags::CLONE_NEWCGROUP);
+            }
+            if Uid::effective().is_root() && self.network.is_none() {
+                namespaces.remove(CloneFlags::CLONE_NEWUSER);
+            }
+            unshare(namespaces)?;
+        }
+        if namespaces.contains(CloneFlags::CLONE_NEWUSER) {
+            write_file(c"/proc/self/setgroups", b"deny")?;
+            write_file(c"/proc/self/uid_map", &self.uid_map)?;
+            write_file(c"/proc/self/gid_map", &self.gid_map)?;
+        }
+
+        // A new PID namespace only applies to children: fork once more and
+        // stay behind to pass on the exit status.
+        if namespaces.contains(CloneFlags::CLONE_NEWPID) {
+            // SAFETY: the parent only closes descriptors, waits and exits.
+            if let ForkResult::Parent { child } = unsafe { unistd::fork() }? {
+                wait_and_exit(child);
+            }
+            die_with_parent()?;
+        }
+
+        self.pivot()?;
+        if self.new_session {
+            unistd::setsid()?;
+        }
+        // Landlock rules name paths as the sandbox sees them, so they apply
+        // last, in the new root.
+        if let Some(landlock) = &self.landlock {
+            landlock.restrict_self()?;
+        }
+        // Last, as the filter may deny what setting up the sandbox needs.
+        if let Some(seccomp) = &self.seccomp {
+            seccomp.install()?;
+        }
+        Ok(())
+    }
+
+    /// Assembles the root on a tmpfs staged over `/tmp`, which only this
+    /// mount namespace sees, and enters it.
+    fn pivot(&self) -> nix::Result<()> {
+        let none: Option<&CStr> = None;
+        mount(none, c"/", none, MsFlags::MS_SLAVE | MsFlags::MS_REC, none)?;
+        mount(Some(c"tmpfs"), c"/tmp", Some(c"tmpfs"), MsFlags::MS_NOSUID | MsFlags::MS_NODEV, none)?;
+        unistd::chdir(c"/tmp")?;
+        unistd::mkdir(c"newroot", Mode::from_bits_truncate(0o755))?;
+        mount(Some(c"tmpfs"), c"newroot", Some(c"tmpfs"), MsFlags::MS_NOSUID | MsFlags::MS_NODEV, Some(c"mode=0755"))?;
+        unistd::mkdir(c"oldroot", Mode::from_bits_truncate(0o755))?;
+        unistd::pivot_root(c"/tmp", c"oldroot")?;
+        unistd::chdir(c"/")?;
+
+        for step in &self.steps {
+            run_step(step)?;
+        }
+
+        unistd::chdir(c"/newroot")?;
+        unistd::pivot_root(c".", c".")?;
+        umount2(c".", MntFlags::MNT_DETACH)?;
+        match &self.cwd {
+            Some(cwd) if unistd::chdir(cwd.as_c_str()).is_ok() => Ok(()),
+            _ => unistd::chdir(c"/"),
+        }
+    }
+}
+
+fn run_step(step: &Step) -> nix::Result<()> {
+    let none: Option<&CStr> = None;
+    match step {
+        Step::Dir(path) => match unistd::mkdir(path.as_c_str(), Mode::from_bits_truncate(0o755)) {
+            Err(Errno::EEXIST) => Ok(()),
+            result => result,
+        },
+        Step::File(path) => {
+            let fd = nix::fcntl::open(path.as_c_str(), OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_CLOEXEC, Mode::from_bits_truncate(0o644))?;
+            unistd::close(fd)
+        }
+        Step::Bind { src, dest, read_only } => {
+            mount(Some(src.as_c_str()), dest.as_c_str(), none, MsFlags::MS_BIND | MsFlags::MS_REC, none)?;
+            if *read_only {
+                // The bind brings the mounts below `src` along; like bwrap's
+                // --ro-bind, they are read-only too.
+                match set_read_only_recursive(dest) {
+                    Err(Errno::ENOSYS) => {
+                        remount_read_only(dest)?;
+                        remount_submounts_read_only(dest)?;
+                    }
+                    result => result?,
+                }
+            }
+            Ok(())
+        }
+        Step::Tmpfs(path) => mount(Some(c"tmpfs"), path.as_c_str(), Some(c"tmpfs"), MsFlags::MS_NOSUID | MsFlags::MS_NODEV, Some(c"mode=0755")),
+        Step::Proc(path) => mount(
+            Some(c"proc"),
+            path.as_c_str(),
+            Some(c"proc"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            none,
+        ),
+        Step::Symlink { target, link } => unistd::symlinkat(target.as_c_str(), None, link.as_c_str()),
+    }
+}
+
+/// Makes `dest` and every mount below it read-only at once, on kernels
+/// with `mount_setattr` (5.12 and later).
+fn set_read_only_recursive(dest: &CStr) -> nix::Result<()> {
+    let attr = MountAttr { attr_set: MOUNT_ATTR_RDONLY, attr_clr: 0, propagation: 0, userns_fd: 0 };
+    // SAFETY: `dest` is NUL-terminated and `attr` outlives the call.
+    Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            dest.as_ptr(),
+            libc::AT_RECURSIVE as libc::c_uint,
+            &attr as *const MountAttr,
+            size_of::<MountAttr>(),
+        )
+    })
+    .map(drop)
+}
+
+/// Remounts the mount at `dest` read-only.
+fn remount_read_only(dest: &CStr) -> nix::Result<()> {
+    let none: Option<&CStr> = None;
+    // A remount inside a user namespace must keep the flags the mount is
+    // locked with.
+    let flags = statvfs(dest)?.flags();
+    let mut remount = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if flags.contains(fs_flag) {
+            remount |= ms_flag;
+        }
+    }
+    mount(none, dest, none, remount, none)
+}
+
+/// Remounts every mount below `dest` read-only, one at a time, as listed
+/// in the mount table. Reads it through fixed buffers, since the child
+/// may not allocate; a line too long for them fails the sandbox rather
+/// than leave a mount writable.
+fn remount_submounts_read_only(dest: &CStr) -> nix::Result<()> {
+    let fd = nix::fcntl::open(OLD_MOUNTINFO, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+    let mut buf = [0u8; 16 * 1024];
+    let mut len = 0;
+    let result = loop {
+        let read = match unistd::read(fd, &mut buf[len..]) {
+            Ok(read) => read,
+            Err(err) => break Err(err),
+        };
+        len += read;
+        let mut start = 0;
+        let mut failed = None;
+        while let Some(end) = buf[start..len].iter().position(|&b| b == b'\n') {
+            if let Err(err) = remount_if_below(&buf[start..start + end], dest) {
+                failed = Some(err);
+                break;
+            }
+            start += end + 1;
+        }
+        if let Some(err) = failed {
+            break Err(err);
+        }
+        buf.copy_within(start..len, 0);
+        len -= start;
+        if read == 0 {
+            break Ok(());
+        }
+        if len == buf.len() {
+            break Err(Errno::E2BIG);
+        }
+    };
+    let _ = unistd::close(fd);
+    result
+}
+
+/// Remounts the mount a mountinfo line describes read-only if its mount
+/// point is below `dest`.
+fn remount_if_below(line: &[u8], dest: &CStr) -> nix::Result<()> {
+    let Some(escaped) = line.split(|&b| b == b' ').nth(4) else {
+        return Ok(());
+    };
+    // Mount points have spaces and the like written as `\ooo`.
+    let mut path = [0u8; libc::PATH_MAX as usize + 1];
+    let mut len = 0;
+    let mut i = 0;
+    while i < escaped.len() {
+        if len == path.len() - 1 {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        let octal = escaped.get(i + 1..i + 4).filter(|digits| escaped[i] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        match octal {
+            Some(digits) => {
+                path[len] = digits.iter().fold(0u8, |byte, d| byte.wrapping_mul(8).wrapping_add(d - b'0'));
+                i += 4;
+            }
+            None => {
+                path[len] = escaped[i];
+                i += 1;
+            }
+        }
+        len += 1;
+    }
+    let dest = dest.to_bytes();
+    if len <= dest.len() || !path.starts_with(dest) || path[dest.len()] != b'/' {
+        return Ok(());
+    }
+    match CStr::from_bytes_with_nul(&path[..len + 1]) {
+        Ok(submount) => remount_read_only(submount),
+        Err(_) => Err(Errno::EINVAL),
+    }
+}
+
+fn add_steps(steps: &mut Vec<Step>, mount: &Mount) -> Result<()> {
+    let dest = mount.dest();
+    if !dest.is_absolute() {
+        bail!("Sandbox mount destination {} is not absolute", dest.display());
+    }
+    for parent in dest.ancestors().skip(1).collect::<Vec<_>>().into_iter().rev().skip(1) {
+        steps.push(Step::Dir(rooted(NEW_ROOT, parent)?));
+    }
+    match mount {
+        Mount::Bind { src, optional, mode, .. } => {
+            add_bind(steps, src, dest, *mode == AccessMode::ReadOnly, *optional)?;
+        }
+        Mount::DevBind { src, optional, .. } => add_bind(steps, src, dest, false, *optional)?,
+        Mount::Tmpfs(_) => {
+            steps.push(Step::Dir(rooted(NEW_ROOT, dest)?));
+            steps.push(Step::Tmpfs(rooted(NEW_ROOT, dest)?));
+        }
+        Mount::Proc(_) => {
+            steps.push(Step::Dir(rooted(NEW_ROOT, dest)?));
+            steps.push(Step::Proc(rooted(NEW_ROOT, dest)?));
+        }
+        Mount::Dev(_) => {
+            steps.push(Step::Dir(rooted(NEW_ROOT, dest)?));
+            steps.push(Step::Tmpfs(rooted(NEW_ROOT, dest)?));
+            for node in DEV_NODES {
+                let host = Path::new("/dev").join(node);
+                if host.exists() {
+                    add_bind(steps, &host, &dest.join(node), false, false)?;
+                }
+            }
+            for (link, target) in [
+                ("fd", "/proc/self/fd"),
+                ("stdin", "/proc/self/fd/0"),
+                ("stdout", "/proc/self/fd/1"),
+                ("stderr", "/proc/self/fd/2"),
+            ] {
+                steps.push(Step::Symlink { target: cstring(OsStr::new(target))?, link: rooted(NEW_ROOT, &dest.join(link))? });
+            }
+            steps.push(Step::Dir(rooted(NEW_ROOT, &dest.join("shm"))?));
+            steps.push(Step::Tmpfs(rooted(NEW_ROOT, &dest.join("shm"))?));
+        }
+        Mount::Dir(_) => steps.push(Step::Dir(rooted(NEW_ROOT, dest)?)),
+        Mount::Symlink { target, .. } => {
+            steps.push(Step::Symlink { target: cstring(target.as_os_str())?, link: rooted(NEW_ROOT, dest)? });
+        }
+    }
+    Ok(())
+}
+
+fn add_bind(steps: &mut Vec<Step>, src: &Path, dest: &Path, read_only: bool, optional: bool) -> Result<()> {
+    // The mount namespace starts as a copy of ours, so the host path looks
+    // the same under OLD_ROOT.
+    let meta = match std::fs::metadata(src) {
+        Ok(meta) => meta,
+        Err(_) if optional => return Ok(()),
+        Err(err) => return Err(err).with_context(|| format!("Cannot bind {} into the sandbox", src.display())),
+    };
+    let mount_point = rooted(NEW_ROOT, dest)?;
+    steps.push(if meta.is_dir() { Step::Dir(mount_point.clone()) } else { Step::File(mount_point.clone()) });
+    steps.push(Step::Bind { src: rooted(OLD_ROOT, src)?, dest: mount_point, read_only });
+    Ok(())
+}
+
+/// `path` as seen under `root` after the first `pivot_root`.
+fn rooted(root: &str, path: &Path) -> Result<CString> {
+    if !path.is_absolute() {
+        bail!("Sandbox path {} is not absolute", path.display());
+    }
+    let mut rooted = PathBuf::from(root);
+    rooted.push(path.strip_prefix("/").unwrap_or(path));
+    cstring(rooted.as_os_str())
+}
+
+fn cstring(path: &OsStr) -> Result<CString> {
+    CString::new(path.as_bytes()).with_context(|| format!("Sandbox path {:?} contains a NUL byte", path))
+}
+
+fn die_with_parent() -> nix::Result<()> {
+    // SAFETY: prctl with integer arguments.
+    Errno::result(unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) }).map(drop)
+}
+
+pub(crate) fn write_file(path: &CStr, data: &[u8]) -> nix::Result<()> {
+    let fd = nix::fcntl::open(path, OFlag::O_WRONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+    let result = unistd::write(fd, data).map(drop);
+    unistd::close(fd)?;
+    result
+}
+
+/// The intermediate process of a PID namespace: waits for the sandboxed
+/// child and exits the same way. Its descriptors are closed first, so the
+/// spawner sees exec succeed as soon as the child execs.
+fn wait_and_exit(child: unistd::Pid) -> ! {
+    // SAFETY: raw syscalls on integers, then _exit.
+    unsafe {
+        if libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0) != 0 {
+            for fd in 3..1024 {
+                libc::close(fd);
+            }
+        }
+        let code = loop {
+            match waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, code)) => break code,
+                Ok(WaitStatus::Signaled(_, signal, _)) => break 128 + signal as i32,
+                Err(Errno::EINTR) | Ok(_) => continue,
+                Err(_) => break 1,
+            }
+        };
+        libc::_exit(code)
+    }
+}
@@ -1,8 +1,10 @@
-
-use std::process::Command;
-use anyhow::{Result, Context};
+use anyhow::Result;
 use tracing::{info, warn, error};
 use apf_core::types::NetworkLevel;
+use crate::plan::{Namespace, PlanContributor, SandboxPlan};
+use crate::sandbox::SandboxBackend;
+use crate::seccomp::SeccompRule;
+use nix::libc;
 
 pub struct NetworkBackend {
     pub allowed_level: NetworkLevel,
@@ -14,31 +16,8 @@ impl NetworkBackend {
     }
 
     pub fn enforce_network_policy(&self, command: &[String]) -> Result<()> {
-        // Example: Use bubblewrap or firejail to restrict network
-        let mut args = vec!["--unshare-net".to_string(), "--die-with-parent".to_string()];
-        match self.allowed_level {
-            NetworkLevel::None => {
-                args.push("--network none".to_string());
-            }
-            NetworkLevel::Lan => {
-                // Allow LAN, block Internet (stub)
-                args.push("--network lan".to_string());
-                warn!("LAN-only enforcement is not fully implemented");
-            }
-            NetworkLevel::Internet => {
-                // Allow full network
-                args.push("--network internet".to_string());
-            }
-        }
-        info!("Launching network-restricted sandbox: {:?}", args);
-        let mut net_cmd = Command::new("bubblewrap");
-        for arg in &args {
-            net_cmd.arg(arg);
-        }
-        for c in command {
-            net_cmd.arg(c);
-        }
-        let status = net_cmd.status().context("Failed to launch network sandbox")?;
+        let plan = SandboxPlan::from_contributors(&[&SandboxBackend::new(), self]);
+        let status = plan.launch(command)?;
         if !status.success() {
             error!("Network sandbox exited with failure: {:?}", status);
             return Err(anyhow::anyhow!("Network sandbox failed"));
@@ -47,3 +26,34 @@ impl NetworkBackend {
         Ok(())
     }
 }
+
+/// Keeps the host's network namespace when Internet access is allowed,
+/// moves LAN-only apps into a namespace that can only reach the LAN, and
+/// refuses IP sockets when no network is allowed. Per-destination rules
+/// can't be enforced here and get no network either; `CgroupNetworkBackend`
+/// enforces them.
+impl PlanContributor for NetworkBackend {
+    fn contribute(&self, plan: &mut SandboxPlan) {
+        match self.allowed_level {
+            NetworkLevel::None => deny_ip_sockets(plan),
+            NetworkLevel::Lan => {
+                plan.lan_only();
+            }
+            NetworkLevel::Internet => {
+                plan.share(Namespace::Net);
+            }
+            NetworkLevel::Rule(_) => {
+                warn!("Per-destination network rules need the cgroup backend; no network allowed");
+                deny_ip_sockets(plan);
+            }
+        }
+    }
+}
+
+/// The empty network namespace already has no route out; refusing IP
+/// sockets also covers runtimes without one.
+fn deny_ip_sockets(plan: &mut SandboxPlan) {
+    for family in [libc::AF_INET, libc::AF_INET6] {
+        plan.seccomp(SeccompRule::deny("socket").when_arg(0, family as u32));
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::mem::size_of;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileExt;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::thread::JoinHandle;
+use anyhow::{bail, Context, Result};
+use apf_core::types::{Cidr, HostPattern, NetworkLevel, NetworkRule, PermissionType, PortRange, Protocol};
+use nix::errno::Errno;
+use nix::libc;
+use tracing::{debug, warn};
+use crate::seccomp::{SeccompAction, SeccompFilter, SeccompRule};
+
+/// Syscalls that name a destination for a socket.
+pub const CONNECT_SYSCALLS: [&str; 4] = ["connect", "sendto", "sendmsg", "sendmmsg"];
+
+/// Largest address the kernel accepts, `struct sockaddr_storage`.
+const SOCKADDR_MAX: usize = 128;
+
+/// Most messages one `sendmmsg` sends, as in the kernel.
+const SENDMMSG_MAX: u64 = 1024;
+
+/// Rules handing every destination-naming syscall to the supervisor.
+/// io_uring is refused outright: a connect submitted through a ring never
+/// passes the filter.
+pub fn connect_rules() -> Vec<SeccompRule> {
+    let mut rules: Vec<SeccompRule> =
+        CONNECT_SYSCALLS.iter().map(|syscall| SeccompRule::new(*syscall, SeccompAction::Notify)).collect();
+    rules.push(SeccompRule::deny("io_uring_setup"));
+    rules
+}
+
+/// A sandboxed process about to reach a destination for the first time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectRequest {
+    /// The process, as this process sees it.
+    pub pid: u32,
+    pub syscall: &'static str,
+    pub destination: SocketAddr,
+    /// `None` for sockets other than TCP and UDP, or when the socket
+    /// can't be inspected.
+    pub protocol: Option<Protocol>,
+}
+
+impl ConnectRequest {
+    /// The single destination as a rule: its address, port and protocol.
+    pub fn rule(&self) -> NetworkRule {
+        let port = self.destination.port();
+        NetworkRule {
+            host: HostPattern::Cidr(Cidr::host(self.destination.ip())),
+            ports: Some(PortRange { start: port, end: port }),
+            protocol: self.protocol,
+        }
+    }
+
+    pub fn permission(&self) -> PermissionType {
+        PermissionType::Network(NetworkLevel::Rule(self.rule()))
+    }
+}
+
+/// Carries the listener of a sandboxed process's notification filter back
+/// to the process that launched it. `apply` the channel to the command,
+/// spawn it, then `receive` the supervisor.
+///
+/// The filter traps `sendmsg`, so the listener can't be passed over the
+/// socket once installed. The child writes its number instead and waits;
+/// a thread here learns the child's pid from the message's credentials,
+/// copies the listener out with `pidfd_getfd` and lets the child go on.
+#[derive(Debug)]
+pub struct NotifyChannel {
+    child: OwnedFd,
+    filter: SeccompFilter,
+    receiver: JoinHandle<Result<OwnedFd>>,
+}
+
+impl NotifyChannel {
+    pub fn new() -> Result<Self> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for both ends.
+        Errno::result(unsafe {
+            libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0, fds.as_mut_ptr())
+        })
+        .context("Failed to create the seccomp listener channel")?;
+        // SAFETY: both ends are new descriptors we own.
+        let (parent, child) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let on: libc::c_int = 1;
+        // SAFETY: the option value is one int.
+        Errno::result(unsafe {
+            libc::setsockopt(
+                parent.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PASSCRED,
+                (&on as *const libc::c_int).cast(),
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        })?;
+        let filter = SeccompFilter::compile(&connect_rules())?;
+        let receiver = std::thread::Builder::new()
+            .name("apf-notify-listener".into())
+            .spawn(move || take_listener(&parent))?;
+        Ok(Self { child, filter, receiver })
+    }
+
+    /// Installs the notification filter in `cmd`'s process just before it
+    /// execs and hands its listener over. Apply it after everything else
+    /// that sets the process up, so none of that waits on the supervisor.
+    ///
+    /// Installing the filter sets `PR_SET_NO_NEW_PRIVS` before `cmd` is
+    /// exec'd, so a setuid `cmd` runs without its privileges. A setuid bwrap
+    /// can't set up its sandbox that way; `SandboxPlan::runtime` picks the
+    /// built-in launcher for plans that prompt.
+    pub fn apply(&self, cmd: &mut Command) {
+        let filter = self.filter.clone();
+        let sock = self.child.as_raw_fd();
+        // SAFETY: only syscalls on memory prepared before the fork.
+        unsafe {
+            cmd.pre_exec(move || {
+                let listener = filter.install_listener()?;
+                let handed_over = hand_over(sock, listener);
+                libc::close(listener);
+                handed_over.map_err(std::io::Error::from)
+            });
+        }
+    }
+
+    /// The supervisor for the spawned command.
+    pub fn receive(self) -> Result<ConnectSupervisor> {
+        let Self { child, receiver, .. } = self;
+        // With our copy of the child's end closed, a child that failed
+        // before handing over reads as end of file instead of blocking.
+        drop(child);
+        let listener = receiver.join()
+            .map_err(|_| anyhow::anyhow!("Seccomp listener receiver panicked"))?
+            .context("Failed to receive the seccomp listener")?;
+        Ok(ConnectSupervisor::new(listener))
+    }
+}
+
+/// Writes the listener's number and waits until the parent has a copy.
+/// Safe to call between `fork` and `exec`.
+fn hand_over(sock: RawFd, listener: RawFd) -> nix::Result<()> {
+    let number = listener.to_ne_bytes();
+    // SAFETY: plain reads and writes of buffers on this stack frame.
+    unsafe {
+        Errno::result(libc::write(sock, number.as_ptr().cast(), number.len()))?;
+        let mut ack = [0u8];
+        let len = Errno::result(libc::read(sock, ack.as_mut_ptr().cast(), 1))?;
+        if len != 1 || ack != *b"k" {
+            return Err(Errno::EPERM);
+        }
+    }
+    Ok(())
+}
+
+/// The other side of `hand_over`.
+fn take_listener(sock: &OwnedFd) -> Result<OwnedFd> {
+    let mut number = [0u8; size_of::<RawFd>()];
+    let mut iov = libc::iovec { iov_base: number.as_mut_ptr().cast(), iov_len: number.len() };
+    let mut control = [0u64; 8];
+    // SAFETY: the header points at buffers on this stack frame; the
+    // credentials are only read when the kernel attached them.
+    let (len, pid) = unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = size_of::<[u64; 8]>() as _;
+        let len = Errno::result(libc::recvmsg(sock.as_raw_fd(), &mut msg, 0))?;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if len == 0 || cmsg.is_null() || (*cmsg).cmsg_type != libc::SCM_CREDENTIALS {
+            bail!("The sandboxed process handed over no listener");
+        }
+        let creds = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::ucred);
+        (len as usize, creds.pid)
+    };
+    if len != number.len() {
+        bail!("Malformed seccomp listener hand-over");
+    }
+    let taken = pidfd_getfd(pid, RawFd::from_ne_bytes(number));
+    let ack: &[u8] = if taken.is_ok() { b"k" } else { b"e" };
+    // SAFETY: writes a static buffer.
+    Errno::result(unsafe { libc::write(sock.as_raw_fd(), ack.as_ptr().cast(), 1) })?;
+    taken.with_context(|| format!("Cannot copy the seccomp listener out of process {}", pid))
+}
+
+/// A copy of descriptor `fd` of process `pid`.
+fn pidfd_getfd(pid: libc::pid_t, fd: RawFd) -> nix::Result<OwnedFd> {
+    // SAFETY: plain syscalls; the descriptors returned are ours to own.
+    unsafe {
+        let pidfd = Errno::result(libc::syscall(libc::SYS_pidfd_open, pid, 0))?;
+        let pidfd = OwnedFd::from_raw_fd(pidfd as RawFd);
+        let copy = Errno::result(libc::syscall(libc::SYS_pidfd_getfd, pidfd.as_raw_fd(), fd, 0))?;
+        Ok(OwnedFd::from_raw_fd(copy as RawFd))
+    }
+}
+
+/// Answers a notification filter's syscalls: those reaching a destination
+/// not seen before are put to the caller's `decide`, and the answer is
+/// remembered for the rest of the sandbox's life.
+///
+/// The destination is read from the process's memory before the syscall
+/// is let through, so another thread of the process could change it in
+/// between. This decides what to ask about; it isn't a boundary against a
+/// process set on getting around it, which the cgroup or namespace
+/// backends are.
+#[derive(Debug)]
+pub struct ConnectSupervisor {
+    listener: OwnedFd,
+    answers: HashMap<NetworkRule, bool>,
+}
+
+/// What becomes of one notified syscall.
+enum Verdict {
+    Continue,
+    Fail(i32),
+    /// The process went away meanwhile.
+    Gone,
+}
+
+impl ConnectSupervisor {
+    pub fn new(listener: OwnedFd) -> Self {
+        Self { listener, answers: HashMap::new() }
+    }
+
+    /// Answers notifications until no process is left under the filter.
+    pub fn run(&mut self, mut decide: impl FnMut(&ConnectRequest) -> bool) -> Result<()> {
+        let fd = self.listener.as_raw_fd();
+        loop {
+            let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+            // SAFETY: `pollfd` is one valid pollfd.
+            match Errno::result(unsafe { libc::poll(&mut pollfd, 1, -1) }) {
+                Ok(_) => {}
+                Err(Errno::EINTR) => continue,
+                Err(err) => return Err(err).context("Failed to wait for seccomp notifications"),
+            }
+            if pollfd.revents & libc::POLLIN == 0 {
+                debug!("No process left under the seccomp listener");
+                return Ok(());
+            }
+
+            // SAFETY: the kernel wants a zeroed struct to fill in.
+            let mut notif: libc::seccomp_notif = unsafe { std::mem::zeroed() };
+            // SAFETY: `notif` is the struct this request writes.
+            match Errno::result(unsafe { libc::ioctl(fd, libc::SECCOMP_IOCTL_NOTIF_RECV, &mut notif) }) {
+                Ok(_) => {}
+                Err(Errno::EINTR | Errno::ENOENT) => continue,
+                Err(err) => return Err(err).context("Failed to receive a seccomp notification"),
+            }
+
+            let mut resp = libc::seccomp_notif_resp { id: notif.id, val: 0, error: 0, flags: 0 };
+            match self.handle(&notif, &mut decide) {
+                Verdict::Continue => resp.flags = libc::SECCOMP_USER_NOTIF_FLAG_CONTINUE as u32,
+                Verdict::Fail(errno) => resp.error = -errno,
+                Verdict::Gone => continue,
+            }
+            // SAFETY: `resp` is the struct this request reads.
+            if let Err(err) = Errno::result(unsafe { libc::ioctl(fd, libc::SECCOMP_IOCTL_NOTIF_SEND, &resp) }) {
+                if err != Errno::ENOENT {
+                    warn!("Failed to answer seccomp notification: {}", err);
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, notif: &libc::seccomp_notif, decide: &mut impl FnMut(&ConnectRequest) -> bool) -> Verdict {
+        let Some(syscall) = syscall_name(notif.data.nr) else {
+            return Verdict::Continue;
+        };
+        let destinations = read_destinations(notif);
+        // The pid may have been reused if the process died while its
+        // memory was read.
+        if !self.is_valid(notif.id) {
+            return Verdict::Gone;
+        }
+        let (fd, destinations) = match destinations {
+            Ok(found) => found,
+            Err(err) => {
+                warn!("Cannot read the destination of {} in process {}: {}", syscall, notif.pid, err);
+                return Verdict::Fail(libc::EPERM);
+            }
+        };
+        if destinations.is_empty() {
+            return Verdict::Continue;
+        }
+        let protocol = socket_protocol(notif.pid, fd);
+        for destination in destinations {
+            let request = ConnectRequest { pid: notif.pid, syscall, destination, protocol };
+            let rule = request.rule();
+            let allowed = match self.answers.get(&rule) {
+                Some(allowed) => *allowed,
+                None => {
+                    let allowed = decide(&request);
+                    debug!("{} to {}: {}", syscall, rule, if allowed { "allowed" } else { "denied" });
+                    self.answers.insert(rule, allowed);
+                    allowed
+                }
+            };
+            if !allowed {
+                return Verdict::Fail(libc::EPERM);
+            }
+        }
+        Verdict::Continue
+    }
+
+    fn is_valid(&self, id: u64) -> bool {
+        // SAFETY: the request reads one u64.
+        unsafe { libc::ioctl(self.listener.as_raw_fd(), libc::SECCOMP_IOCTL_NOTIF_ID_VALID, &id) == 0 }
+    }
+}
+
+fn syscall_name(nr: libc::c_int) -> Option<&'static str> {
+    CONNECT_SYSCALLS.into_iter().find(|name| crate::seccomp::syscall_number(name) == Some(nr as libc::c_long))
+}
+
+/// The socket descriptor and the IP destinations the notified syscall
+/// names. Addresses of other families are left out, as is the missing
+/// address of a send on a connected socket.
+fn read_destinations(notif: &libc::seccomp_notif) -> std::io::Result<(i32, Vec<SocketAddr>)> {
+    let memory = File::open(format!("/proc/{}/mem", notif.pid))?;
+    let args = notif.data.args;
+    let fd = args[0] as i32;
+    let mut names = Vec::new();
+    match syscall_name(notif.data.nr) {
+        Some("connect") => names.push((args[1], args[2])),
+        Some("sendto") => names.push((args[4], args[5])),
+        Some("sendmsg") => names.push(msg_name(&read_struct::<libc::msghdr>(&memory, args[1])?)),
+        Some("sendmmsg") => {
+            let stride = size_of::<libc::mmsghdr>() as u64;
+            for i in 0..args[2].min(SENDMMSG_MAX) {
+                let header = read_struct::<libc::mmsghdr>(&memory, args[1] + i * stride)?;
+                names.push(msg_name(&header.msg_hdr));
+            }
+        }
+        _ => {}
+    }
+    let mut destinations = Vec::new();
+    for (addr, len) in names {
+        if addr == 0 {
+            continue;
+        }
+        let mut buf = vec![0u8; (len as usize).min(SOCKADDR_MAX)];
+        memory.read_exact_at(&mut buf, addr)?;
+        destinations.extend(parse_sockaddr(&buf));
+    }
+    Ok((fd, destinations))
+}
+
+fn msg_name(header: &libc::msghdr) -> (u64, u64) {
+    (header.msg_name as u64, header.msg_namelen as u64)
+}
+
+fn read_struct<T>(memory: &File, addr: u64) -> std::io::Result<T> {
+    let mut buf = vec![0u8; size_of::<T>()];
+    memory.read_exact_at(&mut buf, addr)?;
+    // SAFETY: `buf` holds `size_of::<T>()` bytes, and the structs read
+    // here are plain integers and pointers valid for any bit pattern.
+    Ok(unsafe { std::ptr::read_unaligned(buf.as_ptr().cast()) })
+}
+
+/// An IPv4 or IPv6 `struct sockaddr`.
+pub fn parse_sockaddr(buf: &[u8]) -> Option<SocketAddr> {
+    let family = u16::from_ne_bytes(buf.get(..2)?.try_into().ok()?) as libc::c_int;
+    let port = u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?);
+    match family {
+        libc::AF_INET if buf.len() >= size_of::<libc::sockaddr_in>() => {
+            let ip: [u8; 4] = buf[4..8].try_into().ok()?;
+            Some(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(ip), port)))
+        }
+        libc::AF_INET6 if buf.len() >= size_of::<libc::sockaddr_in6>() => {
+            let flowinfo = u32::from_be_bytes(buf[4..8].try_into().ok()?);
+            let ip: [u8; 16] = buf[8..24].try_into().ok()?;
+            let scope_id = u32::from_ne_bytes(buf[24..28].try_into().ok()?);
+            Some(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), port, flowinfo, scope_id)))
+        }
+        _ => None,
+    }
+}
+
+/// TCP or UDP, from a copy of the process's socket.
+fn socket_protocol(pid: u32, fd: i32) -> Option<Protocol> {
+    let sock = pidfd_getfd(pid as libc::pid_t, fd).ok()?;
+    let mut protocol: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `protocol` and `len` describe one int.
+    Errno::result(unsafe {
+        libc::getsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PROTOCOL,
+            (&mut protocol as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    })
+    .ok()?;
+    match protocol {
+        libc::IPPROTO_TCP => Some(Protocol::Tcp),
+        libc::IPPROTO_UDP => Some(Protocol::Udp),
+        _ => None,
+    }
+}
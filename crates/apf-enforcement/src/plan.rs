@@ -0,0 +1,699 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::{bail, Context, Result};
+use apf_core::types::{NetworkLevel, NetworkRule};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use tracing::{info, warn};
+use crate::bwrap::{self, Bwrap, BwrapOption};
+use crate::cgroup::{self, CgroupNetwork};
+use crate::lan::{self, LanNetwork};
+use crate::landlock::{self, LandlockRuleset};
+use crate::native::{self, NativeSandbox};
+use crate::notify::{ConnectRequest, NotifyChannel};
+use crate::dns::Blocklist;
+use crate::proxy::{EgressPolicy, EgressProxy, EgressRequest};
+use crate::seccomp::{SeccompFilter, SeccompRule};
+use crate::filesystem::AccessMode;
+
+/// The sandboxes this process is waiting for: a token, the sandbox's pid
+/// until it has been reaped, and its cgroup.
+type Waiting = Vec<(u64, Option<u32>, Option<Arc<CgroupNetwork>>)>;
+
+static WAITING: Mutex<Waiting> = Mutex::new(Vec::new());
+static NEXT_TOKEN: AtomicU64 = AtomicU64::new(0);
+
+/// Sends `signal` to every sandbox this process is waiting for, and to
+/// whatever they left running in their cgroups. A launcher that is
+/// interrupted passes the signal on this way, so the sandbox ends first
+/// and its cgroup and nftables rules are still cleaned up.
+pub fn signal_sandboxes(signal: Signal) {
+    let waiting = WAITING.lock().unwrap_or_else(PoisonError::into_inner);
+    for (_, pid, cgroup) in waiting.iter() {
+        if let Some(pid) = pid {
+            let _ = kill(Pid::from_raw(*pid as i32), signal);
+        }
+        if let Some(cgroup) = cgroup {
+            if let Err(err) = cgroup.signal(signal) {
+                warn!("Failed to signal cgroup {}: {:#}", cgroup.cgroup().display(), err);
+            }
+        }
+    }
+}
+
+/// A sandbox's entry in `WAITING`, removed on drop.
+struct WaitingFor(u64);
+
+impl WaitingFor {
+    fn register(pid: u32, cgroup: Option<Arc<CgroupNetwork>>) -> Self {
+        let token = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+        WAITING.lock().unwrap_or_else(PoisonError::into_inner).push((token, Some(pid), cgroup));
+        Self(token)
+    }
+
+    /// The sandbox has been reaped, so its pid may be reused.
+    fn reaped(&self) {
+        let mut waiting = WAITING.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(entry) = waiting.iter_mut().find(|(token, _, _)| *token == self.0) {
+            entry.1 = None;
+        }
+    }
+}
+
+impl Drop for WaitingFor {
+    fn drop(&mut self) {
+        WAITING.lock().unwrap_or_else(PoisonError::into_inner).retain(|(token, _, _)| *token != self.0);
+    }
+}
+
+/// A Linux namespace the sandbox can unshare from the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Namespace {
+    User,
+    Ipc,
+    Pid,
+    Net,
+    Uts,
+    Cgroup,
+}
+
+impl Namespace {
+    pub const ALL: [Namespace; 6] = [
+        Namespace::User,
+        Namespace::Ipc,
+        Namespace::Pid,
+        Namespace::Net,
+        Namespace::Uts,
+        Namespace::Cgroup,
+    ];
+}
+
+/// A mount in the sandbox's otherwise empty root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mount {
+    /// A host path; `optional` skips it when `src` doesn't exist.
+    Bind { src: PathBuf, dest: PathBuf, mode: AccessMode, optional: bool },
+    /// A host path with device nodes usable.
+    DevBind { src: PathBuf, dest: PathBuf, optional: bool },
+    Tmpfs(PathBuf),
+    Proc(PathBuf),
+    /// A minimal `/dev` with only the standard nodes.
+    Dev(PathBuf),
+    Dir(PathBuf),
+    Symlink { target: PathBuf, link: PathBuf },
+}
+
+impl Mount {
+    pub fn bind(path: impl Into<PathBuf>, mode: AccessMode) -> Self {
+        let path = path.into();
+        Mount::Bind { src: path.clone(), dest: path, mode, optional: false }
+    }
+
+    pub fn dest(&self) -> &Path {
+        match self {
+            Mount::Bind { dest, .. } | Mount::DevBind { dest, .. } => dest,
+            Mount::Tmpfs(path) | Mount::Proc(path) | Mount::Dev(path) | Mount::Dir(path) => path,
+            Mount::Symlink { link, .. } => link,
+        }
+    }
+}
+
+impl From<&Mount> for BwrapOption {
+    fn from(mount: &Mount) -> Self {
+        match mount.clone() {
+            Mount::Bind { src, dest, mode: AccessMode::ReadOnly, optional } => BwrapOption::RoBind { src, dest, optional },
+            Mount::Bind { src, dest, mode: AccessMode::ReadWrite, optional } => BwrapOption::Bind { src, dest, optional },
+            Mount::DevBind { src, dest, optional } => BwrapOption::DevBind { src, dest, optional },
+            Mount::Tmpfs(path) => BwrapOption::Tmpfs(path),
+            Mount::Proc(path) => BwrapOption::Proc(path),
+            Mount::Dev(path) => BwrapOption::Dev(path),
+            Mount::Dir(path) => BwrapOption::Dir(path),
+            Mount::Symlink { target, link } => BwrapOption::Symlink { target, link },
+        }
+    }
+}
+
+/// The host's `/usr` and `/etc` read-only, `/bin`, `/lib` and friends as
+/// the host has them (symlinks into `/usr` on merged-usr systems), and
+/// fresh `/proc`, `/dev` and `/tmp`. Enough to start most programs.
+pub fn runtime_mounts() -> Vec<Mount> {
+    let mut mounts = vec![Mount::bind("/usr", AccessMode::ReadOnly), Mount::bind("/etc", AccessMode::ReadOnly)];
+    for dir in ["/bin", "/sbin", "/lib", "/lib32", "/lib64"] {
+        match std::fs::read_link(dir) {
+            Ok(target) => mounts.push(Mount::Symlink { target, link: dir.into() }),
+            Err(_) if Path::new(dir).is_dir() => mounts.push(Mount::bind(dir, AccessMode::ReadOnly)),
+            Err(_) => {}
+        }
+    }
+    mounts.extend([Mount::Proc("/proc".into()), Mount::Dev("/dev".into()), Mount::Tmpfs("/tmp".into())]);
+    mounts
+}
+
+/// Anything that shapes the sandbox an app runs in.
+pub trait PlanContributor {
+    fn contribute(&self, plan: &mut SandboxPlan);
+}
+
+/// The one sandbox an app is launched into, composed from what every
+/// backend contributes. Starts with every namespace unshared and nothing
+/// mounted; backends share namespaces back and add mounts, environment
+/// changes and seccomp rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SandboxPlan {
+    shared: BTreeSet<Namespace>,
+    mounts: Vec<Mount>,
+    clear_env: bool,
+    /// `None` unsets the variable.
+    env: BTreeMap<OsString, Option<OsString>>,
+    new_session: bool,
+    seccomp: Vec<SeccompRule>,
+    landlock: Option<LandlockRuleset>,
+    lan_only: bool,
+    cgroup_network: Option<NetworkLevel>,
+    cgroup_rules: Vec<NetworkRule>,
+    prompt_connect: bool,
+    egress_proxy: Option<Vec<SocketAddr>>,
+    dns_blocklist: Blocklist,
+}
+
+impl SandboxPlan {
+    pub fn new() -> Self {
+        Self {
+            shared: BTreeSet::new(),
+            mounts: Vec::new(),
+            clear_env: false,
+            env: BTreeMap::new(),
+            new_session: false,
+            seccomp: Vec::new(),
+            landlock: None,
+            lan_only: false,
+            cgroup_network: None,
+            cgroup_rules: Vec::new(),
+            prompt_connect: false,
+            egress_proxy: None,
+            dns_blocklist: Blocklist::default(),
+        }
+    }
+
+    pub fn from_contributors(contributors: &[&dyn PlanContributor]) -> Self {
+        let mut plan = Self::new();
+        for contributor in contributors {
+            plan.contribute(*contributor);
+        }
+        plan
+    }
+
+    pub fn contribute(&mut self, contributor: &dyn PlanContributor) -> &mut Self {
+        contributor.contribute(self);
+        self
+    }
+
+    /// Keeps the host's namespace. Any one contributor sharing it is enough.
+    pub fn share(&mut self, namespace: Namespace) -> &mut Self {
+        self.shared.insert(namespace);
+        self
+    }
+
+    pub fn is_unshared(&self, namespace: Namespace) -> bool {
+        !self.shared.contains(&namespace)
+    }
+
+    /// Adds a mount. Adding the same mount twice is a no-op, and a later
+    /// mount over the same destination wins.
+    pub fn mount(&mut self, mount: Mount) -> &mut Self {
+        if !self.mounts.contains(&mount) {
+            self.mounts.push(mount);
+        }
+        self
+    }
+
+    /// The mounts in the order they are made: parents before the paths
+    /// inside them, otherwise in the order they were added, so a tmpfs
+    /// contributed late can't hide an earlier bind below it.
+    pub fn mounts(&self) -> Vec<&Mount> {
+        let mut mounts: Vec<&Mount> = self.mounts.iter().collect();
+        mounts.sort_by_key(|mount| mount.dest().components().count());
+        mounts
+    }
+
+    pub fn clear_env(&mut self) -> &mut Self {
+        self.clear_env = true;
+        self
+    }
+
+    pub fn setenv(&mut self, name: impl Into<OsString>, value: impl Into<OsString>) -> &mut Self {
+        self.env.insert(name.into(), Some(value.into()));
+        self
+    }
+
+    pub fn unsetenv(&mut self, name: impl Into<OsString>) -> &mut Self {
+        self.env.insert(name.into(), None);
+        self
+    }
+
+    pub fn clears_env(&self) -> bool {
+        self.clear_env
+    }
+
+    /// Variables to set, or to unset where the value is `None`.
+    pub fn env(&self) -> &BTreeMap<OsString, Option<OsString>> {
+        &self.env
+    }
+
+    pub fn new_session(&mut self) -> &mut Self {
+        self.new_session = true;
+        self
+    }
+
+    pub fn starts_new_session(&self) -> bool {
+        self.new_session
+    }
+
+    pub fn seccomp(&mut self, rule: SeccompRule) -> &mut Self {
+        self.seccomp.push(rule);
+        self
+    }
+
+    pub fn seccomp_rules(&self) -> &[SeccompRule] {
+        &self.seccomp
+    }
+
+    /// The seccomp rules compiled into one filter, if there are any.
+    pub fn seccomp_filter(&self) -> Result<Option<SeccompFilter>> {
+        if self.seccomp.is_empty() {
+            return Ok(None);
+        }
+        SeccompFilter::compile(&self.seccomp).map(Some)
+    }
+
+    /// Confines the sandboxed process with Landlock as well, on top of
+    /// whatever the runtime does. Rulesets from several contributors merge.
+    pub fn landlock(&mut self, ruleset: LandlockRuleset) -> &mut Self {
+        match &mut self.landlock {
+            Some(existing) => existing.merge(&ruleset),
+            None => self.landlock = Some(ruleset),
+        }
+        self
+    }
+
+    pub fn landlock_ruleset(&self) -> Option<&LandlockRuleset> {
+        self.landlock.as_ref()
+    }
+
+    /// Starts the sandbox in a network namespace of its own whose
+    /// nftables rules only let traffic out to the LAN. The runtimes join
+    /// that namespace instead of the host's, so `Net` counts as shared.
+    pub fn lan_only(&mut self) -> &mut Self {
+        self.lan_only = true;
+        self.share(Namespace::Net)
+    }
+
+    pub fn is_lan_only(&self) -> bool {
+        self.lan_only
+    }
+
+    /// Whether the sandbox gets a network namespace, and so a loopback, of
+    /// its own rather than the host's.
+    pub fn has_own_network(&self) -> bool {
+        self.is_unshared(Namespace::Net) || self.lan_only || self.egress_proxy.is_some()
+    }
+
+    /// Confines the sandbox's sockets to `level` and the destinations of
+    /// `rules` with nftables rules matching a cgroup it is started in, for
+    /// apps that keep the host's network namespace.
+    pub fn cgroup_network(&mut self, level: NetworkLevel, rules: Vec<NetworkRule>) -> &mut Self {
+        self.cgroup_network = Some(level);
+        self.cgroup_rules = rules;
+        self
+    }
+
+    pub fn cgroup_network_level(&self) -> Option<&NetworkLevel> {
+        self.cgroup_network.as_ref()
+    }
+
+    pub fn cgroup_network_rules(&self) -> &[NetworkRule] {
+        &self.cgroup_rules
+    }
+
+    /// Suspends the sandbox's syscalls that reach a destination, so the
+    /// launcher's supervisor can ask about each new one at run time.
+    pub fn prompt_connect(&mut self) -> &mut Self {
+        self.prompt_connect = true;
+        self
+    }
+
+    pub fn prompts_connect(&self) -> bool {
+        self.prompt_connect
+    }
+
+    /// Starts the sandbox in a network namespace with only loopback, where
+    /// the egress proxy is its one way out, relaying DNS to `resolvers`.
+    /// The runtimes join that namespace, so `Net` counts as shared.
+    pub fn proxy_egress(&mut self, resolvers: Vec<SocketAddr>) -> &mut Self {
+        self.egress_proxy = Some(resolvers);
+        self.share(Namespace::Net)
+    }
+
+    pub fn proxies_egress(&self) -> bool {
+        self.egress_proxy.is_some()
+    }
+
+    /// The resolvers the egress proxy's DNS stub relays to.
+    pub fn egress_resolvers(&self) -> Option<&[SocketAddr]> {
+        self.egress_proxy.as_deref()
+    }
+
+    /// Names the egress proxy refuses to resolve or connect to. Blocklists
+    /// from several contributors merge.
+    pub fn block_domains(&mut self, blocklist: &Blocklist) -> &mut Self {
+        self.dns_blocklist.merge(blocklist);
+        self
+    }
+
+    pub fn dns_blocklist(&self) -> &Blocklist {
+        &self.dns_blocklist
+    }
+
+    pub(crate) fn apply_env(&self, cmd: &mut Command) {
+        if self.clear_env {
+            cmd.env_clear();
+        }
+        for (name, value) in &self.env {
+            match value {
+                Some(value) => cmd.env(name, value),
+                None => cmd.env_remove(name),
+            };
+        }
+    }
+
+    /// The plan as one bwrap invocation.
+    pub fn bwrap(&self) -> Bwrap {
+        let mut bwrap = Bwrap::new();
+        if self.shared.iter().all(|ns| *ns == Namespace::Net) {
+            bwrap.unshare_all();
+            if !self.is_unshared(Namespace::Net) {
+                bwrap.share_net();
+            }
+        } else {
+            for namespace in Namespace::ALL.into_iter().filter(|ns| self.is_unshared(*ns)) {
+                bwrap.option(BwrapOption::Unshare(namespace));
+            }
+        }
+        bwrap.die_with_parent();
+        if self.new_session {
+            bwrap.new_session();
+        }
+        for mount in self.mounts() {
+            bwrap.option(mount.into());
+        }
+        if self.clear_env {
+            bwrap.option(BwrapOption::ClearEnv);
+        }
+        for (name, value) in &self.env {
+            match value {
+                Some(value) => bwrap.setenv(name.clone(), value.clone()),
+                None => bwrap.unsetenv(name.clone()),
+            };
+        }
+        bwrap
+    }
+
+    /// A `Command` running `command` in the sandbox, through bwrap when
+    /// the host has it and the built-in launcher otherwise.
+    pub fn command<S: AsRef<OsStr>>(&self, command: &[S]) -> Result<Command> {
+        self.command_with(self.runtime(), command)
+    }
+
+    /// The runtime `command` uses. bwrap can't apply Landlock, so a plan
+    /// with a Landlock ruleset prefers the built-in launcher. So does one
+    /// that prompts for connections: the notification filter is installed
+    /// with no-new-privs before bwrap execs, which a setuid bwrap can't
+    /// run under.
+    pub fn runtime(&self) -> SandboxRuntime {
+        match SandboxRuntime::detect() {
+            SandboxRuntime::Bwrap if (self.landlock.is_some() || self.prompt_connect) && native::available() => {
+                SandboxRuntime::Native
+            }
+            runtime => runtime,
+        }
+    }
+
+    pub fn command_with<S: AsRef<OsStr>>(&self, runtime: SandboxRuntime, command: &[S]) -> Result<Command> {
+        if self.prompt_connect {
+            bail!("Connection prompts need a supervisor; use supervised_command_with or launch_supervised");
+        }
+        self.command_in(runtime, command, self.create_network(runtime)?, self.create_cgroup()?, None)
+    }
+
+    /// The command and the channel its supervisor comes through, for plans
+    /// that prompt for connections. Spawn the command, then `receive` the
+    /// supervisor and run it while the command does.
+    pub fn supervised_command_with<S: AsRef<OsStr>>(
+        &self,
+        runtime: SandboxRuntime,
+        command: &[S],
+    ) -> Result<(Command, NotifyChannel)> {
+        let channel = NotifyChannel::new()?;
+        let cmd = self.command_in(runtime, command, self.create_network(runtime)?, self.create_cgroup()?, Some(&channel))?;
+        Ok((cmd, channel))
+    }
+
+    /// The command and the egress proxy answering it, for plans that proxy
+    /// egress. Keep the proxy for as long as the command runs, and tell
+    /// `policy` the pid of the command once it is spawned.
+    pub fn proxied_command_with<S: AsRef<OsStr>>(
+        &self,
+        runtime: SandboxRuntime,
+        command: &[S],
+        policy: Arc<dyn EgressPolicy>,
+    ) -> Result<(Command, EgressProxy)> {
+        self.proxied_command_in(runtime, command, self.create_cgroup()?, policy)
+    }
+
+    fn proxied_command_in<S: AsRef<OsStr>>(
+        &self,
+        runtime: SandboxRuntime,
+        command: &[S],
+        cgroup: Option<Arc<CgroupNetwork>>,
+        policy: Arc<dyn EgressPolicy>,
+    ) -> Result<(Command, EgressProxy)> {
+        let Some(resolvers) = &self.egress_proxy else {
+            bail!("The plan doesn't proxy egress");
+        };
+        if self.prompt_connect {
+            bail!("Connection prompts and the egress proxy can't be combined");
+        }
+        if runtime == SandboxRuntime::Landlock {
+            bail!("The egress proxy needs a runtime that can create namespaces");
+        }
+        let network = LanNetwork::loopback_only()?;
+        let proxy = EgressProxy::start(&network, resolvers, &self.dns_blocklist, policy)?;
+        let cmd = self.command_in(runtime, command, Some(network), cgroup, None)?;
+        Ok((cmd, proxy))
+    }
+
+    /// The LAN-only namespace the runtime joins, for plans that have one.
+    fn create_network(&self, runtime: SandboxRuntime) -> Result<Option<LanNetwork>> {
+        if self.egress_proxy.is_some() {
+            bail!("The egress proxy needs a policy; use proxied_command_with or launch_proxied");
+        }
+        if !self.lan_only {
+            return Ok(None);
+        }
+        if runtime == SandboxRuntime::Landlock {
+            bail!("LAN-only networking needs a runtime that can create namespaces");
+        }
+        LanNetwork::create().map(Some)
+    }
+
+    fn create_cgroup(&self) -> Result<Option<Arc<CgroupNetwork>>> {
+        self.cgroup_network.as_ref()
+            .map(|level| CgroupNetwork::create(level, &self.cgroup_rules).map(Arc::new))
+            .transpose()
+    }
+
+    /// The command, joining `network` and started in `cgroup` when there
+    /// are those, and handing its connections to `notify`'s supervisor
+    /// when there is that. The command holds on to the network and the
+    /// cgroup; the cgroup is cleaned up once it and the processes in it are
+    /// gone.
+    fn command_in<S: AsRef<OsStr>>(
+        &self,
+        runtime: SandboxRuntime,
+        command: &[S],
+        network: Option<LanNetwork>,
+        mut cgroup: Option<Arc<CgroupNetwork>>,
+        notify: Option<&NotifyChannel>,
+    ) -> Result<Command> {
+        let mut cmd = match runtime {
+            SandboxRuntime::Bwrap => {
+                if self.landlock.is_some() {
+                    warn!("Landlock rules are not applied under bwrap");
+                }
+                let mut bwrap = self.bwrap();
+                if let Some(filter) = self.seccomp_filter()? {
+                    bwrap.seccomp(&filter);
+                }
+                let mut cmd = bwrap.command(command)?;
+                if let Some(network) = network {
+                    // The closure owns the network, keeping it up for as
+                    // long as the command.
+                    // SAFETY: `join` only makes syscalls.
+                    unsafe {
+                        cmd.pre_exec(move || {
+                            let (userns, netns) = network.namespace_fds();
+                            lan::join(userns, netns).map_err(std::io::Error::from)
+                        });
+                    }
+                }
+                cmd
+            }
+            SandboxRuntime::Native => {
+                let mut sandbox = NativeSandbox::new(self)?;
+                if let Some(network) = network {
+                    sandbox = sandbox.joining(network);
+                }
+                // The built-in launcher enters the cgroup before it
+                // unshares anything.
+                if let Some(cgroup) = cgroup.take() {
+                    sandbox = sandbox.in_cgroup(cgroup);
+                }
+                sandbox.command(self, command)?
+            }
+            SandboxRuntime::Landlock => landlock::command(self, command)?,
+        };
+        if let Some(cgroup) = cgroup {
+            // Writing to the open cgroup.procs is allowed under Landlock
+            // and the seccomp filter, so the order doesn't matter here.
+            // SAFETY: `enter` only makes a syscall.
+            unsafe {
+                cmd.pre_exec(move || cgroup::enter(cgroup.procs_fd()).map_err(std::io::Error::from));
+            }
+        }
+        if let Some(channel) = notify {
+            channel.apply(&mut cmd);
+        }
+        Ok(cmd)
+    }
+
+    /// Runs `command` in the sandbox and waits for it, and for anything it
+    /// left running in the sandbox's cgroup. With connection prompts or the
+    /// egress proxy there is no one to ask, so every new destination is
+    /// refused.
+    pub fn launch<S: AsRef<OsStr>>(&self, command: &[S]) -> Result<ExitStatus> {
+        if self.proxies_egress() {
+            return self.launch_proxied(command, |request: &EgressRequest| {
+                warn!("No one to ask about {}:{} through the proxy; refused", request.host, request.port);
+                false
+            });
+        }
+        self.launch_supervised(command, |request: &ConnectRequest| {
+            warn!("No one to ask about {} to {}; refused", request.syscall, request.destination);
+            false
+        })
+    }
+
+    /// Like `launch`, asking `decide` whether the sandbox may reach each
+    /// new destination when the plan prompts for connections.
+    pub fn launch_supervised<S, F>(&self, command: &[S], decide: F) -> Result<ExitStatus>
+    where
+        S: AsRef<OsStr>,
+        F: FnMut(&ConnectRequest) -> bool + Send + 'static,
+    {
+        let runtime = self.runtime();
+        info!("Launching sandbox with {:?}: {:?}", runtime, self.bwrap().option_args());
+        let cgroup = self.create_cgroup()?;
+        let channel = self.prompt_connect.then(NotifyChannel::new).transpose()?;
+        let mut child = self.command_in(runtime, command, self.create_network(runtime)?, cgroup.clone(), channel.as_ref())?
+            .spawn()
+            .context("Failed to launch sandbox")?;
+        let supervisor = match channel.map(NotifyChannel::receive).transpose() {
+            Ok(supervisor) => supervisor,
+            Err(err) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(err);
+            }
+        };
+        let supervisor = supervisor.map(|mut supervisor| std::thread::spawn(move || supervisor.run(decide)));
+
+        let status = Self::wait(child, cgroup)?;
+        if let Some(supervisor) = supervisor {
+            match supervisor.join() {
+                Ok(result) => result?,
+                Err(_) => bail!("Connection supervisor panicked"),
+            }
+        }
+        Ok(status)
+    }
+
+    /// Like `launch`, for plans that proxy egress, asking `policy` whether
+    /// the sandbox may reach each host it connects to through the proxy.
+    pub fn launch_proxied<S, P>(&self, command: &[S], policy: P) -> Result<ExitStatus>
+    where
+        S: AsRef<OsStr>,
+        P: EgressPolicy + 'static,
+    {
+        let runtime = self.runtime();
+        info!("Launching sandbox behind the egress proxy with {:?}: {:?}", runtime, self.bwrap().option_args());
+        let cgroup = self.create_cgroup()?;
+        let policy: Arc<dyn EgressPolicy> = Arc::new(policy);
+        let (mut cmd, proxy) = self.proxied_command_in(runtime, command, cgroup.clone(), policy.clone())?;
+        let child = cmd.spawn()
+            .context("Failed to launch sandbox")?;
+        policy.sandbox_started(child.id());
+        let status = Self::wait(child, cgroup);
+        drop(proxy);
+        status
+    }
+
+    /// Waits for the sandbox, and for anything it left running in its
+    /// cgroup. Meanwhile `signal_sandboxes` reaches both.
+    fn wait(mut child: Child, cgroup: Option<Arc<CgroupNetwork>>) -> Result<ExitStatus> {
+        let waiting = WaitingFor::register(child.id(), cgroup.clone());
+        let status = child.wait().context("Failed to wait for sandbox")?;
+        waiting.reaped();
+        if let Some(cgroup) = cgroup {
+            if cgroup.is_populated()? {
+                info!("Waiting for the processes left in cgroup {}", cgroup.cgroup().display());
+                cgroup.wait_empty()?;
+            }
+        }
+        Ok(status)
+    }
+}
+
+/// What sets the sandbox up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SandboxRuntime {
+    Bwrap,
+    /// The built-in launcher in `native`, for hosts without bwrap.
+    Native,
+    /// Only the plan's Landlock ruleset, for hosts where neither of the
+    /// others can create namespaces.
+    Landlock,
+}
+
+impl SandboxRuntime {
+    pub fn detect() -> Self {
+        if bwrap::is_available() {
+            SandboxRuntime::Bwrap
+        } else if native::available() {
+            SandboxRuntime::Native
+        } else {
+            SandboxRuntime::Landlock
+        }
+    }
+}
+
+impl Default for SandboxPlan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,492 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use apf_core::types::{Cidr, HostPattern, NetworkLevel, NetworkRule, PermissionType, PortRange, Protocol};
+use nix::errno::Errno;
+use nix::libc;
+use tracing::{debug, info, warn};
+use crate::dns::{self, Blocklist, DnsQuery, DnsStub, ResolvedNames};
+use crate::lan::LanNetwork;
+use crate::plan::{PlanContributor, SandboxPlan};
+
+/// Where the proxy listens inside the sandbox.
+pub const PROXY_IP: Ipv4Addr = Ipv4Addr::LOCALHOST;
+pub const HTTP_PROXY_PORT: u16 = 3128;
+pub const SOCKS_PORT: u16 = 1080;
+
+/// Longest request head the HTTP proxy reads.
+const HEAD_MAX: usize = 16 * 1024;
+
+/// How long the proxy tries to reach a destination.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A host the sandbox asked the proxy to reach.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EgressRequest {
+    /// The name the app asked for, or the one the address it gave was
+    /// looked up by through the DNS stub; the address itself otherwise.
+    pub host: String,
+    pub port: u16,
+    /// `http` or `socks5`, whichever the app asked through.
+    pub via: &'static str,
+}
+
+impl EgressRequest {
+    /// The single destination as a rule: a host name where there is one,
+    /// the port, and TCP.
+    pub fn rule(&self) -> NetworkRule {
+        let host = match self.host.parse::<IpAddr>() {
+            Ok(ip) => HostPattern::Cidr(Cidr::host(ip)),
+            Err(_) => HostPattern::Domain(self.host.trim_end_matches('.').to_ascii_lowercase()),
+        };
+        NetworkRule {
+            host,
+            ports: Some(PortRange { start: self.port, end: self.port }),
+            protocol: Some(Protocol::Tcp),
+        }
+    }
+
+    pub fn permission(&self) -> PermissionType {
+        PermissionType::Network(NetworkLevel::Rule(self.rule()))
+    }
+}
+
+/// What the egress proxy asks about the sandbox's traffic. Closures
+/// deciding about hosts are policies that ignore DNS.
+pub trait EgressPolicy: Send + Sync {
+    /// Whether the sandbox may reach a host; asked on every connection to
+    /// a host not blocked outright.
+    fn allows(&self, request: &EgressRequest) -> bool;
+
+    /// Told of every query the DNS stub takes, blocked ones included.
+    fn dns_query(&self, _query: &DnsQuery) {}
+
+    /// Told the pid of the sandbox once it has been started, before any of
+    /// its traffic reaches the proxy.
+    fn sandbox_started(&self, _pid: u32) {}
+}
+
+impl<F: Fn(&EgressRequest) -> bool + Send + Sync> EgressPolicy for F {
+    fn allows(&self, request: &EgressRequest) -> bool {
+        self(request)
+    }
+}
+
+/// The variables pointing HTTP clients at the proxy, in the spellings
+/// they look for.
+pub fn proxy_env() -> Vec<(&'static str, String)> {
+    let http = format!("http://{}:{}", PROXY_IP, HTTP_PROXY_PORT);
+    let socks = format!("socks5h://{}:{}", PROXY_IP, SOCKS_PORT);
+    vec![
+        ("http_proxy", http.clone()),
+        ("HTTP_PROXY", http.clone()),
+        ("https_proxy", http.clone()),
+        ("HTTPS_PROXY", http),
+        ("all_proxy", socks.clone()),
+        ("ALL_PROXY", socks),
+        ("no_proxy", "localhost,127.0.0.1,::1".to_string()),
+        ("NO_PROXY", "localhost,127.0.0.1,::1".to_string()),
+    ]
+}
+
+/// Sends the sandbox's traffic through the egress proxy, relaying DNS to
+/// `resolvers`, the host's by default, and refusing blocked names.
+pub struct EgressProxyBackend {
+    resolvers: Vec<SocketAddr>,
+    blocklist: Blocklist,
+}
+
+impl EgressProxyBackend {
+    /// Relaying to the host's resolvers, with the global blocklist.
+    pub fn new() -> Self {
+        let mut backend = Self::with_resolvers(dns::host_resolvers());
+        match Blocklist::load(dns::GLOBAL_BLOCKLIST) {
+            Ok(blocklist) => {
+                backend.block(&blocklist);
+            }
+            Err(err) => warn!("Global DNS blocklist not applied: {:#}", err),
+        }
+        backend
+    }
+
+    pub fn with_resolvers(resolvers: Vec<SocketAddr>) -> Self {
+        Self { resolvers, blocklist: Blocklist::default() }
+    }
+
+    /// Refuses the names in `blocklist` too, such as an app's own list.
+    pub fn block(&mut self, blocklist: &Blocklist) -> &mut Self {
+        self.blocklist.merge(blocklist);
+        self
+    }
+}
+
+impl Default for EgressProxyBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlanContributor for EgressProxyBackend {
+    fn contribute(&self, plan: &mut SandboxPlan) {
+        plan.proxy_egress(self.resolvers.clone()).block_domains(&self.blocklist);
+        for (name, value) in proxy_env() {
+            plan.setenv(name, value);
+        }
+    }
+}
+
+/// An HTTP and SOCKS5 proxy and a DNS stub answering a sandbox whose
+/// network namespace has nothing but loopback, so they are its only way
+/// out. They listen inside the namespace and connect out from the host's,
+/// asking the policy about every host first; unlike rules on addresses,
+/// that sees the names apps reach. Blocked names are neither resolved nor
+/// connected to, and the policy hears of every DNS query.
+///
+/// The DNS stub answers at the resolvers' own addresses, so the sandbox's
+/// copy of the host's resolv.conf keeps working, and remembers what names
+/// resolved to: an app connecting through SOCKS to an address it looked up
+/// is asked about under the name.
+///
+/// Stops accepting when dropped; connections already relayed carry on.
+pub struct EgressProxy {
+    stop: Option<OwnedFd>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// The proxy's listeners and what its connections share.
+struct Listeners {
+    http: TcpListener,
+    socks: TcpListener,
+    dns: Vec<DnsStub>,
+    policy: Arc<dyn EgressPolicy>,
+    names: Arc<ResolvedNames>,
+    blocklist: Arc<Blocklist>,
+}
+
+impl EgressProxy {
+    pub fn start(
+        network: &LanNetwork,
+        resolvers: &[SocketAddr],
+        blocklist: &Blocklist,
+        policy: Arc<dyn EgressPolicy>,
+    ) -> Result<Self> {
+        for resolver in resolvers {
+            if !resolver.ip().is_loopback() {
+                network.add_local_address(resolver.ip())?;
+            }
+        }
+        let mut addrs = vec![
+            (SocketAddr::from((PROXY_IP, HTTP_PROXY_PORT)), libc::SOCK_STREAM),
+            (SocketAddr::from((PROXY_IP, SOCKS_PORT)), libc::SOCK_STREAM),
+        ];
+        addrs.extend(resolvers.iter().map(|resolver| (*resolver, libc::SOCK_DGRAM)));
+        let mut sockets = network.bind_inside(&addrs).context("Failed to start the egress proxy")?.into_iter();
+
+        let names = Arc::new(ResolvedNames::default());
+        let blocklist = Arc::new(blocklist.clone());
+        let observing = policy.clone();
+        let observer: dns::DnsObserver = Arc::new(move |query: &DnsQuery| observing.dns_query(query));
+        let http = TcpListener::from(sockets.next().expect("one socket per address"));
+        let socks = TcpListener::from(sockets.next().expect("one socket per address"));
+        let dns = sockets.zip(resolvers)
+            .map(|(socket, resolver)| {
+                DnsStub::new(UdpSocket::from(socket), *resolver, names.clone())
+                    .blocking(blocklist.clone())
+                    .observed_by(observer.clone())
+            })
+            .collect();
+        let listeners = Listeners { http, socks, dns, policy, names, blocklist };
+
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for both ends.
+        Errno::result(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+        // SAFETY: both ends are new descriptors we own.
+        let (stopped, stop) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let thread = std::thread::Builder::new()
+            .name("apf-egress-proxy".into())
+            .spawn(move || listeners.run(stopped))?;
+        info!("Egress proxy listening on {}:{} (HTTP) and {}:{} (SOCKS5)", PROXY_IP, HTTP_PROXY_PORT, PROXY_IP, SOCKS_PORT);
+        Ok(Self { stop: Some(stop), thread: Some(thread) })
+    }
+}
+
+impl Drop for EgressProxy {
+    fn drop(&mut self) {
+        // Closing the write end wakes the listener thread with a hangup.
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Listeners {
+    fn run(self, stopped: OwnedFd) {
+        let mut fds = vec![stopped.as_raw_fd(), self.http.as_raw_fd(), self.socks.as_raw_fd()];
+        fds.extend(self.dns.iter().map(|stub| stub.socket().as_raw_fd()));
+        let mut pollfds: Vec<libc::pollfd> = fds.iter().map(|fd| libc::pollfd { fd: *fd, events: libc::POLLIN, revents: 0 }).collect();
+        loop {
+            // SAFETY: `pollfds` is a valid array of its length.
+            match Errno::result(unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1) }) {
+                Ok(_) => {}
+                Err(Errno::EINTR) => continue,
+                Err(err) => {
+                    warn!("Egress proxy stopped: {}", err);
+                    return;
+                }
+            }
+            if pollfds[0].revents != 0 {
+                debug!("Egress proxy stopped");
+                return;
+            }
+            if pollfds[1].revents != 0 {
+                self.accept(&self.http, "http", serve_http);
+            }
+            if pollfds[2].revents != 0 {
+                self.accept(&self.socks, "socks5", serve_socks);
+            }
+            for (stub, pollfd) in self.dns.iter().zip(&pollfds[3..]) {
+                if pollfd.revents != 0 {
+                    if let Err(err) = stub.serve_one() {
+                        warn!("Failed to take a DNS query from the sandbox: {}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Hands a new connection to `serve` on a thread of its own.
+    fn accept(&self, listener: &TcpListener, via: &'static str, serve: fn(TcpStream, &Destinations) -> io::Result<()>) {
+        let client = match listener.accept() {
+            Ok((client, _)) => client,
+            Err(err) => {
+                warn!("Egress proxy failed to accept a {} connection: {}", via, err);
+                return;
+            }
+        };
+        let destinations = Destinations {
+            policy: self.policy.clone(),
+            names: self.names.clone(),
+            blocklist: self.blocklist.clone(),
+        };
+        let spawned = std::thread::Builder::new()
+            .name("apf-egress-conn".into())
+            .spawn(move || {
+                if let Err(err) = serve(client, &destinations) {
+                    debug!("{} connection through the egress proxy ended: {}", via, err);
+                }
+            });
+        if let Err(err) = spawned {
+            warn!("Egress proxy cannot serve a {} connection: {}", via, err);
+        }
+    }
+}
+
+/// Decides about and connects to the hosts connections ask for.
+struct Destinations {
+    policy: Arc<dyn EgressPolicy>,
+    names: Arc<ResolvedNames>,
+    blocklist: Arc<Blocklist>,
+}
+
+impl Destinations {
+    /// A connection to `host`, if it isn't blocked and the policy allows
+    /// it. `None` when it isn't.
+    fn open(&self, host: &str, port: u16, via: &'static str) -> Option<io::Result<TcpStream>> {
+        let address = host.parse::<IpAddr>().ok();
+        let name = address.and_then(|address| self.names.name_of(&address)).unwrap_or_else(|| host.to_string());
+        let request = EgressRequest { host: name, port, via };
+        if self.blocklist.contains(&request.host) {
+            info!(host = %request.host, port, via, "Egress to a blocked host refused");
+            return None;
+        }
+        let allowed = self.policy.allows(&request);
+        info!(host = %request.host, port, via, allowed, "Egress through the proxy");
+        if !allowed {
+            return None;
+        }
+        // An address the app gave is dialled as given, even when it is
+        // asked about by name.
+        Some(match address {
+            Some(address) => TcpStream::connect_timeout(&SocketAddr::new(address, port), CONNECT_TIMEOUT),
+            None => connect(host, port),
+        })
+    }
+}
+
+fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut last = io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", host));
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last = err,
+        }
+    }
+    Err(last)
+}
+
+/// Copies both ways until each side is done sending.
+fn relay(client: TcpStream, upstream: TcpStream) -> io::Result<()> {
+    let mut to_upstream = (client.try_clone()?, upstream.try_clone()?);
+    std::thread::Builder::new()
+        .name("apf-egress-relay".into())
+        .spawn(move || {
+            let _ = io::copy(&mut to_upstream.0, &mut to_upstream.1);
+            let _ = to_upstream.1.shutdown(Shutdown::Write);
+        })?;
+    let copied = io::copy(&mut &upstream, &mut &client);
+    let _ = client.shutdown(Shutdown::Write);
+    copied.map(drop)
+}
+
+/// `host:port`, the host in brackets if it is an IPv6 address.
+fn parse_authority(authority: &str, default_port: Option<u16>) -> Option<(String, u16)> {
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        let port = match rest.strip_prefix(':') {
+            Some(port) => port.parse().ok()?,
+            None if rest.is_empty() => default_port?,
+            None => return None,
+        };
+        return Some((host.to_string(), port));
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some((host.to_string(), port.parse().ok()?)),
+        None => Some((authority.to_string(), default_port?)),
+    }
+}
+
+/// Reads up to the end of a request head; returns the head and anything
+/// read past it.
+fn read_head(client: &mut TcpStream) -> io::Result<(String, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            return Ok((String::from_utf8_lossy(&buf).into_owned(), rest));
+        }
+        if buf.len() > HEAD_MAX {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request head too long"));
+        }
+        let len = client.read(&mut chunk)?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+}
+
+/// `CONNECT host:port` tunnels, and plain HTTP requests for absolute URLs
+/// forwarded one per connection.
+fn serve_http(mut client: TcpStream, destinations: &Destinations) -> io::Result<()> {
+    let (head, rest) = read_head(&mut client)?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default(), parts.next().unwrap_or("HTTP/1.1"));
+
+    let (authority, forwarded) = if method.eq_ignore_ascii_case("CONNECT") {
+        (parse_authority(target, None), None)
+    } else if let Some(url) = target.strip_prefix("http://") {
+        let (authority, path) = match url.find('/') {
+            Some(slash) => url.split_at(slash),
+            None => (url, "/"),
+        };
+        // The origin server gets the path, and the connection is closed
+        // after one response so every request is decided on its own.
+        let mut forwarded = format!("{} {} {}\r\n", method, path, version);
+        for header in lines.filter(|line| !line.is_empty()) {
+            let name = header.split(':').next().unwrap_or_default().trim().to_ascii_lowercase();
+            if !matches!(name.as_str(), "connection" | "proxy-connection" | "keep-alive" | "proxy-authorization") {
+                forwarded.push_str(header);
+                forwarded.push_str("\r\n");
+            }
+        }
+        forwarded.push_str("Connection: close\r\n\r\n");
+        (parse_authority(authority, Some(80)), Some(forwarded))
+    } else {
+        (None, None)
+    };
+    let Some((host, port)) = authority else {
+        return client.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n");
+    };
+
+    let mut upstream = match destinations.open(&host, port, "http") {
+        None => return client.write_all(b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\n"),
+        Some(Err(err)) => {
+            client.write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n")?;
+            return Err(err);
+        }
+        Some(Ok(upstream)) => upstream,
+    };
+    match forwarded {
+        Some(forwarded) => upstream.write_all(forwarded.as_bytes())?,
+        None => client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?,
+    }
+    upstream.write_all(&rest)?;
+    relay(client, upstream)
+}
+
+/// SOCKS5 replies.
+const SOCKS_SUCCEEDED: u8 = 0;
+const SOCKS_NOT_ALLOWED: u8 = 2;
+const SOCKS_HOST_UNREACHABLE: u8 = 4;
+const SOCKS_COMMAND_UNSUPPORTED: u8 = 7;
+const SOCKS_ADDRESS_UNSUPPORTED: u8 = 8;
+
+/// SOCKS5 `CONNECT` without authentication.
+fn serve_socks(mut client: TcpStream, destinations: &Destinations) -> io::Result<()> {
+    let mut greeting = [0u8; 2];
+    client.read_exact(&mut greeting)?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    client.read_exact(&mut methods)?;
+    if greeting[0] != 5 || !methods.contains(&0) {
+        return client.write_all(&[5, 0xff]);
+    }
+    client.write_all(&[5, 0])?;
+
+    let reply = |client: &mut TcpStream, code: u8| client.write_all(&[5, code, 0, 1, 0, 0, 0, 0, 0, 0]);
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request)?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip)?;
+            IpAddr::from(ip).to_string()
+        }
+        3 => {
+            let mut len = [0u8];
+            client.read_exact(&mut len)?;
+            let mut name = vec![0u8; len[0] as usize];
+            client.read_exact(&mut name)?;
+            String::from_utf8_lossy(&name).into_owned()
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            client.read_exact(&mut ip)?;
+            IpAddr::from(ip).to_string()
+        }
+        _ => return reply(&mut client, SOCKS_ADDRESS_UNSUPPORTED),
+    };
+    let mut port = [0u8; 2];
+    client.read_exact(&mut port)?;
+    if request[1] != 1 {
+        return reply(&mut client, SOCKS_COMMAND_UNSUPPORTED);
+    }
+
+    let upstream = match destinations.open(&host, u16::from_be_bytes(port), "socks5") {
+        None => return reply(&mut client, SOCKS_NOT_ALLOWED),
+        Some(Err(err)) => {
+            reply(&mut client, SOCKS_HOST_UNREACHABLE)?;
+            return Err(err);
+        }
+        Some(Ok(upstream)) => upstream,
+    };
+    reply(&mut client, SOCKS_SUCCEEDED)?;
+    relay(client, upstream)
+}
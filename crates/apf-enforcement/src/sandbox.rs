@@ -1,7 +1,7 @@
-
-use std::process::Command;
-use anyhow::{Result, Context};
+use anyhow::Result;
 use tracing::{info, error};
+use crate::plan::{runtime_mounts, PlanContributor, SandboxPlan};
+use crate::seccomp::hardened_rules;
 
 pub struct SandboxBackend;
 
@@ -11,16 +11,7 @@ impl SandboxBackend {
     }
 
     pub fn enforce_sandbox_policy(&self, command: &[String]) -> Result<()> {
-        let args = vec!["--unshare-all", "--die-with-parent"];
-        info!("Launching sandbox: {:?}", args);
-        let mut sb_cmd = Command::new("bubblewrap");
-        for arg in &args {
-            sb_cmd.arg(arg);
-        }
-        for c in command {
-            sb_cmd.arg(c);
-        }
-        let status = sb_cmd.status().context("Failed to launch sandbox")?;
+        let status = SandboxPlan::from_contributors(&[self]).launch(command)?;
         if !status.success() {
             error!("Sandbox exited with failure: {:?}", status);
             return Err(anyhow::anyhow!("Sandbox failed"));
@@ -29,3 +20,17 @@ impl SandboxBackend {
         Ok(())
     }
 }
+
+/// The base every app sandbox starts from: its own session, the system
+/// runtime over an otherwise empty root and the hardened seccomp deny list.
+impl PlanContributor for SandboxBackend {
+    fn contribute(&self, plan: &mut SandboxPlan) {
+        plan.new_session();
+        for mount in runtime_mounts() {
+            plan.mount(mount);
+        }
+        for rule in hardened_rules() {
+            plan.seccomp(rule);
+        }
+    }
+}
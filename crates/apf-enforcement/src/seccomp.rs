@@ -0,0 +1,348 @@
+use std::fs::File;
+use std::os::fd::RawFd;
+use anyhow::{bail, Result};
+use nix::errno::Errno;
+use nix::libc;
+use tracing::warn;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Syscall numbers at and above this are the x32 ABI on x86_64, which
+/// would otherwise reach the same syscalls under other numbers.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Offsets into `struct seccomp_data`.
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+const DATA_ARGS: u32 = 16;
+
+/// What a filtered syscall does instead of running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeccompAction {
+    Allow,
+    Errno(i32),
+    Log,
+    KillProcess,
+    /// Suspends the caller until the supervisor holding the filter's
+    /// listener answers for it.
+    Notify,
+}
+
+impl SeccompAction {
+    fn ret(self) -> u32 {
+        match self {
+            SeccompAction::Allow => libc::SECCOMP_RET_ALLOW,
+            SeccompAction::Errno(errno) => libc::SECCOMP_RET_ERRNO | (errno as u32 & libc::SECCOMP_RET_DATA),
+            SeccompAction::Log => libc::SECCOMP_RET_LOG,
+            SeccompAction::KillProcess => libc::SECCOMP_RET_KILL_PROCESS,
+            SeccompAction::Notify => libc::SECCOMP_RET_USER_NOTIF,
+        }
+    }
+}
+
+/// How much of a syscall argument a rule compares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgWidth {
+    /// The low 32 bits, for arguments the kernel takes as an `int`: it
+    /// ignores the high bits, so a rule must too or setting them gets past
+    /// it.
+    U32,
+    /// All 64 bits, for pointer and `long` arguments.
+    U64,
+}
+
+/// A syscall argument that must equal `value` for a rule to apply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeccompArg {
+    pub index: u8,
+    pub value: u64,
+    pub width: ArgWidth,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeccompRule {
+    pub syscall: String,
+    pub action: SeccompAction,
+    pub arg: Option<SeccompArg>,
+}
+
+impl SeccompRule {
+    pub fn new(syscall: impl Into<String>, action: SeccompAction) -> Self {
+        Self { syscall: syscall.into(), action, arg: None }
+    }
+
+    /// Fails the syscall with `EPERM`.
+    pub fn deny(syscall: impl Into<String>) -> Self {
+        Self::new(syscall, SeccompAction::Errno(libc::EPERM))
+    }
+
+    /// Applies only when `int` argument `index` equals `value`.
+    pub fn when_arg(mut self, index: u8, value: u32) -> Self {
+        self.arg = Some(SeccompArg { index, value: value.into(), width: ArgWidth::U32 });
+        self
+    }
+
+    /// Applies only when 64-bit argument `index` equals `value`.
+    pub fn when_arg64(mut self, index: u8, value: u64) -> Self {
+        self.arg = Some(SeccompArg { index, value, width: ArgWidth::U64 });
+        self
+    }
+}
+
+/// Syscalls no desktop app needs and that widen the kernel's attack
+/// surface or reach past the sandbox: tracing other processes, kernel
+/// keyrings, eBPF, perf, userfaultfd, mounts, module and kexec loading,
+/// and changing system state such as the clock or swap.
+pub const HARDENED_DENY: &[&str] = &[
+    "ptrace",
+    "process_vm_readv",
+    "process_vm_writev",
+    "keyctl",
+    "add_key",
+    "request_key",
+    "bpf",
+    "perf_event_open",
+    "userfaultfd",
+    "mount",
+    "umount2",
+    "pivot_root",
+    "move_mount",
+    "open_tree",
+    "fsopen",
+    "fsmount",
+    "fspick",
+    "mount_setattr",
+    "kexec_load",
+    "kexec_file_load",
+    "init_module",
+    "finit_module",
+    "delete_module",
+    "open_by_handle_at",
+    "name_to_handle_at",
+    "swapon",
+    "swapoff",
+    "reboot",
+    "acct",
+    "quotactl",
+    "syslog",
+    "fanotify_init",
+    "lookup_dcookie",
+    "vhangup",
+    "clock_settime",
+    "clock_adjtime",
+    "settimeofday",
+    "adjtimex",
+];
+
+/// The hardened deny list, plus `TIOCSTI`, which pushes input into the
+/// terminal the app was started from.
+pub fn hardened_rules() -> Vec<SeccompRule> {
+    let mut rules: Vec<SeccompRule> = HARDENED_DENY.iter().map(|syscall| SeccompRule::deny(*syscall)).collect();
+    // The request type differs between libcs.
+    #[allow(clippy::unnecessary_cast)]
+    rules.push(SeccompRule::deny("ioctl").when_arg(1, libc::TIOCSTI as u32));
+    rules
+}
+
+/// The syscall's number on this architecture.
+pub fn syscall_number(name: &str) -> Option<libc::c_long> {
+    let nr = match name {
+        "ptrace" => libc::SYS_ptrace,
+        "process_vm_readv" => libc::SYS_process_vm_readv,
+        "process_vm_writev" => libc::SYS_process_vm_writev,
+        "keyctl" => libc::SYS_keyctl,
+        "add_key" => libc::SYS_add_key,
+        "request_key" => libc::SYS_request_key,
+        "bpf" => libc::SYS_bpf,
+        "perf_event_open" => libc::SYS_perf_event_open,
+        "userfaultfd" => libc::SYS_userfaultfd,
+        "mount" => libc::SYS_mount,
+        "umount2" => libc::SYS_umount2,
+        "pivot_root" => libc::SYS_pivot_root,
+        "move_mount" => libc::SYS_move_mount,
+        "open_tree" => libc::SYS_open_tree,
+        "fsopen" => libc::SYS_fsopen,
+        "fsmount" => libc::SYS_fsmount,
+        "fspick" => libc::SYS_fspick,
+        "mount_setattr" => libc::SYS_mount_setattr,
+        "kexec_load" => libc::SYS_kexec_load,
+        "kexec_file_load" => libc::SYS_kexec_file_load,
+        "init_module" => libc::SYS_init_module,
+        "finit_module" => libc::SYS_finit_module,
+        "delete_module" => libc::SYS_delete_module,
+        "open_by_handle_at" => libc::SYS_open_by_handle_at,
+        "name_to_handle_at" => libc::SYS_name_to_handle_at,
+        "swapon" => libc::SYS_swapon,
+        "swapoff" => libc::SYS_swapoff,
+        "reboot" => libc::SYS_reboot,
+        "acct" => libc::SYS_acct,
+        "quotactl" => libc::SYS_quotactl,
+        "syslog" => libc::SYS_syslog,
+        "fanotify_init" => libc::SYS_fanotify_init,
+        "lookup_dcookie" => libc::SYS_lookup_dcookie,
+        "vhangup" => libc::SYS_vhangup,
+        "clock_settime" => libc::SYS_clock_settime,
+        "clock_adjtime" => libc::SYS_clock_adjtime,
+        "settimeofday" => libc::SYS_settimeofday,
+        "adjtimex" => libc::SYS_adjtimex,
+        "ioctl" => libc::SYS_ioctl,
+        "socket" => libc::SYS_socket,
+        "socketpair" => libc::SYS_socketpair,
+        "connect" => libc::SYS_connect,
+        "sendto" => libc::SYS_sendto,
+        "sendmsg" => libc::SYS_sendmsg,
+        "sendmmsg" => libc::SYS_sendmmsg,
+        "unshare" => libc::SYS_unshare,
+        "setns" => libc::SYS_setns,
+        "personality" => libc::SYS_personality,
+        "io_uring_setup" => libc::SYS_io_uring_setup,
+        _ => return None,
+    };
+    Some(nr)
+}
+
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code: code as u16, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code: code as u16, jt, jf, k }
+}
+
+fn load(offset: u32) -> libc::sock_filter {
+    stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset)
+}
+
+fn ret(action: u32) -> libc::sock_filter {
+    stmt(libc::BPF_RET | libc::BPF_K, action)
+}
+
+/// A compiled seccomp-BPF program. Syscalls no rule matches are allowed;
+/// the first matching rule decides the others.
+#[derive(Debug, Clone)]
+pub struct SeccompFilter {
+    program: Vec<libc::sock_filter>,
+}
+
+impl SeccompFilter {
+    /// Compiles `rules` for this architecture. Rules for syscalls it
+    /// doesn't have are left out.
+    pub fn compile(rules: &[SeccompRule]) -> Result<Self> {
+        let jeq = libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K;
+        let mut program = vec![
+            load(DATA_ARCH),
+            jump(jeq, AUDIT_ARCH, 1, 0),
+            ret(libc::SECCOMP_RET_KILL_PROCESS),
+            load(DATA_NR),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            jump(libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K, X32_SYSCALL_BIT, 0, 1),
+            ret(libc::SECCOMP_RET_KILL_PROCESS),
+        ]);
+
+        for rule in rules {
+            let Some(nr) = syscall_number(&rule.syscall) else {
+                warn!("Unknown syscall {} in seccomp rule, skipped", rule.syscall);
+                continue;
+            };
+            let nr = nr as u32;
+            let action = rule.action.ret();
+            match rule.arg {
+                None => program.extend([jump(jeq, nr, 0, 1), ret(action)]),
+                Some(SeccompArg { index, value, width }) => {
+                    if index > 5 {
+                        bail!("Syscall argument {} out of range in seccomp rule for {}", index, rule.syscall);
+                    }
+                    // Arguments are 64-bit and loaded a 32-bit half at a
+                    // time; `int` ones only have their low half compared.
+                    // Every mismatch lands on reloading the syscall number.
+                    let low = DATA_ARGS + 8 * index as u32;
+                    match width {
+                        ArgWidth::U32 => program.extend([
+                            jump(jeq, nr, 0, 3),
+                            load(low),
+                            jump(jeq, value as u32, 0, 1),
+                            ret(action),
+                            load(DATA_NR),
+                        ]),
+                        ArgWidth::U64 => program.extend([
+                            jump(jeq, nr, 0, 5),
+                            load(low),
+                            jump(jeq, value as u32, 0, 3),
+                            load(low + 4),
+                            jump(jeq, (value >> 32) as u32, 0, 1),
+                            ret(action),
+                            load(DATA_NR),
+                        ]),
+                    }
+                }
+            }
+        }
+        program.push(ret(libc::SECCOMP_RET_ALLOW));
+        if program.len() > libc::BPF_MAXINSNS as usize {
+            bail!("Seccomp filter of {} instructions is too long", program.len());
+        }
+        Ok(Self { program })
+    }
+
+    pub fn len(&self) -> usize {
+        self.program.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.program.is_empty()
+    }
+
+    /// The program as the raw `struct sock_filter` array bwrap reads from
+    /// its `--seccomp` descriptor.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.program.len() * 8);
+        for insn in &self.program {
+            bytes.extend_from_slice(&insn.code.to_ne_bytes());
+            bytes.push(insn.jt);
+            bytes.push(insn.jf);
+            bytes.extend_from_slice(&insn.k.to_ne_bytes());
+        }
+        bytes
+    }
+
+    /// An anonymous file holding `to_bytes`, for `--seccomp`.
+    pub fn to_file(&self) -> Result<File> {
+        crate::bwrap::memfd("apf-seccomp", &self.to_bytes())
+    }
+
+    /// Installs the filter on the calling thread and everything it execs.
+    /// Safe to call between `fork` and `exec`.
+    pub fn install(&self) -> nix::Result<()> {
+        self.set_mode_filter(0).map(drop)
+    }
+
+    /// Installs the filter like `install` and returns the listener that
+    /// receives its `Notify` actions. Safe to call between `fork` and
+    /// `exec`.
+    pub fn install_listener(&self) -> nix::Result<RawFd> {
+        self.set_mode_filter(libc::SECCOMP_FILTER_FLAG_NEW_LISTENER).map(|fd| fd as RawFd)
+    }
+
+    fn set_mode_filter(&self, flags: libc::c_ulong) -> nix::Result<libc::c_long> {
+        let prog = libc::sock_fprog {
+            len: self.program.len() as u16,
+            filter: self.program.as_ptr() as *mut libc::sock_filter,
+        };
+        // SAFETY: `prog` points at the program, which outlives the call.
+        unsafe {
+            Errno::result(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+            Errno::result(libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                flags,
+                &prog as *const libc::sock_fprog,
+            ))
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::ffi::OsString;
+use apf_enforcement::{Bwrap, BwrapOption, Namespace};
+
+fn strings(argv: &[OsString]) -> Vec<&str> {
+    argv.iter().map(|arg| arg.to_str().unwrap()).collect()
+}
+
+#[test]
+fn test_argv_keeps_paths_as_separate_args() {
+    let mut bwrap = Bwrap::new();
+    bwrap.unshare_all()
+        .bind("/home/user/My Documents", "/home/user/My Documents")
+        .tmpfs("/tmp")
+        .setenv("LANG", "C.UTF-8");
+    assert_eq!(
+        strings(&bwrap.argv(&["ls", "-l"])),
+        [
+            "bwrap", "--unshare-all",
+            "--bind", "/home/user/My Documents", "/home/user/My Documents",
+            "--tmpfs", "/tmp",
+            "--setenv", "LANG", "C.UTF-8",
+            "--", "ls", "-l",
+        ]
+    );
+}
+
+#[test]
+fn test_optional_binds_use_try_variants() {
+    let mut bwrap = Bwrap::new();
+    bwrap.option(BwrapOption::RoBind { src: "/opt".into(), dest: "/opt".into(), optional: true })
+        .option(BwrapOption::DevBind { src: "/dev/dri".into(), dest: "/dev/dri".into(), optional: true });
+    assert_eq!(
+        strings(&bwrap.option_args()),
+        ["--ro-bind-try", "/opt", "/opt", "--dev-bind-try", "/dev/dri", "/dev/dri"]
+    );
+}
+
+#[test]
+fn test_single_namespace_flags() {
+    let mut bwrap = Bwrap::new();
+    bwrap.option(BwrapOption::Unshare(Namespace::User)).option(BwrapOption::Unshare(Namespace::Pid));
+    assert_eq!(strings(&bwrap.option_args()), ["--unshare-user-try", "--unshare-pid"]);
+}
+
+#[test]
+fn test_args_data_is_nul_terminated() {
+    let mut bwrap = Bwrap::new();
+    bwrap.unshare_all().ro_bind("/usr", "/usr");
+    assert_eq!(bwrap.args_data(), b"--unshare-all\0--ro-bind\0/usr\0/usr\0");
+    assert_eq!(strings(&bwrap.argv_with_args_fd(7, &["true"])), ["bwrap", "--args", "7", "--", "true"]);
+}
+
+#[test]
+fn test_long_option_lists_go_through_args_fd() {
+    let mut bwrap = Bwrap::new();
+    for i in 0..600 {
+        bwrap.tmpfs(format!("/tmp/{}", i));
+    }
+    let cmd = bwrap.command(&["true"]).unwrap();
+    let args: Vec<_> = cmd.get_args().map(|arg| arg.to_str().unwrap()).collect();
+    assert_eq!(args[0], "--args");
+    assert_eq!(&args[2..], ["--", "true"]);
+}
@@ -0,0 +1,243 @@
+//! Cgroup-based network filtering. Creating cgroups needs a writable
+//! cgroup v2 hierarchy and loading rules needs nft; tests skip without
+//! them.
+
+use std::path::Path;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use apf_core::types::{NetworkLevel, NetworkRule};
+use apf_enforcement::cgroup::{cgroup2_mount, cgroup_ruleset, current_cgroup, destination_matches, stale_tables};
+use apf_enforcement::{CgroupNetwork, CgroupNetworkBackend, Namespace, SandboxBackend, SandboxPlan};
+
+fn rule(s: &str) -> NetworkRule {
+    s.parse().unwrap()
+}
+
+/// A cgroup for `level`, or `None` where this host can't make one.
+fn cgroup_network(level: &NetworkLevel) -> Option<CgroupNetwork> {
+    match CgroupNetwork::create(level, &[]) {
+        Ok(network) => Some(network),
+        Err(err) => {
+            eprintln!("cannot create a cgroup ({:#}), skipping", err);
+            None
+        }
+    }
+}
+
+fn wait_gone(path: &Path) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while path.exists() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    true
+}
+
+#[test]
+fn test_cgroup_ruleset_levels() {
+    let cgroup = Path::new("user.slice/apf-1-0");
+    assert_eq!(cgroup_ruleset("apf_cg_1_0", cgroup, &NetworkLevel::Internet, &[], &[]), None);
+
+    let none = cgroup_ruleset("apf_cg_1_0", cgroup, &NetworkLevel::None, &[], &[]).unwrap();
+    assert!(none.contains("table inet apf_cg_1_0 {"));
+    assert!(none.contains("socket cgroupv2 level 2 \"user.slice/apf-1-0\" goto app"));
+    assert!(none.contains("policy accept;"));
+    // The host's loopback is the host's services, not the app's.
+    assert!(!none.contains("oifname \"lo\""));
+    assert!(none.contains("goto app\n\t}\n\tchain app {\n\t\tdrop\n"));
+    assert!(!none.contains("daddr"));
+
+    let lan = cgroup_ruleset("apf_cg_1_0", cgroup, &NetworkLevel::Lan, &[], &["127.0.0.53:53".parse().unwrap()]).unwrap();
+    assert!(!lan.contains("oifname \"lo\""));
+    assert!(!lan.contains("127.0.0.53"));
+    assert!(lan.contains("ip daddr { 10.0.0.0/8,"));
+    assert!(lan.contains("ip6 daddr { fc00::/7,"));
+}
+
+#[test]
+fn test_destination_matches() {
+    assert_eq!(destination_matches(&rule("cidr:203.0.113.0/24?port=443&proto=tcp")), vec![
+        "ip daddr 203.0.113.0/24 tcp dport 443".to_string(),
+    ]);
+    assert_eq!(destination_matches(&rule("cidr:2001:db8::1?port=8000-8080")), vec![
+        "ip6 daddr 2001:db8::1/128 meta l4proto { tcp, udp } th dport 8000-8080".to_string(),
+    ]);
+    assert_eq!(destination_matches(&rule("cidr:198.51.100.7?proto=udp")), vec![
+        "ip daddr 198.51.100.7/32 meta l4proto udp".to_string(),
+    ]);
+    assert!(destination_matches(&rule("host:*.example.com")).is_empty());
+
+    let localhost = destination_matches(&rule("host:localhost?port=80"));
+    assert!(localhost.contains(&"ip daddr 127.0.0.1 meta l4proto { tcp, udp } th dport 80".to_string()), "{:?}", localhost);
+
+    let resolvers = ["192.0.2.53:53".parse().unwrap(), "[2001:db8::53]:53".parse().unwrap()];
+    let ruleset = cgroup_ruleset("t", Path::new("apf"), &NetworkLevel::Rule(rule("host:localhost")), &[], &resolvers).unwrap();
+    assert!(ruleset.contains("socket cgroupv2 level 1 \"apf\""));
+    // Lookups go to the host's resolvers only.
+    assert!(ruleset.contains("ip daddr 192.0.2.53 meta l4proto { tcp, udp } th dport 53 accept"));
+    assert!(ruleset.contains("ip6 daddr 2001:db8::53 meta l4proto { tcp, udp } th dport 53 accept"));
+    assert!(!ruleset.contains("\t\tmeta l4proto { tcp, udp } th dport 53"));
+
+    let cidr = cgroup_ruleset("t", Path::new("apf"), &NetworkLevel::Rule(rule("cidr:203.0.113.0/24")), &[], &resolvers).unwrap();
+    assert!(!cidr.contains("dport 53"));
+}
+
+#[test]
+fn test_cgroup_ruleset_accepts_every_rule() {
+    let resolvers = ["192.0.2.53:53".parse().unwrap()];
+    let rules = [rule("cidr:203.0.113.0/24?port=443&proto=tcp"), rule("cidr:198.51.100.7?proto=udp")];
+    let ruleset = cgroup_ruleset("t", Path::new("apf"), &NetworkLevel::None, &rules, &resolvers).unwrap();
+    assert!(ruleset.contains("\t\tip daddr 203.0.113.0/24 tcp dport 443 accept\n"), "{}", ruleset);
+    assert!(ruleset.contains("\t\tip daddr 198.51.100.7/32 meta l4proto udp accept\n"), "{}", ruleset);
+    assert!(!ruleset.contains("dport 53"));
+    assert!(ruleset.ends_with("\t\tdrop\n\t}\n}\n"));
+
+    // Rules add to the level rather than replace it.
+    let lan = cgroup_ruleset("t", Path::new("apf"), &NetworkLevel::Lan, &[rule("host:localhost")], &resolvers).unwrap();
+    assert!(lan.contains("ip daddr { 10.0.0.0/8"), "{}", lan);
+    assert!(lan.contains("ip daddr 192.0.2.53 meta l4proto { tcp, udp } th dport 53 accept"));
+    assert!(lan.contains("ip daddr 127.0.0.1"));
+    assert_eq!(cgroup_ruleset("t", Path::new("apf"), &NetworkLevel::Internet, &rules, &resolvers), None);
+}
+
+#[test]
+fn test_stale_tables() {
+    let mount = std::env::temp_dir().join(format!("apf-cgroup-mount-{}", std::process::id()));
+    std::fs::create_dir_all(mount.join("user.slice/apf-1-0")).unwrap();
+    let ruleset = "table inet filter {\n\
+                   \tchain output {\n\
+                   \t\tsocket cgroupv2 level 1 \"elsewhere\" drop\n\
+                   \t}\n\
+                   }\n\
+                   table inet apf_cg_1_0 {\n\
+                   \tchain output {\n\
+                   \t\ttype filter hook output priority filter; policy accept;\n\
+                   \t\tsocket cgroupv2 level 2 \"user.slice/apf-1-0\" goto app\n\
+                   \t}\n\
+                   }\n\
+                   table inet apf_cg_2_0 {\n\
+                   \tchain output {\n\
+                   \t\tsocket cgroupv2 level 2 \"user.slice/apf-2-0\" goto app\n\
+                   \t}\n\
+                   }\n\
+                   table inet apf_cg_3_0 {\n\
+                   \tchain output {\n\
+                   \t\tsocket cgroupv2 level 2 21794 goto app\n\
+                   \t}\n\
+                   }\n";
+    assert_eq!(stale_tables(ruleset, &mount), vec!["apf_cg_2_0".to_string(), "apf_cg_3_0".to_string()]);
+    std::fs::remove_dir_all(&mount).unwrap();
+}
+
+#[test]
+fn test_cgroup_backend_keeps_host_network() {
+    let plan = SandboxPlan::from_contributors(&[&CgroupNetworkBackend::new(NetworkLevel::Lan)]);
+    assert!(!plan.is_unshared(Namespace::Net));
+    assert!(!plan.is_lan_only());
+    assert!(!plan.has_own_network());
+    assert_eq!(plan.cgroup_network_level(), Some(&NetworkLevel::Lan));
+    assert!(plan.cgroup_network_rules().is_empty());
+
+    let rules = vec![rule("cidr:203.0.113.0/24"), rule("host:api.example.com?port=443")];
+    let plan = SandboxPlan::from_contributors(&[&CgroupNetworkBackend::new(NetworkLevel::None).allow(rules.clone())]);
+    assert_eq!(plan.cgroup_network_level(), Some(&NetworkLevel::None));
+    assert_eq!(plan.cgroup_network_rules(), &rules[..]);
+
+    let lan = SandboxPlan::from_contributors(&[&apf_enforcement::network::NetworkBackend::new(NetworkLevel::Lan)]);
+    assert!(lan.has_own_network());
+}
+
+#[test]
+fn test_cgroup_network_tracks_processes() {
+    let Some(network) = cgroup_network(&NetworkLevel::Internet) else {
+        return;
+    };
+    let dir = network.dir().to_path_buf();
+    assert!(dir.starts_with(cgroup2_mount().unwrap()));
+    assert!(network.cgroup().starts_with(current_cgroup().unwrap()));
+    assert_eq!(network.table(), None);
+    assert!(!network.is_populated().unwrap());
+
+    let output = network.command("/bin/cat").arg("/proc/self/cgroup").output().unwrap();
+    let own = format!("0::/{}", network.cgroup().display());
+    assert!(String::from_utf8_lossy(&output.stdout).lines().any(|line| line == own));
+
+    network.wait_empty().unwrap();
+    drop(network);
+    assert!(!dir.exists());
+}
+
+#[test]
+fn test_cgroup_cleaned_up_when_empty() {
+    let Some(network) = cgroup_network(&NetworkLevel::Internet) else {
+        return;
+    };
+    let dir = network.dir().to_path_buf();
+    // The shell exits at once; the sleep it leaves behind keeps the
+    // cgroup populated past the drop.
+    let status = network.command("/bin/sh")
+        .args(["-c", "sleep 0.5 &"])
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(network.is_populated().unwrap());
+    drop(network);
+    assert!(dir.exists());
+    assert!(wait_gone(&dir), "{} left behind", dir.display());
+}
+
+#[test]
+fn test_plan_launch_in_cgroup() {
+    if cgroup_network(&NetworkLevel::Internet).is_none() {
+        return;
+    }
+    let plan = SandboxPlan::from_contributors(&[
+        &SandboxBackend::new(),
+        &CgroupNetworkBackend::new(NetworkLevel::Internet),
+    ]);
+    let status = plan.launch(&["/bin/true"]).unwrap();
+    assert!(status.success());
+
+    let parent = cgroup2_mount().unwrap().join(current_cgroup().unwrap());
+    let prefix = format!("apf-{}-", std::process::id());
+    let leftover: Vec<_> = std::fs::read_dir(&parent).unwrap()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with(&prefix))
+        .collect();
+    // Other tests in this binary may have cgroups of their own up now, so
+    // only check that launching doesn't leave them piling up.
+    assert!(leftover.len() <= 2, "{:?}", leftover);
+}
+
+#[test]
+fn test_cgroup_rules_drop_internet() {
+    if !Path::new("/usr/sbin/nft").exists() && !Path::new("/usr/bin/nft").exists() {
+        eprintln!("nft unavailable, skipping");
+        return;
+    }
+    let Some(network) = cgroup_network(&NetworkLevel::None) else {
+        return;
+    };
+    let table = network.table().unwrap().to_string();
+    let listed = std::process::Command::new("nft").args(["list", "table", "inet", &table]).output().unwrap();
+    assert!(listed.status.success());
+
+    // Loopback passes, anything routed elsewhere is refused on send.
+    let output = network.command("python3")
+        .args(["-c", "import socket\n\
+            s = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)\n\
+            s.sendto(b'x', ('127.0.0.1', 9))\n\
+            try:\n    s.sendto(b'x', ('203.0.113.1', 9))\n    print('sent')\n\
+            except OSError:\n    print('dropped')\n"])
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "dropped");
+
+    drop(network);
+    let listed = std::process::Command::new("nft").args(["list", "table", "inet", &table]).output().unwrap();
+    assert!(!listed.status.success());
+}
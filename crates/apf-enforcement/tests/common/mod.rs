@@ -0,0 +1,26 @@
+//! Helpers shared by the integration tests; each test binary uses a subset.
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use apf_enforcement::{SandboxPlan, SandboxRuntime};
+
+pub fn python3_available() -> bool {
+    std::process::Command::new("python3").arg("-c").arg("pass").status().is_ok_and(|status| status.success())
+}
+
+/// Creates an empty directory for one test below `base`. Sandbox tests pick
+/// `base` so it isn't already visible or writable through other rules.
+pub fn scratch_dir(base: impl AsRef<Path>, name: &str) -> PathBuf {
+    let dir = base.as_ref().join(format!("apf-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn run(plan: &SandboxPlan, runtime: SandboxRuntime, script: &str) -> Output {
+    plan.command_with(runtime, &["/bin/sh", "-c", script])
+        .unwrap()
+        .output()
+        .unwrap()
+}
@@ -0,0 +1,132 @@
+//! Connection prompts through seccomp user notification, against
+//! listeners on loopback. Skips without user namespaces or python3.
+
+mod common;
+
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use apf_core::types::Protocol;
+use apf_enforcement::native::user_namespaces_available;
+use apf_enforcement::notify::{connect_rules, parse_sockaddr};
+use apf_enforcement::seccomp::{SeccompAction, SeccompRule};
+use apf_enforcement::{ConnectRequest, Namespace, SandboxBackend, SandboxPlan, SandboxRuntime};
+use common::python3_available;
+
+#[test]
+fn test_parse_sockaddr() {
+    let mut v4 = vec![0u8; 16];
+    v4[..2].copy_from_slice(&(nix::libc::AF_INET as u16).to_ne_bytes());
+    v4[2..4].copy_from_slice(&443u16.to_be_bytes());
+    v4[4..8].copy_from_slice(&[192, 0, 2, 1]);
+    assert_eq!(parse_sockaddr(&v4), Some("192.0.2.1:443".parse().unwrap()));
+    assert_eq!(parse_sockaddr(&v4[..8]), None);
+
+    let mut v6 = vec![0u8; 28];
+    v6[..2].copy_from_slice(&(nix::libc::AF_INET6 as u16).to_ne_bytes());
+    v6[2..4].copy_from_slice(&53u16.to_be_bytes());
+    v6[8..24].copy_from_slice(&"2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+    assert_eq!(parse_sockaddr(&v6), Some("[2001:db8::1]:53".parse().unwrap()));
+
+    let mut unix = vec![0u8; 110];
+    unix[..2].copy_from_slice(&(nix::libc::AF_UNIX as u16).to_ne_bytes());
+    assert_eq!(parse_sockaddr(&unix), None);
+}
+
+#[test]
+fn test_connect_request_rule() {
+    let request = ConnectRequest {
+        pid: 1,
+        syscall: "connect",
+        destination: "203.0.113.9:8443".parse().unwrap(),
+        protocol: Some(Protocol::Tcp),
+    };
+    assert_eq!(request.rule().to_string(), "cidr:203.0.113.9/32?port=8443&proto=tcp");
+}
+
+#[test]
+fn test_connect_rules_refuse_io_uring() {
+    let rules = connect_rules();
+    assert!(rules.contains(&SeccompRule::deny("io_uring_setup")));
+    assert_eq!(rules.iter().filter(|rule| rule.action == SeccompAction::Notify).count(), 4);
+}
+
+#[test]
+fn test_prompting_prefers_the_builtin_launcher() {
+    if !apf_enforcement::native::available() {
+        eprintln!("namespaces unavailable, skipping");
+        return;
+    }
+    let mut plan = SandboxPlan::new();
+    plan.prompt_connect();
+    assert_eq!(plan.runtime(), SandboxRuntime::Native);
+}
+
+#[test]
+fn test_command_without_supervisor_refused() {
+    let mut plan = SandboxPlan::new();
+    plan.prompt_connect();
+    assert!(plan.command_with(SandboxRuntime::Native, &["/bin/true"]).is_err());
+}
+
+#[test]
+fn test_connect_prompts_decide_and_cache() {
+    if !user_namespaces_available() || !python3_available() {
+        eprintln!("user namespaces or python3 unavailable, skipping");
+        return;
+    }
+    let open = TcpListener::bind("127.0.0.1:0").unwrap();
+    let allowed_port = open.local_addr().unwrap().port();
+    // Bound but never listening, so a connection that got through would
+    // be refused rather than denied.
+    let closed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let denied_port = closed.local_addr().unwrap().port();
+
+    let mut plan = SandboxPlan::from_contributors(&[&SandboxBackend::new()]);
+    plan.share(Namespace::Net).prompt_connect();
+    let script = format!(
+        "import socket\n\
+         def attempt(kind, port):\n\
+         \x20   s = socket.socket(socket.AF_INET, kind)\n\
+         \x20   try:\n\
+         \x20       if kind == socket.SOCK_STREAM:\n\
+         \x20           s.connect(('127.0.0.1', port))\n\
+         \x20       else:\n\
+         \x20           s.sendto(b'x', ('127.0.0.1', port))\n\
+         \x20       return 'ok'\n\
+         \x20   except PermissionError:\n\
+         \x20       return 'denied'\n\
+         \x20   finally:\n\
+         \x20       s.close()\n\
+         print(attempt(socket.SOCK_STREAM, {0}), attempt(socket.SOCK_STREAM, {0}),\n\
+         \x20     attempt(socket.SOCK_STREAM, {1}), attempt(socket.SOCK_DGRAM, {1}))\n",
+        allowed_port, denied_port,
+    );
+    let (mut cmd, channel) = plan
+        .supervised_command_with(SandboxRuntime::Native, &["python3", "-c", &script])
+        .unwrap();
+    let child = cmd.stdout(std::process::Stdio::piped()).spawn().unwrap();
+    let mut supervisor = channel.receive().unwrap();
+
+    let asked: Arc<Mutex<Vec<ConnectRequest>>> = Arc::default();
+    let record = asked.clone();
+    let supervising = std::thread::spawn(move || {
+        supervisor.run(move |request| {
+            record.lock().unwrap().push(request.clone());
+            request.destination.port() == allowed_port
+        })
+    });
+    let output = child.wait_with_output().unwrap();
+    supervising.join().unwrap().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "ok ok denied denied");
+    let asked = asked.lock().unwrap();
+    let seen: Vec<(SocketAddr, Option<Protocol>)> = asked.iter().map(|r| (r.destination, r.protocol)).collect();
+    // The second connection to the open port was answered from the cache.
+    assert_eq!(seen, vec![
+        (SocketAddr::from(([127, 0, 0, 1], allowed_port)), Some(Protocol::Tcp)),
+        (SocketAddr::from(([127, 0, 0, 1], denied_port)), Some(Protocol::Tcp)),
+        (SocketAddr::from(([127, 0, 0, 1], denied_port)), Some(Protocol::Udp)),
+    ]);
+    drop(open);
+}
@@ -0,0 +1,272 @@
+//! DNS blocklists and the stub's reports of what was looked up, against a
+//! fake upstream resolver on the host's loopback. The sandboxed test skips
+//! without user namespaces or python3.
+
+mod common;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use apf_enforcement::dns::{self, name_error, question, DnsStub, ResolvedNames, QUERIES_MAX, RCODE_NAME_ERROR, TYPE_A};
+use apf_enforcement::native::user_namespaces_available;
+use apf_enforcement::{
+    Blocklist, DnsQuery, EgressPolicy, EgressProxyBackend, EgressRequest, SandboxBackend, SandboxPlan, SandboxRuntime,
+};
+use common::python3_available;
+
+/// Waits for the stub's reports, which arrive after the answers.
+fn reported(queries: &Mutex<Vec<DnsQuery>>, count: usize) -> Vec<DnsQuery> {
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    loop {
+        let queries = queries.lock().unwrap();
+        if queries.len() >= count || std::time::Instant::now() > deadline {
+            return queries.clone();
+        }
+        drop(queries);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn query(id: u16, name: &str) -> Vec<u8> {
+    let mut message = Vec::new();
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.extend_from_slice(&[0, 0, 1, 0, 1]);
+    message
+}
+
+/// A resolver answering every query with 192.0.2.7, noting the names it
+/// was asked about.
+fn fake_upstream() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+    let asked: Arc<Mutex<Vec<String>>> = Arc::default();
+    let record = asked.clone();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 512];
+        while let Ok((len, client)) = socket.recv_from(&mut buf) {
+            let query = &buf[..len];
+            record.lock().unwrap().push(question(query).unwrap().0);
+            let mut response = query.to_vec();
+            response[2..4].copy_from_slice(&[0x81, 0x80]);
+            response[6..8].copy_from_slice(&1u16.to_be_bytes());
+            response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 7]);
+            let _ = socket.send_to(&response, client);
+        }
+    });
+    (addr, asked)
+}
+
+/// Refuses nothing but `blocked.test` and notes what it is asked and told.
+#[derive(Default)]
+struct Recorder {
+    asked: Mutex<Vec<String>>,
+    queries: Mutex<Vec<DnsQuery>>,
+}
+
+impl EgressPolicy for Recorder {
+    fn allows(&self, request: &EgressRequest) -> bool {
+        self.asked.lock().unwrap().push(request.host.clone());
+        request.host != "blocked.test"
+    }
+
+    fn dns_query(&self, query: &DnsQuery) {
+        self.queries.lock().unwrap().push(query.clone());
+    }
+}
+
+#[test]
+fn test_blocklist_hosts_format() {
+    let hosts = "# trackers\n\
+                 127.0.0.1 localhost\n\
+                 0.0.0.0 Ads.Example.com. tracker.example.net # inline\n\
+                 ::1 ip6-localhost metrics.example.org\n\
+                 not-an-address example.org\n";
+    let blocklist = Blocklist::parse_hosts(hosts);
+    assert_eq!(blocklist.len(), 3);
+    assert!(blocklist.contains("ads.example.com"));
+    assert!(blocklist.contains("TRACKER.example.net."));
+    assert!(blocklist.contains("metrics.example.org"));
+    assert!(!blocklist.contains("localhost"));
+    assert!(!blocklist.contains("example.org"));
+    // Listing a name doesn't block the names below it.
+    assert!(!blocklist.contains("cdn.ads.example.com"));
+
+    let mut merged = Blocklist::parse_hosts("0.0.0.0 telemetry.example.com\n");
+    merged.merge(&blocklist);
+    assert_eq!(merged.len(), 4);
+
+    let path = std::env::temp_dir().join(format!("apf-blocklist-{}", std::process::id()));
+    assert_eq!(Blocklist::load(&path).unwrap(), Blocklist::default());
+    std::fs::write(&path, hosts).unwrap();
+    assert_eq!(Blocklist::load(&path).unwrap(), blocklist);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_app_blocklist_path() {
+    let chat = apf_core::app_id::AppId::from_desktop("org.example.Chat", false);
+    assert_eq!(dns::app_blocklist(&chat), Some(std::path::PathBuf::from("/etc/appfence/dns-blocklist.d/org.example.Chat")));
+    let tool = apf_core::app_id::AppId::from_desktop("/usr/bin/tool", true);
+    assert_eq!(dns::app_blocklist(&tool), None);
+    assert_eq!(dns::app_blocklist(&apf_core::app_id::AppId::from_desktop("..", false)), None);
+}
+
+#[test]
+fn test_name_error() {
+    let asked = query(0x4242, "ads.example.com");
+    let refused = name_error(&asked).unwrap();
+    assert_eq!(refused[..2], asked[..2]);
+    assert_eq!(refused[2] & 0x80, 0x80);
+    assert_eq!(refused[3] & 0x0f, RCODE_NAME_ERROR);
+    assert_eq!(question(&refused), Some(("ads.example.com".to_string(), TYPE_A)));
+    assert!(dns::answer_addresses(&refused).is_empty());
+    assert_eq!(name_error(&asked[..8]), None);
+}
+
+#[test]
+fn test_stub_refuses_blocked_names() {
+    let (upstream, upstream_asked) = fake_upstream();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let stub_addr = socket.local_addr().unwrap();
+    let names = Arc::new(ResolvedNames::default());
+    let seen: Arc<Mutex<Vec<DnsQuery>>> = Arc::default();
+    let observe = seen.clone();
+    let stub = DnsStub::new(socket, upstream, names.clone())
+        .blocking(Arc::new(Blocklist::parse_hosts("0.0.0.0 ads.example.test\n")))
+        .observed_by(Arc::new(move |query: &DnsQuery| observe.lock().unwrap().push(query.clone())));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut buf = [0u8; 512];
+    let mut rcodes = Vec::new();
+    for (id, name) in [(1, "ADS.example.test"), (2, "app.example.test")] {
+        client.send_to(&query(id, name), stub_addr).unwrap();
+        stub.serve_one().unwrap();
+        let len = client.recv(&mut buf).unwrap();
+        assert_eq!(u16::from_be_bytes([buf[0], buf[1]]), id);
+        rcodes.push(buf[..len][3] & 0x0f);
+    }
+
+    assert_eq!(rcodes, vec![RCODE_NAME_ERROR, 0]);
+    // The blocked name never left the host.
+    assert_eq!(*upstream_asked.lock().unwrap(), vec!["app.example.test".to_string()]);
+    assert_eq!(reported(&seen, 2), vec![
+        DnsQuery { name: "ads.example.test".into(), qtype: TYPE_A, blocked: true },
+        DnsQuery { name: "app.example.test".into(), qtype: TYPE_A, blocked: false },
+    ]);
+    assert_eq!(names.name_of(&"192.0.2.7".parse().unwrap()).as_deref(), Some("app.example.test"));
+}
+
+#[test]
+fn test_stub_refuses_several_questions() {
+    let (upstream, upstream_asked) = fake_upstream();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let stub_addr = socket.local_addr().unwrap();
+    let stub = DnsStub::new(socket, upstream, Arc::new(ResolvedNames::default()))
+        .blocking(Arc::new(Blocklist::parse_hosts("0.0.0.0 ads.example.test\n")));
+
+    // The blocked name would be relayed behind an allowed one.
+    let mut two = query(1, "app.example.test");
+    two[5] = 2;
+    two.extend_from_slice(&query(1, "ads.example.test")[12..]);
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    client.send_to(&two, stub_addr).unwrap();
+    stub.serve_one().unwrap();
+    assert!(client.recv(&mut [0u8; 512]).is_err());
+    assert!(upstream_asked.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_stub_drops_queries_past_its_limit() {
+    // An upstream that never answers keeps every relayed query waiting.
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let stub_addr = socket.local_addr().unwrap();
+    let stub = DnsStub::new(socket, silent.local_addr().unwrap(), Arc::new(ResolvedNames::default()))
+        .blocking(Arc::new(Blocklist::parse_hosts("0.0.0.0 ads.example.test\n")));
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    for id in 0..QUERIES_MAX as u16 {
+        client.send_to(&query(id, "app.example.test"), stub_addr).unwrap();
+        stub.serve_one().unwrap();
+    }
+
+    // A blocked name is answered at once, but not while the stub is full.
+    client.send_to(&query(1000, "ads.example.test"), stub_addr).unwrap();
+    stub.serve_one().unwrap();
+    assert!(client.recv(&mut [0u8; 512]).is_err());
+
+    // Once the waiting queries time out there is room again.
+    std::thread::sleep(Duration::from_secs(6));
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    client.send_to(&query(1001, "ads.example.test"), stub_addr).unwrap();
+    stub.serve_one().unwrap();
+    let mut buf = [0u8; 512];
+    let len = client.recv(&mut buf).unwrap();
+    assert_eq!(u16::from_be_bytes([buf[0], buf[1]]), 1001);
+    assert_eq!(buf[..len][3] & 0x0f, RCODE_NAME_ERROR);
+    drop(silent);
+}
+
+#[test]
+fn test_blocklist_plan() {
+    let mut backend = EgressProxyBackend::with_resolvers(vec!["127.0.0.53:53".parse().unwrap()]);
+    backend.block(&Blocklist::parse_hosts("0.0.0.0 ads.example.test\n"));
+    let plan = SandboxPlan::from_contributors(&[&SandboxBackend::new(), &backend]);
+    assert!(plan.dns_blocklist().contains("ads.example.test"));
+}
+
+#[test]
+fn test_sandbox_blocked_names() {
+    if !user_namespaces_available() || !python3_available() {
+        eprintln!("user namespaces or python3 unavailable, skipping");
+        return;
+    }
+    let (upstream, upstream_asked) = fake_upstream();
+    let mut backend = EgressProxyBackend::with_resolvers(vec![upstream]);
+    backend.block(&Blocklist::parse_hosts("0.0.0.0 ads.example.test\n"));
+    let plan = SandboxPlan::from_contributors(&[&SandboxBackend::new(), &backend]);
+    let script = format!(
+        "import socket, struct\n\
+         def lookup(name):\n\
+         \x20   q = struct.pack('>HHHHHH', 0x1234, 0x0100, 1, 0, 0, 0)\n\
+         \x20   q += b''.join(bytes([len(l)]) + l.encode() for l in name.split('.')) + b'\\x00\\x00\\x01\\x00\\x01'\n\
+         \x20   u = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)\n\
+         \x20   u.settimeout(10)\n\
+         \x20   u.sendto(q, ('{dns_ip}', {dns_port}))\n\
+         \x20   return 'rcode %d' % (u.recv(512)[3] & 0x0f)\n\
+         def socks(name):\n\
+         \x20   s = socket.create_connection(('127.0.0.1', 1080))\n\
+         \x20   s.sendall(b'\\x05\\x01\\x00')\n\
+         \x20   assert s.recv(2) == b'\\x05\\x00'\n\
+         \x20   s.sendall(b'\\x05\\x01\\x00\\x03' + bytes([len(name)]) + name.encode() + struct.pack('>H', 9))\n\
+         \x20   return 'refused %d' % s.recv(10)[1]\n\
+         print('|'.join([lookup('ads.example.test'), lookup('app.example.test'),\n\
+         \x20   socks('ads.example.test'), socks('blocked.test')]))\n",
+        dns_ip = upstream.ip(),
+        dns_port = upstream.port(),
+    );
+
+    let recorder = Arc::new(Recorder::default());
+    let (mut cmd, proxy) = plan
+        .proxied_command_with(SandboxRuntime::Native, &["python3", "-c", &script], recorder.clone())
+        .unwrap();
+    let output = cmd.output().unwrap();
+    drop(proxy);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "rcode 3|rcode 0|refused 2|refused 2");
+    assert_eq!(*upstream_asked.lock().unwrap(), vec!["app.example.test".to_string()]);
+    // A blocked name is refused without asking.
+    assert_eq!(*recorder.asked.lock().unwrap(), vec!["blocked.test".to_string()]);
+    let queries = reported(&recorder.queries, 2);
+    let seen: Vec<(&str, bool)> = queries.iter().map(|query| (query.name.as_str(), query.blocked)).collect();
+    assert_eq!(seen, vec![("ads.example.test", true), ("app.example.test", false)]);
+}
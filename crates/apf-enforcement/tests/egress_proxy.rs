@@ -0,0 +1,207 @@
+//! The egress proxy against an echo server and a fake resolver on the
+//! host's loopback. Skips without user namespaces or python3.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use apf_enforcement::dns::{answer_addresses, parse_resolv_conf, question, TYPE_A};
+use apf_enforcement::native::user_namespaces_available;
+use apf_enforcement::{EgressProxyBackend, EgressRequest, Namespace, SandboxBackend, SandboxPlan, SandboxRuntime};
+use common::python3_available;
+
+fn query(id: u16, name: &str) -> Vec<u8> {
+    let mut message = Vec::new();
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.extend_from_slice(&[0, 0, 1, 0, 1]);
+    message
+}
+
+/// `query` answered with one A record pointing back at its question.
+fn response(query: &[u8], address: [u8; 4]) -> Vec<u8> {
+    let mut message = query.to_vec();
+    message[2..4].copy_from_slice(&[0x81, 0x80]);
+    message[6..8].copy_from_slice(&1u16.to_be_bytes());
+    message.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+    message.extend_from_slice(&address);
+    message
+}
+
+#[test]
+fn test_egress_request_rule() {
+    let named = EgressRequest { host: "Updates.Example.COM.".into(), port: 443, via: "http" };
+    assert_eq!(named.rule().to_string(), "host:updates.example.com?port=443&proto=tcp");
+    let literal = EgressRequest { host: "2001:db8::5".into(), port: 80, via: "socks5" };
+    assert_eq!(literal.rule().to_string(), "cidr:2001:db8::5/128?port=80&proto=tcp");
+}
+
+#[test]
+fn test_dns_messages() {
+    let asked = query(7, "App.Example.test");
+    assert_eq!(question(&asked), Some(("app.example.test".to_string(), TYPE_A)));
+    assert!(answer_addresses(&asked).is_empty());
+
+    let answered = response(&asked, [192, 0, 2, 7]);
+    assert_eq!(question(&answered).unwrap().0, "app.example.test");
+    assert_eq!(answer_addresses(&answered), vec![IpAddr::from([192, 0, 2, 7])]);
+    // A truncated answer yields nothing rather than garbage.
+    assert!(answer_addresses(&answered[..answered.len() - 2]).is_empty());
+
+    // A pointer to itself doesn't loop forever.
+    let mut looping = asked.clone();
+    looping.truncate(12);
+    looping.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]);
+    assert_eq!(question(&looping), None);
+}
+
+#[test]
+fn test_parse_resolv_conf() {
+    let conf = "# generated\nsearch lan\nnameserver 192.168.1.1\nnameserver fe80::1%eth0\noptions edns0\n";
+    assert_eq!(parse_resolv_conf(conf), vec![
+        "192.168.1.1".parse::<IpAddr>().unwrap(),
+        "fe80::1".parse().unwrap(),
+    ]);
+    assert_eq!(parse_resolv_conf(""), vec!["127.0.0.1".parse::<IpAddr>().unwrap()]);
+}
+
+#[test]
+fn test_egress_proxy_plan() {
+    let resolver: SocketAddr = "127.0.0.53:53".parse().unwrap();
+    let plan = SandboxPlan::from_contributors(&[
+        &SandboxBackend::new(),
+        &EgressProxyBackend::with_resolvers(vec![resolver]),
+    ]);
+    assert!(plan.proxies_egress());
+    assert!(!plan.is_unshared(Namespace::Net));
+    assert_eq!(plan.egress_resolvers(), Some(&[resolver][..]));
+    assert_eq!(
+        plan.env().get(std::ffi::OsStr::new("https_proxy")),
+        Some(&Some("http://127.0.0.1:3128".into())),
+    );
+    // Without a policy there is no proxy to answer the sandbox.
+    assert!(plan.command_with(SandboxRuntime::Native, &["/bin/true"]).is_err());
+}
+
+#[test]
+fn test_egress_proxy_connections() {
+    if !user_namespaces_available() || !python3_available() {
+        eprintln!("user namespaces or python3 unavailable, skipping");
+        return;
+    }
+    let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = echo.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for stream in echo.incoming() {
+            let Ok(mut stream) = stream else { break };
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 4096];
+                while let Ok(len) = stream.read(&mut buf) {
+                    if len == 0 || stream.write_all(&buf[..len]).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let resolver_addr = resolver.local_addr().unwrap();
+    resolver.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 512];
+        while let Ok((len, client)) = resolver.recv_from(&mut buf) {
+            let _ = resolver.send_to(&response(&buf[..len], [192, 0, 2, 7]), client);
+        }
+    });
+
+    let plan = SandboxPlan::from_contributors(&[
+        &SandboxBackend::new(),
+        &EgressProxyBackend::with_resolvers(vec![resolver_addr]),
+    ]);
+    let script = format!(
+        "import socket, struct\n\
+         def head(s):\n\
+         \x20   data = b''\n\
+         \x20   while b'\\r\\n\\r\\n' not in data:\n\
+         \x20       chunk = s.recv(1024)\n\
+         \x20       if not chunk:\n\
+         \x20           break\n\
+         \x20       data += chunk\n\
+         \x20   return data.decode()\n\
+         def tunnel(host):\n\
+         \x20   s = socket.create_connection(('127.0.0.1', 3128))\n\
+         \x20   s.sendall(('CONNECT %s:{port} HTTP/1.1\\r\\nHost: %s\\r\\n\\r\\n' % (host, host)).encode())\n\
+         \x20   code = head(s).split(' ')[1]\n\
+         \x20   if code != '200':\n\
+         \x20       return code\n\
+         \x20   s.sendall(b'ping')\n\
+         \x20   return s.recv(4).decode()\n\
+         def plain():\n\
+         \x20   s = socket.create_connection(('127.0.0.1', 3128))\n\
+         \x20   s.sendall(b'GET http://localhost:{port}/path HTTP/1.1\\r\\nHost: localhost\\r\\nProxy-Connection: keep-alive\\r\\n\\r\\n')\n\
+         \x20   lines = head(s).split('\\r\\n')\n\
+         \x20   return lines[0] + ('' if 'Connection: close' in lines else ' kept open')\n\
+         def socks(address):\n\
+         \x20   s = socket.create_connection(('127.0.0.1', 1080))\n\
+         \x20   s.sendall(b'\\x05\\x01\\x00')\n\
+         \x20   assert s.recv(2) == b'\\x05\\x00'\n\
+         \x20   s.sendall(b'\\x05\\x01\\x00' + address + struct.pack('>H', {port}))\n\
+         \x20   reply = s.recv(10)\n\
+         \x20   if reply[1] != 0:\n\
+         \x20       return 'refused %d' % reply[1]\n\
+         \x20   s.sendall(b'ping')\n\
+         \x20   return s.recv(4).decode()\n\
+         def lookup(name):\n\
+         \x20   q = struct.pack('>HHHHHH', 0x1234, 0x0100, 1, 0, 0, 0)\n\
+         \x20   q += b''.join(bytes([len(l)]) + l.encode() for l in name.split('.')) + b'\\x00\\x00\\x01\\x00\\x01'\n\
+         \x20   u = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)\n\
+         \x20   u.settimeout(10)\n\
+         \x20   u.sendto(q, ('{dns_ip}', {dns_port}))\n\
+         \x20   return socket.inet_ntoa(u.recv(512)[-4:])\n\
+         def direct():\n\
+         \x20   try:\n\
+         \x20       socket.create_connection(('127.0.0.1', {port}), timeout=2)\n\
+         \x20       return 'reached'\n\
+         \x20   except OSError:\n\
+         \x20       return 'unreachable'\n\
+         print('|'.join([tunnel('localhost'), tunnel('blocked.test'), plain(),\n\
+         \x20   socks(b'\\x03\\x09localhost'), lookup('app.example.test'),\n\
+         \x20   socks(b'\\x01' + socket.inet_aton('192.0.2.7')), direct()]))\n",
+        port = port,
+        dns_ip = resolver_addr.ip(),
+        dns_port = resolver_addr.port(),
+    );
+
+    let asked: Arc<Mutex<Vec<EgressRequest>>> = Arc::default();
+    let record = asked.clone();
+    let (mut cmd, proxy) = plan
+        .proxied_command_with(SandboxRuntime::Native, &["python3", "-c", &script], Arc::new(move |request: &EgressRequest| {
+            record.lock().unwrap().push(request.clone());
+            request.host == "localhost"
+        }))
+        .unwrap();
+    let output = cmd.output().unwrap();
+    drop(proxy);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        "ping|403|GET /path HTTP/1.1|ping|192.0.2.7|refused 2|unreachable",
+    );
+    let asked = asked.lock().unwrap();
+    let seen: Vec<(&str, u16, &str)> = asked.iter().map(|r| (r.host.as_str(), r.port, r.via)).collect();
+    // The address looked up through the stub is asked about by name.
+    assert_eq!(seen, vec![
+        ("localhost", port, "http"),
+        ("blocked.test", port, "http"),
+        ("localhost", port, "http"),
+        ("localhost", port, "socks5"),
+        ("app.example.test", port, "socks5"),
+    ]);
+}
@@ -0,0 +1,129 @@
+//! LAN-only networking against local namespaces; nothing here reaches
+//! outside the host. Tests needing nft or python3 skip without them.
+
+use std::path::Path;
+use std::process::Output;
+use apf_core::types::NetworkLevel;
+use apf_enforcement::lan::{lan_ruleset, LAN_IPV4, LAN_IPV6};
+use apf_enforcement::native::user_namespaces_available;
+use apf_enforcement::network::NetworkBackend;
+use apf_enforcement::{LanNetwork, Namespace, NativeSandbox, SandboxBackend, SandboxPlan};
+
+fn on_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+        .unwrap_or(false)
+}
+
+fn shell(network: &LanNetwork, script: &str) -> Output {
+    network.command("/bin/sh").args(["-c", script]).output().unwrap()
+}
+
+#[test]
+fn test_lan_ruleset_allows_only_lan() {
+    let ruleset = lan_ruleset();
+    assert!(ruleset.contains("policy drop;"));
+    assert!(ruleset.contains("oifname \"lo\" accept"));
+    for range in LAN_IPV4.iter().chain(LAN_IPV6.iter()) {
+        assert!(ruleset.contains(range), "{} missing from ruleset", range);
+    }
+    assert!(!ruleset.contains("0.0.0.0/0"));
+}
+
+#[test]
+fn test_network_backend_lan_plan() {
+    let plan = SandboxPlan::from_contributors(&[&SandboxBackend::new(), &NetworkBackend::new(NetworkLevel::Lan)]);
+    assert!(plan.is_lan_only());
+    // The runtime joins the LAN namespace rather than unsharing a new one.
+    assert!(!plan.is_unshared(Namespace::Net));
+    assert!(plan.seccomp_rules().iter().all(|rule| rule.syscall != "socket"));
+
+    let internet = SandboxPlan::from_contributors(&[&NetworkBackend::new(NetworkLevel::Internet)]);
+    assert!(!internet.is_lan_only());
+
+    // A rule can't be enforced in a namespace, so it gets no network.
+    let rule = NetworkLevel::Rule("host:example.org?port=443".parse().unwrap());
+    let plan = SandboxPlan::from_contributors(&[&SandboxBackend::new(), &NetworkBackend::new(rule)]);
+    assert!(plan.is_unshared(Namespace::Net));
+    assert_eq!(plan.seccomp_rules().iter().filter(|rule| rule.syscall == "socket").count(), 2);
+}
+
+#[test]
+fn test_loopback_only_namespace() {
+    if !user_namespaces_available() {
+        eprintln!("user namespaces unavailable, skipping");
+        return;
+    }
+    let network = LanNetwork::loopback_only().unwrap();
+    let holder = network.holder_pid();
+    assert!(Path::new(&format!("/proc/{}", holder)).exists());
+
+    let output = shell(&network, "ip -o link");
+    assert!(output.status.success());
+    let links = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(links.lines().count(), 1, "{}", links);
+    assert!(links.contains("lo:") && links.contains("UP"), "{}", links);
+
+    drop(network);
+    assert!(!Path::new(&format!("/proc/{}", holder)).exists());
+}
+
+#[test]
+fn test_native_sandbox_joins_network() {
+    if !user_namespaces_available() {
+        eprintln!("user namespaces unavailable, skipping");
+        return;
+    }
+    let network = LanNetwork::loopback_only().unwrap();
+    let expected = std::fs::read_link(format!("/proc/{}/ns/net", network.holder_pid())).unwrap();
+
+    let mut plan = SandboxPlan::from_contributors(&[&SandboxBackend::new()]);
+    plan.lan_only();
+    let output = NativeSandbox::new(&plan)
+        .unwrap()
+        .joining(network)
+        .command(&plan, &["/bin/sh", "-c", "readlink /proc/self/ns/net; id -u"])
+        .unwrap()
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next(), expected.to_str());
+    assert_eq!(lines.next().unwrap(), nix::unistd::Uid::current().to_string());
+}
+
+#[test]
+fn test_lan_rules_drop_internet() {
+    if !user_namespaces_available() || !on_path("nft") || !on_path("python3") {
+        eprintln!("nft, python3 or user namespaces unavailable, skipping");
+        return;
+    }
+    let network = LanNetwork::unlinked().unwrap();
+    // A veth pair stands in for the link; the default route makes
+    // non-LAN destinations leave through it too.
+    let setup = shell(
+        &network,
+        "ip link add apf0 type veth peer name apf1 && \
+         ip addr add 192.168.77.1/24 dev apf0 && \
+         ip link set apf0 up && ip link set apf1 up && \
+         ip route add default via 192.168.77.2",
+    );
+    assert!(setup.status.success(), "{}", String::from_utf8_lossy(&setup.stderr));
+
+    let send = |dest: &str| {
+        let script = format!(
+            "import socket\n\
+             s = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)\n\
+             try:\n    s.sendto(b'x', ('{}', 9))\n    print('sent')\n\
+             except PermissionError:\n    print('dropped')\n",
+            dest,
+        );
+        let output = network.command("python3").args(["-c", &script]).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    };
+    assert_eq!(send("192.168.77.2"), "sent");
+    assert_eq!(send("224.0.0.251"), "sent");
+    assert_eq!(send("203.0.113.1"), "dropped");
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use std::path::PathBuf;
+use apf_core::types::{AccessMode, EnforcementStrength, FilesystemAccess};
+use apf_enforcement::landlock::abi_version;
+use apf_enforcement::native::available as native_available;
+use apf_enforcement::{
+    FilesystemBackend, LandlockBackend, SandboxBackend, SandboxPlan, SandboxRuntime,
+};
+use common::run;
+
+fn access(path: &str, mode: AccessMode) -> FilesystemAccess {
+    FilesystemAccess { path: PathBuf::from(path), mode }
+}
+
+/// Outside `/tmp`, which the system rules leave writable.
+fn scratch_dir(name: &str) -> PathBuf {
+    common::scratch_dir(env!("CARGO_TARGET_TMPDIR"), &format!("landlock-{}", name))
+}
+
+#[test]
+fn test_strength_without_landlock_is_weak() {
+    let mut backend = LandlockBackend::with_abi(None);
+    backend.add_rule(access("/home/user/Documents", AccessMode::ReadOnly));
+    assert_eq!(backend.strength(), EnforcementStrength::Weak);
+    assert!(backend.ruleset().is_none());
+}
+
+#[test]
+fn test_strength_with_landlock_is_medium() {
+    let mut backend = LandlockBackend::with_abi(Some(3));
+    backend.add_rule(access("/home/user/Documents", AccessMode::ReadOnly));
+    backend.add_rule(access("/home/user/Downloads", AccessMode::ReadWrite));
+    backend.add_rule(access("/home/user/.ssh", AccessMode::Deny));
+    assert_eq!(backend.strength(), EnforcementStrength::Medium);
+    assert!(backend.unenforceable().is_empty());
+
+    let ruleset = backend.ruleset().unwrap();
+    assert_eq!(ruleset.abi(), 3);
+    assert!(!ruleset.rules().iter().any(|(path, _)| path.ends_with(".ssh")));
+}
+
+#[test]
+fn test_narrower_rules_under_a_parent_are_unenforceable() {
+    let mut backend = LandlockBackend::with_abi(Some(3));
+    backend.add_rule(access("/home/user/Documents", AccessMode::ReadWrite));
+    backend.add_rule(access("/home/user/Documents/taxes", AccessMode::Deny));
+    backend.add_rule(access("/home/user/Documents/notes", AccessMode::ReadWrite));
+    backend.add_rule(access("/etc/shadow", AccessMode::Deny));
+
+    let unenforceable: Vec<_> = backend.unenforceable().into_iter().map(|rule| rule.path.clone()).collect();
+    assert_eq!(unenforceable, [PathBuf::from("/home/user/Documents/taxes"), PathBuf::from("/etc/shadow")]);
+    assert_eq!(backend.strength(), EnforcementStrength::Weak);
+}
+
+#[test]
+fn test_landlock_runtime_confines_the_host() {
+    if abi_version().is_none() {
+        eprintln!("Landlock unavailable, skipping");
+        return;
+    }
+    let allowed = scratch_dir("allowed");
+    let other = scratch_dir("other");
+    std::fs::write(allowed.join("note"), "hello").unwrap();
+    std::fs::write(other.join("secret"), "hidden").unwrap();
+
+    let mut landlock = LandlockBackend::new();
+    landlock.add_rule(FilesystemAccess { path: allowed.clone(), mode: AccessMode::ReadOnly });
+    let plan = SandboxPlan::from_contributors(&[&landlock]);
+
+    let script = format!(
+        "cat {allowed}/note; echo; touch {allowed}/new 2>/dev/null || echo no-write; \
+         cat {other}/secret 2>/dev/null || echo no-read; echo ok > /tmp/apf-landlock-$$ && echo tmp",
+        allowed = allowed.display(),
+        other = other.display()
+    );
+    let output = run(&plan, SandboxRuntime::Landlock, &script);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().collect::<Vec<_>>(), ["hello", "no-write", "no-read", "tmp"]);
+    std::fs::remove_dir_all(&allowed).unwrap();
+    std::fs::remove_dir_all(&other).unwrap();
+}
+
+#[test]
+fn test_landlock_composes_with_the_native_sandbox() {
+    if abi_version().is_none() || !native_available() {
+        eprintln!("Landlock or namespaces unavailable, skipping");
+        return;
+    }
+    let dir = scratch_dir("native");
+    let mut filesystem = FilesystemBackend::new();
+    filesystem.add_allowed_path(dir.clone(), apf_enforcement::AccessMode::ReadWrite);
+    let mut landlock = LandlockBackend::new();
+    landlock.add_rule(FilesystemAccess { path: dir.clone(), mode: AccessMode::ReadWrite });
+    let plan = SandboxPlan::from_contributors(&[&SandboxBackend::new(), &filesystem, &landlock]);
+    assert_eq!(plan.runtime(), SandboxRuntime::Native);
+
+    let output = run(&plan, SandboxRuntime::Native, &format!("echo written > {}/out", dir.display()));
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read_to_string(dir.join("out")).unwrap(), "written\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_landlock_runtime_refuses_plans_without_rules() {
+    let plan = SandboxPlan::from_contributors(&[&SandboxBackend::new()]);
+    assert!(plan.command_with(SandboxRuntime::Landlock, &["true"]).is_err());
+}
@@ -0,0 +1,120 @@
+//! Runs the built-in launcher for real. It needs nothing but unprivileged
+//! user namespaces, and skips where the host has them disabled.
+
+mod common;
+
+use std::path::PathBuf;
+use std::process::Output;
+use apf_core::types::NetworkLevel;
+use apf_enforcement::native::user_namespaces_available;
+use apf_enforcement::network::NetworkBackend;
+use apf_enforcement::{AccessMode, FilesystemBackend, SandboxBackend, SandboxPlan, SandboxRuntime};
+
+/// Outside the home directories, which the sandbox must not show.
+fn scratch_dir(name: &str) -> PathBuf {
+    common::scratch_dir(std::env::temp_dir(), &format!("native-{}", name))
+}
+
+fn run(plan: &SandboxPlan, script: &str) -> Output {
+    common::run(plan, SandboxRuntime::Native, script)
+}
+
+#[test]
+fn test_native_sandbox_isolates() {
+    if !user_namespaces_available() {
+        eprintln!("user namespaces unavailable, skipping");
+        return;
+    }
+    let dir = scratch_dir("isolates");
+    std::fs::write(dir.join("note"), "hello").unwrap();
+
+    let mut filesystem = FilesystemBackend::new();
+    filesystem.add_allowed_path(dir.clone(), AccessMode::ReadOnly);
+    let plan = SandboxPlan::from_contributors(&[
+        &SandboxBackend::new(),
+        &filesystem,
+        &NetworkBackend::new(NetworkLevel::None),
+    ]);
+
+    let script = format!(
+        "echo pid=$$; cat {dir}/note; echo; touch {dir}/new 2>/dev/null && echo writable; \
+         test -e /home || test -e /root || echo no-home; \
+         grep -c : /proc/net/dev; test -c /dev/null && echo dev-null",
+        dir = dir.display()
+    );
+    let output = run(&plan, &script);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{:?}", output);
+    let lines: Vec<&str> = stdout.lines().collect();
+    // The shell is PID 1 of its own namespace, the bind is read-only,
+    // nothing else of the host is there and only loopback exists.
+    assert_eq!(lines, ["pid=1", "hello", "no-home", "1", "dev-null"]);
+    assert!(!dir.join("new").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_native_sandbox_writes_through_rw_binds() {
+    if !user_namespaces_available() {
+        eprintln!("user namespaces unavailable, skipping");
+        return;
+    }
+    let dir = scratch_dir("rw");
+    let mut filesystem = FilesystemBackend::new();
+    filesystem.add_allowed_path(dir.clone(), AccessMode::ReadWrite);
+    let mut plan = SandboxPlan::from_contributors(&[&SandboxBackend::new(), &filesystem]);
+    plan.setenv("APF_TEST", "set");
+
+    let output = run(&plan, &format!("echo $APF_TEST > {}/out", dir.display()));
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read_to_string(dir.join("out")).unwrap(), "set\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_native_sandbox_passes_exit_status() {
+    if !user_namespaces_available() {
+        eprintln!("user namespaces unavailable, skipping");
+        return;
+    }
+    let plan = SandboxPlan::from_contributors(&[&SandboxBackend::new()]);
+    assert_eq!(run(&plan, "exit 3").status.code(), Some(3));
+}
+
+#[test]
+fn test_native_sandbox_rejects_missing_binds() {
+    let mut filesystem = FilesystemBackend::new();
+    filesystem.add_allowed_path(PathBuf::from("/nonexistent/apf"), AccessMode::ReadOnly);
+    let plan = SandboxPlan::from_contributors(&[&filesystem]);
+    assert!(plan.command_with(SandboxRuntime::Native, &["true"]).is_err());
+}
+
+#[test]
+fn test_native_sandbox_read_only_binds_are_recursive() {
+    use nix::mount::{mount, umount2, MntFlags, MsFlags};
+
+    if !nix::unistd::Uid::effective().is_root() {
+        eprintln!("not root, can't mount below the bind, skipping");
+        return;
+    }
+    let dir = scratch_dir("nested");
+    let drive = dir.join("drive");
+    std::fs::create_dir(&drive).unwrap();
+    // Like a removable drive mounted below a read-only path.
+    mount(Some("tmpfs"), &drive, Some("tmpfs"), MsFlags::empty(), None::<&str>).unwrap();
+
+    let mut filesystem = FilesystemBackend::new();
+    filesystem.add_allowed_path(dir.clone(), AccessMode::ReadOnly);
+    let plan = SandboxPlan::from_contributors(&[&SandboxBackend::new(), &filesystem]);
+    let output = run(&plan, &format!(
+        "touch {dir}/top 2>/dev/null && echo top-writable; touch {dir}/drive/inner 2>/dev/null && echo drive-writable; echo done",
+        dir = dir.display(),
+    ));
+    let inner_exists = drive.join("inner").exists();
+    umount2(&drive, MntFlags::MNT_DETACH).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "done");
+    assert!(!inner_exists);
+}
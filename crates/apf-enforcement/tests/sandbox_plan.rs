@@ -0,0 +1,110 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use apf_core::types::{DeviceType, NetworkLevel};
+use nix::sys::signal::Signal;
+use apf_enforcement::network::NetworkBackend;
+use apf_enforcement::plan::{runtime_mounts, signal_sandboxes};
+use apf_enforcement::{
+    AccessMode, BwrapOption, CgroupNetwork, CgroupNetworkBackend, DeviceBackend, FilesystemBackend, Mount, Namespace,
+    SandboxBackend, SandboxPlan,
+};
+
+fn strings(argv: &[OsString]) -> Vec<&str> {
+    argv.iter().map(|arg| arg.to_str().unwrap()).collect()
+}
+
+#[test]
+fn test_backends_compose_into_one_invocation() {
+    let mut filesystem = FilesystemBackend::new();
+    filesystem.add_allowed_path(PathBuf::from("/home/user/Documents"), AccessMode::ReadOnly);
+    filesystem.add_allowed_path(PathBuf::from("/home/user/Downloads"), AccessMode::ReadWrite);
+    let network = NetworkBackend::new(NetworkLevel::Internet);
+
+    let plan = SandboxPlan::from_contributors(&[&SandboxBackend::new(), &filesystem, &network]);
+    let argv = plan.bwrap().argv(&["app"]);
+    let argv = strings(&argv);
+
+    assert_eq!(argv.iter().filter(|arg| **arg == "bwrap").count(), 1);
+    assert_eq!(&argv[..5], ["bwrap", "--unshare-all", "--share-net", "--die-with-parent", "--new-session"]);
+    assert_eq!(&argv[argv.len() - 8..], [
+        "--ro-bind", "/home/user/Documents", "/home/user/Documents",
+        "--bind", "/home/user/Downloads", "/home/user/Downloads",
+        "--", "app",
+    ]);
+    for mount in runtime_mounts() {
+        assert!(plan.bwrap().options().contains(&BwrapOption::from(&mount)));
+    }
+}
+
+#[test]
+fn test_namespaces_stay_unshared_without_network() {
+    let plan = SandboxPlan::from_contributors(&[&SandboxBackend::new(), &NetworkBackend::new(NetworkLevel::None)]);
+    assert!(plan.is_unshared(Namespace::Net));
+    let bwrap = plan.bwrap();
+    assert_eq!(bwrap.options()[0], BwrapOption::UnshareAll);
+    assert!(!bwrap.options().contains(&BwrapOption::ShareNet));
+}
+
+#[test]
+fn test_sharing_other_namespaces_unshares_the_rest_one_by_one() {
+    let mut plan = SandboxPlan::new();
+    plan.share(Namespace::Pid).share(Namespace::Net);
+    assert_eq!(
+        strings(&plan.bwrap().option_args()),
+        ["--unshare-user-try", "--unshare-ipc", "--unshare-uts", "--unshare-cgroup-try", "--die-with-parent"]
+    );
+}
+
+#[test]
+fn test_mounts_parents_first_and_once() {
+    let mut plan = SandboxPlan::new();
+    plan.mount(Mount::bind("/home/user/Documents", AccessMode::ReadOnly))
+        .mount(Mount::Tmpfs("/home".into()))
+        .mount(Mount::bind("/home/user/Documents", AccessMode::ReadOnly));
+    let dests: Vec<_> = plan.mounts().iter().map(|mount| mount.dest().to_path_buf()).collect();
+    assert_eq!(dests, [PathBuf::from("/home"), PathBuf::from("/home/user/Documents")]);
+}
+
+#[test]
+fn test_env_changes() {
+    let mut plan = SandboxPlan::new();
+    plan.setenv("TMPDIR", "/tmp").unsetenv("SSH_AUTH_SOCK").setenv("LANG", "C").unsetenv("LANG");
+    assert_eq!(
+        strings(&plan.bwrap().option_args())[2..],
+        ["--unsetenv", "LANG", "--unsetenv", "SSH_AUTH_SOCK", "--setenv", "TMPDIR", "/tmp"]
+    );
+}
+
+#[test]
+fn test_device_backend_binds_nodes_optionally() {
+    let devices = DeviceBackend::new(vec![DeviceType::Microphone]);
+    let plan = SandboxPlan::from_contributors(&[&devices]);
+    assert_eq!(
+        plan.mounts(),
+        [&Mount::DevBind { src: "/dev/snd".into(), dest: "/dev/snd".into(), optional: true }]
+    );
+}
+
+#[test]
+fn test_signals_reach_the_sandbox_and_its_cgroup() {
+    if let Err(err) = CgroupNetwork::create(&NetworkLevel::Internet, &[]) {
+        eprintln!("cannot create a cgroup ({:#}), skipping", err);
+        return;
+    }
+    let plan = SandboxPlan::from_contributors(&[
+        &SandboxBackend::new(),
+        &CgroupNetworkBackend::new(NetworkLevel::Internet),
+    ]);
+    // The background sleep stays in the cgroup after the shell is gone.
+    let launched = std::thread::spawn(move || plan.launch(&["/bin/sh", "-c", "sleep 60 & exec sleep 60"]));
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !launched.is_finished() {
+        assert!(Instant::now() < deadline, "the sandbox outlived SIGTERM");
+        signal_sandboxes(Signal::SIGTERM);
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    let status = launched.join().unwrap().unwrap();
+    assert!(!status.success());
+}
@@ -0,0 +1,132 @@
+use nix::libc;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, ForkResult};
+use apf_core::types::NetworkLevel;
+use apf_enforcement::native::available as native_available;
+use apf_enforcement::network::NetworkBackend;
+use apf_enforcement::seccomp::{hardened_rules, syscall_number, HARDENED_DENY};
+use apf_enforcement::{
+    Bwrap, SandboxBackend, SandboxPlan, SandboxRuntime, SeccompAction, SeccompFilter, SeccompRule,
+};
+
+/// Runs `probe` in a forked child under `filter` and returns the errno it
+/// reports, or 0 when the syscall succeeded.
+fn errno_under(filter: &SeccompFilter, probe: fn() -> libc::c_long) -> i32 {
+    // SAFETY: the child only installs the filter, makes raw syscalls and exits.
+    match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            if filter.install().is_err() {
+                unsafe { libc::_exit(255) }
+            }
+            let errno = if probe() < 0 { nix::errno::errno() } else { 0 };
+            unsafe { libc::_exit(errno) }
+        }
+        ForkResult::Parent { child } => match waitpid(child, None).unwrap() {
+            WaitStatus::Exited(_, code) => code,
+            status => panic!("probe did not exit: {:?}", status),
+        },
+    }
+}
+
+fn network_none_filter() -> SeccompFilter {
+    let plan = SandboxPlan::from_contributors(&[&SandboxBackend::new(), &NetworkBackend::new(NetworkLevel::None)]);
+    plan.seccomp_filter().unwrap().unwrap()
+}
+
+#[test]
+fn test_hardened_list_compiles_on_this_architecture() {
+    for syscall in HARDENED_DENY {
+        assert!(syscall_number(syscall).is_some(), "{}", syscall);
+    }
+    let filter = SeccompFilter::compile(&hardened_rules()).unwrap();
+    assert_eq!(filter.to_bytes().len(), filter.len() * 8);
+}
+
+#[test]
+fn test_unknown_syscalls_are_skipped() {
+    let known = SeccompFilter::compile(&[SeccompRule::deny("ptrace")]).unwrap();
+    let with_unknown = SeccompFilter::compile(&[SeccompRule::deny("ptrace"), SeccompRule::deny("frobnicate")]).unwrap();
+    assert_eq!(known.len(), with_unknown.len());
+    assert!(SeccompFilter::compile(&[SeccompRule::deny("socket").when_arg(6, 0)]).is_err());
+}
+
+#[test]
+fn test_hardened_syscalls_fail_with_eperm() {
+    let filter = network_none_filter();
+    assert_eq!(errno_under(&filter, || unsafe { libc::syscall(libc::SYS_ptrace, libc::PTRACE_TRACEME, 0, 0, 0) }), libc::EPERM);
+    assert_eq!(errno_under(&filter, || unsafe { libc::syscall(libc::SYS_keyctl, 0, 0, 0, 0, 0) }), libc::EPERM);
+    assert_eq!(errno_under(&filter, || unsafe { libc::syscall(libc::SYS_bpf, 0, 0, 0) }), libc::EPERM);
+    assert_eq!(errno_under(&filter, || unsafe { libc::syscall(libc::SYS_userfaultfd, 0) }), libc::EPERM);
+    assert_eq!(errno_under(&filter, || unsafe { libc::syscall(libc::SYS_getpid) }), 0);
+}
+
+#[test]
+fn test_network_none_refuses_ip_sockets_only() {
+    let filter = network_none_filter();
+    assert_eq!(errno_under(&filter, || unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) as libc::c_long }), libc::EPERM);
+    assert_eq!(errno_under(&filter, || unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM, 0) as libc::c_long }), libc::EPERM);
+    assert_eq!(errno_under(&filter, || unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM, 0) as libc::c_long }), 0);
+
+    let internet = SandboxPlan::from_contributors(&[&SandboxBackend::new(), &NetworkBackend::new(NetworkLevel::Internet)]);
+    let filter = internet.seccomp_filter().unwrap().unwrap();
+    assert_eq!(errno_under(&filter, || unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) as libc::c_long }), 0);
+}
+
+#[test]
+fn test_int_arguments_ignore_high_bits() {
+    // The kernel truncates these to an int, so setting the high bits must
+    // not get past the rules.
+    let filter = network_none_filter();
+    assert_eq!(errno_under(&filter, || unsafe {
+        libc::syscall(libc::SYS_socket, (1i64 << 32) | libc::AF_INET as i64, libc::SOCK_STREAM, 0)
+    }), libc::EPERM);
+    assert_eq!(errno_under(&filter, || unsafe {
+        libc::syscall(libc::SYS_socket, (0xffff_ffffi64 << 32) | libc::AF_INET6 as i64, libc::SOCK_DGRAM, 0)
+    }), libc::EPERM);
+    assert_eq!(errno_under(&filter, || unsafe {
+        #[allow(clippy::unnecessary_cast)]
+        let cmd = (1i64 << 32) | libc::TIOCSTI as i64;
+        let byte = 0u8;
+        libc::syscall(libc::SYS_ioctl, 0, cmd, &byte as *const u8)
+    }), libc::EPERM);
+}
+
+#[test]
+fn test_64_bit_arguments_compare_every_bit() {
+    let filter = SeccompFilter::compile(&[SeccompRule::deny("personality").when_arg64(0, 0x1_0000_0008)]).unwrap();
+    assert_eq!(errno_under(&filter, || unsafe { libc::syscall(libc::SYS_personality, 0x1_0000_0008i64) }), libc::EPERM);
+    assert_ne!(errno_under(&filter, || unsafe { libc::syscall(libc::SYS_personality, 0xffff_ffffi64) }), libc::EPERM);
+}
+
+#[test]
+fn test_errno_action_is_configurable() {
+    let filter = SeccompFilter::compile(&[SeccompRule::new("personality", SeccompAction::Errno(libc::ENOSYS))]).unwrap();
+    assert_eq!(errno_under(&filter, || unsafe { libc::syscall(libc::SYS_personality, 0xffffffffu32) }), libc::ENOSYS);
+}
+
+#[test]
+fn test_native_sandbox_installs_the_filter() {
+    if !native_available() {
+        eprintln!("namespaces unavailable, skipping");
+        return;
+    }
+    let plan = SandboxPlan::from_contributors(&[&SandboxBackend::new()]);
+    let output = plan.command_with(SandboxRuntime::Native, &["/bin/sh", "-c", "grep ^Seccomp: /proc/self/status"])
+        .unwrap()
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8_lossy(&output.stdout).split_whitespace().collect::<Vec<_>>(), ["Seccomp:", "2"]);
+}
+
+#[test]
+fn test_bwrap_gets_the_filter_by_descriptor() {
+    let filter = SeccompFilter::compile(&hardened_rules()).unwrap();
+    let mut bwrap = Bwrap::new();
+    bwrap.unshare_all().seccomp(&filter);
+    let cmd = bwrap.command(&["true"]).unwrap();
+    let args: Vec<_> = cmd.get_args().map(|arg| arg.to_str().unwrap()).collect();
+    assert_eq!(args[..2], ["--unshare-all", "--seccomp"]);
+    assert!(args[2].parse::<i32>().is_ok());
+    assert_eq!(args[3..], ["--", "true"]);
+}
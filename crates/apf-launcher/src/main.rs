@@ -1,10 +1,24 @@
 
-use clap::Parser;
+mod prompt;
+
+use clap::{Parser, ValueEnum};
 use tracing::{info, error};
 use std::process::Command;
 // use std::os::unix::process::CommandExt;
 use std::fs;
+use std::path::PathBuf;
 use anyhow::Context;
+use apf_core::app_id::AppId;
+use apf_core::types::{self, FilesystemAccess, NetworkLevel, NetworkRule};
+use apf_enforcement::dns;
+use apf_enforcement::network::NetworkBackend;
+use apf_enforcement::plan::signal_sandboxes;
+use apf_enforcement::{
+    AccessMode, Blocklist, CgroupNetworkBackend, EgressProxyBackend, FilesystemBackend, LandlockBackend, PlanContributor, SandboxBackend, SandboxPlan,
+};
+use nix::sys::signal::Signal;
+use tokio::signal::unix::{signal, SignalKind};
+use prompt::ConnectPrompter;
 
 #[derive(Parser)]
 #[command(name = "apf-run")]
@@ -19,6 +33,134 @@ struct Args {
     app_id: Option<String>,
     #[arg(long)]
     cgroup: Option<String>,
+
+    /// Run the command in a sandbox composed from the options below
+    #[arg(long)]
+    sandbox: bool,
+    /// Network access inside the sandbox
+    #[arg(long, value_enum, default_value = "none")]
+    network: Network,
+    /// Keep the host's network namespace and enforce --network with
+    /// nftables rules on a cgroup of the sandbox's own
+    #[arg(long)]
+    host_network: bool,
+    /// Destination the sandbox may reach on top of --network, such as
+    /// host:api.example.com?port=443 or cidr:203.0.113.0/24; may be
+    /// repeated
+    #[arg(long = "allow-net", value_name = "RULE", requires = "host_network")]
+    allow_net: Vec<NetworkRule>,
+    /// Ask through the daemon before the sandbox reaches each new
+    /// destination; use with --network lan or internet
+    #[arg(long)]
+    prompt_network: bool,
+    /// Send the sandbox's traffic through a proxy that asks the daemon
+    /// about every host it reaches, by name; replaces --network
+    #[arg(long, conflicts_with_all = ["host_network", "prompt_network"])]
+    egress_proxy: bool,
+    /// Hosts file of names the egress proxy refuses to resolve or reach,
+    /// on top of /etc/appfence/dns-blocklist and the app's own
+    /// /etc/appfence/dns-blocklist.d/<app-id>; may be repeated
+    #[arg(long = "dns-blocklist", value_name = "FILE", requires = "egress_proxy")]
+    dns_blocklists: Vec<PathBuf>,
+    /// Path to make readable inside the sandbox
+    #[arg(long = "ro", value_name = "PATH")]
+    ro_paths: Vec<PathBuf>,
+    /// Path to make readable and writable inside the sandbox
+    #[arg(long = "rw", value_name = "PATH")]
+    rw_paths: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Network {
+    None,
+    Lan,
+    Internet,
+}
+
+impl From<Network> for NetworkLevel {
+    fn from(network: Network) -> Self {
+        match network {
+            Network::None => NetworkLevel::None,
+            Network::Lan => NetworkLevel::Lan,
+            Network::Internet => NetworkLevel::Internet,
+        }
+    }
+}
+
+/// The sandbox every backend contributes to; the app is launched into it
+/// once.
+fn sandbox_plan(args: &Args) -> anyhow::Result<SandboxPlan> {
+    let mut filesystem = FilesystemBackend::new();
+    let mut landlock = LandlockBackend::new();
+    for path in &args.ro_paths {
+        filesystem.add_allowed_path(path.clone(), AccessMode::ReadOnly);
+        landlock.add_rule(FilesystemAccess { path: path.clone(), mode: types::AccessMode::ReadOnly });
+    }
+    for path in &args.rw_paths {
+        filesystem.add_allowed_path(path.clone(), AccessMode::ReadWrite);
+        landlock.add_rule(FilesystemAccess { path: path.clone(), mode: types::AccessMode::ReadWrite });
+    }
+    info!("Landlock enforcement: {:?}", landlock.strength());
+    let network: Box<dyn PlanContributor> = if args.egress_proxy {
+        let mut proxy = EgressProxyBackend::new();
+        if let Some(path) = dns::app_blocklist(&app_id(args)?) {
+            proxy.block(&Blocklist::load(&path)?);
+        }
+        for path in &args.dns_blocklists {
+            proxy.block(&Blocklist::load(path)?);
+        }
+        Box::new(proxy)
+    } else if args.host_network {
+        Box::new(CgroupNetworkBackend::new(args.network.into()).allow(args.allow_net.iter().cloned()))
+    } else {
+        Box::new(NetworkBackend::new(args.network.into()))
+    };
+    let mut plan = SandboxPlan::from_contributors(&[&SandboxBackend::new(), &filesystem, &landlock, network.as_ref()]);
+    if args.prompt_network {
+        plan.prompt_connect();
+    }
+    Ok(plan)
+}
+
+/// Passes SIGINT and SIGTERM on to the sandbox instead of dying of them,
+/// so the launcher is still around to clean up its cgroup and rules once
+/// the sandbox has ended.
+fn forward_signals() -> anyhow::Result<()> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        loop {
+            let received = tokio::select! {
+                Some(()) = interrupt.recv() => Signal::SIGINT,
+                Some(()) = terminate.recv() => Signal::SIGTERM,
+                else => break,
+            };
+            info!("Passing {} on to the sandbox", received);
+            signal_sandboxes(received);
+        }
+    });
+    Ok(())
+}
+
+/// The identity prompts are made under: `--app-id` when given, otherwise
+/// the executable.
+fn app_id(args: &Args) -> anyhow::Result<AppId> {
+    if let Some(id) = &args.app_id {
+        return Ok(AppId::from_desktop(id.clone(), false));
+    }
+    let program = PathBuf::from(&args.command[0]);
+    let path = if program.components().count() > 1 {
+        program
+    } else {
+        std::env::var_os("PATH")
+            .and_then(|path| std::env::split_paths(&path).map(|dir| dir.join(&program)).find(|path| path.is_file()))
+            .with_context(|| format!("{} not found on PATH", program.display()))?
+    };
+    let user_owned = {
+        use std::os::unix::fs::MetadataExt;
+        fs::metadata(&path)?.uid() != 0
+    };
+    Ok(AppId::from_executable(&path, false, user_owned)?)
 }
 
 #[tokio::main]
@@ -53,18 +195,34 @@ async fn main() -> anyhow::Result<()> {
     }
 
     // Launch the command and track process tree
-    let mut cmd = Command::new(&args.command[0]);
-    for arg in &args.command[1..] {
-        cmd.arg(arg);
-    }
-    // Optionally set cgroup (stub)
-    // TODO: attach process to cgroup
+    let status = if args.sandbox {
+        // Waits for anything the app left running in its sandbox too.
+        let plan = sandbox_plan(&args)?;
+        forward_signals()?;
+        if plan.proxies_egress() {
+            let prompter = ConnectPrompter::new(&app_id(&args)?).await
+                .context("The egress proxy needs the AppFence daemon")?;
+            tokio::task::block_in_place(|| plan.launch_proxied(&args.command, prompter))?
+        } else if plan.prompts_connect() {
+            let prompter = ConnectPrompter::new(&app_id(&args)?).await
+                .context("Connection prompts need the AppFence daemon")?
+                .own_loopback(plan.has_own_network());
+            tokio::task::block_in_place(|| plan.launch_supervised(&args.command, move |request| prompter.decide(request)))?
+        } else {
+            tokio::task::block_in_place(|| plan.launch(&args.command))?
+        }
+    } else {
+        let mut cmd = Command::new(&args.command[0]);
+        cmd.args(&args.command[1..]);
+        // Optionally set cgroup (stub)
+        // TODO: attach process to cgroup
 
-    let mut child = cmd.spawn().context("Failed to launch application")?;
-    info!("Launched PID: {}", child.id());
+        let mut child = cmd.spawn().context("Failed to launch application")?;
+        info!("Launched PID: {}", child.id());
 
-    // Wait for process and handle failures
-    let status = child.wait().context("Failed to wait for child process")?;
+        // Wait for process and handle failures
+        child.wait().context("Failed to wait for child process")?
+    };
     if !status.success() {
         error!("Application exited with failure: {:?}", status);
         // Failure rollback logic (stub)
@@ -0,0 +1,185 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+use anyhow::{bail, Result};
+use futures_util::StreamExt;
+use tokio::runtime::Handle;
+use tracing::{debug, info, warn};
+use apf_core::app_id::AppId;
+use apf_core::error::ApfError;
+use apf_core::types::PermissionType;
+use apf_enforcement::{ConnectRequest, DnsQuery, EgressPolicy, EgressRequest};
+
+/// How long a connection waits for the user before it is refused.
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[zbus::proxy(
+    interface = "org.apf.Daemon",
+    default_service = "org.apf.Daemon",
+    default_path = "/org/apf/Daemon"
+)]
+trait Daemon {
+    fn request_permission(
+        &self,
+        app_id_json: &str,
+        pid: u32,
+        uid: u32,
+        permission_json: &str,
+    ) -> zbus::Result<(bool, String, bool)>;
+
+    fn record_dns_query(&self, app_id_json: &str, pid: u32, uid: u32, name: &str, blocked: bool) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn request_decided(&self, request_id: &str, granted: bool) -> zbus::Result<()>;
+}
+
+/// Turns an error reply of the daemon back into the `ApfError` it sent,
+/// leaving other D-Bus failures as they are.
+fn daemon_error(error: zbus::Error) -> anyhow::Error {
+    if let zbus::Error::MethodError(name, message, _) = &error {
+        if let Some(mapped) = ApfError::from_dbus(name.as_str(), message.as_deref().unwrap_or_default()) {
+            return mapped.into();
+        }
+    }
+    error.into()
+}
+
+/// Puts each new destination the sandbox reaches to the daemon, which
+/// answers from policy or prompts the user.
+pub struct ConnectPrompter {
+    handle: Handle,
+    daemon: DaemonProxy<'static>,
+    app_id_json: String,
+    uid: u32,
+    own_loopback: bool,
+    /// The sandbox the egress proxy answers, which its requests are
+    /// attributed to.
+    sandbox_pid: OnceLock<u32>,
+}
+
+impl ConnectPrompter {
+    pub async fn new(app_id: &AppId) -> Result<Self> {
+        let connection = zbus::Connection::system().await?;
+        Ok(Self {
+            handle: Handle::current(),
+            daemon: DaemonProxy::new(&connection).await?,
+            app_id_json: serde_json::to_string(app_id)?,
+            uid: nix::unistd::Uid::current().as_raw(),
+            own_loopback: false,
+            sandbox_pid: OnceLock::new(),
+        })
+    }
+
+    /// Lets connections to loopback go ahead unasked, for sandboxes with a
+    /// network namespace of their own. The host's loopback reaches the
+    /// host's services and is put to the daemon like anything else.
+    pub fn own_loopback(mut self, own: bool) -> Self {
+        self.own_loopback = own;
+        self
+    }
+
+    /// Whether the connection may go ahead. Loopback may without asking
+    /// when it is the sandbox's own; anything going wrong refuses it.
+    /// Called from the supervisor's thread, so it blocks on the runtime.
+    pub fn decide(&self, request: &ConnectRequest) -> bool {
+        if self.own_loopback && request.destination.ip().is_loopback() {
+            return true;
+        }
+        let what = format!("{} to {}", request.syscall, request.destination);
+        self.decide_permission(request.pid, &request.permission(), &what)
+    }
+
+    /// Whether the sandbox may reach a host through the egress proxy.
+    /// Every connection is put to the daemon, which logs each one. The
+    /// host's own loopback is no exception: it is not the sandbox's.
+    pub fn decide_egress(&self, request: &EgressRequest) -> bool {
+        let what = format!("{}:{} through the proxy", request.host, request.port);
+        self.decide_permission(self.sandbox_pid(), &request.permission(), &what)
+    }
+
+    /// Has the daemon write a name the sandbox looked up to the audit log.
+    pub fn record_dns_query(&self, query: &DnsQuery) {
+        let recorded = self.handle.block_on(self.daemon.record_dns_query(
+            &self.app_id_json,
+            self.sandbox_pid(),
+            self.uid,
+            &query.name,
+            query.blocked,
+        ));
+        if let Err(e) = recorded.map_err(daemon_error) {
+            match e.downcast_ref::<ApfError>() {
+                // Lookups of names that aren't host names (service records
+                // and the like) have no rule to be audited under.
+                Some(ApfError::InvalidPermission(_)) => {
+                    debug!("DNS query for {} not audited: not a host name", query.name);
+                }
+                _ => warn!("DNS query for {} not audited: {:#}", query.name, e),
+            }
+        }
+    }
+
+    /// Nothing reaches the proxy before the sandbox is started, so its pid
+    /// is known by then; this launcher's own stands in otherwise.
+    fn sandbox_pid(&self) -> u32 {
+        self.sandbox_pid.get().copied().unwrap_or_else(std::process::id)
+    }
+
+    fn decide_permission(&self, pid: u32, permission: &PermissionType, what: &str) -> bool {
+        match self.handle.block_on(self.ask(pid, permission, what)) {
+            Ok(granted) => granted,
+            Err(e) => {
+                match e.downcast_ref::<ApfError>() {
+                    Some(ApfError::NotAuthorized(detail)) => {
+                        warn!("Refusing {}: the daemon doesn't trust this launcher ({})", what, detail);
+                    }
+                    Some(ApfError::InvalidPermission(detail)) => {
+                        warn!("Refusing {}: the daemon can't express it as a permission ({})", what, detail);
+                    }
+                    _ => warn!("Refusing {}: {:#}", what, e),
+                }
+                false
+            }
+        }
+    }
+
+    async fn ask(&self, pid: u32, permission: &PermissionType, what: &str) -> Result<bool> {
+        // Listen before asking, so a quick answer isn't missed.
+        let mut decided = self.daemon.receive_request_decided().await?;
+        let permission_json = serde_json::to_string(permission)?;
+        let (pending, request_id, granted) = self.daemon
+            .request_permission(&self.app_id_json, pid, self.uid, &permission_json)
+            .await
+            .map_err(daemon_error)?;
+        if !pending {
+            return Ok(granted);
+        }
+
+        info!("Waiting for an answer to prompt {} about {}", request_id, what);
+        let answer = async {
+            while let Some(signal) = decided.next().await {
+                let args = signal.args()?;
+                if args.request_id == request_id {
+                    return Ok(args.granted);
+                }
+            }
+            bail!("The daemon went away before answering")
+        };
+        match tokio::time::timeout(PROMPT_TIMEOUT, answer).await {
+            Ok(answer) => answer,
+            Err(_) => bail!("No answer to prompt {} in time", request_id),
+        }
+    }
+}
+
+impl EgressPolicy for ConnectPrompter {
+    fn allows(&self, request: &EgressRequest) -> bool {
+        self.decide_egress(request)
+    }
+
+    fn dns_query(&self, query: &DnsQuery) {
+        self.record_dns_query(query);
+    }
+
+    fn sandbox_started(&self, pid: u32) {
+        let _ = self.sandbox_pid.set(pid);
+    }
+}
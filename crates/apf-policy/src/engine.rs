@@ -1,91 +1,134 @@
 
 use anyhow::Result;
-use apf_core::{app_id::AppId, types::{PermissionType, PromptDecision}};
+use apf_core::{app_id::AppId, types::{NetworkLevel, PermissionType, PolicyScope, PromptDecision}};
+use chrono::NaiveDateTime;
+use std::sync::Arc;
+use crate::clock::{Clock, SystemClock};
+use crate::network::covering_permissions;
+use crate::paths::{is_templated, resolved_matches};
+use crate::sensitivity::{DefaultAction, SensitivityClassifier, UserDirs, UserDirsCache};
 use crate::storage::PolicyStorage;
 
+/// The rules among `stored`, the rules of one scope, that decide a request
+/// for `permission`, in the order they are consulted. A request to reach a
+/// network destination is decided by the rules covering it, see
+/// `covering_permissions`; a filesystem request by its own rule and then by
+/// rules written with path placeholders, resolved against `dirs`; anything
+/// else by its own rule only. The daemon and the simulator both resolve
+/// requests with this.
+pub fn deciding_rules<'a>(app_id: &AppId, permission: &PermissionType, stored: &'a [(PermissionType, PromptDecision)], dirs: Option<&UserDirs>) -> Vec<&'a (PermissionType, PromptDecision)> {
+    let own = || stored.iter().filter(move |(stored, _)| stored == permission);
+    match permission {
+        PermissionType::Network(NetworkLevel::Rule(destination)) => covering_permissions(destination, stored),
+        PermissionType::Filesystem(requested) => {
+            let mut rules: Vec<_> = own().collect();
+            if let Some(dirs) = dirs.filter(|_| stored.iter().any(|(permission, _)| is_templated(permission))) {
+                rules.extend(resolved_matches(requested, stored, dirs, app_id));
+            }
+            rules
+        }
+        _ => own().collect(),
+    }
+}
+
 pub struct PolicyEngine {
     storage: PolicyStorage,
+    clock: Arc<dyn Clock>,
+    classifier: SensitivityClassifier,
+    user_dirs: UserDirsCache,
 }
 
 impl PolicyEngine {
     pub fn new(storage: PolicyStorage) -> Self {
-        Self { storage }
+        Self::with_clock(storage, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(storage: PolicyStorage, clock: Arc<dyn Clock>) -> Self {
+        Self { storage, clock, classifier: SensitivityClassifier::default(), user_dirs: UserDirsCache::default() }
+    }
+
+    /// Replaces the classifier that decides requests without a stored decision.
+    pub fn set_classifier(&mut self, classifier: SensitivityClassifier) {
+        self.classifier = classifier;
     }
 
-    pub async fn should_prompt(&self, app_id: &AppId, permission: &PermissionType) -> Result<bool> {
-        let decision = self.storage.get_decision(app_id, permission).await?;
-        
-        if decision.is_some() {
-            return Ok(false); // Don't prompt if we have a decision
+    pub async fn should_prompt(&self, app_id: &AppId, uid: u32, permission: &PermissionType) -> Result<bool> {
+        let decision = self.get_cached_decision(app_id, uid, permission).await?;
+
+        match decision {
+            Some(decision) => Ok(decision.is_ask()), // Don't prompt if we have a decision
+            None => Ok(self.default_action(uid, permission) == DefaultAction::Prompt),
         }
+    }
+
+    /// What happens to a request from `uid` that no stored decision covers.
+    pub fn default_action(&self, uid: u32, permission: &PermissionType) -> DefaultAction {
+        self.classifier.classify(uid, permission, &self.user_dirs)
+    }
 
-        let requires_prompt = Self::is_sensitive_permission(permission);
-        
-        Ok(requires_prompt)
-    }
-
-    fn is_sensitive_permission(permission: &PermissionType) -> bool {
-        use apf_core::types::{NetworkLevel, DeviceType};
-        
-        match permission {
-            PermissionType::Network(NetworkLevel::Internet) => true,
-            PermissionType::Network(NetworkLevel::Lan) => true,
-            PermissionType::Device(DeviceType::Camera) => true,
-            PermissionType::Device(DeviceType::Microphone) => true,
-            PermissionType::Device(DeviceType::Screen) => true,
-            PermissionType::Filesystem(fs) => {
-                let path_str = fs.path.to_string_lossy();
-                path_str.contains("/home") || 
-                path_str.contains("/Documents") || 
-                path_str.contains("/Downloads")
+    /// Looks up the decision for a request from `uid` made at local time `at`,
+    /// consulting scopes in `PolicyScope::resolution_order` and the rules of
+    /// each as `deciding_rules` orders them, with path placeholders resolved
+    /// for `uid`. Scheduled decisions outside their schedule are skipped.
+    pub(crate) async fn resolve_decision(&self, app_id: &AppId, uid: u32, permission: &PermissionType, at: NaiveDateTime) -> Result<Option<PromptDecision>> {
+        let dirs = match permission {
+            PermissionType::Filesystem(_) => self.user_dirs.get(uid),
+            _ => None,
+        };
+        for scope in PolicyScope::resolution_order(uid) {
+            let stored = self.storage.get_app_policies(app_id, scope).await?;
+            let decision = deciding_rules(app_id, permission, &stored, dirs.as_deref()).into_iter()
+                .map(|(_, decision)| decision)
+                .find(|decision| decision.applies_at(at));
+            if let Some(decision) = decision {
+                return Ok(Some(decision.clone()));
             }
-            PermissionType::Clipboard => true,
-            PermissionType::Autostart => true,
-            _ => false,
         }
+        Ok(None)
     }
 
-    pub async fn get_cached_decision(&self, app_id: &AppId, permission: &PermissionType) -> Result<Option<PromptDecision>> {
-        self.storage.get_decision(app_id, permission).await
+    pub async fn get_cached_decision(&self, app_id: &AppId, uid: u32, permission: &PermissionType) -> Result<Option<PromptDecision>> {
+        self.resolve_decision(app_id, uid, permission, self.clock.now()).await
     }
 
-    pub async fn store_decision(&mut self, app_id: &AppId, permission: &PermissionType, decision: PromptDecision) -> Result<()> {
-        self.storage.store_decision(app_id, permission, decision).await
+    pub async fn store_decision(&mut self, app_id: &AppId, scope: PolicyScope, permission: &PermissionType, decision: PromptDecision) -> Result<()> {
+        self.storage.store_decision(app_id, scope, permission, decision).await
     }
 
-    pub async fn get_app_policy(&self, app_id: &AppId) -> Result<Vec<(PermissionType, PromptDecision)>> {
-        self.storage.get_app_policies(app_id).await
+    pub async fn get_app_policy(&self, app_id: &AppId, scope: PolicyScope) -> Result<Vec<(PermissionType, PromptDecision)>> {
+        self.storage.get_app_policies(app_id, scope).await
     }
 
-    pub async fn update_app_policy(&mut self, app_id: &AppId, policies: Vec<(PermissionType, PromptDecision)>) -> Result<()> {
+    pub async fn update_app_policy(&mut self, app_id: &AppId, scope: PolicyScope, policies: Vec<(PermissionType, PromptDecision)>) -> Result<()> {
         for (permission, decision) in policies {
-            self.storage.store_decision(app_id, &permission, decision).await?;
+            self.storage.store_decision(app_id, scope, &permission, decision).await?;
         }
-        
+
         Ok(())
     }
 
-    pub async fn delete_policy(&mut self, app_id: &AppId, permission: &PermissionType) -> Result<()> {
-        self.storage.delete_policy(app_id, permission).await
+    pub async fn delete_policy(&mut self, app_id: &AppId, scope: PolicyScope, permission: &PermissionType) -> Result<()> {
+        self.storage.delete_policy(app_id, scope, permission).await
     }
 
-    pub async fn delete_app_policy(&mut self, app_id: &AppId) -> Result<()> {
-        self.storage.delete_app_policies(app_id).await
+    pub async fn delete_app_policy(&mut self, app_id: &AppId, scope: PolicyScope) -> Result<()> {
+        self.storage.delete_app_policies(app_id, scope).await
     }
 
     pub async fn cleanup_expired(&mut self) -> Result<usize> {
         self.storage.cleanup_expired().await
     }
 
-    pub async fn evaluate_permission(&self, app_id: &AppId, permission: &PermissionType) -> Result<Option<bool>> {
-        if let Some(decision) = self.storage.get_decision(app_id, permission).await? {
-            let granted = matches!(
-                decision,
-                PromptDecision::AllowAlways | PromptDecision::AllowOnce | PromptDecision::AllowDuration(_)
-            );
-            Ok(Some(granted))
-        } else {
-            Ok(None) // No decision found
+    pub async fn evaluate_permission(&self, app_id: &AppId, uid: u32, permission: &PermissionType) -> Result<Option<bool>> {
+        self.evaluate_permission_at(app_id, uid, permission, self.clock.now()).await
+    }
+
+    /// `None` when the request is left to the user, either because nothing
+    /// is stored or because the permission is pinned to `Ask`.
+    pub(crate) async fn evaluate_permission_at(&self, app_id: &AppId, uid: u32, permission: &PermissionType, at: NaiveDateTime) -> Result<Option<bool>> {
+        match self.resolve_decision(app_id, uid, permission, at).await? {
+            Some(decision) if !decision.is_ask() => Ok(Some(decision.is_allow())),
+            _ => Ok(None), // No decision found
         }
     }
 }
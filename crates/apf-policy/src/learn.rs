@@ -0,0 +1,29 @@
+use apf_core::types::{AccessMode, NetworkLevel, PermissionType, PromptDecision};
+
+use crate::policy_set::{AppPolicy, PolicyRule};
+
+/// Drafts a policy that grants every permission in `observed`, typically the
+/// requests an app made while in learning mode.
+///
+/// Stored decisions match requests exactly, so nothing is folded into a
+/// broader grant: each distinct permission becomes its own `AllowAlways`
+/// rule, in the order it was first observed. Requests for no access at all
+/// are dropped.
+pub fn draft_policy(app_id: &str, uid: Option<u32>, observed: &[PermissionType]) -> AppPolicy {
+    let mut needed: Vec<PermissionType> = Vec::new();
+
+    for permission in observed {
+        match permission {
+            PermissionType::Network(NetworkLevel::None) => continue,
+            PermissionType::Filesystem(fs) if fs.mode == AccessMode::Deny => continue,
+            _ if needed.contains(permission) => continue,
+            _ => needed.push(permission.clone()),
+        }
+    }
+
+    let rules = needed.into_iter()
+        .map(|permission| PolicyRule { permission, decision: PromptDecision::AllowAlways })
+        .collect();
+
+    AppPolicy { id: app_id.to_string(), uid, rules }
+}
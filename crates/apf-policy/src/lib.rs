@@ -1,6 +1,22 @@
 
+pub mod clock;
 pub mod engine;
+pub mod learn;
+pub mod lint;
+pub mod network;
+pub mod paths;
+pub mod policy_set;
+pub mod sensitivity;
+pub mod simulate;
 pub mod storage;
 
-pub use engine::PolicyEngine;
+pub use clock::{Clock, FixedClock, SystemClock};
+pub use engine::{deciding_rules, PolicyEngine};
+pub use learn::draft_policy;
+pub use lint::{lint_app, lint_app_with_dirs, lint_policy_set, LintCode, LintFinding, ScopedRule, Severity};
+pub use network::covering_permissions;
+pub use paths::{is_templated, resolved_matches};
+pub use policy_set::{AppPolicy, PolicyRule, PolicySet};
+pub use sensitivity::{DefaultAction, SensitivityClassifier, SensitivityRule, UserDirs, UserDirsCache};
+pub use simulate::{Outcome, OutcomeChange, RecordedRequest, SimulationReport};
 pub use storage::PolicyStorage;
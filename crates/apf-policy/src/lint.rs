@@ -0,0 +1,334 @@
+
+use apf_core::app_id::{AppId, AppOrigin};
+use apf_core::types::{AccessMode, DeviceType, FilesystemAccess, NetworkLevel, PathVar, PermissionType, PolicyScope, PromptDecision};
+use serde::{Deserialize, Serialize};
+use std::path::Component;
+
+use crate::network::covering_permissions;
+use crate::paths::is_templated;
+use crate::policy_set::PolicySet;
+use crate::sensitivity::UserDirs;
+
+/// A stored rule together with the scope it was stored in.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ScopedRule {
+    pub scope: PolicyScope,
+    pub permission: PermissionType,
+    pub decision: PromptDecision,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LintCode {
+    /// Two rules on the same target disagree, or one limits access inside a
+    /// directory another grants more of.
+    Conflict,
+    /// A rule can never change the outcome because another rule, the same
+    /// or a broader one consulted before it, already decides it.
+    Shadowed,
+    /// Write access to the whole filesystem or a home directory.
+    BroadWrite,
+    /// Access to SSH or GnuPG key material.
+    CredentialAccess,
+    /// Internet access combined with a capture device for an app of unknown
+    /// origin.
+    CaptureExfiltration,
+}
+
+impl LintCode {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Conflict => "conflict",
+            Self::Shadowed => "shadowed",
+            Self::BroadWrite => "broad-write",
+            Self::CredentialAccess => "credential-access",
+            Self::CaptureExfiltration => "capture-exfiltration",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LintFinding {
+    pub app_id: String,
+    pub severity: Severity,
+    pub code: LintCode,
+    pub message: String,
+    pub rules: Vec<ScopedRule>,
+}
+
+/// Lints the rules that apply to one app. `rules` may mix the system scope
+/// with any number of user scopes; each user scope is checked together with
+/// the system rules that take precedence over it, with path placeholders
+/// resolved for that user.
+///
+/// `origin` is `None` when it is not known, e.g. for rules from a policy file.
+pub fn lint_app(app_id: &str, origin: Option<&AppOrigin>, rules: &[ScopedRule]) -> Vec<LintFinding> {
+    lint_app_with_dirs(app_id, origin, rules, UserDirs::for_uid)
+}
+
+/// Like `lint_app`, looking users' directories up with `dirs`.
+pub fn lint_app_with_dirs(app_id: &str, origin: Option<&AppOrigin>, rules: &[ScopedRule], dirs: impl Fn(u32) -> Option<UserDirs>) -> Vec<LintFinding> {
+    let app = match origin {
+        Some(AppOrigin::Flatpak) => AppId::from_flatpak(app_id),
+        _ => AppId::from_desktop(app_id, origin == Some(&AppOrigin::System)),
+    };
+    let mut users: Vec<PolicyScope> = rules.iter()
+        .map(|rule| rule.scope)
+        .filter(|scope| *scope != PolicyScope::System)
+        .collect();
+    users.sort();
+    users.dedup();
+
+    let system: Vec<&ScopedRule> = rules.iter().filter(|rule| rule.scope == PolicyScope::System).collect();
+    let mut findings = lint_effective(&app, origin, &system, None, None);
+
+    for user in users {
+        let effective: Vec<&ScopedRule> = rules.iter()
+            .filter(|rule| rule.scope == PolicyScope::System || rule.scope == user)
+            .collect();
+        let user_dirs = match user {
+            PolicyScope::User(uid) if effective.iter().any(|rule| is_templated(&rule.permission)) => dirs(uid),
+            _ => None,
+        };
+        findings.extend(lint_effective(&app, origin, &effective, Some(user), user_dirs.as_ref()));
+    }
+
+    findings
+}
+
+/// Lints every app in a policy file.
+pub fn lint_policy_set(set: &PolicySet) -> Vec<LintFinding> {
+    let mut ids: Vec<&str> = set.apps.iter().map(|app| app.id.as_str()).collect();
+    ids.sort();
+    ids.dedup();
+
+    let mut findings = Vec::new();
+    for id in ids {
+        let rules: Vec<ScopedRule> = set.apps.iter()
+            .filter(|app| app.id == id)
+            .flat_map(|app| app.rules.iter().map(move |rule| ScopedRule {
+                scope: app.scope(),
+                permission: rule.permission.clone(),
+                decision: rule.decision.clone(),
+            }))
+            .collect();
+        findings.extend(lint_app(id, None, &rules));
+    }
+    findings
+}
+
+/// Lints one effective rule set. With `focus` set, only findings involving a
+/// rule from that scope are reported, so system-only findings are not
+/// repeated for every user. Placeholders are resolved against `dirs` where
+/// given, and otherwise only compared with paths under the same one.
+fn lint_effective(app: &AppId, origin: Option<&AppOrigin>, rules: &[&ScopedRule], focus: Option<PolicyScope>, dirs: Option<&UserDirs>) -> Vec<LintFinding> {
+    let app_id = &app.primary;
+    let resolve = |fs: &FilesystemAccess| dirs.and_then(|dirs| dirs.resolve(fs, app)).unwrap_or_else(|| fs.clone());
+    let in_focus = |involved: &[&ScopedRule]| focus.is_none_or(|scope| involved.iter().any(|rule| rule.scope == scope));
+    let mut findings = Vec::new();
+    let mut report = |severity, code, message: String, involved: &[&ScopedRule]| {
+        if in_focus(involved) {
+            findings.push(LintFinding {
+                app_id: app_id.to_string(),
+                severity,
+                code,
+                message,
+                rules: involved.iter().map(|rule| (*rule).clone()).collect(),
+            });
+        }
+    };
+
+    for (i, a) in rules.iter().enumerate() {
+        for b in &rules[i + 1..] {
+            check_pair(a, b, &resolve, &mut report);
+        }
+    }
+
+    for rule in rules {
+        if !rule.decision.is_allow() {
+            continue;
+        }
+        if let PermissionType::Filesystem(fs) = &rule.permission {
+            if fs.mode == AccessMode::ReadWrite && is_broad_path(fs) {
+                report(Severity::Error, LintCode::BroadWrite,
+                    format!("Read-write access to {}", fs.path.display()), &[rule]);
+            }
+            if fs.mode != AccessMode::Deny && is_credential_path(fs) {
+                report(Severity::Error, LintCode::CredentialAccess,
+                    format!("Access to key material under {}", fs.path.display()), &[rule]);
+            }
+        }
+    }
+
+    if !matches!(origin, Some(AppOrigin::System) | Some(AppOrigin::Flatpak)) {
+        // Any host or address the app may reach can carry the capture off.
+        let network = rules.iter().find(|rule| {
+            rule.decision.is_allow() && matches!(rule.permission,
+                PermissionType::Network(NetworkLevel::Internet) | PermissionType::Network(NetworkLevel::Rule(_)))
+        });
+        if let Some(network) = network {
+            for rule in rules {
+                if let PermissionType::Device(device) = &rule.permission {
+                    if rule.decision.is_allow() && is_capture_device(device) {
+                        report(Severity::Warning, LintCode::CaptureExfiltration,
+                            format!("Network access ({}) combined with {:?} for an app of unknown origin", network.permission, device),
+                            &[network, rule]);
+                    }
+                }
+            }
+        }
+    }
+
+    findings
+}
+
+fn check_pair(
+    a: &ScopedRule,
+    b: &ScopedRule,
+    resolve: &impl Fn(&FilesystemAccess) -> FilesystemAccess,
+    report: &mut impl FnMut(Severity, LintCode, String, &[&ScopedRule]),
+) {
+    // Scheduled rules only apply part of the time and cannot shadow or
+    // contradict anything outright.
+    if a.decision.schedule().is_some() || b.decision.schedule().is_some() {
+        return;
+    }
+
+    if a.permission != b.permission {
+        match (&a.permission, &b.permission) {
+            (PermissionType::Network(_), PermissionType::Network(_)) => {
+                check_network_pair(a, b, report);
+                check_network_pair(b, a, report);
+            }
+            (PermissionType::Filesystem(fa), PermissionType::Filesystem(fb)) => {
+                let (fa, fb) = (resolve(fa), resolve(fb));
+                check_nested_paths(a, &fa, b, &fb, report);
+                check_nested_paths(b, &fb, a, &fa, report);
+            }
+            _ => {}
+        }
+        return;
+    }
+
+    if a.scope != b.scope {
+        let (system, user) = if a.scope == PolicyScope::System { (a, b) } else { (b, a) };
+        report(Severity::Warning, LintCode::Shadowed,
+            format!("{} rule is overridden by the system-wide rule for {:?}", user.scope, user.permission),
+            &[user, system]);
+    } else if a.decision != b.decision {
+        report(Severity::Error, LintCode::Conflict,
+            format!("Conflicting {} rules for {:?}: {:?} and {:?}", a.scope, a.permission, a.decision, b.decision),
+            &[a, b]);
+    } else {
+        report(Severity::Warning, LintCode::Shadowed,
+            format!("Duplicate {} rules for {:?}", a.scope, a.permission), &[a, b]);
+    }
+}
+
+/// Reports `rule`, a per-destination network rule, when `other` decides
+/// every connection it covers before it is consulted: `other` is a system
+/// rule covering it, or comes first among the rules `covering_permissions`
+/// orders for it.
+fn check_network_pair(rule: &ScopedRule, other: &ScopedRule, report: &mut impl FnMut(Severity, LintCode, String, &[&ScopedRule])) {
+    let PermissionType::Network(NetworkLevel::Rule(destinations)) = &rule.permission else {
+        return;
+    };
+    let other_entry = (other.permission.clone(), other.decision.clone());
+    if rule.scope != other.scope {
+        if other.scope == PolicyScope::System && !covering_permissions(destinations, std::slice::from_ref(&other_entry)).is_empty() {
+            report(Severity::Warning, LintCode::Shadowed,
+                format!("{} rule for {} is overridden by the system-wide rule for {}", rule.scope, rule.permission, other.permission),
+                &[rule, other]);
+        }
+        return;
+    }
+    let stored = [(rule.permission.clone(), rule.decision.clone()), other_entry];
+    if covering_permissions(destinations, &stored).first() == Some(&&stored[1]) {
+        report(Severity::Warning, LintCode::Shadowed,
+            format!("{} rule for {} is never consulted: {} decides first", rule.scope, rule.permission, other.permission),
+            &[rule, other]);
+    }
+}
+
+/// Reports `inner` when its path lies below that of `outer` and it grants
+/// less: neither bind mounts nor Landlock can take access away inside a
+/// directory already granted more.
+fn check_nested_paths(
+    inner: &ScopedRule,
+    inner_fs: &FilesystemAccess,
+    outer: &ScopedRule,
+    outer_fs: &FilesystemAccess,
+    report: &mut impl FnMut(Severity, LintCode, String, &[&ScopedRule]),
+) {
+    // Asking neither grants nor denies anything to compare.
+    if inner.decision.is_ask() || outer.decision.is_ask() {
+        return;
+    }
+    if inner_fs.path == outer_fs.path || !inner_fs.path.starts_with(&outer_fs.path) {
+        return;
+    }
+    if !outer.decision.is_allow() || outer_fs.mode == AccessMode::Deny {
+        return;
+    }
+    let limit = access_limit(inner_fs, &inner.decision);
+    if access_rank(&limit) < access_rank(&outer_fs.mode) {
+        report(Severity::Error, LintCode::Conflict,
+            format!("{} is limited to {:?} inside {}, which grants {:?}; the limit can't be enforced",
+                inner.permission, limit, outer.permission, outer_fs.mode),
+            &[inner, outer]);
+    }
+}
+
+/// The most access a filesystem rule leaves its path: the mode it grants,
+/// or for a denial, the mode below the one denied.
+fn access_limit(fs: &FilesystemAccess, decision: &PromptDecision) -> AccessMode {
+    match (decision.is_allow(), &fs.mode) {
+        (true, mode) => mode.clone(),
+        (false, AccessMode::ReadWrite) => AccessMode::ReadOnly,
+        (false, _) => AccessMode::Deny,
+    }
+}
+
+fn access_rank(mode: &AccessMode) -> u8 {
+    match mode {
+        AccessMode::Deny => 0,
+        AccessMode::ReadOnly => 1,
+        AccessMode::ReadWrite => 2,
+    }
+}
+
+/// `/`, `/home`, `/root`, a direct child of `/home`, or `$HOME`.
+fn is_broad_path(fs: &FilesystemAccess) -> bool {
+    if fs.path_var() == Some(PathVar::Home) {
+        return fs.path.components().count() == 1;
+    }
+    let components: Vec<Component> = fs.path.components().collect();
+    match components.as_slice() {
+        [Component::RootDir] => true,
+        [Component::RootDir, Component::Normal(dir)] => *dir == "home" || *dir == "root",
+        [Component::RootDir, Component::Normal(dir), Component::Normal(_)] => *dir == "home",
+        _ => false,
+    }
+}
+
+/// A path through `.ssh` or `.gnupg`, wherever the home directory is, or
+/// gpg-agent's sockets under `$XDG_RUNTIME_DIR/gnupg`.
+fn is_credential_path(fs: &FilesystemAccess) -> bool {
+    if fs.path_var() == Some(PathVar::Runtime) && fs.path.components().nth(1) == Some(Component::Normal("gnupg".as_ref())) {
+        return true;
+    }
+    fs.path.components().any(|component| {
+        matches!(component, Component::Normal(name) if name == ".ssh" || name == ".gnupg")
+    })
+}
+
+fn is_capture_device(device: &DeviceType) -> bool {
+    matches!(device, DeviceType::Microphone | DeviceType::Camera | DeviceType::Screen)
+}
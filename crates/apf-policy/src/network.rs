@@ -0,0 +1,30 @@
+
+use apf_core::types::{NetworkLevel, NetworkRule, PermissionType, PromptDecision};
+
+/// The stored permissions that can decide a request to reach
+/// `destination`, in the order they are consulted.
+///
+/// Rules covering the destination come first, the most specific one
+/// leading and denials ahead of grants among equally specific rules. The
+/// network levels follow: `Lan` and then `Internet` for a destination on
+/// the local network, `Internet` for anything else.
+pub fn covering_permissions<'a>(destination: &NetworkRule, stored: &'a [(PermissionType, PromptDecision)]) -> Vec<&'a (PermissionType, PromptDecision)> {
+    let mut rules: Vec<_> = stored.iter()
+        .filter_map(|entry| match &entry.0 {
+            PermissionType::Network(NetworkLevel::Rule(rule)) if rule.covers(destination) => Some((rule, entry)),
+            _ => None,
+        })
+        .collect();
+    rules.sort_by_key(|(rule, (_, decision))| std::cmp::Reverse((rule.specificity(), !decision.is_allow())));
+    let mut rules: Vec<_> = rules.into_iter().map(|(_, entry)| entry).collect();
+
+    let levels: &[NetworkLevel] = if destination.is_lan() {
+        &[NetworkLevel::Lan, NetworkLevel::Internet]
+    } else {
+        &[NetworkLevel::Internet]
+    };
+    for level in levels {
+        rules.extend(stored.iter().filter(|(permission, _)| *permission == PermissionType::Network(level.clone())));
+    }
+    rules
+}
@@ -0,0 +1,23 @@
+
+use apf_core::{app_id::AppId, types::{FilesystemAccess, PermissionType, PromptDecision}};
+
+use crate::sensitivity::UserDirs;
+
+/// Stored filesystem permissions whose path starts with a placeholder and,
+/// resolved against `dirs` for `app_id`, asks for exactly `requested`.
+pub fn resolved_matches<'a>(requested: &FilesystemAccess, stored: &'a [(PermissionType, PromptDecision)], dirs: &UserDirs, app_id: &AppId) -> Vec<&'a (PermissionType, PromptDecision)> {
+    stored.iter()
+        .filter(|(permission, _)| match permission {
+            PermissionType::Filesystem(fs) if fs.path_var().is_some() => {
+                dirs.resolve(fs, app_id).as_ref() == Some(requested)
+            }
+            _ => false,
+        })
+        .collect()
+}
+
+/// Whether `permission` is a filesystem permission written with a path
+/// placeholder.
+pub fn is_templated(permission: &PermissionType) -> bool {
+    matches!(permission, PermissionType::Filesystem(fs) if fs.path_var().is_some())
+}
@@ -0,0 +1,100 @@
+
+use anyhow::{Context, Result};
+use apf_core::types::{PermissionType, PolicyScope, PromptDecision};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A set of per-app rules loaded from a policy file.
+///
+/// Policy files are TOML (`.toml`) or JSON (anything else):
+///
+/// ```toml
+/// [[app]]
+/// id = "org.example.Chat"
+/// uid = 1000  # omit for a system-wide rule
+///
+/// [[app.rule]]
+/// permission = "net:internet"
+/// decision = "AllowAlways"
+/// ```
+///
+/// Permissions are written in their textual form; the serde form of
+/// `PermissionType`, e.g. `{ Network = "Internet" }`, is accepted as well.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct PolicySet {
+    #[serde(default, rename = "app")]
+    pub apps: Vec<AppPolicy>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AppPolicy {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<PolicyRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PolicyRule {
+    #[serde(with = "permission_text")]
+    pub permission: PermissionType,
+    pub decision: PromptDecision,
+}
+
+impl PolicySet {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read policy file {}", path.display()))?;
+
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            Self::from_toml(&content)
+                .with_context(|| format!("Invalid policy file {}", path.display()))
+        } else {
+            Self::from_json(&content)
+                .with_context(|| format!("Invalid policy file {}", path.display()))
+        }
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    pub fn from_json(content: &str) -> Result<Self> {
+        Ok(serde_json::from_str(content)?)
+    }
+
+    pub fn app(&self, id: &str, scope: PolicyScope) -> Option<&AppPolicy> {
+        self.apps.iter().find(|app| app.id == id && app.scope() == scope)
+    }
+}
+
+impl AppPolicy {
+    pub fn scope(&self) -> PolicyScope {
+        self.uid.map_or(PolicyScope::System, PolicyScope::User)
+    }
+}
+
+mod permission_text {
+    use apf_core::types::PermissionType;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(permission: &PermissionType, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(permission)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PermissionType, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Typed(PermissionType),
+            Text(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Typed(permission) => Ok(permission),
+            Repr::Text(text) => text.parse().map_err(D::Error::custom),
+        }
+    }
+}
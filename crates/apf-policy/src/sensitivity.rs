@@ -0,0 +1,452 @@
+
+use anyhow::{Context, Result};
+use apf_core::app_id::{AppId, AppOrigin};
+use apf_core::types::{AccessMode, DeviceType, FilesystemAccess, HostPattern, NetworkLevel, PathVar, PermissionType};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Read;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Largest `user-dirs.dirs` read; the real file is a few hundred bytes.
+const USER_DIRS_MAX: u64 = 16 * 1024;
+
+/// How long a user's directories are reused before they are looked up again.
+const USER_DIRS_TTL: Duration = Duration::from_secs(60);
+
+/// What happens to a request no stored decision covers.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DefaultAction {
+    Allow,
+    Prompt,
+    Deny,
+}
+
+/// Built-in classification, used when no configuration file is installed.
+pub const DEFAULT_CONFIG: &str = r#"
+default = "deny"
+
+[[rule]]
+class = "net:none"
+action = "allow"
+
+[[rule]]
+class = "net"
+action = "prompt"
+
+[[rule]]
+class = "device"
+action = "prompt"
+
+[[rule]]
+class = "clipboard"
+action = "prompt"
+
+[[rule]]
+class = "background"
+action = "prompt"
+
+[[rule]]
+class = "autostart"
+action = "prompt"
+
+[[rule]]
+class = "fs"
+path = "dotfiles"
+action = "prompt"
+
+[[rule]]
+class = "fs"
+path = "xdg:documents"
+action = "prompt"
+
+[[rule]]
+class = "fs"
+path = "xdg:download"
+action = "prompt"
+
+[[rule]]
+class = "fs"
+path = "xdg:pictures"
+action = "prompt"
+
+[[rule]]
+class = "fs"
+path = "~"
+action = "prompt"
+
+[[rule]]
+class = "fs"
+path = "/usr"
+mode = "ReadOnly"
+action = "allow"
+"#;
+
+const CLASSES: &[&str] = &[
+    "net", "net:none", "net:lan", "net:internet", "net:host", "net:cidr",
+    "fs",
+    "device", "device:microphone", "device:camera", "device:screen", "device:usb",
+    "clipboard", "background", "autostart",
+];
+
+/// Decides the default action for each permission class. Classes are the
+/// prefixes of the textual permission form. Rules are tried in order and
+/// the first match wins; `default` applies when none matches.
+///
+/// ```toml
+/// default = "deny"
+///
+/// [[rule]]
+/// class = "fs"              # or "net:lan", "device:camera", "clipboard", ...
+/// path = "xdg:documents"    # "dotfiles", "~/dir" or "/abs/dir"; fs only
+/// mode = "ReadWrite"        # optional, fs only
+/// action = "prompt"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SensitivityClassifier {
+    pub default: DefaultAction,
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<SensitivityRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SensitivityRule {
+    pub class: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<AccessMode>,
+    pub action: DefaultAction,
+}
+
+/// The XDG base and user directories of one user.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserDirs {
+    pub home: PathBuf,
+    pub desktop: PathBuf,
+    pub documents: PathBuf,
+    pub download: PathBuf,
+    pub music: PathBuf,
+    pub pictures: PathBuf,
+    pub videos: PathBuf,
+    pub config: PathBuf,
+    pub data: PathBuf,
+    pub cache: PathBuf,
+    /// `XDG_RUNTIME_DIR`; unknown when only the home directory is.
+    pub runtime: Option<PathBuf>,
+}
+
+impl Default for SensitivityClassifier {
+    fn default() -> Self {
+        Self::from_toml(DEFAULT_CONFIG).expect("built-in sensitivity config is valid")
+    }
+}
+
+impl SensitivityClassifier {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read sensitivity config {}", path.display()))?;
+        Self::from_toml(&content)
+            .with_context(|| format!("Invalid sensitivity config {}", path.display()))
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self> {
+        let classifier: Self = toml::from_str(content)?;
+        for rule in &classifier.rules {
+            if !CLASSES.contains(&rule.class.as_str()) {
+                anyhow::bail!("Unknown permission class {:?}", rule.class);
+            }
+            if (rule.path.is_some() || rule.mode.is_some()) && rule.class != "fs" {
+                anyhow::bail!("path and mode only apply to the fs class, not {:?}", rule.class);
+            }
+            if let Some(path) = &rule.path {
+                let valid = path == "dotfiles"
+                    || path.starts_with('/')
+                    || path == "~"
+                    || path.starts_with("~/")
+                    || path.strip_prefix("xdg:").is_some_and(|name| UserDirs::KNOWN.contains(&name));
+                if !valid {
+                    anyhow::bail!("Unknown path pattern {:?}", path);
+                }
+            }
+        }
+        Ok(classifier)
+    }
+
+    /// Classifies a request made by `uid`, resolving home-relative rules
+    /// against that user's directories from `dirs`.
+    pub fn classify(&self, uid: u32, permission: &PermissionType, dirs: &UserDirsCache) -> DefaultAction {
+        let dirs = match permission {
+            PermissionType::Filesystem(_) => dirs.get(uid),
+            _ => None,
+        };
+        self.classify_for(permission, dirs.as_deref())
+    }
+
+    /// Classifies `permission`. Without `dirs`, home-relative rules never match.
+    pub fn classify_for(&self, permission: &PermissionType, dirs: Option<&UserDirs>) -> DefaultAction {
+        self.rules.iter()
+            .find(|rule| rule.matches(permission, dirs))
+            .map_or(self.default, |rule| rule.action)
+    }
+}
+
+impl SensitivityRule {
+    fn matches(&self, permission: &PermissionType, dirs: Option<&UserDirs>) -> bool {
+        let (kind, sub) = permission_class(permission);
+        let class_matches = match self.class.split_once(':') {
+            Some((rule_kind, rule_sub)) => rule_kind == kind && Some(rule_sub) == sub,
+            None => self.class == kind,
+        };
+        if !class_matches {
+            return false;
+        }
+
+        let PermissionType::Filesystem(fs) = permission else {
+            return true;
+        };
+        if self.mode.as_ref().is_some_and(|mode| *mode != fs.mode) {
+            return false;
+        }
+        match &self.path {
+            None => true,
+            Some(pattern) => path_matches(pattern, &fs.path, dirs),
+        }
+    }
+}
+
+impl UserDirs {
+    const KNOWN: &'static [&'static str] = &[
+        "desktop", "documents", "download", "music", "pictures", "videos", "config", "data", "cache",
+    ];
+
+    /// The XDG defaults for a home directory.
+    pub fn new(home: impl Into<PathBuf>) -> Self {
+        let home = home.into();
+        Self {
+            desktop: home.join("Desktop"),
+            documents: home.join("Documents"),
+            download: home.join("Downloads"),
+            music: home.join("Music"),
+            pictures: home.join("Pictures"),
+            videos: home.join("Videos"),
+            config: home.join(".config"),
+            data: home.join(".local/share"),
+            cache: home.join(".cache"),
+            runtime: None,
+            home,
+        }
+    }
+
+    /// Directories of the user with `uid`: the home directory from the
+    /// password database, the user directories from its `user-dirs.dirs`
+    /// and the runtime directory logind creates. The user's environment is
+    /// not visible here, so base directories keep their defaults.
+    pub fn for_uid(uid: u32) -> Option<Self> {
+        let user = nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(uid)).ok()??;
+        let mut dirs = Self::new(user.dir);
+        dirs.runtime = Some(PathBuf::from(format!("/run/user/{}", uid)));
+        dirs.load_user_dirs(uid);
+        Some(dirs)
+    }
+
+    /// Directories of the user running this process, honouring the XDG
+    /// environment variables.
+    pub fn for_current_user() -> Option<Self> {
+        let env_dir = |name: &str| std::env::var_os(name).map(PathBuf::from).filter(|dir| dir.is_absolute());
+        let mut dirs = Self::new(env_dir("HOME")?);
+        if let Some(config) = env_dir("XDG_CONFIG_HOME") {
+            dirs.config = config;
+        }
+        if let Some(data) = env_dir("XDG_DATA_HOME") {
+            dirs.data = data;
+        }
+        if let Some(cache) = env_dir("XDG_CACHE_HOME") {
+            dirs.cache = cache;
+        }
+        dirs.runtime = env_dir("XDG_RUNTIME_DIR");
+        dirs.load_user_dirs(nix::unistd::geteuid().as_raw());
+        Some(dirs)
+    }
+
+    /// Applies the `user-dirs.dirs` of the user with `owner`. The file is
+    /// the user's to write while this may run as root, so only a regular
+    /// file of theirs, not reached through a symlink and of sane size, is
+    /// read; opening doesn't wait on a FIFO.
+    fn load_user_dirs(&mut self, owner: u32) {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::libc::O_NOFOLLOW | nix::libc::O_NONBLOCK)
+            .open(self.config.join("user-dirs.dirs"));
+        let Ok(file) = file else {
+            return;
+        };
+        let Ok(metadata) = file.metadata() else {
+            return;
+        };
+        if !metadata.is_file() || metadata.uid() != owner || metadata.len() > USER_DIRS_MAX {
+            return;
+        }
+        let mut content = String::new();
+        if file.take(USER_DIRS_MAX).read_to_string(&mut content).is_ok() {
+            self.apply_user_dirs(&content);
+        }
+    }
+
+    /// Takes the user directories from the contents of a `user-dirs.dirs`
+    /// file, e.g. `XDG_DOCUMENTS_DIR="$HOME/Dokumente"`. Entries must name
+    /// a directory below the home directory, without `..`; others are
+    /// ignored, so the file can't stretch rules on user directories to the
+    /// rest of the system.
+    pub fn apply_user_dirs(&mut self, content: &str) {
+        for line in content.lines().map(str::trim) {
+            if line.starts_with('#') {
+                continue;
+            }
+            let Some((name, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches('"');
+            let path = match value.strip_prefix("$HOME") {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => self.home.join(rest.trim_start_matches('/')),
+                Some(_) => continue,
+                None if value.starts_with('/') => PathBuf::from(value),
+                None => continue,
+            };
+            if path.components().any(|component| component == Component::ParentDir) || !path.starts_with(&self.home) {
+                continue;
+            }
+            let dir = match name.trim() {
+                "XDG_DESKTOP_DIR" => &mut self.desktop,
+                "XDG_DOCUMENTS_DIR" => &mut self.documents,
+                "XDG_DOWNLOAD_DIR" => &mut self.download,
+                "XDG_MUSIC_DIR" => &mut self.music,
+                "XDG_PICTURES_DIR" => &mut self.pictures,
+                "XDG_VIDEOS_DIR" => &mut self.videos,
+                _ => continue,
+            };
+            *dir = path;
+        }
+    }
+
+    /// The directory `var` stands for when `app_id` makes a request. An
+    /// app's data directory is the one Flatpak gives it, or a directory
+    /// named after its id under `data` otherwise.
+    pub fn path_var(&self, var: PathVar, app_id: &AppId) -> Option<PathBuf> {
+        let dir = match var {
+            PathVar::Home => self.home.clone(),
+            PathVar::Desktop => self.desktop.clone(),
+            PathVar::Documents => self.documents.clone(),
+            PathVar::Download => self.download.clone(),
+            PathVar::Music => self.music.clone(),
+            PathVar::Pictures => self.pictures.clone(),
+            PathVar::Videos => self.videos.clone(),
+            PathVar::Runtime => self.runtime.clone()?,
+            PathVar::AppData if app_id.origin == AppOrigin::Flatpak => {
+                self.home.join(".var/app").join(&app_id.primary).join("data")
+            }
+            PathVar::AppData => self.data.join(app_id.primary.trim_start_matches('/').replace('/', "_")),
+        };
+        Some(dir)
+    }
+
+    /// `access` with its placeholder resolved for a request from `app_id`.
+    pub fn resolve(&self, access: &FilesystemAccess, app_id: &AppId) -> Option<FilesystemAccess> {
+        access.resolve(|var| self.path_var(var, app_id))
+    }
+
+    fn xdg_dir(&self, name: &str) -> Option<&Path> {
+        let dir = match name {
+            "desktop" => &self.desktop,
+            "documents" => &self.documents,
+            "download" => &self.download,
+            "music" => &self.music,
+            "pictures" => &self.pictures,
+            "videos" => &self.videos,
+            "config" => &self.config,
+            "data" => &self.data,
+            "cache" => &self.cache,
+            _ => return None,
+        };
+        Some(dir)
+    }
+}
+
+/// Users' directories, looked up once per uid and reused for a while, so
+/// requests don't each read the user's files.
+#[derive(Debug, Default)]
+pub struct UserDirsCache {
+    entries: Mutex<HashMap<u32, CachedUserDirs>>,
+}
+
+/// When a user's directories were looked up, and what was found.
+type CachedUserDirs = (Instant, Option<Arc<UserDirs>>);
+
+impl UserDirsCache {
+    /// The directories of the user with `uid`, see `UserDirs::for_uid`.
+    pub fn get(&self, uid: u32) -> Option<Arc<UserDirs>> {
+        let now = Instant::now();
+        if let Some((looked_up, dirs)) = self.entries.lock().expect("user dirs cache poisoned").get(&uid) {
+            if now.duration_since(*looked_up) < USER_DIRS_TTL {
+                return dirs.clone();
+            }
+        }
+        // Looked up without the lock, so one user's slow home directory
+        // holds up no one else.
+        let dirs = UserDirs::for_uid(uid).map(Arc::new);
+        let mut entries = self.entries.lock().expect("user dirs cache poisoned");
+        entries.retain(|_, (looked_up, _)| now.duration_since(*looked_up) < USER_DIRS_TTL);
+        entries.insert(uid, (now, dirs.clone()));
+        dirs
+    }
+}
+
+fn permission_class(permission: &PermissionType) -> (&'static str, Option<&'static str>) {
+    match permission {
+        PermissionType::Network(level) => ("net", Some(match level {
+            NetworkLevel::None => "none",
+            NetworkLevel::Lan => "lan",
+            NetworkLevel::Internet => "internet",
+            NetworkLevel::Rule(rule) => match rule.host {
+                HostPattern::Domain(_) => "host",
+                HostPattern::Cidr(_) => "cidr",
+            },
+        })),
+        PermissionType::Filesystem(_) => ("fs", None),
+        PermissionType::Device(device) => ("device", Some(match device {
+            DeviceType::Microphone => "microphone",
+            DeviceType::Camera => "camera",
+            DeviceType::Screen => "screen",
+            DeviceType::Usb => "usb",
+        })),
+        PermissionType::Clipboard => ("clipboard", None),
+        PermissionType::BackgroundExecution => ("background", None),
+        PermissionType::Autostart => ("autostart", None),
+    }
+}
+
+fn path_matches(pattern: &str, path: &Path, dirs: Option<&UserDirs>) -> bool {
+    if pattern.starts_with('/') {
+        return path.starts_with(pattern);
+    }
+
+    let Some(dirs) = dirs else {
+        return false;
+    };
+    if pattern == "dotfiles" {
+        return path.strip_prefix(&dirs.home).ok()
+            .and_then(|rest| rest.components().next())
+            .is_some_and(|first| matches!(first, Component::Normal(name) if name.to_string_lossy().starts_with('.')));
+    }
+    if let Some(name) = pattern.strip_prefix("xdg:") {
+        return dirs.xdg_dir(name).is_some_and(|dir| path.starts_with(dir));
+    }
+    match pattern.strip_prefix('~') {
+        Some(rest) => path.starts_with(dirs.home.join(rest.trim_start_matches('/'))),
+        None => false,
+    }
+}
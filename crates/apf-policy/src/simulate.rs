@@ -0,0 +1,123 @@
+
+use anyhow::Result;
+use apf_core::{app_id::AppId, types::PermissionType};
+use chrono::{Local, NaiveDateTime, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::engine::PolicyEngine;
+use crate::sensitivity::DefaultAction;
+
+/// What a permission request resolved to, as seen by the requesting app.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Outcome {
+    Granted,
+    Denied,
+    Prompted,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Granted => "granted",
+            Self::Denied => "denied",
+            Self::Prompted => "prompted",
+        }
+    }
+}
+
+/// A permission request taken from the audit history.
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub timestamp: i64,
+    pub app_id: AppId,
+    pub uid: u32,
+    pub permission: PermissionType,
+    pub outcome: Outcome,
+}
+
+impl RecordedRequest {
+    /// Builds a record from the `granted`/`was_prompted` columns of an audit entry.
+    ///
+    /// A prompted request counts as `Prompted` regardless of the answer given,
+    /// since a candidate policy can only decide whether the prompt happens.
+    pub fn from_audit(timestamp: i64, app_id: AppId, uid: u32, permission: PermissionType, granted: bool, was_prompted: bool) -> Self {
+        let outcome = match (was_prompted, granted) {
+            (true, _) => Outcome::Prompted,
+            (false, true) => Outcome::Granted,
+            (false, false) => Outcome::Denied,
+        };
+        Self { timestamp, app_id, uid, permission, outcome }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OutcomeChange {
+    pub timestamp: i64,
+    pub uid: u32,
+    pub permission: PermissionType,
+    pub before: Outcome,
+    pub after: Outcome,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SimulationReport {
+    pub replayed: usize,
+    /// Changed outcomes grouped by `AppId::primary`.
+    pub changes: BTreeMap<String, Vec<OutcomeChange>>,
+}
+
+impl SimulationReport {
+    pub fn changed_count(&self) -> usize {
+        self.changes.values().map(Vec::len).sum()
+    }
+}
+
+impl PolicyEngine {
+    /// Resolves a request made at local time `at` the same way the daemon
+    /// would, without asking the user.
+    pub async fn predict_outcome(&self, app_id: &AppId, uid: u32, permission: &PermissionType, at: NaiveDateTime) -> Result<Outcome> {
+        let outcome = match self.resolve_decision(app_id, uid, permission, at).await? {
+            Some(decision) if decision.is_ask() => Outcome::Prompted,
+            Some(decision) if decision.is_allow() => Outcome::Granted,
+            Some(_) => Outcome::Denied,
+            None => match self.default_action(uid, permission) {
+                DefaultAction::Allow => Outcome::Granted,
+                DefaultAction::Prompt => Outcome::Prompted,
+                DefaultAction::Deny => Outcome::Denied,
+            },
+        };
+        Ok(outcome)
+    }
+
+    /// Replays recorded requests against this engine's policies and reports
+    /// every request whose outcome would differ from what was recorded.
+    /// Scheduled decisions are evaluated at the time each request was made.
+    pub async fn simulate(&self, history: &[RecordedRequest]) -> Result<SimulationReport> {
+        let mut report = SimulationReport::default();
+
+        for request in history {
+            let at = Local.timestamp_opt(request.timestamp, 0)
+                .single()
+                .map(|time| time.naive_local())
+                .unwrap_or_default();
+            let after = self.predict_outcome(&request.app_id, request.uid, &request.permission, at).await?;
+            report.replayed += 1;
+
+            if after != request.outcome {
+                report.changes
+                    .entry(request.app_id.primary.clone())
+                    .or_default()
+                    .push(OutcomeChange {
+                        timestamp: request.timestamp,
+                        uid: request.uid,
+                        permission: request.permission.clone(),
+                        before: request.outcome,
+                        after,
+                    });
+            }
+        }
+
+        Ok(report)
+    }
+}
@@ -1,36 +1,92 @@
 
 use anyhow::Result;
-use apf_core::{app_id::AppId, types::{PermissionType, PromptDecision}};
+use apf_core::{app_id::AppId, types::{PermissionType, PolicyScope, PromptDecision}};
+use std::collections::HashMap;
 use std::path::Path;
 
-pub struct PolicyStorage;
+#[derive(Debug, Clone)]
+struct StoredDecision {
+    decision: PromptDecision,
+    expires_at: Option<i64>,
+}
+
+pub struct PolicyStorage {
+    // Keyed by `AppId::primary` and scope, matching the daemon database.
+    policies: HashMap<(String, PolicyScope), HashMap<PermissionType, StoredDecision>>,
+}
 
 impl PolicyStorage {
     pub fn new(_db_path: impl AsRef<Path>) -> Result<Self> {
-        Ok(Self)
+        Ok(Self::in_memory())
     }
 
-    pub async fn get_decision(&self, _app_id: &AppId, _permission: &PermissionType) -> Result<Option<PromptDecision>> {
-        Ok(None)
+    pub fn in_memory() -> Self {
+        Self { policies: HashMap::new() }
     }
 
-    pub async fn store_decision(&mut self, _app_id: &AppId, _permission: &PermissionType, _decision: PromptDecision) -> Result<()> {
+    pub async fn get_decision(&self, app_id: &AppId, scope: PolicyScope, permission: &PermissionType) -> Result<Option<PromptDecision>> {
+        let now = current_timestamp();
+        let decision = self.policies
+            .get(&(app_id.primary.clone(), scope))
+            .and_then(|app| app.get(permission))
+            .filter(|stored| stored.expires_at.is_none_or(|expiry| now <= expiry))
+            .map(|stored| stored.decision.clone());
+        Ok(decision)
+    }
+
+    pub async fn store_decision(&mut self, app_id: &AppId, scope: PolicyScope, permission: &PermissionType, decision: PromptDecision) -> Result<()> {
+        let expires_at = match &decision {
+            PromptDecision::AllowDuration(duration) => Some(current_timestamp() + duration.as_secs() as i64),
+            _ => None,
+        };
+        self.policies
+            .entry((app_id.primary.clone(), scope))
+            .or_default()
+            .insert(permission.clone(), StoredDecision { decision, expires_at });
         Ok(())
     }
 
-    pub async fn get_app_policies(&self, _app_id: &AppId) -> Result<Vec<(PermissionType, PromptDecision)>> {
-        Ok(Vec::new())
+    pub async fn get_app_policies(&self, app_id: &AppId, scope: PolicyScope) -> Result<Vec<(PermissionType, PromptDecision)>> {
+        let now = current_timestamp();
+        let policies = self.policies
+            .get(&(app_id.primary.clone(), scope))
+            .map(|app| {
+                app.iter()
+                    .filter(|(_, stored)| stored.expires_at.is_none_or(|expiry| now <= expiry))
+                    .map(|(permission, stored)| (permission.clone(), stored.decision.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(policies)
     }
 
-    pub async fn delete_policy(&mut self, _app_id: &AppId, _permission: &PermissionType) -> Result<()> {
+    pub async fn delete_policy(&mut self, app_id: &AppId, scope: PolicyScope, permission: &PermissionType) -> Result<()> {
+        if let Some(app) = self.policies.get_mut(&(app_id.primary.clone(), scope)) {
+            app.remove(permission);
+        }
         Ok(())
     }
 
-    pub async fn delete_app_policies(&mut self, _app_id: &AppId) -> Result<()> {
+    pub async fn delete_app_policies(&mut self, app_id: &AppId, scope: PolicyScope) -> Result<()> {
+        self.policies.remove(&(app_id.primary.clone(), scope));
         Ok(())
     }
 
     pub async fn cleanup_expired(&mut self) -> Result<usize> {
-        Ok(0)
+        let now = current_timestamp();
+        let mut count = 0;
+        for app in self.policies.values_mut() {
+            let before = app.len();
+            app.retain(|_, stored| stored.expires_at.is_none_or(|expiry| now <= expiry));
+            count += before - app.len();
+        }
+        Ok(count)
     }
 }
+
+fn current_timestamp() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
@@ -0,0 +1,69 @@
+use apf_core::app_id::AppId;
+use apf_core::types::{AccessMode, DeviceType, FilesystemAccess, NetworkLevel, PermissionType, PolicyScope, PromptDecision};
+use apf_policy::{draft_policy, PolicyEngine, PolicyStorage};
+
+fn fs(path: &str, mode: AccessMode) -> PermissionType {
+    PermissionType::Filesystem(FilesystemAccess { path: path.into(), mode })
+}
+
+/// Applies a draft for `observed` and asserts the engine then allows every
+/// permission that was observed.
+async fn assert_draft_allows(observed: &[PermissionType]) -> Vec<PermissionType> {
+    let app = AppId::from_desktop("org.example.Editor", false);
+    let draft = draft_policy(&app.primary, Some(1000), observed);
+    assert_eq!(draft.uid, Some(1000));
+    assert!(draft.rules.iter().all(|rule| rule.decision == PromptDecision::AllowAlways));
+
+    let mut engine = PolicyEngine::new(PolicyStorage::in_memory());
+    for rule in &draft.rules {
+        engine.store_decision(&app, PolicyScope::User(1000), &rule.permission, rule.decision.clone()).await.unwrap();
+    }
+    for permission in observed {
+        if matches!(permission, PermissionType::Network(NetworkLevel::None))
+            || matches!(permission, PermissionType::Filesystem(fs) if fs.mode == AccessMode::Deny)
+        {
+            continue;
+        }
+        assert_eq!(engine.evaluate_permission(&app, 1000, permission).await.unwrap(), Some(true), "{permission}");
+    }
+
+    draft.rules.into_iter().map(|rule| rule.permission).collect()
+}
+
+#[tokio::test]
+async fn test_draft_grants_every_observed_permission() {
+    let observed = vec![
+        PermissionType::Network(NetworkLevel::Lan),
+        fs("/home/user/Projects/app/src", AccessMode::ReadOnly),
+        PermissionType::Device(DeviceType::Camera),
+        fs("/home/user/Projects/app", AccessMode::ReadOnly),
+        PermissionType::Network(NetworkLevel::Internet),
+        PermissionType::Device(DeviceType::Camera),
+        fs("/home/user/Projects/app", AccessMode::ReadWrite),
+        fs("/home/user/Projects/app/src/main.rs", AccessMode::ReadWrite),
+        fs("/etc/hosts", AccessMode::Deny),
+        PermissionType::Network(NetworkLevel::None),
+    ];
+
+    let permissions = assert_draft_allows(&observed).await;
+    assert_eq!(permissions, vec![
+        PermissionType::Network(NetworkLevel::Lan),
+        fs("/home/user/Projects/app/src", AccessMode::ReadOnly),
+        PermissionType::Device(DeviceType::Camera),
+        fs("/home/user/Projects/app", AccessMode::ReadOnly),
+        PermissionType::Network(NetworkLevel::Internet),
+        fs("/home/user/Projects/app", AccessMode::ReadWrite),
+        fs("/home/user/Projects/app/src/main.rs", AccessMode::ReadWrite),
+    ]);
+}
+
+#[tokio::test]
+async fn test_draft_keeps_paths_below_granted_parent() {
+    let observed = vec![
+        fs("/srv/data", AccessMode::ReadOnly),
+        fs("/srv/data/cache", AccessMode::ReadWrite),
+        fs("/srv/data/input", AccessMode::ReadOnly),
+    ];
+
+    assert_eq!(assert_draft_allows(&observed).await, observed);
+}
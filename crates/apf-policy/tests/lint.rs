@@ -0,0 +1,229 @@
+use apf_core::app_id::AppOrigin;
+use apf_core::types::{AccessMode, DeviceType, FilesystemAccess, NetworkLevel, PermissionType, PolicyScope, PromptDecision};
+use apf_policy::{lint_app, lint_app_with_dirs, lint_policy_set, LintCode, PolicySet, ScopedRule, Severity, UserDirs};
+use std::path::PathBuf;
+
+fn fs(path: &str, mode: AccessMode) -> PermissionType {
+    PermissionType::Filesystem(FilesystemAccess { path: PathBuf::from(path), mode })
+}
+
+fn rule(scope: PolicyScope, permission: PermissionType, decision: PromptDecision) -> ScopedRule {
+    ScopedRule { scope, permission, decision }
+}
+
+fn codes(findings: &[apf_policy::LintFinding]) -> Vec<LintCode> {
+    findings.iter().map(|finding| finding.code).collect()
+}
+
+#[test]
+fn test_dangerous_filesystem_grants() {
+    let user = PolicyScope::User(1000);
+    let rules = vec![
+        rule(user, fs("/", AccessMode::ReadWrite), PromptDecision::AllowAlways),
+        rule(user, fs("/home/alice", AccessMode::ReadWrite), PromptDecision::AllowAlways),
+        rule(user, fs("/home/alice/.ssh/id_ed25519", AccessMode::ReadOnly), PromptDecision::AllowAlways),
+        rule(user, fs("/home/alice/Documents", AccessMode::ReadWrite), PromptDecision::DenyAlways),
+    ];
+
+    let findings = lint_app("org.example.Tool", Some(&AppOrigin::System), &rules);
+    let broad = findings.iter().filter(|f| f.code == LintCode::BroadWrite).count();
+    assert_eq!(broad, 2);
+    assert!(findings.iter().any(|f| f.code == LintCode::CredentialAccess && f.severity == Severity::Error));
+}
+
+#[test]
+fn test_dangerous_grants_through_placeholders() {
+    let user = PolicyScope::User(1000);
+    let rules: Vec<ScopedRule> = ["fs:$HOME?mode=rw", "fs:$HOME/.ssh?mode=ro", "fs:$XDG_RUNTIME_DIR/gnupg/S.gpg-agent?mode=rw"]
+        .iter()
+        .map(|uri| rule(user, uri.parse().unwrap(), PromptDecision::AllowAlways))
+        .collect();
+
+    let findings = lint_app("org.example.Tool", Some(&AppOrigin::System), &rules);
+    let found: Vec<(LintCode, String)> = findings.iter()
+        .filter(|f| f.code != LintCode::Conflict)
+        .map(|f| (f.code, f.rules[0].permission.to_string()))
+        .collect();
+    assert_eq!(found, vec![
+        (LintCode::BroadWrite, "fs:$HOME?mode=rw".to_string()),
+        (LintCode::CredentialAccess, "fs:$HOME/.ssh?mode=ro".to_string()),
+        (LintCode::CredentialAccess, "fs:$XDG_RUNTIME_DIR/gnupg/S.gpg-agent?mode=rw".to_string()),
+    ]);
+
+    let documents = rule(user, "fs:$XDG_DOCUMENTS_DIR?mode=rw".parse().unwrap(), PromptDecision::AllowAlways);
+    assert!(lint_app("org.example.Tool", Some(&AppOrigin::System), &[documents]).is_empty());
+}
+
+#[test]
+fn test_conflicting_and_independent_paths() {
+    let user = PolicyScope::User(1000);
+    let rules = vec![
+        rule(user, fs("/srv/data", AccessMode::ReadOnly), PromptDecision::AllowAlways),
+        rule(user, fs("/srv/data", AccessMode::ReadOnly), PromptDecision::DenyAlways),
+        rule(user, fs("/srv/data", AccessMode::ReadWrite), PromptDecision::DenyAlways),
+        rule(user, fs("/srv/data/reports", AccessMode::ReadOnly), PromptDecision::AllowAlways),
+        rule(user, fs("/srv/data/reports", AccessMode::ReadWrite), PromptDecision::DenyAlways),
+    ];
+
+    // Each mode and each path is decided by its own rule, so only the two
+    // read-only rules for /srv/data disagree.
+    let findings = lint_app("org.example.Tool", Some(&AppOrigin::System), &rules);
+    assert_eq!(codes(&findings), vec![LintCode::Conflict]);
+    assert_eq!(findings[0].rules[0].permission, fs("/srv/data", AccessMode::ReadOnly));
+}
+
+#[test]
+fn test_restriction_inside_broader_grant() {
+    let user = PolicyScope::User(1000);
+    let rules = vec![
+        rule(user, fs("/home/u", AccessMode::ReadWrite), PromptDecision::AllowAlways),
+        rule(user, fs("/home/u/.ssh", AccessMode::Deny), PromptDecision::AllowAlways),
+        // Denying writes still leaves reading, which the parent allows too.
+        rule(user, fs("/home/u/Music", AccessMode::ReadOnly), PromptDecision::AllowAlways),
+    ];
+
+    let findings = lint_app("org.example.Tool", Some(&AppOrigin::System), &rules);
+    let conflicts: Vec<_> = findings.iter().filter(|f| f.code == LintCode::Conflict).collect();
+    assert_eq!(conflicts.len(), 2);
+    assert!(conflicts.iter().all(|f| f.severity == Severity::Error));
+    assert_eq!(conflicts[0].rules[0].permission, fs("/home/u/.ssh", AccessMode::Deny));
+    assert_eq!(conflicts[1].rules[0].permission, fs("/home/u/Music", AccessMode::ReadOnly));
+
+    let writable = vec![
+        rule(user, fs("/srv/data", AccessMode::ReadOnly), PromptDecision::AllowAlways),
+        rule(user, fs("/srv/data/cache", AccessMode::ReadWrite), PromptDecision::AllowAlways),
+        rule(user, fs("/srv/data/input", AccessMode::ReadWrite), PromptDecision::DenyAlways),
+    ];
+    assert!(lint_app("org.example.Tool", Some(&AppOrigin::System), &writable).is_empty());
+}
+
+#[test]
+fn test_restriction_inside_placeholder_grant() {
+    let rules = vec![
+        rule(PolicyScope::System, "fs:$XDG_DOCUMENTS_DIR?mode=rw".parse().unwrap(), PromptDecision::AllowAlways),
+        rule(PolicyScope::User(1000), fs("/home/alice/Documents/private", AccessMode::Deny), PromptDecision::AllowAlways),
+    ];
+
+    let findings = lint_app_with_dirs("org.example.Tool", Some(&AppOrigin::System), &rules, |_| Some(UserDirs::new("/home/alice")));
+    assert_eq!(codes(&findings), vec![LintCode::Conflict]);
+    assert_eq!(findings[0].rules[1].scope, PolicyScope::System);
+
+    let elsewhere = lint_app_with_dirs("org.example.Tool", Some(&AppOrigin::System), &rules, |_| Some(UserDirs::new("/home/bob")));
+    assert!(elsewhere.is_empty());
+}
+
+#[test]
+fn test_destination_shadowed_by_system_network_level() {
+    let host: PermissionType = "net:host:api.x.com".parse().unwrap();
+    let rules = vec![
+        rule(PolicyScope::System, PermissionType::Network(NetworkLevel::Internet), PromptDecision::DenyAlways),
+        rule(PolicyScope::User(1000), host.clone(), PromptDecision::AllowAlways),
+    ];
+
+    let findings = lint_app("org.example.Chat", Some(&AppOrigin::Flatpak), &rules);
+    assert_eq!(codes(&findings), vec![LintCode::Shadowed]);
+    assert_eq!(findings[0].rules[0].permission, host);
+
+    // The LAN level only decides destinations on the local network.
+    let lan = vec![
+        rule(PolicyScope::System, PermissionType::Network(NetworkLevel::Lan), PromptDecision::DenyAlways),
+        rule(PolicyScope::User(1000), host, PromptDecision::AllowAlways),
+        rule(PolicyScope::User(1000), "net:cidr:192.168.1.0/24".parse().unwrap(), PromptDecision::AllowAlways),
+    ];
+    let findings = lint_app("org.example.Chat", Some(&AppOrigin::Flatpak), &lan);
+    assert_eq!(codes(&findings), vec![LintCode::Shadowed]);
+    assert_eq!(findings[0].rules[0].permission.to_string(), "net:cidr:192.168.1.0/24");
+}
+
+#[test]
+fn test_user_rule_shadowed_by_system_rule() {
+    let camera = PermissionType::Device(DeviceType::Camera);
+    let rules = vec![
+        rule(PolicyScope::System, camera.clone(), PromptDecision::DenyAlways),
+        rule(PolicyScope::User(1000), camera.clone(), PromptDecision::AllowAlways),
+        rule(PolicyScope::User(1001), camera, PromptDecision::AllowAlways),
+    ];
+
+    let findings = lint_app("org.example.Meet", Some(&AppOrigin::Flatpak), &rules);
+    assert_eq!(codes(&findings), vec![LintCode::Shadowed, LintCode::Shadowed]);
+    assert_eq!(findings[0].rules[0].scope, PolicyScope::User(1000));
+    assert_eq!(findings[1].rules[0].scope, PolicyScope::User(1001));
+}
+
+#[test]
+fn test_repeated_rules_in_one_scope() {
+    let user = PolicyScope::User(1000);
+    let clipboard = PermissionType::Clipboard;
+    let camera = PermissionType::Device(DeviceType::Camera);
+    let rules = vec![
+        rule(user, clipboard.clone(), PromptDecision::AllowAlways),
+        rule(user, clipboard, PromptDecision::DenyAlways),
+        rule(PolicyScope::System, camera.clone(), PromptDecision::DenyAlways),
+        rule(PolicyScope::System, camera, PromptDecision::DenyAlways),
+    ];
+
+    let findings = lint_app("org.example.Chat", Some(&AppOrigin::Flatpak), &rules);
+    assert_eq!(codes(&findings), vec![LintCode::Shadowed, LintCode::Conflict]);
+    assert_eq!(findings[0].rules[0].scope, PolicyScope::System);
+    assert_eq!(findings[1].severity, Severity::Error);
+    assert_eq!(findings[1].rules[0].permission, PermissionType::Clipboard);
+}
+
+#[test]
+fn test_capture_with_internet_depends_on_origin() {
+    let user = PolicyScope::User(1000);
+    let rules = vec![
+        rule(user, PermissionType::Network(NetworkLevel::Internet), PromptDecision::AllowAlways),
+        rule(user, PermissionType::Device(DeviceType::Microphone), PromptDecision::AllowAlways),
+    ];
+
+    let unknown = lint_app("recorder", Some(&AppOrigin::User), &rules);
+    assert_eq!(codes(&unknown), vec![LintCode::CaptureExfiltration]);
+
+    let packaged = lint_app("org.example.Recorder", Some(&AppOrigin::Flatpak), &rules);
+    assert!(packaged.is_empty());
+
+    let host = vec![
+        rule(user, "net:host:*.example.com".parse().unwrap(), PromptDecision::AllowAlways),
+        rule(user, PermissionType::Device(DeviceType::Camera), PromptDecision::AllowAlways),
+    ];
+    assert_eq!(codes(&lint_app("recorder", Some(&AppOrigin::User), &host)), vec![LintCode::CaptureExfiltration]);
+}
+
+#[test]
+fn test_lint_policy_file() {
+    let set = PolicySet::from_toml(r#"
+[[app]]
+id = "org.example.Chat"
+
+[[app.rule]]
+permission = "clipboard"
+decision = "AllowAlways"
+
+[[app.rule]]
+permission = "clipboard"
+decision = "DenyAlways"
+"#).unwrap();
+
+    let findings = lint_policy_set(&set);
+    assert_eq!(codes(&findings), vec![LintCode::Conflict]);
+    assert_eq!(findings[0].app_id, "org.example.Chat");
+}
+
+#[test]
+fn test_lan_denied_with_internet_allowed_is_clean() {
+    let set = PolicySet::from_toml(r#"
+[[app]]
+id = "org.example.Chat"
+
+[[app.rule]]
+permission = { Network = "Internet" }
+decision = "AllowAlways"
+
+[[app.rule]]
+permission = { Network = "Lan" }
+decision = "DenyAlways"
+"#).unwrap();
+
+    assert!(lint_policy_set(&set).is_empty());
+}
@@ -0,0 +1,52 @@
+use apf_core::app_id::AppId;
+use apf_core::types::{PermissionType, PolicyScope, PromptDecision};
+use apf_policy::{PolicyEngine, PolicyStorage};
+
+fn perm(uri: &str) -> PermissionType {
+    uri.parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_chat_app_reaches_its_own_servers_only() {
+    let app = AppId::from_desktop("org.example.Chat", false);
+    let mut storage = PolicyStorage::in_memory();
+    let rules = [
+        ("net:host:*.example-chat.com?port=443&proto=tcp", PromptDecision::AllowAlways),
+        ("net:host:ads.example-chat.com", PromptDecision::DenyAlways),
+        ("net:internet", PromptDecision::DenyAlways),
+    ];
+    for (uri, decision) in rules {
+        storage.store_decision(&app, PolicyScope::User(1000), &perm(uri), decision).await.unwrap();
+    }
+    let engine = PolicyEngine::new(storage);
+
+    let cases = [
+        ("net:host:eu.example-chat.com?port=443&proto=tcp", Some(true)),
+        ("net:host:ads.example-chat.com?port=443&proto=tcp", Some(false)),
+        ("net:host:eu.example-chat.com?port=80&proto=tcp", Some(false)),
+        ("net:host:tracker.example.net?port=443&proto=tcp", Some(false)),
+        ("net:cidr:192.168.1.20?port=631&proto=tcp", Some(false)),
+    ];
+    for (uri, expected) in cases {
+        assert_eq!(engine.evaluate_permission(&app, 1000, &perm(uri)).await.unwrap(), expected, "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn test_lan_destinations_fall_back_to_lan_level() {
+    let app = AppId::from_desktop("org.example.Player", false);
+    let mut storage = PolicyStorage::in_memory();
+    storage.store_decision(&app, PolicyScope::User(1000), &perm("net:lan"), PromptDecision::AllowAlways).await.unwrap();
+    storage.store_decision(&app, PolicyScope::System, &perm("net:cidr:10.9.0.0/16"), PromptDecision::DenyAlways).await.unwrap();
+    let engine = PolicyEngine::new(storage);
+
+    let cases = [
+        ("net:cidr:192.168.1.20?port=8009&proto=tcp", Some(true)),
+        ("net:cidr:10.9.1.1?port=8009&proto=tcp", Some(false)),
+        ("net:cidr:1.1.1.1?port=443&proto=tcp", None),
+        ("net:host:media.example.com?port=443&proto=tcp", None),
+    ];
+    for (uri, expected) in cases {
+        assert_eq!(engine.evaluate_permission(&app, 1000, &perm(uri)).await.unwrap(), expected, "{}", uri);
+    }
+}
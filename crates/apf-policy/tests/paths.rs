@@ -0,0 +1,33 @@
+use apf_core::app_id::AppId;
+use apf_core::types::{AccessMode, FilesystemAccess, PermissionType, PolicyScope, PromptDecision};
+use apf_policy::{PolicyEngine, PolicyStorage, UserDirs};
+
+fn fs(path: impl Into<std::path::PathBuf>, mode: AccessMode) -> PermissionType {
+    PermissionType::Filesystem(FilesystemAccess { path: path.into(), mode })
+}
+
+#[tokio::test]
+async fn test_placeholder_rules_resolve_for_requesting_user() {
+    let uid = nix::unistd::getuid().as_raw();
+    let dirs = UserDirs::for_uid(uid).unwrap();
+    let app = AppId::from_desktop("org.example.Notes", false);
+
+    let mut storage = PolicyStorage::in_memory();
+    storage.store_decision(&app, PolicyScope::System, &fs("$HOME/.ssh", AccessMode::ReadOnly), PromptDecision::DenyAlways).await.unwrap();
+    storage.store_decision(&app, PolicyScope::User(uid), &fs("$APP_DATA", AccessMode::ReadWrite), PromptDecision::AllowAlways).await.unwrap();
+    storage.store_decision(&app, PolicyScope::User(uid), &fs("$XDG_RUNTIME_DIR/notes.sock", AccessMode::ReadWrite), PromptDecision::AllowAlways).await.unwrap();
+    let engine = PolicyEngine::new(storage);
+
+    let cases = [
+        (fs(dirs.home.join(".ssh"), AccessMode::ReadOnly), Some(false)),
+        (fs(dirs.home.join(".ssh"), AccessMode::ReadWrite), None),
+        (fs(dirs.data.join("org.example.Notes"), AccessMode::ReadWrite), Some(true)),
+        (fs(format!("/run/user/{}/notes.sock", uid), AccessMode::ReadWrite), Some(true)),
+    ];
+    for (permission, expected) in cases {
+        assert_eq!(engine.evaluate_permission(&app, uid, &permission).await.unwrap(), expected, "{}", permission);
+    }
+
+    let other = AppId::from_desktop("org.example.Other", false);
+    assert_eq!(engine.evaluate_permission(&other, uid, &fs(dirs.home.join(".ssh"), AccessMode::ReadOnly)).await.unwrap(), None);
+}
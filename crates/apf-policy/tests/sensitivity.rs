@@ -0,0 +1,94 @@
+use apf_core::types::{AccessMode, DeviceType, FilesystemAccess, NetworkLevel, PermissionType};
+use apf_policy::{DefaultAction, SensitivityClassifier, UserDirs};
+
+fn fs(path: &str, mode: AccessMode) -> PermissionType {
+    PermissionType::Filesystem(FilesystemAccess { path: path.into(), mode })
+}
+
+#[test]
+fn test_builtin_classification() {
+    let classifier = SensitivityClassifier::default();
+    let dirs = UserDirs::new("/home/alice");
+    let classify = |permission: PermissionType| classifier.classify_for(&permission, Some(&dirs));
+
+    assert_eq!(classify(PermissionType::Network(NetworkLevel::Internet)), DefaultAction::Prompt);
+    assert_eq!(classify(PermissionType::Network(NetworkLevel::None)), DefaultAction::Allow);
+    assert_eq!(classify(PermissionType::Device(DeviceType::Usb)), DefaultAction::Prompt);
+    assert_eq!(classify(fs("/home/alice/.ssh/id_ed25519", AccessMode::ReadOnly)), DefaultAction::Prompt);
+    assert_eq!(classify(fs("/home/alice/Documents/cv.odt", AccessMode::ReadWrite)), DefaultAction::Prompt);
+    assert_eq!(classify(fs("/home/alice/src", AccessMode::ReadOnly)), DefaultAction::Prompt);
+    assert_eq!(classify(fs("/usr/share/fonts", AccessMode::ReadOnly)), DefaultAction::Allow);
+    assert_eq!(classify(fs("/usr/share/fonts", AccessMode::ReadWrite)), DefaultAction::Deny);
+    assert_eq!(classify(fs("/srv/www", AccessMode::ReadOnly)), DefaultAction::Deny);
+}
+
+#[test]
+fn test_configured_rules_match_in_order() {
+    let classifier = SensitivityClassifier::from_toml(r#"
+        default = "prompt"
+
+        [[rule]]
+        class = "fs"
+        path = "dotfiles"
+        action = "deny"
+
+        [[rule]]
+        class = "fs"
+        path = "xdg:download"
+        mode = "ReadOnly"
+        action = "allow"
+
+        [[rule]]
+        class = "device:camera"
+        action = "deny"
+    "#).unwrap();
+    let dirs = UserDirs::new("/home/bob");
+
+    assert_eq!(classifier.classify_for(&fs("/home/bob/.gnupg", AccessMode::ReadOnly), Some(&dirs)), DefaultAction::Deny);
+    assert_eq!(classifier.classify_for(&fs("/home/bob/Downloads/a.iso", AccessMode::ReadOnly), Some(&dirs)), DefaultAction::Allow);
+    assert_eq!(classifier.classify_for(&fs("/home/bob/Downloads/a.iso", AccessMode::ReadWrite), Some(&dirs)), DefaultAction::Prompt);
+    assert_eq!(classifier.classify_for(&PermissionType::Device(DeviceType::Camera), None), DefaultAction::Deny);
+    assert_eq!(classifier.classify_for(&PermissionType::Device(DeviceType::Microphone), None), DefaultAction::Prompt);
+    // Home-relative rules cannot match without the user's directories.
+    assert_eq!(classifier.classify_for(&fs("/home/bob/.gnupg", AccessMode::ReadOnly), None), DefaultAction::Prompt);
+}
+
+#[test]
+fn test_invalid_rules_are_rejected() {
+    assert!(SensitivityClassifier::from_toml("default = \"deny\"\n[[rule]]\nclass = \"printer\"\naction = \"allow\"").is_err());
+    assert!(SensitivityClassifier::from_toml("default = \"deny\"\n[[rule]]\nclass = \"clipboard\"\npath = \"/tmp\"\naction = \"allow\"").is_err());
+    assert!(SensitivityClassifier::from_toml("default = \"deny\"\n[[rule]]\nclass = \"fs\"\npath = \"xdg:games\"\naction = \"allow\"").is_err());
+}
+
+#[test]
+fn test_user_dirs_file_and_placeholders() {
+    use apf_core::{app_id::AppId, types::PathVar};
+    use std::path::PathBuf;
+
+    let mut dirs = UserDirs::new("/home/carol");
+    dirs.apply_user_dirs(r#"
+# This file is written by xdg-user-dirs-update
+XDG_DESKTOP_DIR="$HOME/Schreibtisch"
+XDG_DOCUMENTS_DIR="$HOME/Dokumente"
+XDG_DOWNLOAD_DIR="/home/carol/Netz"
+XDG_MUSIC_DIR="$HOMEmusic"
+XDG_PICTURES_DIR="Bilder"
+XDG_TEMPLATES_DIR="$HOME/Vorlagen"
+XDG_VIDEOS_DIR="/etc"
+"#);
+    // Nothing outside the home directory, however it is spelled.
+    dirs.apply_user_dirs("XDG_MUSIC_DIR=\"$HOME/../..\"\nXDG_PICTURES_DIR=\"/home/carol/../../etc\"\n");
+
+    assert_eq!(dirs.desktop, PathBuf::from("/home/carol/Schreibtisch"));
+    assert_eq!(dirs.documents, PathBuf::from("/home/carol/Dokumente"));
+    assert_eq!(dirs.download, PathBuf::from("/home/carol/Netz"));
+    assert_eq!(dirs.music, PathBuf::from("/home/carol/Music"));
+    assert_eq!(dirs.pictures, PathBuf::from("/home/carol/Pictures"));
+    assert_eq!(dirs.videos, PathBuf::from("/home/carol/Videos"));
+
+    let app = AppId::from_desktop("org.example.Notes", false);
+    assert_eq!(dirs.path_var(PathVar::AppData, &app), Some(PathBuf::from("/home/carol/.local/share/org.example.Notes")));
+    assert_eq!(dirs.path_var(PathVar::Runtime, &app), None);
+    let resolved = dirs.resolve(&FilesystemAccess { path: "$XDG_DOCUMENTS_DIR/a".into(), mode: AccessMode::ReadOnly }, &app);
+    assert_eq!(resolved.map(|fs| fs.path), Some(PathBuf::from("/home/carol/Dokumente/a")));
+}
@@ -0,0 +1,108 @@
+use apf_core::app_id::AppId;
+use apf_core::types::{DeviceType, NetworkLevel, PermissionType, PolicyScope, PromptDecision};
+use apf_policy::{Outcome, PolicyEngine, PolicySet, PolicyStorage, RecordedRequest};
+
+const CANDIDATE: &str = r#"
+[[app]]
+id = "org.example.Chat"
+uid = 1000
+
+[[app.rule]]
+permission = "net:internet"
+decision = "DenyAlways"
+
+[[app.rule]]
+permission = "Clipboard"
+decision = "AllowAlways"
+"#;
+
+#[test]
+fn test_policy_set_from_toml() {
+    let set = PolicySet::from_toml(CANDIDATE).unwrap();
+    let app = set.app("org.example.Chat", PolicyScope::User(1000)).unwrap();
+    assert_eq!(app.rules.len(), 2);
+    assert_eq!(app.rules[0].permission, PermissionType::Network(NetworkLevel::Internet));
+    assert_eq!(app.rules[0].decision, PromptDecision::DenyAlways);
+    assert_eq!(app.rules[1].permission, PermissionType::Clipboard);
+
+    let written = toml::to_string(&set).unwrap();
+    assert!(written.contains("permission = \"clipboard\""));
+    assert!(PolicySet::from_toml("[[app]]\nid = \"x\"\n[[app.rule]]\npermission = \"net:wan\"\ndecision = \"Ask\"").is_err());
+}
+
+#[tokio::test]
+async fn test_simulate_reports_changed_outcomes() {
+    let set = PolicySet::from_toml(CANDIDATE).unwrap();
+    let app = AppId::from_desktop("org.example.Chat", false);
+
+    let mut storage = PolicyStorage::in_memory();
+    for rule in &set.app(&app.primary, PolicyScope::User(1000)).unwrap().rules {
+        storage.store_decision(&app, PolicyScope::User(1000), &rule.permission, rule.decision.clone()).await.unwrap();
+    }
+    let engine = PolicyEngine::new(storage);
+
+    let history = vec![
+        RecordedRequest::from_audit(1, app.clone(), 1000, PermissionType::Network(NetworkLevel::Internet), true, false),
+        RecordedRequest::from_audit(2, app.clone(), 1000, PermissionType::Clipboard, true, true),
+        RecordedRequest::from_audit(3, app.clone(), 1000, PermissionType::Device(DeviceType::Camera), false, true),
+    ];
+
+    let report = engine.simulate(&history).await.unwrap();
+    assert_eq!(report.replayed, 3);
+    assert_eq!(report.changed_count(), 2);
+
+    let changes = &report.changes[&app.primary];
+    assert_eq!(changes[0].before, Outcome::Granted);
+    assert_eq!(changes[0].after, Outcome::Denied);
+    assert_eq!(changes[1].before, Outcome::Prompted);
+    assert_eq!(changes[1].after, Outcome::Granted);
+}
+
+#[tokio::test]
+async fn test_system_scope_takes_precedence() {
+    let app = AppId::from_desktop("org.example.Chat", false);
+    let camera = PermissionType::Device(DeviceType::Camera);
+
+    let mut storage = PolicyStorage::in_memory();
+    storage.store_decision(&app, PolicyScope::User(1000), &camera, PromptDecision::AllowAlways).await.unwrap();
+    storage.store_decision(&app, PolicyScope::System, &camera, PromptDecision::DenyAlways).await.unwrap();
+    let engine = PolicyEngine::new(storage);
+
+    assert_eq!(engine.evaluate_permission(&app, 1000, &camera).await.unwrap(), Some(false));
+    assert_eq!(engine.evaluate_permission(&app, 1001, &camera).await.unwrap(), Some(false));
+}
+
+#[tokio::test]
+async fn test_user_scope_is_per_uid() {
+    let app = AppId::from_desktop("org.example.Chat", false);
+    let camera = PermissionType::Device(DeviceType::Camera);
+
+    let mut storage = PolicyStorage::in_memory();
+    storage.store_decision(&app, PolicyScope::User(1000), &camera, PromptDecision::AllowAlways).await.unwrap();
+    let engine = PolicyEngine::new(storage);
+
+    assert_eq!(engine.evaluate_permission(&app, 1000, &camera).await.unwrap(), Some(true));
+    assert_eq!(engine.evaluate_permission(&app, 1001, &camera).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_ask_prompts_even_when_default_allows() {
+    let app = AppId::from_desktop("org.example.Chat", false);
+    let offline = PermissionType::Network(NetworkLevel::None);
+
+    let mut storage = PolicyStorage::in_memory();
+    storage.store_decision(&app, PolicyScope::User(1000), &offline, PromptDecision::Ask).await.unwrap();
+    let engine = PolicyEngine::new(storage);
+
+    assert!(engine.should_prompt(&app, 1000, &offline).await.unwrap());
+    assert_eq!(engine.evaluate_permission(&app, 1000, &offline).await.unwrap(), None);
+    assert!(!engine.should_prompt(&app, 1001, &offline).await.unwrap());
+
+    let history = vec![
+        RecordedRequest::from_audit(1, app.clone(), 1000, offline.clone(), true, false),
+        RecordedRequest::from_audit(2, app.clone(), 1001, offline.clone(), true, false),
+    ];
+    let report = engine.simulate(&history).await.unwrap();
+    assert_eq!(report.changed_count(), 1);
+    assert_eq!(report.changes[&app.primary][0].after, Outcome::Prompted);
+}